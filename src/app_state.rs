@@ -1,9 +1,25 @@
-use std::sync::{atomic::AtomicBool, Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize},
+        Arc,
+    },
+};
 use tokio::sync::{broadcast, Mutex};
 
 use crate::{
-    configuration::Config, controlled_program::ControlledProgramInstance, master::SlaveConnection,
-    messages::ServerInfo, specializations::SpecializationRegistry,
+    audit::AuditLog,
+    auth::{LoginThrottle, SessionStore},
+    configuration::Config,
+    console_history::ConsoleHistory,
+    controlled_program::ControlledProgramInstance,
+    master::SlaveLink,
+    messages::ServerInfo,
+    metrics::ProcessMetrics,
+    restart_policy::RestartTracker,
+    scheduler::Scheduler,
+    slave::SlaveEventFeed,
+    specializations::SpecializationRegistry,
 };
 
 /// Shared application state for the server controller.
@@ -22,13 +38,36 @@ pub struct AppState {
     pub config: Arc<Mutex<Config>>,
     /// List of servers running on slave nodes.
     pub slave_servers: Arc<Mutex<Vec<ServerInfo>>>,
-    /// List of slave node connections.
-    pub slave_connections: Arc<Mutex<Vec<SlaveConnection>>>,
+    /// Links to slave nodes and their latest health, keyed by `address:port`.
+    pub slave_links: Arc<Mutex<HashMap<String, SlaveLink>>>,
+    /// Events about this node's servers, for the masters connected to it.
+    pub slave_events: SlaveEventFeed,
     /// Global crash prevention flag.
     #[allow(dead_code)]
     pub global_crash_prevention: Arc<AtomicBool>,
     /// Registry of available server specializations.
     pub specialization_registry: Arc<SpecializationRegistry>,
+    /// Open console history files, written by the supervisor loop.
+    pub console_history: Arc<Mutex<ConsoleHistory>>,
+    /// Crash history and pending restarts, keyed by server name.
+    pub restart_trackers: Arc<Mutex<HashMap<String, RestartTracker>>>,
+    /// Runtime state of scheduled tasks.
+    pub scheduler: Arc<Mutex<Scheduler>>,
+    /// Servers with a backup or restore currently running.
+    pub backups_in_progress: Arc<Mutex<HashSet<String>>>,
+    /// Servers taken out of `servers` that are still shutting down, so they can't be started
+    /// again until their process has exited.
+    pub stopping: Arc<Mutex<HashSet<String>>>,
+    /// Logged-in web UI and API sessions.
+    pub sessions: Arc<Mutex<SessionStore>>,
+    /// Failed logins by address.
+    pub login_throttle: Arc<Mutex<LoginThrottle>>,
+    /// The open audit log file.
+    pub audit_log: Arc<Mutex<AuditLog>>,
+    /// The latest resource usage of each running server, keyed by server name.
+    pub metrics: Arc<Mutex<HashMap<String, ProcessMetrics>>>,
+    /// Number of open websocket connections.
+    pub websocket_clients: Arc<AtomicUsize>,
 }
 impl AppState {
     /// Creates a new AppState instance.
@@ -48,9 +87,20 @@ impl AppState {
             running: Arc::new(AtomicBool::new(true)),
             config: Arc::new(Mutex::new(config)),
             slave_servers: Arc::new(Mutex::new(vec![])),
-            slave_connections: Arc::new(Mutex::new(vec![])),
+            slave_links: Arc::new(Mutex::new(HashMap::new())),
+            slave_events: SlaveEventFeed::new(),
             global_crash_prevention: Arc::new(AtomicBool::new(true)),
             specialization_registry,
+            console_history: Arc::new(Mutex::new(ConsoleHistory::default())),
+            restart_trackers: Arc::new(Mutex::new(HashMap::new())),
+            scheduler: Arc::new(Mutex::new(Scheduler::default())),
+            backups_in_progress: Arc::new(Mutex::new(HashSet::new())),
+            stopping: Arc::new(Mutex::new(HashSet::new())),
+            sessions: Arc::new(Mutex::new(SessionStore::default())),
+            login_throttle: Arc::new(Mutex::new(LoginThrottle::default())),
+            audit_log: Arc::new(Mutex::new(AuditLog::default())),
+            metrics: Arc::new(Mutex::new(HashMap::new())),
+            websocket_clients: Arc::new(AtomicUsize::new(0)),
         }
     }
 
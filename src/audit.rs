@@ -0,0 +1,293 @@
+/// Append-only audit log of control actions.
+///
+/// Every action a client takes on a server or the configuration is appended to the audit file as
+/// one JSON object per line, recording when it happened, who did it and from where. The file is
+/// kept apart from the tracing logs and is never rewritten by the controller.
+use chrono::Local;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+use tracing::*;
+
+use crate::{
+    app_state::AppState, environment::REDACTED, files::lines_backwards, permissions::Access,
+};
+
+/// Largest page of audit entries a client can request at once.
+pub const MAX_PAGE_ENTRIES: usize = 500;
+
+/// Number of entries returned when a request does not set a limit.
+pub const DEFAULT_PAGE_ENTRIES: usize = 100;
+
+/// Longest payload summary kept in an entry, in characters.
+const MAX_SUMMARY_CHARS: usize = 200;
+
+/// Audit log settings, stored in `Config`.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct AuditSettings {
+    /// Whether control actions are recorded at all.
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// The file entries are appended to.
+    #[serde(default = "default_file")]
+    pub file: String,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_file() -> String {
+    "logs/audit.log".to_string()
+}
+
+impl Default for AuditSettings {
+    fn default() -> Self {
+        Self {
+            enabled: default_enabled(),
+            file: default_file(),
+        }
+    }
+}
+
+/// One recorded action.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AuditEntry {
+    /// RFC 3339 timestamp of the action.
+    pub timestamp: String,
+    /// The logged-in user, or `"anonymous"` when logging in is turned off.
+    pub user: String,
+    /// The address the request came from.
+    pub address: String,
+    /// What was done, such as `"stdin"`, `"start"` or `"config-change"`.
+    pub action: String,
+    /// The server acted on, if the action concerns a single server.
+    #[serde(default)]
+    pub server_name: Option<String>,
+    /// A short description of the payload, such as the command sent.
+    #[serde(default)]
+    pub summary: String,
+}
+
+impl AuditEntry {
+    /// Starts an entry for an action taken now.
+    ///
+    /// # Arguments
+    /// * `access` - The client taking the action.
+    /// * `address` - Where the client connected from.
+    /// * `action` - What was done.
+    pub fn new(access: &Access, address: SocketAddr, action: &str) -> Self {
+        Self {
+            timestamp: Local::now().to_rfc3339(),
+            user: access.username().to_string(),
+            address: address.to_string(),
+            action: action.to_string(),
+            server_name: None,
+            summary: String::new(),
+        }
+    }
+
+    /// Sets the server the action concerns.
+    pub fn on(mut self, server_name: &str) -> Self {
+        self.server_name = Some(server_name.to_string());
+        self
+    }
+
+    /// Sets the payload summary, cutting it short if it is long.
+    pub fn summary(mut self, summary: impl Into<String>) -> Self {
+        let summary: String = summary.into();
+        self.summary = if summary.chars().count() > MAX_SUMMARY_CHARS {
+            summary.chars().take(MAX_SUMMARY_CHARS).collect::<String>() + "..."
+        } else {
+            summary
+        };
+        self
+    }
+
+    /// Sets the summary of a console command. Only the command itself is kept, as its
+    /// arguments may be passwords, such as those of `login` or RCON commands.
+    pub fn command(self, line: &str) -> Self {
+        let line = line.trim();
+        match line.split_once(char::is_whitespace) {
+            Some((command, _)) => self.summary(format!("{} {}", command, REDACTED)),
+            None => self.summary(line),
+        }
+    }
+
+    fn matches(&self, query: &AuditQuery) -> bool {
+        let matches = |filter: &Option<String>, value: Option<&str>| {
+            filter
+                .as_deref()
+                .filter(|filter| !filter.is_empty())
+                .is_none_or(|filter| value == Some(filter))
+        };
+        matches(&query.user, Some(&self.user))
+            && matches(&query.action, Some(&self.action))
+            && matches(&query.server_name, self.server_name.as_deref())
+    }
+}
+
+/// Which entries to read back, and how many.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct AuditQuery {
+    /// Only entries older than this cursor, the `next_before` of the previous page. Unlike
+    /// `offset`, it keeps pointing at the same entry while new ones are appended.
+    #[serde(default)]
+    pub before: Option<u64>,
+    /// How many matching entries, counted from the newest (or from `before`), to skip.
+    #[serde(default)]
+    pub offset: usize,
+    /// Maximum number of entries to return.
+    #[serde(default)]
+    pub limit: Option<usize>,
+    /// Only entries by this user.
+    #[serde(default)]
+    pub user: Option<String>,
+    /// Only entries concerning this server.
+    #[serde(default)]
+    pub server_name: Option<String>,
+    /// Only entries of this action.
+    #[serde(default)]
+    pub action: Option<String>,
+}
+
+/// One page of the audit log, newest entry first.
+pub struct AuditPage {
+    /// The entries of this page.
+    pub entries: Vec<AuditEntry>,
+    /// Whether older matching entries exist beyond this page.
+    pub has_more: bool,
+    /// The `before` cursor of the next, older page, if there is one.
+    pub next_before: Option<u64>,
+}
+
+/// Writer side of the audit log, keeping the file open between entries.
+#[derive(Default)]
+pub struct AuditLog {
+    file: Option<(PathBuf, File)>,
+}
+
+impl AuditLog {
+    /// Appends an entry to the audit file.
+    ///
+    /// # Arguments
+    /// * `settings` - Current audit settings.
+    /// * `entry` - The action to record.
+    pub fn append(&mut self, settings: &AuditSettings, entry: &AuditEntry) -> io::Result<()> {
+        if !settings.enabled {
+            return Ok(());
+        }
+        let path = PathBuf::from(&settings.file);
+        // Settings may have moved the file since it was opened
+        if self.file.as_ref().is_some_and(|(open, _)| *open != path) {
+            self.file = None;
+        }
+        if self.file.is_none() {
+            self.file = Some((path.clone(), open_audit_file(&path)?));
+        }
+        let mut line = serde_json::to_string(entry).map_err(io::Error::other)?;
+        line.push('\n');
+        let (_, file) = self.file.as_mut().unwrap();
+        file.write_all(line.as_bytes())?;
+        file.flush()
+    }
+}
+
+fn open_audit_file(path: &Path) -> io::Result<File> {
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        fs::create_dir_all(parent)?;
+    }
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Records an action in the audit log, logging a warning if it can't be written.
+///
+/// # Arguments
+/// * `state` - The shared application state.
+/// * `entry` - The action to record.
+pub async fn record(state: &AppState, entry: AuditEntry) {
+    let settings = state.config.lock().await.audit.clone();
+    if let Err(e) = state.audit_log.lock().await.append(&settings, &entry) {
+        warn!("Could not write audit log entry: {}", e);
+    }
+}
+
+/// Reads one page of the audit log, counting back from the newest entry.
+///
+/// The file is read from its end, so recent pages stay quick however long the log grows.
+/// Blocking; call it from `spawn_blocking`.
+///
+/// # Arguments
+/// * `settings` - Current audit settings.
+/// * `query` - Which entries to return.
+pub fn read_page(settings: &AuditSettings, query: &AuditQuery) -> io::Result<AuditPage> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_ENTRIES)
+        .clamp(1, MAX_PAGE_ENTRIES);
+    let mut file = match File::open(&settings.file) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Ok(AuditPage {
+                entries: vec![],
+                has_more: false,
+                next_before: None,
+            })
+        }
+        Err(e) => return Err(e),
+    };
+    let length = file.metadata()?.len();
+    let end = query.before.map_or(length, |before| before.min(length));
+    let mut entries = vec![];
+    let mut skipped = 0;
+    let mut has_more = false;
+    let mut oldest = None;
+    lines_backwards(&mut file, end, |start, line| {
+        let Ok(entry) = serde_json::from_str::<AuditEntry>(line) else {
+            return true;
+        };
+        if !entry.matches(query) {
+            return true;
+        }
+        if skipped < query.offset {
+            skipped += 1;
+            return true;
+        }
+        if entries.len() == limit {
+            has_more = true;
+            return false;
+        }
+        entries.push(entry);
+        oldest = Some(start);
+        true
+    })?;
+    Ok(AuditPage {
+        entries,
+        has_more,
+        next_before: oldest.filter(|_| has_more),
+    })
+}
+
+/// Reads a page of the audit log without blocking the runtime.
+///
+/// # Arguments
+/// * `state` - The shared application state.
+/// * `query` - Which entries to return.
+pub async fn query(state: &AppState, query: AuditQuery) -> Result<AuditPage, String> {
+    let settings = state.config.lock().await.audit.clone();
+    tokio::task::spawn_blocking(move || read_page(&settings, &query))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| {
+            warn!("Could not read audit log: {}", e);
+            e.to_string()
+        })
+}
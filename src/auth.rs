@@ -0,0 +1,651 @@
+/// User accounts, login sessions and the authentication check for the web server.
+///
+/// Accounts live in a JSON users file with argon2 password hashes. Logging in through
+/// `/api/login` issues a random session token, which the browser keeps as a cookie and scripts
+/// send as a bearer token (or a `token` query parameter for websockets). Every other route
+/// of the web server rejects requests without a valid session. An address that keeps failing
+/// to log in has to wait longer and longer between attempts.
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use axum::{
+    extract::{rejection::JsonRejection, ConnectInfo, Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Redirect, Response},
+    Json,
+};
+use chrono::{DateTime, Local};
+use rand::Rng;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs, io,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+use tracing::*;
+
+use crate::{
+    app_state::AppState,
+    configuration::Config,
+    permissions::{self, Access, Role},
+    prometheus,
+};
+
+/// Name of the cookie holding the session token.
+const SESSION_COOKIE: &str = "rsc_session";
+
+/// Name of the account created when the users file is empty.
+const BOOTSTRAP_USERNAME: &str = "admin";
+
+/// Routes that can be reached without logging in.
+const PUBLIC_PATHS: [&str; 3] = ["/login", "/api/login", "/favicon.ico"];
+
+/// Failed logins an address may make before it has to wait between attempts.
+const FREE_LOGIN_FAILURES: u32 = 5;
+
+/// Longest an address is made to wait between login attempts.
+const MAX_LOGIN_BACKOFF: Duration = Duration::from_secs(300);
+
+/// How long the failed logins of an address are remembered after its last attempt.
+const LOGIN_FAILURE_MEMORY: Duration = Duration::from_secs(15 * 60);
+
+/// Authentication settings, stored in the `auth` object of the configuration.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct AuthSettings {
+    /// Whether the web UI, websocket and API require logging in.
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// JSON file holding the user accounts.
+    #[serde(default = "default_users_file")]
+    pub users_file: String,
+    /// How long a login stays valid.
+    #[serde(default = "default_session_ttl_secs")]
+    pub session_ttl_secs: u64,
+    /// Roles that can be given to users, by name.
+    #[serde(default = "permissions::default_roles")]
+    pub roles: BTreeMap<String, Role>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_users_file() -> String {
+    "users.json".to_string()
+}
+
+fn default_session_ttl_secs() -> u64 {
+    24 * 60 * 60
+}
+
+impl Default for AuthSettings {
+    fn default() -> Self {
+        Self {
+            enabled: default_enabled(),
+            users_file: default_users_file(),
+            session_ttl_secs: default_session_ttl_secs(),
+            roles: permissions::default_roles(),
+        }
+    }
+}
+
+/// A user account as stored in the users file.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UserAccount {
+    /// Login name.
+    pub username: String,
+    /// Argon2 hash of the password in PHC string format.
+    pub password_hash: String,
+    /// Names of the roles the user has. Accounts written before roles existed are admins.
+    #[serde(default = "default_user_roles")]
+    pub roles: Vec<String>,
+}
+
+fn default_user_roles() -> Vec<String> {
+    vec!["admin".to_string()]
+}
+
+/// Layout of the users file.
+#[derive(Serialize, Deserialize, Default)]
+struct UsersFile {
+    users: Vec<UserAccount>,
+}
+
+/// Reads the user accounts, treating a missing file as having none.
+///
+/// # Arguments
+/// * `path` - Path of the users file.
+pub fn load_users(path: &str) -> io::Result<Vec<UserAccount>> {
+    let data = match fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let file: UsersFile = serde_json::from_str(&data).map_err(io::Error::other)?;
+    Ok(file.users)
+}
+
+/// Writes the user accounts, replacing the whole file.
+fn save_users(path: &str, users: Vec<UserAccount>) -> io::Result<()> {
+    if let Some(parent) = Path::new(path).parent() {
+        if !parent.as_os_str().is_empty() {
+            fs::create_dir_all(parent)?;
+        }
+    }
+    let data = serde_json::to_string_pretty(&UsersFile { users }).map_err(io::Error::other)?;
+    let temp = format!("{}.tmp", path);
+    fs::write(&temp, data)?;
+    fs::rename(&temp, path)
+}
+
+/// Hashes a password with argon2 and a random salt.
+pub fn hash_password(password: &str) -> Result<String, String> {
+    let salt =
+        SaltString::encode_b64(&rand::thread_rng().gen::<[u8; 16]>()).map_err(|e| e.to_string())?;
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| e.to_string())
+}
+
+/// Checks a password against a stored hash.
+fn verify_password(password_hash: &str, password: &str) -> bool {
+    PasswordHash::new(password_hash)
+        .and_then(|hash| Argon2::default().verify_password(password.as_bytes(), &hash))
+        .is_ok()
+}
+
+/// A hash of a random password, checked when a login names no existing user so that it takes
+/// as long as one that does.
+fn dummy_password_hash() -> &'static str {
+    static HASH: OnceLock<String> = OnceLock::new();
+    HASH.get_or_init(|| hash_password(&random_token(16)).unwrap_or_default())
+}
+
+/// Creates a user, or changes the password and optionally the roles of an existing one.
+///
+/// # Arguments
+/// * `settings` - The authentication settings naming the users file.
+/// * `username` - The account to create or update.
+/// * `password` - The new password.
+/// * `roles` - The roles to give the user. New users default to `admin`, existing users keep
+///   their roles.
+pub fn set_password(
+    settings: &AuthSettings,
+    username: &str,
+    password: &str,
+    roles: Option<Vec<String>>,
+) -> Result<(), String> {
+    if username.trim().is_empty() {
+        return Err("Username must not be empty".to_string());
+    }
+    if password.is_empty() {
+        return Err("Password must not be empty".to_string());
+    }
+    if let Some(unknown) = roles
+        .iter()
+        .flatten()
+        .find(|role| !settings.roles.contains_key(*role))
+    {
+        return Err(format!("Unknown role '{}'", unknown));
+    }
+    let mut users = load_users(&settings.users_file).map_err(|e| e.to_string())?;
+    let password_hash = hash_password(password)?;
+    match users.iter_mut().find(|user| user.username == username) {
+        Some(user) => {
+            user.password_hash = password_hash;
+            if let Some(roles) = roles {
+                user.roles = roles;
+            }
+        }
+        None => users.push(UserAccount {
+            username: username.to_owned(),
+            password_hash,
+            roles: roles.unwrap_or_else(default_user_roles),
+        }),
+    }
+    save_users(&settings.users_file, users).map_err(|e| e.to_string())
+}
+
+/// Creates an `admin` account with a random password if authentication is on and no account
+/// exists yet, so a fresh install isn't left open. The password is printed once to standard
+/// error, and kept out of the log so it doesn't end up in the log file.
+///
+/// # Arguments
+/// * `settings` - The authentication settings.
+pub fn ensure_admin(settings: &AuthSettings) {
+    if !settings.enabled {
+        return;
+    }
+    match load_users(&settings.users_file) {
+        Ok(users) if users.is_empty() => {}
+        Ok(_) => return,
+        Err(e) => {
+            error!("Could not read users file '{}': {}", settings.users_file, e);
+            return;
+        }
+    }
+    let password = random_token(12);
+    match set_password(settings, BOOTSTRAP_USERNAME, &password, None) {
+        Ok(()) => {
+            eprintln!(
+                "No user accounts found, created '{}' with password '{}'. Change it with --add-user {}",
+                BOOTSTRAP_USERNAME, password, BOOTSTRAP_USERNAME
+            );
+            warn!(
+                "No user accounts found, created '{}', its password was printed to standard error",
+                BOOTSTRAP_USERNAME
+            );
+        }
+        Err(e) => error!("Could not create the first user account: {}", e),
+    }
+}
+
+/// Handles `--add-user <username> [--role <role>]...`: asks for a password and saves the
+/// account.
+///
+/// The password is read from the `RSC_PASSWORD` environment variable if set, otherwise from
+/// standard input.
+///
+/// # Arguments
+/// * `settings` - The authentication settings naming the users file.
+/// * `username` - The account to create or update.
+/// * `roles` - The roles given with `--role`, if any.
+pub fn add_user_from_cli(
+    settings: &AuthSettings,
+    username: &str,
+    roles: Option<Vec<String>>,
+) -> Result<(), String> {
+    let password = match std::env::var("RSC_PASSWORD") {
+        Ok(password) => password,
+        Err(_) => {
+            use std::io::Write;
+            print!("Password for '{}': ", username);
+            let _ = io::stdout().flush();
+            let mut line = String::new();
+            io::stdin()
+                .read_line(&mut line)
+                .map_err(|e| e.to_string())?;
+            line.trim_end_matches(['\r', '\n']).to_string()
+        }
+    };
+    set_password(settings, username, &password, roles)
+}
+
+/// Random lowercase hex string of `bytes` random bytes.
+pub fn random_token(bytes: usize) -> String {
+    let mut rng = rand::thread_rng();
+    (0..bytes)
+        .map(|_| format!("{:02x}", rng.gen::<u8>()))
+        .collect()
+}
+
+/// A logged-in user.
+struct Session {
+    username: String,
+    roles: Vec<String>,
+    expires: DateTime<Local>,
+}
+
+/// Active sessions, keyed by token.
+#[derive(Default)]
+pub struct SessionStore {
+    sessions: HashMap<String, Session>,
+}
+
+impl SessionStore {
+    /// Starts a session for a user.
+    ///
+    /// # Returns
+    /// * The session token and when it expires.
+    fn create(
+        &mut self,
+        username: &str,
+        roles: Vec<String>,
+        ttl_secs: u64,
+    ) -> (String, DateTime<Local>) {
+        let now = Local::now();
+        self.sessions.retain(|_, session| session.expires > now);
+        let token = random_token(32);
+        let expires = now + chrono::Duration::seconds(ttl_secs.min(i64::MAX as u64) as i64);
+        self.sessions.insert(
+            token.clone(),
+            Session {
+                username: username.to_owned(),
+                roles,
+                expires,
+            },
+        );
+        (token, expires)
+    }
+
+    /// Looks up the user a token belongs to and their roles, forgetting the session if it has
+    /// expired.
+    pub fn user(&mut self, token: &str) -> Option<(String, Vec<String>)> {
+        match self.sessions.get(token) {
+            Some(session) if session.expires > Local::now() => {
+                Some((session.username.clone(), session.roles.clone()))
+            }
+            Some(_) => {
+                self.sessions.remove(token);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Ends a session.
+    fn remove(&mut self, token: &str) {
+        self.sessions.remove(token);
+    }
+}
+
+/// Failed logins from one address.
+struct LoginFailures {
+    count: u32,
+    last: Instant,
+    retry_at: Instant,
+}
+
+/// Failed logins by address, slowing down password guessing.
+#[derive(Default)]
+pub struct LoginThrottle {
+    failures: HashMap<IpAddr, LoginFailures>,
+}
+
+impl LoginThrottle {
+    /// How long an address waits after a number of failed logins.
+    fn backoff(count: u32) -> Duration {
+        match count.checked_sub(FREE_LOGIN_FAILURES) {
+            Some(over) => Duration::from_secs(1 << over.min(16)).min(MAX_LOGIN_BACKOFF),
+            None => Duration::ZERO,
+        }
+    }
+
+    /// Lets an address try to log in, unless it still has to wait after failing before.
+    ///
+    /// An address past its free failures is held back again right away, so attempts sent at
+    /// the same time don't all get through.
+    ///
+    /// # Returns
+    /// * `Err` with how long the address still has to wait.
+    fn attempt(&mut self, address: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        let Some(failures) = self.failures.get_mut(&address) else {
+            return Ok(());
+        };
+        if failures.retry_at > now {
+            return Err(failures.retry_at - now);
+        }
+        failures.retry_at = now + Self::backoff(failures.count);
+        Ok(())
+    }
+
+    /// Records a failed login from an address.
+    fn failed(&mut self, address: IpAddr) {
+        let now = Instant::now();
+        self.failures
+            .retain(|_, failures| now - failures.last < LOGIN_FAILURE_MEMORY);
+        let failures = self.failures.entry(address).or_insert(LoginFailures {
+            count: 0,
+            last: now,
+            retry_at: now,
+        });
+        failures.count += 1;
+        failures.last = now;
+        failures.retry_at = failures.retry_at.max(now + Self::backoff(failures.count));
+    }
+
+    /// Forgets the failed logins of an address once it logs in.
+    fn succeeded(&mut self, address: IpAddr) {
+        self.failures.remove(&address);
+    }
+}
+
+/// Finds the session token of a request in its `Authorization` header, session cookie or
+/// `token` query parameter, in that order.
+fn request_token(request: &Request) -> Option<String> {
+    let headers = request.headers();
+    if let Some(token) = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+    {
+        return Some(token.trim().to_owned());
+    }
+    if let Some(token) = cookie_token(headers) {
+        return Some(token);
+    }
+    request.uri().query().and_then(|query| {
+        query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == "token")
+            .map(|(_, value)| value.to_owned())
+    })
+}
+
+/// Reads the session cookie from the request headers.
+fn cookie_token(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(name, _)| *name == SESSION_COOKIE)
+        .map(|(_, value)| value.to_owned())
+}
+
+/// Middleware rejecting requests without a valid session when authentication is enabled.
+///
+/// Browsers asking for the web UI are redirected to the login page; everything else gets a
+/// `401` with a JSON error. Let-through requests carry the [`Access`] of their user as an
+/// extension.
+pub async fn require_auth(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let (auth, prometheus) = {
+        let config = state.config.lock().await;
+        (config.auth.clone(), config.prometheus.clone())
+    };
+    // Scrapers may use the Prometheus token instead of a login, and must once one is set
+    if request.uri().path() == prometheus::METRICS_PATH
+        && prometheus
+            .token
+            .as_deref()
+            .is_some_and(|token| !token.is_empty())
+    {
+        let token = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        if token.is_some_and(|token| prometheus::token_allows(&prometheus, token.trim())) {
+            request.extensions_mut().insert(Access::unrestricted());
+            return next.run(request).await;
+        }
+        if !auth.enabled {
+            return (
+                StatusCode::UNAUTHORIZED,
+                Json(serde_json::json!({ "error": "Invalid token" })),
+            )
+                .into_response();
+        }
+    }
+    if !auth.enabled {
+        request.extensions_mut().insert(Access::unrestricted());
+        return next.run(request).await;
+    }
+    if PUBLIC_PATHS.contains(&request.uri().path()) {
+        return next.run(request).await;
+    }
+    let user = match request_token(&request) {
+        Some(token) => state.sessions.lock().await.user(&token),
+        None => None,
+    };
+    match user {
+        Some((username, roles)) => {
+            request
+                .extensions_mut()
+                .insert(Access::for_user(&username, &roles, &auth.roles));
+            next.run(request).await
+        }
+        None if request.uri().path() == "/" => Redirect::to("/login").into_response(),
+        None => (
+            StatusCode::UNAUTHORIZED,
+            Json(serde_json::json!({ "error": "Not logged in" })),
+        )
+            .into_response(),
+    }
+}
+
+/// Body of `POST /api/login`.
+#[derive(Deserialize)]
+pub struct LoginRequest {
+    username: String,
+    password: String,
+}
+
+/// Reply of `POST /api/login`.
+#[derive(Serialize)]
+struct LoginResponse {
+    /// Session token to send as `Authorization: Bearer <token>`.
+    token: String,
+    /// When the session expires (RFC 3339).
+    expires_at: String,
+}
+
+/// Checks a username and password and starts a session.
+///
+/// The token is returned in the body and set as an HTTP-only cookie for the web UI. Unknown
+/// usernames are checked against a dummy hash, so the reply takes as long as for a wrong
+/// password, and an address that keeps failing is answered with `429` until it has waited.
+pub async fn login(
+    State(state): State<AppState>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    body: Result<Json<LoginRequest>, JsonRejection>,
+) -> Response {
+    let request = match body {
+        Ok(Json(request)) => request,
+        Err(rejection) => {
+            return (
+                rejection.status(),
+                Json(serde_json::json!({ "error": rejection.body_text() })),
+            )
+                .into_response()
+        }
+    };
+    if let Err(wait) = state.login_throttle.lock().await.attempt(address.ip()) {
+        let wait_secs = wait.as_secs() + 1;
+        warn!(
+            "Refused login attempt from {}, too many failed logins",
+            address.ip()
+        );
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, wait_secs.to_string())],
+            Json(serde_json::json!({
+                "error": format!("Too many failed logins, try again in {}s", wait_secs)
+            })),
+        )
+            .into_response();
+    }
+    let settings = state.config.lock().await.auth.clone();
+    let username = request.username.clone();
+    // Hashing is deliberately slow, so keep it off the async workers
+    let roles = tokio::task::spawn_blocking(move || {
+        let dummy_hash = dummy_password_hash();
+        let users = load_users(&settings.users_file).unwrap_or_else(|e| {
+            error!("Could not read users file '{}': {}", settings.users_file, e);
+            vec![]
+        });
+        match users
+            .into_iter()
+            .find(|user| user.username == request.username)
+        {
+            Some(user) => {
+                verify_password(&user.password_hash, &request.password).then_some(user.roles)
+            }
+            None => {
+                verify_password(dummy_hash, &request.password);
+                None
+            }
+        }
+    })
+    .await
+    .unwrap_or(None);
+    let Some(roles) = roles else {
+        state.login_throttle.lock().await.failed(address.ip());
+        warn!(
+            "Failed login attempt for '{}' from {}",
+            username,
+            address.ip()
+        );
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(serde_json::json!({ "error": "Invalid username or password" })),
+        )
+            .into_response();
+    };
+
+    state.login_throttle.lock().await.succeeded(address.ip());
+    let (ttl_secs, secure) = {
+        let config = state.config.lock().await;
+        (config.auth.session_ttl_secs, cookie_secure_flag(&config))
+    };
+    let (token, expires) = state
+        .sessions
+        .lock()
+        .await
+        .create(&username, roles, ttl_secs);
+    info!("'{}' logged in", username);
+    let cookie = format!(
+        "{}={}; Path=/; HttpOnly; SameSite=Strict; Max-Age={}{}",
+        SESSION_COOKIE, token, ttl_secs, secure
+    );
+    let mut response = Json(LoginResponse {
+        token,
+        expires_at: expires.to_rfc3339(),
+    })
+    .into_response();
+    if let Ok(value) = HeaderValue::from_str(&cookie) {
+        response.headers_mut().insert(header::SET_COOKIE, value);
+    }
+    response
+}
+
+/// The `Secure` cookie attribute when the web UI is served over TLS, so the session cookie is
+/// never sent over plain HTTP.
+fn cookie_secure_flag(config: &Config) -> &'static str {
+    if config.tls.enabled {
+        "; Secure"
+    } else {
+        ""
+    }
+}
+
+/// Ends the session of the request and clears the session cookie.
+pub async fn logout(State(state): State<AppState>, request: Request) -> Response {
+    if let Some(token) = request_token(&request) {
+        state.sessions.lock().await.remove(&token);
+    }
+    let secure = cookie_secure_flag(&*state.config.lock().await);
+    let cookie = format!(
+        "{}=; Path=/; HttpOnly; SameSite=Strict; Max-Age=0{}",
+        SESSION_COOKIE, secure
+    );
+    let mut response = StatusCode::NO_CONTENT.into_response();
+    if let Ok(value) = HeaderValue::from_str(&cookie) {
+        response.headers_mut().insert(header::SET_COOKIE, value);
+    }
+    response
+}
@@ -0,0 +1,1016 @@
+/// Backups of managed servers' working directories.
+///
+/// Archives are written as tar.gz or zip files into `{directory}/{server}-{hash}/`, filtered by the
+/// include/exclude globs of the server's [`BackupDescriptor`], and pruned by count and age
+/// after each backup. Specializations can pause saving around a backup through
+/// `pre_backup_commands`/`post_backup_commands`.
+use chrono::{DateTime, Local};
+use glob::{MatchOptions, Pattern};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter},
+    path::{Component, Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use tracing::*;
+
+use crate::{
+    app_state::AppState,
+    files::{sanitize_file_name, unique_file_name},
+    messages::BackupStatus,
+    servers,
+};
+
+/// How long to give a server to finish writing after its pre-backup commands.
+const SAVE_SETTLE: Duration = Duration::from_secs(3);
+
+/// Folder in a working directory that a backup is unpacked into before it is swapped in.
+const RESTORE_STAGING_DIR: &str = ".rsc-restore";
+
+/// Folder in a working directory holding what a restore replaces until it has finished.
+const RESTORE_REPLACED_DIR: &str = ".rsc-replaced";
+
+/// Backup settings for a server, stored on its `ControlledProgramDescriptor`.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct BackupDescriptor {
+    /// Folder that holds one backup directory per server.
+    #[serde(default = "default_directory")]
+    pub directory: String,
+    /// Globs, relative to the working directory, of what to back up. Empty means everything.
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Globs, relative to the working directory, of what to leave out.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Archive format of new backups.
+    #[serde(default)]
+    pub format: ArchiveFormat,
+    /// Keep at most this many backups.
+    #[serde(default)]
+    pub keep_count: Option<usize>,
+    /// Delete backups older than this many days.
+    #[serde(default)]
+    pub keep_days: Option<u64>,
+}
+
+fn default_directory() -> String {
+    "backups".to_string()
+}
+
+impl Default for BackupDescriptor {
+    fn default() -> Self {
+        Self {
+            directory: default_directory(),
+            include: vec![],
+            exclude: vec![],
+            format: ArchiveFormat::default(),
+            keep_count: None,
+            keep_days: None,
+        }
+    }
+}
+
+/// Archive format of a backup.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ArchiveFormat {
+    /// Gzip-compressed tarball.
+    #[default]
+    #[serde(rename = "tar.gz")]
+    TarGz,
+    /// Deflate-compressed zip file.
+    #[serde(rename = "zip")]
+    Zip,
+}
+
+impl ArchiveFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::TarGz => "tar.gz",
+            ArchiveFormat::Zip => "zip",
+        }
+    }
+
+    fn from_file_name(name: &str) -> Option<Self> {
+        if name.ends_with(".tar.gz") {
+            Some(ArchiveFormat::TarGz)
+        } else if name.ends_with(".zip") {
+            Some(ArchiveFormat::Zip)
+        } else {
+            None
+        }
+    }
+}
+
+/// A backup archive on disk.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BackupInfo {
+    /// File name of the archive inside the server's backup directory.
+    pub file_name: String,
+    /// Size of the archive in bytes.
+    pub size: u64,
+    /// When the archive was written (RFC 3339).
+    pub created: String,
+}
+
+/// Directory a server's backups are written to.
+fn backup_dir(settings: &BackupDescriptor, server_name: &str) -> PathBuf {
+    Path::new(&settings.directory).join(unique_file_name(server_name))
+}
+
+/// Moves a server's backups out of the directory named after its sanitized name alone, which
+/// was used before the hash was added.
+///
+/// The old directory is left alone when another configured server maps to it too, since it
+/// can't be told whose backups are in it.
+///
+/// # Arguments
+/// * `settings` - The server's backup settings.
+/// * `server_name` - The server whose backups to move.
+/// * `shared` - Whether another configured server has the same sanitized name.
+fn adopt_legacy_backups(settings: &BackupDescriptor, server_name: &str, shared: bool) {
+    let legacy = Path::new(&settings.directory).join(sanitize_file_name(server_name));
+    let dir = backup_dir(settings, server_name);
+    if !legacy.is_dir() || dir.exists() {
+        return;
+    }
+    if shared {
+        warn!(
+            "Backups in '{}' may belong to '{}' or to another server with a similar name, move them to '{}' by hand",
+            legacy.display(),
+            server_name,
+            dir.display()
+        );
+        return;
+    }
+    match fs::rename(&legacy, &dir) {
+        Ok(()) => info!(
+            "Moved the backups of '{}' to '{}'",
+            server_name,
+            dir.display()
+        ),
+        Err(e) => warn!(
+            "Could not move the backups of '{}' to '{}': {}",
+            server_name,
+            dir.display(),
+            e
+        ),
+    }
+}
+
+/// The `YYYYmmdd-HHMMSS` timestamp an archive name ends with, which sorts like creation time.
+fn archive_timestamp(file_name: &str) -> &str {
+    let stem = file_name
+        .strip_suffix(".tar.gz")
+        .or_else(|| file_name.strip_suffix(".zip"))
+        .unwrap_or(file_name);
+    stem.get(stem.len().saturating_sub(15)..).unwrap_or(stem)
+}
+
+/// Compiles a list of globs, skipping (and logging) invalid ones.
+fn compile_patterns(globs: &[String]) -> Vec<Pattern> {
+    globs
+        .iter()
+        .filter_map(|glob| match Pattern::new(glob.trim_end_matches('/')) {
+            Ok(pattern) => Some(pattern),
+            Err(e) => {
+                warn!("Ignoring invalid backup glob '{}': {}", glob, e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Checks a relative path, or any of the directories containing it, against a set of globs.
+fn matches_any(patterns: &[Pattern], relative: &Path) -> bool {
+    let options = MatchOptions {
+        case_sensitive: true,
+        require_literal_separator: true,
+        require_literal_leading_dot: false,
+    };
+    relative
+        .ancestors()
+        .filter(|path| !path.as_os_str().is_empty())
+        .any(|path| {
+            let path = path.to_string_lossy().replace('\\', "/");
+            patterns
+                .iter()
+                .any(|pattern| pattern.matches_with(&path, options))
+        })
+}
+
+/// Collects the files of a working directory selected by the backup globs.
+///
+/// # Returns
+/// * Pairs of absolute path and path relative to the working directory.
+fn collect_files(
+    settings: &BackupDescriptor,
+    working_dir: &Path,
+    skip_dir: &Path,
+) -> io::Result<Vec<(PathBuf, PathBuf)>> {
+    let include = compile_patterns(&settings.include);
+    let exclude = compile_patterns(&settings.exclude);
+    let skip_dir = skip_dir.canonicalize().ok();
+    let mut files = vec![];
+    let walker = walkdir::WalkDir::new(working_dir)
+        .follow_links(false)
+        .into_iter()
+        .filter_entry(|entry| {
+            if entry.depth() == 1
+                && (entry.file_name() == RESTORE_STAGING_DIR
+                    || entry.file_name() == RESTORE_REPLACED_DIR)
+            {
+                return false;
+            }
+            // Never archive the backups themselves if they live inside the working directory
+            skip_dir.as_ref().is_none_or(|skip| {
+                entry
+                    .path()
+                    .canonicalize()
+                    .map(|path| &path != skip)
+                    .unwrap_or(true)
+            })
+        });
+    for entry in walker {
+        let entry = entry.map_err(io::Error::other)?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let relative = entry
+            .path()
+            .strip_prefix(working_dir)
+            .map_err(io::Error::other)?
+            .to_path_buf();
+        if (!include.is_empty() && !matches_any(&include, &relative))
+            || matches_any(&exclude, &relative)
+        {
+            continue;
+        }
+        files.push((entry.path().to_path_buf(), relative));
+    }
+    Ok(files)
+}
+
+/// Writes a new archive of a server's working directory. Blocking.
+///
+/// # Arguments
+/// * `settings` - The server's backup settings.
+/// * `server_name` - The server being backed up.
+/// * `working_dir` - The directory to archive.
+pub fn create_archive(
+    settings: &BackupDescriptor,
+    server_name: &str,
+    working_dir: &Path,
+) -> io::Result<BackupInfo> {
+    let dir = backup_dir(settings, server_name);
+    fs::create_dir_all(&dir)?;
+    let files = collect_files(settings, working_dir, &dir)?;
+
+    let file_name = format!(
+        "{}-{}.{}",
+        unique_file_name(server_name),
+        Local::now().format("%Y%m%d-%H%M%S"),
+        settings.format.extension()
+    );
+    let path = dir.join(&file_name);
+    // Write under a temporary name so a failed backup never looks like a finished one
+    let partial = dir.join(format!("{}.partial", file_name));
+    let result = match settings.format {
+        ArchiveFormat::TarGz => write_tar_gz(&partial, &files),
+        ArchiveFormat::Zip => write_zip(&partial, &files),
+    };
+    if let Err(e) = result {
+        let _ = fs::remove_file(&partial);
+        return Err(e);
+    }
+    fs::rename(&partial, &path)?;
+    backup_info(&path)
+}
+
+fn write_tar_gz(path: &Path, files: &[(PathBuf, PathBuf)]) -> io::Result<()> {
+    let encoder = flate2::write::GzEncoder::new(
+        BufWriter::new(File::create(path)?),
+        flate2::Compression::default(),
+    );
+    let mut builder = tar::Builder::new(encoder);
+    builder.follow_symlinks(false);
+    for (absolute, relative) in files {
+        builder.append_path_with_name(absolute, relative)?;
+    }
+    builder.into_inner()?.finish()?;
+    Ok(())
+}
+
+fn write_zip(path: &Path, files: &[(PathBuf, PathBuf)]) -> io::Result<()> {
+    let mut zip = zip::ZipWriter::new(BufWriter::new(File::create(path)?));
+    let options = zip::write::SimpleFileOptions::default()
+        .compression_method(zip::CompressionMethod::Deflated)
+        .large_file(true);
+    for (absolute, relative) in files {
+        let name = relative.to_string_lossy().replace('\\', "/");
+        zip.start_file(name, options).map_err(io::Error::other)?;
+        io::copy(&mut BufReader::new(File::open(absolute)?), &mut zip)?;
+    }
+    zip.finish().map_err(io::Error::other)?;
+    Ok(())
+}
+
+fn backup_info(path: &Path) -> io::Result<BackupInfo> {
+    let metadata = fs::metadata(path)?;
+    let created: DateTime<Local> = metadata.modified()?.into();
+    Ok(BackupInfo {
+        file_name: path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        size: metadata.len(),
+        created: created.to_rfc3339(),
+    })
+}
+
+/// Lists a server's backups, newest first. Blocking.
+///
+/// # Arguments
+/// * `settings` - The server's backup settings.
+/// * `server_name` - The server whose backups to list.
+pub fn list_backups(settings: &BackupDescriptor, server_name: &str) -> io::Result<Vec<BackupInfo>> {
+    let entries = match fs::read_dir(backup_dir(settings, server_name)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut backups = vec![];
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if ArchiveFormat::from_file_name(&name).is_some() && entry.path().is_file() {
+            backups.push(backup_info(&entry.path())?);
+        }
+    }
+    // Timestamps in the names sort the same as creation time, and survive copying
+    backups.sort_by(|a, b| {
+        archive_timestamp(&b.file_name)
+            .cmp(archive_timestamp(&a.file_name))
+            .then_with(|| b.file_name.cmp(&a.file_name))
+    });
+    Ok(backups)
+}
+
+/// Deletes backups beyond the retention limits. Blocking.
+///
+/// # Returns
+/// * The file names of the deleted backups.
+pub fn apply_retention(settings: &BackupDescriptor, server_name: &str) -> io::Result<Vec<String>> {
+    let dir = backup_dir(settings, server_name);
+    let now = SystemTime::now();
+    let max_age = settings
+        .keep_days
+        .map(|days| Duration::from_secs(days * 24 * 60 * 60));
+    let mut removed = vec![];
+    for (index, backup) in list_backups(settings, server_name)?.into_iter().enumerate() {
+        let path = dir.join(&backup.file_name);
+        let too_many = settings.keep_count.is_some_and(|keep| index >= keep);
+        let too_old = max_age.is_some_and(|max_age| {
+            fs::metadata(&path)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|modified| now.duration_since(modified).ok())
+                .is_some_and(|age| age > max_age)
+        });
+        if too_many || too_old {
+            fs::remove_file(&path)?;
+            removed.push(backup.file_name);
+        }
+    }
+    Ok(removed)
+}
+
+/// Finds a backup by file name, refusing anything that would point outside the backup folder.
+///
+/// # Arguments
+/// * `settings` - The server's backup settings.
+/// * `server_name` - The server the backup belongs to.
+/// * `file_name` - File name of the archive.
+pub fn resolve_backup(
+    settings: &BackupDescriptor,
+    server_name: &str,
+    file_name: &str,
+) -> Option<PathBuf> {
+    let mut components = Path::new(file_name).components();
+    let is_plain_name = matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    );
+    if !is_plain_name || ArchiveFormat::from_file_name(file_name).is_none() {
+        return None;
+    }
+    let path = backup_dir(settings, server_name).join(file_name);
+    path.is_file().then_some(path)
+}
+
+/// Deletes a file, symlink or folder, if there is one.
+fn remove_path(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Moves every top-level entry of `staging` into `working_dir`, setting aside what each one
+/// replaces in `replaced`.
+///
+/// # Arguments
+/// * `moved` - Collects the names moved so far, and whether each replaced something, so a
+///   failed swap can be undone.
+fn swap_in(
+    staging: &Path,
+    working_dir: &Path,
+    replaced: &Path,
+    moved: &mut Vec<(OsString, bool)>,
+) -> io::Result<()> {
+    for entry in fs::read_dir(staging)? {
+        let name = entry?.file_name();
+        let target = working_dir.join(&name);
+        let had_old = fs::symlink_metadata(&target).is_ok();
+        if had_old {
+            fs::rename(&target, replaced.join(&name))?;
+        }
+        moved.push((name.clone(), had_old));
+        fs::rename(staging.join(&name), &target)?;
+    }
+    Ok(())
+}
+
+/// Puts back what a failed [`swap_in`] set aside.
+fn undo_swap(working_dir: &Path, replaced: &Path, moved: &[(OsString, bool)]) -> io::Result<()> {
+    for (name, had_old) in moved.iter().rev() {
+        let target = working_dir.join(name);
+        remove_path(&target)?;
+        if *had_old {
+            fs::rename(replaced.join(name), &target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Replaces what a backup contains in a working directory with the backed up copy. Blocking.
+///
+/// The archive is unpacked into a staging folder first, so a corrupt archive or a full disk
+/// leaves the working directory as it was. Its top-level files and folders are then moved in,
+/// setting aside the ones they replace, which are only deleted once every move has worked.
+/// Nothing newer is left mixed in with the restored data, and everything the archive doesn't
+/// contain is kept. Both folders live inside the working directory, so the moves stay on one
+/// filesystem even when it is a mount point.
+///
+/// # Arguments
+/// * `archive` - Path of the backup to restore.
+/// * `working_dir` - The server's working directory.
+pub fn restore_archive(archive: &Path, working_dir: &Path) -> io::Result<()> {
+    let name = archive.to_string_lossy();
+    let format = ArchiveFormat::from_file_name(&name)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "unknown archive format"))?;
+    fs::create_dir_all(working_dir)?;
+    let staging = working_dir.join(RESTORE_STAGING_DIR);
+    let replaced = working_dir.join(RESTORE_REPLACED_DIR);
+    if replaced.exists() {
+        return Err(io::Error::other(format!(
+            "an earlier restore was interrupted, '{}' holds the files it replaced",
+            replaced.display()
+        )));
+    }
+    // Left over from an earlier restore that failed while unpacking
+    remove_path(&staging)?;
+
+    fs::create_dir(&staging)?;
+    let unpacked = File::open(archive).and_then(|file| match format {
+        // Both unpackers refuse entries that would land outside the staging folder
+        ArchiveFormat::TarGz => {
+            tar::Archive::new(flate2::read::GzDecoder::new(BufReader::new(file))).unpack(&staging)
+        }
+        ArchiveFormat::Zip => zip::ZipArchive::new(BufReader::new(file))
+            .and_then(|mut zip| zip.extract(&staging))
+            .map_err(io::Error::other),
+    });
+    if let Err(e) = unpacked {
+        let _ = remove_path(&staging);
+        return Err(e);
+    }
+
+    fs::create_dir(&replaced)?;
+    let mut moved = vec![];
+    let swapped = swap_in(&staging, working_dir, &replaced, &mut moved);
+    let _ = remove_path(&staging);
+    match swapped {
+        Ok(()) => remove_path(&replaced),
+        Err(e) => match undo_swap(working_dir, &replaced, &moved) {
+            Ok(()) => {
+                let _ = remove_path(&replaced);
+                Err(e)
+            }
+            Err(undo_error) => Err(io::Error::other(format!(
+                "{}, and putting the old files back failed too, they are in '{}': {}",
+                e,
+                replaced.display(),
+                undo_error
+            ))),
+        },
+    }
+}
+
+/// Looks up a server's working directory and backup settings in the configuration.
+async fn backup_target(
+    state: &AppState,
+    server_name: &str,
+) -> Result<(PathBuf, BackupDescriptor), String> {
+    let config = state.config.lock().await;
+    let (working_dir, settings) = config
+        .servers
+        .iter()
+        .find(|desc| desc.name == server_name)
+        .map(|desc| (PathBuf::from(&desc.working_dir), desc.backup.clone()))
+        .ok_or_else(|| format!("Server '{}' is not configured", server_name))?;
+    let shared = config.servers.iter().any(|desc| {
+        desc.name != server_name
+            && sanitize_file_name(&desc.name) == sanitize_file_name(server_name)
+    });
+    drop(config);
+    adopt_legacy_backups(&settings, server_name, shared);
+    Ok((working_dir, settings))
+}
+
+/// Runs console commands from the server's specialization, if it is running.
+///
+/// # Returns
+/// * Whether any command was sent.
+async fn run_hook_commands(
+    state: &AppState,
+    server_name: &str,
+    commands: impl Fn(&dyn crate::specializations::ServerSpecialization) -> Vec<String>,
+) -> bool {
+    let commands = state
+        .servers
+        .lock()
+        .await
+        .iter()
+        .find(|server| server.name == server_name)
+        .and_then(|server| server.specialization_handler.as_deref().map(&commands))
+        .unwrap_or_default();
+    for command in commands.iter() {
+        if let Err(e) = servers::send_stdin(state, server_name, command).await {
+            warn!("Backup hook command '{}' failed: {}", command, e);
+        }
+    }
+    !commands.is_empty()
+}
+
+/// Broadcasts the outcome of a backup or restore and notes it in the server's console.
+fn send_status(
+    state: &AppState,
+    server_name: &str,
+    action: &str,
+    result: &Result<Option<String>, String>,
+) {
+    let (notice, status) = match result {
+        Ok(file_name) => (
+            format!(
+                "{} finished{}",
+                action,
+                file_name
+                    .as_ref()
+                    .map(|name| format!(": {}", name))
+                    .unwrap_or_default()
+            ),
+            BackupStatus {
+                r#type: "BackupStatus".to_owned(),
+                server_name: server_name.to_owned(),
+                action: action.to_lowercase(),
+                success: true,
+                file_name: file_name.clone(),
+                error: None,
+            },
+        ),
+        Err(e) => (
+            format!("{} failed: {}", action, e),
+            BackupStatus {
+                r#type: "BackupStatus".to_owned(),
+                server_name: server_name.to_owned(),
+                action: action.to_lowercase(),
+                success: false,
+                file_name: None,
+                error: Some(e.clone()),
+            },
+        ),
+    };
+    servers::send_console_notice(state, server_name.to_owned(), None, &notice);
+    let _ = state.tx.send(serde_json::to_string(&status).unwrap());
+}
+
+/// Backs up a server's working directory and applies retention.
+///
+/// If the server is running, its specialization's pre-backup commands are sent first and its
+/// post-backup commands afterwards, whether or not the backup succeeded.
+///
+/// # Arguments
+/// * `state` - The shared application state.
+/// * `server_name` - The server to back up.
+pub async fn run_backup(state: &AppState, server_name: &str) -> Result<BackupInfo, String> {
+    let result = backup_inner(state, server_name).await;
+    send_status(
+        state,
+        server_name,
+        "Backup",
+        &result
+            .as_ref()
+            .map(|info| Some(info.file_name.clone()))
+            .map_err(Clone::clone),
+    );
+    result
+}
+
+async fn backup_inner(state: &AppState, server_name: &str) -> Result<BackupInfo, String> {
+    let (working_dir, settings) = backup_target(state, server_name).await?;
+    if !state
+        .backups_in_progress
+        .lock()
+        .await
+        .insert(server_name.to_owned())
+    {
+        return Err("a backup or restore of this server is already running".to_string());
+    }
+
+    if run_hook_commands(state, server_name, |handler| handler.pre_backup_commands()).await {
+        tokio::time::sleep(SAVE_SETTLE).await;
+    }
+    let name = server_name.to_owned();
+    let result = tokio::task::spawn_blocking(move || {
+        let info = create_archive(&settings, &name, &working_dir)?;
+        for removed in apply_retention(&settings, &name)? {
+            info!("Removed old backup '{}' of '{}'", removed, name);
+        }
+        Ok::<_, io::Error>(info)
+    })
+    .await;
+    run_hook_commands(state, server_name, |handler| handler.post_backup_commands()).await;
+    state.backups_in_progress.lock().await.remove(server_name);
+
+    match result {
+        Ok(Ok(info)) => {
+            info!("Backed up '{}' to '{}'", server_name, info.file_name);
+            Ok(info)
+        }
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(e) => Err(format!("backup task failed: {}", e)),
+    }
+}
+
+/// Lists a server's backups, newest first.
+///
+/// # Arguments
+/// * `state` - The shared application state.
+/// * `server_name` - The server whose backups to list.
+pub async fn backups_of(state: &AppState, server_name: &str) -> Result<Vec<BackupInfo>, String> {
+    let (_, settings) = backup_target(state, server_name).await?;
+    let name = server_name.to_owned();
+    tokio::task::spawn_blocking(move || list_backups(&settings, &name))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())
+}
+
+/// Finds the archive of a server's backup for downloading.
+///
+/// # Arguments
+/// * `state` - The shared application state.
+/// * `server_name` - The server the backup belongs to.
+/// * `file_name` - File name of the archive.
+pub async fn backup_path(state: &AppState, server_name: &str, file_name: &str) -> Option<PathBuf> {
+    let (_, settings) = backup_target(state, server_name).await.ok()?;
+    resolve_backup(&settings, server_name, file_name)
+}
+
+/// Restores a backup, stopping the server first and starting it again if it was running.
+///
+/// # Arguments
+/// * `state` - The shared application state.
+/// * `server_name` - The server to restore.
+/// * `file_name` - File name of the archive to restore.
+pub async fn restore_backup(
+    state: &AppState,
+    server_name: &str,
+    file_name: &str,
+) -> Result<(), String> {
+    let result = restore_inner(state, server_name, file_name).await;
+    send_status(
+        state,
+        server_name,
+        "Restore",
+        &result
+            .as_ref()
+            .map(|_| Some(file_name.to_owned()))
+            .map_err(Clone::clone),
+    );
+    result
+}
+
+async fn restore_inner(state: &AppState, server_name: &str, file_name: &str) -> Result<(), String> {
+    let (working_dir, settings) = backup_target(state, server_name).await?;
+    let archive = resolve_backup(&settings, server_name, file_name)
+        .ok_or_else(|| format!("No backup named '{}'", file_name))?;
+    if !state
+        .backups_in_progress
+        .lock()
+        .await
+        .insert(server_name.to_owned())
+    {
+        return Err("a backup or restore of this server is already running".to_string());
+    }
+
+    // A server only waiting out a crash restart is down, so it stays down after the restore
+    let was_running = state
+        .servers
+        .lock()
+        .await
+        .iter()
+        .any(|server| server.name == server_name);
+    // Also cancels a pending crash restart; fails when there is nothing to stop, which is fine
+    let _ = servers::stop_server(state, server_name).await;
+    let result = tokio::task::spawn_blocking(move || restore_archive(&archive, &working_dir)).await;
+    state.backups_in_progress.lock().await.remove(server_name);
+    match result {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => return Err(e.to_string()),
+        Err(e) => return Err(format!("restore task failed: {}", e)),
+    }
+    info!("Restored '{}' from '{}'", server_name, file_name);
+    if was_running {
+        servers::start_server(state, server_name).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(path: &Path, contents: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    fn settings(directory: &Path) -> BackupDescriptor {
+        BackupDescriptor {
+            directory: directory.to_string_lossy().into_owned(),
+            ..BackupDescriptor::default()
+        }
+    }
+
+    fn selected(settings: &BackupDescriptor, working_dir: &Path) -> Vec<String> {
+        let mut files: Vec<String> = collect_files(settings, working_dir, Path::new("/nowhere"))
+            .unwrap()
+            .into_iter()
+            .map(|(_, relative)| relative.to_string_lossy().replace('\\', "/"))
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn globs_select_what_is_backed_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let working_dir = dir.path();
+        for file in [
+            "world/level.dat",
+            "world/region/r.0.0.mca",
+            "world/cache/tmp.bin",
+            "server.properties",
+            "ops.json",
+            "logs/latest.log",
+            ".rsc-replaced/old.dat",
+        ] {
+            write(&working_dir.join(file), "x");
+        }
+
+        let mut settings = settings(&dir.path().join("backups"));
+        assert_eq!(
+            selected(&settings, working_dir),
+            [
+                "logs/latest.log",
+                "ops.json",
+                "server.properties",
+                "world/cache/tmp.bin",
+                "world/level.dat",
+                "world/region/r.0.0.mca",
+            ]
+        );
+
+        settings.include = vec!["world/".to_string(), "*.json".to_string()];
+        settings.exclude = vec!["world/cache".to_string()];
+        assert_eq!(
+            selected(&settings, working_dir),
+            ["ops.json", "world/level.dat", "world/region/r.0.0.mca"]
+        );
+
+        // `*` doesn't cross folders
+        settings.include = vec!["*.mca".to_string()];
+        settings.exclude = vec![];
+        assert!(selected(&settings, working_dir).is_empty());
+        settings.include = vec!["world/*/*.mca".to_string()];
+        assert_eq!(selected(&settings, working_dir), ["world/region/r.0.0.mca"]);
+    }
+
+    #[test]
+    fn backups_are_kept_apart_by_the_real_server_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings = settings(dir.path());
+        assert_ne!(
+            backup_dir(&settings, "My Server"),
+            backup_dir(&settings, "My_Server")
+        );
+    }
+
+    #[test]
+    fn retention_keeps_the_newest_backups() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut settings = settings(dir.path());
+        let backups = backup_dir(&settings, "srv");
+        let prefix = unique_file_name("srv");
+        let names = [
+            format!("{}-20240103-000000.tar.gz", prefix),
+            format!("{}-20240101-000000.zip", prefix),
+            // Written before the hash was added to archive names
+            "srv-20240102-000000.tar.gz".to_string(),
+            format!("{}-20240104-000000.zip", prefix),
+        ];
+        for name in names.iter() {
+            write(&backups.join(name), "x");
+        }
+        write(&backups.join("notes.txt"), "not a backup");
+
+        let listed: Vec<String> = list_backups(&settings, "srv")
+            .unwrap()
+            .into_iter()
+            .map(|backup| backup.file_name)
+            .collect();
+        assert_eq!(
+            listed,
+            [
+                names[3].clone(),
+                names[0].clone(),
+                names[2].clone(),
+                names[1].clone()
+            ]
+        );
+
+        settings.keep_count = Some(2);
+        assert_eq!(
+            apply_retention(&settings, "srv").unwrap(),
+            [names[2].clone(), names[1].clone()]
+        );
+        assert!(backups.join(&names[0]).exists());
+        assert!(backups.join(&names[3]).exists());
+        assert!(backups.join("notes.txt").exists());
+    }
+
+    #[test]
+    fn retention_deletes_backups_older_than_keep_days() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut settings = settings(dir.path());
+        let backups = backup_dir(&settings, "srv");
+        let old = backups.join("srv-20240101-000000.zip");
+        let new = backups.join("srv-20240102-000000.zip");
+        write(&old, "x");
+        write(&new, "x");
+        File::options()
+            .write(true)
+            .open(&old)
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(3 * 24 * 60 * 60))
+            .unwrap();
+
+        settings.keep_days = Some(7);
+        assert!(apply_retention(&settings, "srv").unwrap().is_empty());
+        settings.keep_days = Some(2);
+        assert_eq!(
+            apply_retention(&settings, "srv").unwrap(),
+            ["srv-20240101-000000.zip"]
+        );
+        assert!(!old.exists());
+        assert!(new.exists());
+    }
+
+    #[test]
+    fn legacy_backups_move_unless_the_folder_is_shared() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings = settings(dir.path());
+        write(&dir.path().join("My_Server/a-20240101-000000.zip"), "x");
+
+        adopt_legacy_backups(&settings, "My Server", true);
+        assert!(dir.path().join("My_Server").exists());
+        adopt_legacy_backups(&settings, "My Server", false);
+        assert!(!dir.path().join("My_Server").exists());
+        assert!(backup_dir(&settings, "My Server")
+            .join("a-20240101-000000.zip")
+            .exists());
+    }
+
+    #[test]
+    fn resolve_backup_refuses_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings = settings(dir.path());
+        write(&dir.path().join("escape.zip"), "x");
+        write(&backup_dir(&settings, "srv").join("ok.zip"), "x");
+        assert!(resolve_backup(&settings, "srv", "ok.zip").is_some());
+        assert!(resolve_backup(&settings, "srv", "../escape.zip").is_none());
+        assert!(resolve_backup(&settings, "srv", "missing.zip").is_none());
+    }
+
+    fn round_trip(format: ArchiveFormat) {
+        let dir = tempfile::tempdir().unwrap();
+        let working_dir = dir.path().join("server");
+        write(&working_dir.join("world/level.dat"), "level 1");
+        write(&working_dir.join("world/region/r.0.0.mca"), "region 1");
+        write(&working_dir.join("server.properties"), "motd=1");
+        let settings = BackupDescriptor {
+            format,
+            exclude: vec!["logs".to_string()],
+            ..settings(&dir.path().join("backups"))
+        };
+        let info = create_archive(&settings, "srv", &working_dir).unwrap();
+
+        write(&working_dir.join("world/level.dat"), "level 2");
+        write(&working_dir.join("world/new.dat"), "newer than the backup");
+        write(&working_dir.join("server.properties"), "motd=2");
+        write(&working_dir.join("logs/latest.log"), "kept");
+
+        let archive = resolve_backup(&settings, "srv", &info.file_name).unwrap();
+        restore_archive(&archive, &working_dir).unwrap();
+        let read = |file: &str| fs::read_to_string(working_dir.join(file)).unwrap();
+        assert_eq!(read("world/level.dat"), "level 1");
+        assert_eq!(read("world/region/r.0.0.mca"), "region 1");
+        assert_eq!(read("server.properties"), "motd=1");
+        assert_eq!(read("logs/latest.log"), "kept");
+        assert!(!working_dir.join("world/new.dat").exists());
+        assert!(!working_dir.join(RESTORE_STAGING_DIR).exists());
+        assert!(!working_dir.join(RESTORE_REPLACED_DIR).exists());
+    }
+
+    #[test]
+    fn restore_round_trip_tar_gz() {
+        round_trip(ArchiveFormat::TarGz);
+    }
+
+    #[test]
+    fn restore_round_trip_zip() {
+        round_trip(ArchiveFormat::Zip);
+    }
+
+    #[test]
+    fn damaged_archive_leaves_the_working_directory_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let working_dir = dir.path().join("server");
+        write(&working_dir.join("world/level.dat"), "level 1");
+        for format in [ArchiveFormat::TarGz, ArchiveFormat::Zip] {
+            let settings = BackupDescriptor {
+                format,
+                ..settings(&dir.path().join("backups"))
+            };
+            let info = create_archive(&settings, "srv", &working_dir).unwrap();
+            let archive = resolve_backup(&settings, "srv", &info.file_name).unwrap();
+            let bytes = fs::read(&archive).unwrap();
+            fs::write(&archive, &bytes[..bytes.len() / 2]).unwrap();
+
+            assert!(restore_archive(&archive, &working_dir).is_err());
+            assert_eq!(
+                fs::read_to_string(working_dir.join("world/level.dat")).unwrap(),
+                "level 1"
+            );
+            assert!(!working_dir.join(RESTORE_STAGING_DIR).exists());
+            fs::remove_file(&archive).unwrap();
+        }
+    }
+
+    #[test]
+    fn interrupted_restore_is_not_overwritten() {
+        let dir = tempfile::tempdir().unwrap();
+        let working_dir = dir.path().join("server");
+        write(&working_dir.join("world/level.dat"), "level 1");
+        let settings = settings(&dir.path().join("backups"));
+        let info = create_archive(&settings, "srv", &working_dir).unwrap();
+        write(
+            &working_dir
+                .join(RESTORE_REPLACED_DIR)
+                .join("world/level.dat"),
+            "only copy",
+        );
+
+        let archive = resolve_backup(&settings, "srv", &info.file_name).unwrap();
+        assert!(restore_archive(&archive, &working_dir).is_err());
+        assert!(working_dir
+            .join(RESTORE_REPLACED_DIR)
+            .join("world/level.dat")
+            .exists());
+    }
+}
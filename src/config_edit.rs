@@ -0,0 +1,271 @@
+/// Changes to the configuration made by clients.
+///
+/// Servers can be added, updated and removed one at a time, the global settings can be changed
+/// without touching the servers, and the whole configuration can still be replaced at once.
+/// Every change is saved to `config.json` and then compared with the previous configuration,
+/// so only the servers it affects are touched: removed servers are stopped, servers whose launch
+/// settings changed are restarted, newly added auto-start servers are started, and every other
+/// running server is left alone.
+use serde_json::{Map, Value};
+use tracing::*;
+
+use crate::{
+    app_state::AppState,
+    config_validation::{has_errors, validate, Diagnostic, DiagnosticCode},
+    configuration::{diff_servers, Config, ServerChanges},
+    controlled_program::ControlledProgramDescriptor,
+    messages::ConfigInfo,
+    servers,
+    websocket::server_info_message,
+};
+
+/// Applies settings that need no restart to the running servers they belong to.
+///
+/// # Arguments
+/// * `state` - The shared application state.
+/// * `names` - The servers whose settings changed.
+pub async fn update_running_settings(state: &AppState, names: &[String]) {
+    let config = state.config.lock().await;
+    let descriptors: Vec<ControlledProgramDescriptor> = config
+        .servers
+        .iter()
+        .filter(|desc| names.contains(&desc.name))
+        .cloned()
+        .collect();
+    drop(config);
+    for server in state.servers.lock().await.iter_mut() {
+        if let Some(desc) = descriptors.iter().find(|desc| desc.name == server.name) {
+            server.update_settings(desc);
+        }
+    }
+}
+
+/// Brings the running servers in line with a configuration change that was already applied.
+///
+/// # Arguments
+/// * `state` - The shared application state.
+/// * `changes` - How the servers differ from the previous configuration.
+async fn apply_server_changes(state: &AppState, changes: &ServerChanges) {
+    let running: Vec<String> = state
+        .servers
+        .lock()
+        .await
+        .iter()
+        .map(|server| server.name.clone())
+        .collect();
+    // Crash restarts still pending would bring back the old settings
+    state
+        .restart_trackers
+        .lock()
+        .await
+        .retain(|name, _| !changes.removed.contains(name) && !changes.relaunch.contains(name));
+
+    let stopping = changes.removed.iter().filter(|name| running.contains(name));
+    futures_util::future::join_all(stopping.map(|name| async move {
+        info!("Stopping '{}', it was removed from the configuration", name);
+        if let Err(e) = servers::stop_server(state, name).await {
+            warn!("Could not stop removed server '{}': {}", name, e);
+        }
+    }))
+    .await;
+
+    let restarting = changes
+        .relaunch
+        .iter()
+        .filter(|name| running.contains(name));
+    futures_util::future::join_all(restarting.map(|name| async move {
+        info!("Restarting '{}' to apply its new configuration", name);
+        if let Err(e) = servers::restart_server(state, name).await {
+            warn!("Could not restart '{}': {}", name, e);
+        }
+    }))
+    .await;
+
+    update_running_settings(state, &changes.updated).await;
+
+    let auto_start: Vec<String> = state
+        .config
+        .lock()
+        .await
+        .servers
+        .iter()
+        .filter(|desc| desc.auto_start && changes.added.contains(&desc.name))
+        .map(|desc| desc.name.clone())
+        .collect();
+    for name in auto_start {
+        if let Err(e) = servers::start_server(state, &name).await {
+            warn!("Could not start newly added server '{}': {}", name, e);
+        }
+    }
+}
+
+/// Edits a copy of the configuration, then saves it and applies the difference.
+///
+/// The configuration stays as it was if the edit fails or the result doesn't pass validation.
+///
+/// # Arguments
+/// * `state` - The shared application state.
+/// * `edit` - Changes the configuration, or returns why it can't.
+///
+/// # Returns
+/// * `Err` with every problem found if the change was refused.
+async fn commit<F>(state: &AppState, edit: F) -> Result<(), Vec<Diagnostic>>
+where
+    F: FnOnce(&mut Config) -> Result<(), Diagnostic>,
+{
+    let mut config = state.config.lock().await;
+    let mut updated = config.clone();
+    edit(&mut updated).map_err(|diagnostic| vec![diagnostic])?;
+    // Clients only ever see redacted secrets, so keep the ones they sent back unchanged
+    updated.restore_secrets(&config);
+    let diagnostics = validate(&updated, &state.specialization_registry);
+    if has_errors(&diagnostics) {
+        return Err(diagnostics);
+    }
+    for diagnostic in diagnostics {
+        warn!("Configuration change: {}", diagnostic);
+    }
+    let changes = diff_servers(&config.servers, &updated.servers);
+    config.change(updated);
+    config.update_config_file("config.json");
+    let config_info = ConfigInfo {
+        r#type: "ConfigInfo".to_owned(),
+        config: config.redacted(),
+    };
+    drop(config);
+    apply_server_changes(state, &changes).await;
+    // Broadcast the new configuration and server list to all clients
+    let _ = state.tx.send(serde_json::to_string(&config_info).unwrap());
+    let server_info = server_info_message(state, false).await;
+    let _ = state.tx.send(serde_json::to_string(&server_info).unwrap());
+    Ok(())
+}
+
+/// The refusal of a change to a server that isn't configured.
+fn unknown_server(server_name: &str) -> Diagnostic {
+    Diagnostic::error(
+        DiagnosticCode::UnknownServer,
+        "servers",
+        format!("server \"{}\" is not configured", server_name),
+    )
+}
+
+/// Replaces the configuration, restarting only the servers the change affects.
+///
+/// # Arguments
+/// * `state` - The shared application state.
+/// * `updated_config` - The configuration to switch to.
+pub async fn apply_config_change(
+    state: &AppState,
+    updated_config: Config,
+) -> Result<(), Vec<Diagnostic>> {
+    commit(state, |config| {
+        *config = updated_config;
+        Ok(())
+    })
+    .await
+}
+
+/// Adds a server to the configuration, starting it if it is marked for auto-start.
+///
+/// # Arguments
+/// * `state` - The shared application state.
+/// * `server` - The new server.
+pub async fn add_server(
+    state: &AppState,
+    server: ControlledProgramDescriptor,
+) -> Result<(), Vec<Diagnostic>> {
+    commit(state, |config| {
+        if config.servers.iter().any(|desc| desc.name == server.name) {
+            return Err(Diagnostic::error(
+                DiagnosticCode::DuplicateServerName,
+                "servers",
+                format!("server \"{}\" already exists", server.name),
+            ));
+        }
+        config.servers.push(server);
+        Ok(())
+    })
+    .await
+}
+
+/// Replaces the configuration of one server, restarting it if it is running and its launch
+/// settings changed.
+///
+/// # Arguments
+/// * `state` - The shared application state.
+/// * `server_name` - The server to update.
+/// * `server` - Its new configuration, which may rename it.
+pub async fn update_server(
+    state: &AppState,
+    server_name: &str,
+    mut server: ControlledProgramDescriptor,
+) -> Result<(), Vec<Diagnostic>> {
+    commit(state, |config| {
+        let desc = config
+            .servers
+            .iter_mut()
+            .find(|desc| desc.name == server_name)
+            .ok_or_else(|| unknown_server(server_name))?;
+        // Matched by the old name, in case the server is renamed
+        server.restore_secrets(desc);
+        *desc = server;
+        Ok(())
+    })
+    .await
+}
+
+/// Removes a server from the configuration, stopping it if it is running.
+///
+/// # Arguments
+/// * `state` - The shared application state.
+/// * `server_name` - The server to remove.
+pub async fn remove_server(state: &AppState, server_name: &str) -> Result<(), Vec<Diagnostic>> {
+    commit(state, |config| {
+        let before = config.servers.len();
+        config.servers.retain(|desc| desc.name != server_name);
+        if config.servers.len() == before {
+            return Err(unknown_server(server_name));
+        }
+        Ok(())
+    })
+    .await
+}
+
+/// Changes top level settings of the configuration, leaving the servers alone.
+///
+/// # Arguments
+/// * `state` - The shared application state.
+/// * `settings` - The settings to change by name, such as `"console_history"`; settings not
+///   listed keep their value.
+pub async fn update_global_settings(
+    state: &AppState,
+    settings: Map<String, Value>,
+) -> Result<(), Vec<Diagnostic>> {
+    commit(state, |config| {
+        let mut json = serde_json::to_value(&*config).unwrap();
+        let fields = json.as_object_mut().unwrap();
+        for (key, value) in settings {
+            if key == "servers" {
+                return Err(Diagnostic::error(
+                    DiagnosticCode::UnknownSetting,
+                    key,
+                    "servers are changed with addServer, updateServer and removeServer",
+                ));
+            }
+            if !fields.contains_key(&key) {
+                return Err(Diagnostic::error(
+                    DiagnosticCode::UnknownSetting,
+                    key,
+                    "there is no such setting",
+                ));
+            }
+            fields.insert(key, value);
+        }
+        *config = serde_json::from_value(json).map_err(|e| {
+            Diagnostic::error(DiagnosticCode::InvalidValue, "settings", e.to_string())
+        })?;
+        Ok(())
+    })
+    .await
+}
@@ -0,0 +1,308 @@
+/// Upgrades of configuration files written for older versions of the controller.
+///
+/// Every configuration carries a `config_version`. Files from before it existed count as
+/// version 0. When a file is loaded, each step of [`MIGRATIONS`] from its version up is applied
+/// in turn, so a file can be several versions behind. On startup the upgraded configuration is
+/// written back to the file, after the original is copied next to it as a backup.
+use chrono::Local;
+use serde_json::{Map, Value};
+use std::fs;
+
+use crate::{
+    config_validation::{Diagnostic, DiagnosticCode},
+    configuration::Config,
+    controlled_program::ControlledProgramDescriptor,
+    environment::{FILE_PREFIX, SECRET_NAME_PARTS},
+};
+
+/// The version of the configuration format this build writes.
+pub const CONFIG_VERSION: u32 = 2;
+
+/// Upgrades a configuration object by one version.
+type Migration = fn(&mut Map<String, Value>);
+
+/// The upgrade steps, in order: the step at index `n` turns version `n` into version `n + 1`.
+const MIGRATIONS: [Migration; CONFIG_VERSION as usize] = [write_out_defaults, mark_secrets];
+
+/// Version 0 to 1.
+///
+/// Settings added over time, such as `specialization_options` or `console_history`, were left
+/// out of older files and only worked through their defaults. They are written out so the file
+/// shows everything that applies. `themes_folder` and `specialized_server_type` are also made
+/// to say what they always meant: no themes folder meant `"themes"`, and an empty
+/// specialization meant none.
+fn write_out_defaults(config: &mut Map<String, Value>) {
+    // Settings every file had to have stay missing for validation to report
+    let required = ["interface", "port", "servers", "slave", "slave_connections"];
+    let Value::Object(defaults) = serde_json::to_value(Config::default()).unwrap() else {
+        return;
+    };
+    for (key, value) in defaults {
+        if !required.contains(&key.as_str()) {
+            config.entry(key).or_insert(value);
+        }
+    }
+    if config.get("themes_folder").is_some_and(Value::is_null) {
+        config.insert("themes_folder".to_string(), Value::from("themes"));
+    }
+
+    let required = [
+        "name",
+        "exe_path",
+        "arguments",
+        "working_dir",
+        "auto_start",
+        "crash_prevention",
+    ];
+    let Value::Object(defaults) = serde_json::to_value(ControlledProgramDescriptor::new(
+        "",
+        "",
+        vec![],
+        String::new(),
+    ))
+    .unwrap() else {
+        return;
+    };
+    let Some(Value::Array(servers)) = config.get_mut("servers") else {
+        return;
+    };
+    for server in servers.iter_mut().filter_map(Value::as_object_mut) {
+        for (key, value) in defaults.iter() {
+            if !required.contains(&key.as_str()) && !server.contains_key(key) {
+                server.insert(key.clone(), value.clone());
+            }
+        }
+        if server.get("specialized_server_type") == Some(&Value::from("")) {
+            server.insert("specialized_server_type".to_string(), Value::Null);
+        }
+    }
+}
+
+/// Version 1 to 2.
+///
+/// Server variables used to be kept from clients when their name looked like a secret. They
+/// are now only kept from clients when marked, so the values that were kept from them before
+/// are marked with `{"value": ..., "secret": true}`.
+fn mark_secrets(config: &mut Map<String, Value>) {
+    let Some(Value::Array(servers)) = config.get_mut("servers") else {
+        return;
+    };
+    for server in servers.iter_mut().filter_map(Value::as_object_mut) {
+        let Some(Value::Object(env)) = server.get_mut("env") else {
+            continue;
+        };
+        for (name, value) in env.iter_mut() {
+            let Value::String(text) = value else {
+                continue;
+            };
+            // References were shown as written, since the secret is elsewhere
+            if text.starts_with(FILE_PREFIX) || text.contains("${") {
+                continue;
+            }
+            let upper = name.to_ascii_uppercase();
+            if SECRET_NAME_PARTS.iter().any(|part| upper.contains(part)) {
+                *value = serde_json::json!({ "value": text, "secret": true });
+            }
+        }
+    }
+}
+
+/// The `config_version` of a configuration, 0 if it has none.
+fn version_of(config: &Map<String, Value>) -> u32 {
+    config
+        .get("config_version")
+        .and_then(Value::as_u64)
+        .map_or(0, |version| version.try_into().unwrap_or(u32::MAX))
+}
+
+/// Upgrades a parsed configuration file to [`CONFIG_VERSION`].
+///
+/// # Arguments
+/// * `json` - The parsed file. Anything that isn't an object is left for validation to report.
+///
+/// # Returns
+/// * The version the configuration had before.
+/// * `Err` if it was written by a newer version of the controller.
+pub fn migrate(json: &mut Value) -> Result<u32, Diagnostic> {
+    let Value::Object(config) = json else {
+        return Ok(CONFIG_VERSION);
+    };
+    let version = version_of(config);
+    if version > CONFIG_VERSION {
+        return Err(Diagnostic::error(
+            DiagnosticCode::UnsupportedVersion,
+            "config_version",
+            format!(
+                "version {} was written by a newer controller, this one reads up to version {}",
+                version, CONFIG_VERSION
+            ),
+        ));
+    }
+    for (step, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        migration(config);
+        config.insert("config_version".to_string(), Value::from(step + 1));
+    }
+    Ok(version)
+}
+
+/// Upgrades a configuration file in place if it is for an older version.
+///
+/// The original is kept as `<path>.v<version>-<timestamp>.bak`. Files that don't parse or
+/// whose upgrade isn't a valid configuration are left alone for validation to report.
+///
+/// # Arguments
+/// * `path` - The configuration file.
+/// * `text` - Its contents.
+///
+/// # Returns
+/// * The contents of the upgraded file and a warning saying where the original went, or `None`
+///   if nothing was upgraded.
+/// * `Err` if the backup or the upgraded file couldn't be written.
+pub fn upgrade_file(path: &str, text: &str) -> Result<Option<(String, Diagnostic)>, String> {
+    let Ok(mut json) = serde_json::from_str::<Value>(text) else {
+        return Ok(None);
+    };
+    let version = match migrate(&mut json) {
+        Ok(version) if version < CONFIG_VERSION => version,
+        _ => return Ok(None),
+    };
+    let Ok(config) = serde_json::from_value::<Config>(json) else {
+        return Ok(None);
+    };
+    let backup = format!(
+        "{}.v{}-{}.bak",
+        path,
+        version,
+        Local::now().format("%Y%m%d-%H%M%S")
+    );
+    fs::write(&backup, text).map_err(|e| format!("Could not back up '{}': {}", path, e))?;
+    let upgraded = serde_json::to_string_pretty(&config).map_err(|e| e.to_string())?;
+    fs::write(path, &upgraded).map_err(|e| format!("Could not write '{}': {}", path, e))?;
+    let notice = Diagnostic::warning(
+        DiagnosticCode::OutdatedVersion,
+        "config_version",
+        format!(
+            "upgraded from version {} to {}, the original was saved as {}",
+            version, CONFIG_VERSION, backup
+        ),
+    );
+    Ok(Some((upgraded, notice)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// A configuration as written before `config_version` existed.
+    fn unversioned() -> Value {
+        json!({
+            "interface": "127.0.0.1",
+            "port": "8080",
+            "servers": [{
+                "name": "mc",
+                "exe_path": "/bin/sh",
+                "arguments": ["start.sh"],
+                "working_dir": "/srv/mc",
+                "auto_start": true,
+                "crash_prevention": true,
+                "specialized_server_type": "",
+                "env": {
+                    "RCON_PASSWORD": "hunter2",
+                    "API_KEY": "@file:/run/secrets/key",
+                    "MOTD": "hello"
+                }
+            }],
+            "slave": false,
+            "slave_connections": []
+        })
+    }
+
+    /// Checks that a migrated configuration loads and stays the same when migrated again.
+    fn assert_round_trips(json: Value) -> Config {
+        let config: Config = serde_json::from_value(json).unwrap();
+        let mut written = serde_json::to_value(&config).unwrap();
+        let expected = written.clone();
+        assert_eq!(migrate(&mut written).unwrap(), CONFIG_VERSION);
+        assert_eq!(written, expected);
+        config
+    }
+
+    #[test]
+    fn unversioned_config_migrates_to_the_current_version() {
+        let mut json = unversioned();
+        assert_eq!(migrate(&mut json).unwrap(), 0);
+        assert_eq!(json["config_version"], json!(CONFIG_VERSION));
+        assert_eq!(json["themes_folder"], json!("themes"));
+        assert!(json.get("console_history").is_some());
+        let server = &json["servers"][0];
+        assert_eq!(server["specialized_server_type"], Value::Null);
+        assert_eq!(
+            server["env"]["RCON_PASSWORD"],
+            json!({"value": "hunter2", "secret": true})
+        );
+        assert_eq!(server["env"]["API_KEY"], json!("@file:/run/secrets/key"));
+        assert_eq!(server["env"]["MOTD"], json!("hello"));
+        let config = assert_round_trips(json);
+        assert_eq!(config.servers[0].name, "mc");
+    }
+
+    #[test]
+    fn version_one_config_only_gets_later_steps() {
+        let mut json = unversioned();
+        json["config_version"] = json!(1);
+        assert_eq!(migrate(&mut json).unwrap(), 1);
+        assert_eq!(json["config_version"], json!(CONFIG_VERSION));
+        // Writing out defaults belongs to version 0, so it is skipped
+        assert!(json.get("console_history").is_none());
+        assert_eq!(
+            json["servers"][0]["env"]["RCON_PASSWORD"],
+            json!({"value": "hunter2", "secret": true})
+        );
+    }
+
+    #[test]
+    fn current_config_is_left_alone() {
+        let mut json = unversioned();
+        migrate(&mut json).unwrap();
+        let expected = json.clone();
+        assert_eq!(migrate(&mut json).unwrap(), CONFIG_VERSION);
+        assert_eq!(json, expected);
+    }
+
+    #[test]
+    fn newer_config_is_rejected() {
+        let mut json = unversioned();
+        json["config_version"] = json!(CONFIG_VERSION + 1);
+        let expected = json.clone();
+        let error = migrate(&mut json).unwrap_err();
+        assert_eq!(error.code, DiagnosticCode::UnsupportedVersion);
+        assert_eq!(json, expected);
+    }
+
+    #[test]
+    fn upgraded_file_keeps_a_backup_of_the_original() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        let path = path.to_str().unwrap();
+        let original = serde_json::to_string_pretty(&unversioned()).unwrap();
+        fs::write(path, &original).unwrap();
+
+        let (upgraded, notice) = upgrade_file(path, &original).unwrap().unwrap();
+        assert_eq!(notice.code, DiagnosticCode::OutdatedVersion);
+        assert_eq!(fs::read_to_string(path).unwrap(), upgraded);
+        let backups: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.starts_with("config.json.v0-") && name.ends_with(".bak"))
+            .collect();
+        assert_eq!(backups.len(), 1);
+        assert_eq!(
+            fs::read_to_string(dir.path().join(&backups[0])).unwrap(),
+            original
+        );
+        assert_round_trips(serde_json::from_str(&upgraded).unwrap());
+
+        assert!(upgrade_file(path, &upgraded).unwrap().is_none());
+    }
+}
@@ -0,0 +1,224 @@
+/// Reloading of `config.json` while the controller runs.
+///
+/// The file is checked for changes every few seconds. A changed file is parsed and validated
+/// like at startup; if it is broken the running configuration is kept and a warning is logged.
+/// Otherwise only what changed is applied: newly added auto-start servers are started, running
+/// servers whose settings were unchanged are left alone, and running servers whose launch
+/// settings were changed, or that were removed, keep running as they are, flagged for the admin
+/// to restart. Settings that need no restart, such as crash prevention, apply right away.
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    time::{Duration, SystemTime},
+};
+use tracing::*;
+
+use crate::{
+    app_state::AppState,
+    config_edit::update_running_settings,
+    config_validation::{describe_errors, has_errors, validate_str},
+    configuration::{diff_servers, Config},
+    messages::ConfigInfo,
+    servers::{send_console_notice, start_server},
+    websocket::server_info_message,
+};
+
+/// The configuration file that is watched.
+const CONFIG_FILE: &str = "config.json";
+
+/// Config reload settings, stored in `Config`.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct ConfigReloadSettings {
+    /// Whether changes to `config.json` are applied without a restart.
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// How often the file is checked for changes.
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_interval_secs() -> u64 {
+    2
+}
+
+impl Default for ConfigReloadSettings {
+    fn default() -> Self {
+        Self {
+            enabled: default_enabled(),
+            interval_secs: default_interval_secs(),
+        }
+    }
+}
+
+/// Settings that are only read at startup and so need the controller itself restarted.
+fn startup_settings_changed(old: &Config, new: &Config) -> Vec<&'static str> {
+    let mut changed = vec![];
+    if old.interface != new.interface {
+        changed.push("interface");
+    }
+    if old.port != new.port {
+        changed.push("port");
+    }
+    if old.slave != new.slave {
+        changed.push("slave");
+    }
+    if serde_json::to_value(&old.slave_connections).ok()
+        != serde_json::to_value(&new.slave_connections).ok()
+    {
+        changed.push("slave_connections");
+    }
+    if old.tls != new.tls {
+        changed.push("tls");
+    }
+    if old.cgroups != new.cgroups {
+        changed.push("cgroups");
+    }
+    changed
+}
+
+/// Reads and validates the configuration file.
+///
+/// # Returns
+/// * `Ok(None)` if the file is empty, as it is for a moment while the controller rewrites it.
+/// * `Err` describing why the file can't be used.
+async fn read_config(state: &AppState) -> Result<Option<Config>, String> {
+    let text = tokio::fs::read_to_string(CONFIG_FILE)
+        .await
+        .map_err(|e| e.to_string())?;
+    if text.trim().is_empty() {
+        return Ok(None);
+    }
+    let (config, diagnostics) = validate_str(&text, &state.specialization_registry);
+    if has_errors(&diagnostics) {
+        return Err(describe_errors(&diagnostics));
+    }
+    for diagnostic in diagnostics {
+        warn!("{}: {}", CONFIG_FILE, diagnostic);
+    }
+    Ok(config)
+}
+
+/// Applies a configuration read from disk, touching only the servers that changed.
+///
+/// # Arguments
+/// * `state` - The shared application state.
+/// * `new_config` - The configuration that was read.
+pub async fn apply_reloaded_config(state: &AppState, new_config: Config) {
+    let mut config = state.config.lock().await;
+    // The controller's own writes, such as a saved schedule, are already applied
+    if serde_json::to_value(&*config).ok() == serde_json::to_value(&new_config).ok() {
+        return;
+    }
+    let changes = diff_servers(&config.servers, &new_config.servers);
+    let restart_settings = startup_settings_changed(&config, &new_config);
+    let auto_start: Vec<String> = new_config
+        .servers
+        .iter()
+        .filter(|desc| desc.auto_start && changes.added.contains(&desc.name))
+        .map(|desc| desc.name.clone())
+        .collect();
+    config.change(new_config);
+    let config_info = ConfigInfo {
+        r#type: "ConfigInfo".to_owned(),
+        config: config.redacted(),
+    };
+    drop(config);
+    info!(
+        "Reloaded {}: {} server(s) added, {} changed, {} removed",
+        CONFIG_FILE,
+        changes.added.len(),
+        changes.relaunch.len() + changes.updated.len(),
+        changes.removed.len()
+    );
+    if !restart_settings.is_empty() {
+        warn!(
+            "{} changed in {} and will only take effect after the controller is restarted",
+            restart_settings.join(", "),
+            CONFIG_FILE
+        );
+    }
+
+    // Running servers keep their old launch settings until someone restarts them
+    let mut flagged: Vec<(String, Option<String>, &str)> = vec![];
+    for server in state.servers.lock().await.iter_mut() {
+        let notice = if changes.relaunch.contains(&server.name) {
+            "Configuration changed, restart the server to apply it"
+        } else if changes.removed.contains(&server.name) {
+            "Removed from the configuration, it can't be started again once stopped"
+        } else {
+            continue;
+        };
+        server.restart_required = true;
+        flagged.push((
+            server.name.clone(),
+            server.specialized_server_type.clone(),
+            notice,
+        ));
+    }
+    update_running_settings(state, &changes.updated).await;
+    for (server_name, server_type, notice) in flagged {
+        warn!(
+            "Server '{}' runs with outdated settings: {}",
+            server_name, notice
+        );
+        send_console_notice(state, server_name, server_type, notice);
+    }
+
+    for server_name in auto_start {
+        match start_server(state, &server_name).await {
+            Ok(()) => info!("Started newly added server '{}'", server_name),
+            Err(e) => warn!(
+                "Could not start newly added server '{}': {}",
+                server_name, e
+            ),
+        }
+    }
+
+    let _ = state.tx.send(serde_json::to_string(&config_info).unwrap());
+    let server_info = server_info_message(state, false).await;
+    let _ = state.tx.send(serde_json::to_string(&server_info).unwrap());
+}
+
+/// When the configuration file was last changed.
+fn modified() -> Option<SystemTime> {
+    fs::metadata(CONFIG_FILE).ok()?.modified().ok()
+}
+
+/// Applies changes to `config.json` as they are saved.
+///
+/// # Arguments
+/// * `state` - The shared application state.
+pub async fn watch_config(state: AppState) {
+    let mut last_modified = modified();
+    loop {
+        let settings = state.config.lock().await.config_reload.clone();
+        tokio::time::sleep(Duration::from_secs(settings.interval_secs.max(1))).await;
+        if !settings.enabled {
+            continue;
+        }
+        let current = modified();
+        if current.is_none() || current == last_modified {
+            continue;
+        }
+        match read_config(&state).await {
+            Ok(Some(config)) => {
+                last_modified = current;
+                apply_reloaded_config(&state, config).await;
+            }
+            // Half-written, so look again on the next check
+            Ok(None) => {}
+            Err(e) => {
+                last_modified = current;
+                warn!(
+                    "Not reloading {}, keeping the running configuration: {}",
+                    CONFIG_FILE, e
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,480 @@
+/// Validation of the configuration file.
+///
+/// A configuration is checked before it is used: at startup, when it is reloaded from disk, when
+/// a client changes it, and with `--check-config`. Every problem found is reported as a
+/// [`Diagnostic`] instead of stopping at the first one, so all of them can be fixed in one go.
+/// Errors make the configuration unusable; warnings describe something that works but is
+/// probably not what was meant.
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{collections::HashSet, fmt, path::Path};
+
+use crate::{
+    config_migration::{migrate, CONFIG_VERSION},
+    configuration::Config,
+    environment::{self, REDACTED},
+    run_as,
+    specializations::SpecializationRegistry,
+};
+
+/// How serious a diagnostic is.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// The configuration can't be used.
+    Error,
+    /// The configuration works, but probably not as intended.
+    Warning,
+}
+
+/// What kind of problem a diagnostic describes.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DiagnosticCode {
+    /// The file isn't valid JSON.
+    Syntax,
+    /// A setting is missing or has the wrong type.
+    InvalidValue,
+    /// A top level setting that doesn't exist.
+    UnknownSetting,
+    /// A server without a name.
+    EmptyServerName,
+    /// Two servers with the same name.
+    DuplicateServerName,
+    /// A server that isn't in the configuration.
+    UnknownServer,
+    /// A server executable that can't be found.
+    MissingExecutable,
+    /// A server working directory that doesn't exist yet.
+    MissingWorkingDir,
+    /// A port that isn't a number between 1 and 65535.
+    InvalidPort,
+    /// A `specialized_server_type` that isn't registered.
+    UnknownSpecialization,
+    /// A `config_version` this controller can't read.
+    UnsupportedVersion,
+    /// A `config_version` that is upgraded when the file is loaded.
+    OutdatedVersion,
+    /// A server variable whose `${NAME}` or `@file:` reference can't be resolved.
+    UnresolvedEnv,
+    /// A `run_as_user`, `run_as_group` or supplementary group that doesn't exist, or that the
+    /// controller can't switch to.
+    UnknownUser,
+}
+
+/// One problem found in a configuration.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Diagnostic {
+    /// How serious the problem is.
+    pub severity: Severity,
+    /// What kind of problem it is.
+    pub code: DiagnosticCode,
+    /// Where in the configuration it is, such as `servers[2].exe_path`.
+    pub path: Option<String>,
+    /// The line of the file it is on, if known.
+    pub line: Option<usize>,
+    /// The column of the file it is on, if known.
+    pub column: Option<usize>,
+    /// What is wrong, and how to fix it.
+    pub message: String,
+}
+
+impl Diagnostic {
+    /// Creates an error at a path of the configuration.
+    pub fn error(
+        code: DiagnosticCode,
+        path: impl Into<String>,
+        message: impl Into<String>,
+    ) -> Self {
+        Self {
+            severity: Severity::Error,
+            code,
+            path: Some(path.into()),
+            line: None,
+            column: None,
+            message: message.into(),
+        }
+    }
+
+    /// Creates a warning at a path of the configuration.
+    pub fn warning(
+        code: DiagnosticCode,
+        path: impl Into<String>,
+        message: impl Into<String>,
+    ) -> Self {
+        Self {
+            severity: Severity::Warning,
+            ..Self::error(code, path, message)
+        }
+    }
+
+    /// Turns a parse error into an error at its line and column.
+    fn from_json_error(error: &serde_json::Error) -> Self {
+        let code = if error.is_data() {
+            DiagnosticCode::InvalidValue
+        } else {
+            DiagnosticCode::Syntax
+        };
+        Self {
+            severity: Severity::Error,
+            code,
+            path: None,
+            // Errors found after parsing have no location
+            line: Some(error.line()).filter(|line| *line > 0),
+            column: Some(error.column()).filter(|_| error.line() > 0),
+            // The location is kept apart, so leave it out of the message
+            message: error
+                .to_string()
+                .trim_end_matches(&format!(
+                    " at line {} column {}",
+                    error.line(),
+                    error.column()
+                ))
+                .to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{}", severity)?;
+        if let (Some(line), Some(column)) = (self.line, self.column) {
+            write!(f, " at line {} column {}", line, column)?;
+        }
+        if let Some(path) = &self.path {
+            write!(f, " in {}", path)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+/// Whether any of the diagnostics is an error.
+pub fn has_errors(diagnostics: &[Diagnostic]) -> bool {
+    diagnostics
+        .iter()
+        .any(|diagnostic| diagnostic.severity == Severity::Error)
+}
+
+/// Joins the errors among the diagnostics into one message, for logs and API replies.
+pub fn describe_errors(diagnostics: &[Diagnostic]) -> String {
+    diagnostics
+        .iter()
+        .filter(|diagnostic| diagnostic.severity == Severity::Error)
+        .map(|diagnostic| diagnostic.to_string())
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// Whether a server's executable can be found the way it will be started.
+///
+/// Paths are checked as they are and relative to the working directory; bare names are looked
+/// up on `PATH`.
+fn executable_exists(exe_path: &str, working_dir: &str) -> bool {
+    let path = Path::new(exe_path);
+    if path.exists() || Path::new(working_dir).join(path).exists() {
+        return true;
+    }
+    if path.components().count() > 1 {
+        return false;
+    }
+    std::env::var_os("PATH").is_some_and(|paths| {
+        std::env::split_paths(&paths).any(|dir| {
+            dir.join(path).is_file()
+                || (cfg!(windows) && dir.join(path).with_extension("exe").is_file())
+        })
+    })
+}
+
+/// Whether a port setting is a usable port number.
+fn valid_port(port: &str) -> bool {
+    port.parse::<u16>().is_ok_and(|port| port != 0)
+}
+
+/// The error for a secret that was sent back redacted where there is no earlier value.
+fn redacted_secret(path: impl Into<String>) -> Diagnostic {
+    Diagnostic::error(
+        DiagnosticCode::InvalidValue,
+        path,
+        "the secret is redacted and there is no earlier value to keep, enter it again",
+    )
+}
+
+/// Checks a parsed configuration.
+///
+/// # Arguments
+/// * `config` - The configuration to check.
+/// * `registry` - The specializations servers may use.
+pub fn validate(config: &Config, registry: &SpecializationRegistry) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+    if config.config_version != CONFIG_VERSION {
+        diagnostics.push(Diagnostic::error(
+            DiagnosticCode::UnsupportedVersion,
+            "config_version",
+            format!(
+                "must be {}, older files are upgraded when they are loaded",
+                CONFIG_VERSION
+            ),
+        ));
+    }
+    if !valid_port(&config.port) {
+        diagnostics.push(Diagnostic::error(
+            DiagnosticCode::InvalidPort,
+            "port",
+            format!(
+                "\"{}\" is not a port number between 1 and 65535",
+                config.port
+            ),
+        ));
+    }
+    if config.slave_secret.as_deref() == Some(REDACTED) {
+        diagnostics.push(redacted_secret("slave_secret"));
+    }
+    if config.prometheus.token.as_deref() == Some(REDACTED) {
+        diagnostics.push(redacted_secret("prometheus.token"));
+    }
+    for (index, slave) in config.slave_connections.iter().enumerate() {
+        if slave.secret.as_deref() == Some(REDACTED) {
+            diagnostics.push(redacted_secret(format!(
+                "slave_connections[{}].secret",
+                index
+            )));
+        }
+        if !valid_port(&slave.port) {
+            diagnostics.push(Diagnostic::error(
+                DiagnosticCode::InvalidPort,
+                format!("slave_connections[{}].port", index),
+                format!(
+                    "\"{}\" is not a port number between 1 and 65535",
+                    slave.port
+                ),
+            ));
+        }
+    }
+
+    let mut names: HashSet<&str> = HashSet::new();
+    for (index, desc) in config.servers.iter().enumerate() {
+        let at = |field: &str| format!("servers[{}].{}", index, field);
+        if desc.name.trim().is_empty() {
+            diagnostics.push(Diagnostic::error(
+                DiagnosticCode::EmptyServerName,
+                at("name"),
+                "every server needs a name",
+            ));
+        } else if !names.insert(desc.name.as_str()) {
+            diagnostics.push(Diagnostic::error(
+                DiagnosticCode::DuplicateServerName,
+                at("name"),
+                format!(
+                    "server name \"{}\" is used more than once; give each server its own name",
+                    desc.name
+                ),
+            ));
+        }
+        if desc.exe_path.trim().is_empty() {
+            diagnostics.push(Diagnostic::error(
+                DiagnosticCode::MissingExecutable,
+                at("exe_path"),
+                "no executable is set",
+            ));
+        } else if !executable_exists(&desc.exe_path, &desc.working_dir) {
+            // Only a warning, since the binary may not be installed yet or sit on a volume that
+            // isn't mounted, and the other servers shouldn't be held up by it
+            diagnostics.push(Diagnostic::warning(
+                DiagnosticCode::MissingExecutable,
+                at("exe_path"),
+                format!(
+                    "\"{}\" does not exist and is not on the PATH, the server won't start until it does",
+                    desc.exe_path
+                ),
+            ));
+        }
+        if !Path::new(&desc.working_dir).is_dir() {
+            diagnostics.push(Diagnostic::warning(
+                DiagnosticCode::MissingWorkingDir,
+                at("working_dir"),
+                format!(
+                    "\"{}\" does not exist and will be created when the server starts",
+                    desc.working_dir
+                ),
+            ));
+        }
+        for (name, value) in desc.env.iter() {
+            let path = format!("servers[{}].env.{}", index, name);
+            if name.is_empty() || name.contains(['=', '\0']) {
+                diagnostics.push(Diagnostic::error(
+                    DiagnosticCode::InvalidValue,
+                    path,
+                    "variable names can't be empty or contain '=' or NUL",
+                ));
+            } else if value.value() == REDACTED {
+                diagnostics.push(Diagnostic::error(
+                    DiagnosticCode::InvalidValue,
+                    path,
+                    "the value is redacted and there is no earlier value to keep, enter it again",
+                ));
+            } else if let Err(e) = environment::resolve(value.value()) {
+                diagnostics.push(Diagnostic::warning(
+                    DiagnosticCode::UnresolvedEnv,
+                    path,
+                    format!("{}, the server won't start", e),
+                ));
+            }
+        }
+        let limits = &desc.limits;
+        let out_of_range = [
+            (
+                "memory_max_mb",
+                limits.memory_max_mb.is_some_and(|mb| mb == 0),
+                "must be at least 1",
+            ),
+            (
+                "cpu_quota_percent",
+                limits.cpu_quota_percent.is_some_and(|percent| percent == 0),
+                "must be at least 1",
+            ),
+            (
+                "cpu_weight",
+                limits
+                    .cpu_weight
+                    .is_some_and(|weight| !(1..=10000).contains(&weight)),
+                "must be between 1 and 10000",
+            ),
+            (
+                "max_pids",
+                limits.max_pids.is_some_and(|pids| pids == 0),
+                "must be at least 1",
+            ),
+            (
+                "nice",
+                limits.nice.is_some_and(|nice| !(-20..=19).contains(&nice)),
+                "must be between -20 and 19",
+            ),
+            (
+                "ionice.level",
+                limits.ionice.is_some_and(|ionice| ionice.level > 7),
+                "must be between 0 and 7",
+            ),
+        ];
+        match run_as::lookup(desc) {
+            Err(e) => diagnostics.push(Diagnostic::error(
+                DiagnosticCode::UnknownUser,
+                at("run_as_user"),
+                e,
+            )),
+            // Checked here too, as --check-config may not run as the controller's user
+            Ok(Some(_)) => {
+                if let Err(e) = run_as::for_server(desc) {
+                    diagnostics.push(Diagnostic::warning(
+                        DiagnosticCode::UnknownUser,
+                        at("run_as_user"),
+                        format!("{}, the server won't start", e),
+                    ));
+                }
+            }
+            Ok(None) => {}
+        }
+        for (field, invalid, message) in out_of_range {
+            if invalid {
+                diagnostics.push(Diagnostic::error(
+                    DiagnosticCode::InvalidValue,
+                    at(&format!("limits.{}", field)),
+                    message,
+                ));
+            }
+        }
+        if let Some(specialization) = desc
+            .specialized_server_type
+            .as_deref()
+            .filter(|specialization| !specialization.is_empty())
+        {
+            if !registry.contains_key(specialization) {
+                let mut names = registry.existing_names();
+                names.sort();
+                let allowed: Vec<String> =
+                    names.iter().map(|name| format!("\"{}\"", name)).collect();
+                diagnostics.push(Diagnostic::warning(
+                    DiagnosticCode::UnknownSpecialization,
+                    at("specialized_server_type"),
+                    format!(
+                        "specialization \"{}\" does not exist, allowed values: {}. The server runs without one.",
+                        specialization,
+                        allowed.join(", ")
+                    ),
+                ));
+            }
+        }
+    }
+    diagnostics
+}
+
+/// Parses and checks the text of a configuration file, upgrading it first if it is for an
+/// older version.
+///
+/// # Arguments
+/// * `text` - The contents of the file.
+/// * `registry` - The specializations servers may use.
+///
+/// # Returns
+/// * The configuration, if it could be parsed, and every problem found.
+pub fn validate_str(
+    text: &str,
+    registry: &SpecializationRegistry,
+) -> (Option<Config>, Vec<Diagnostic>) {
+    let mut json: Value = match serde_json::from_str(text) {
+        Ok(json) => json,
+        Err(e) => return (None, vec![Diagnostic::from_json_error(&e)]),
+    };
+    let mut diagnostics = vec![];
+    let parsed = match migrate(&mut json) {
+        Ok(version) if version < CONFIG_VERSION => {
+            diagnostics.push(Diagnostic::warning(
+                DiagnosticCode::OutdatedVersion,
+                "config_version",
+                format!(
+                    "version {} is upgraded to {} when the controller starts",
+                    version, CONFIG_VERSION
+                ),
+            ));
+            serde_json::from_value::<Config>(json)
+        }
+        // Parsed again from the text so errors keep their line and column
+        Ok(_) => serde_json::from_str::<Config>(text),
+        Err(diagnostic) => return (None, vec![diagnostic]),
+    };
+    match parsed {
+        Ok(config) => {
+            diagnostics.extend(validate(&config, registry));
+            (Some(config), diagnostics)
+        }
+        Err(e) => {
+            diagnostics.push(Diagnostic::from_json_error(&e));
+            (None, diagnostics)
+        }
+    }
+}
+
+/// Checks a configuration file for `--check-config`, printing every problem found.
+///
+/// # Arguments
+/// * `path` - The configuration file to check.
+/// * `registry` - The specializations servers may use.
+///
+/// # Returns
+/// * `Err` if the file can't be read or has errors.
+pub fn check_file(path: &str, registry: &SpecializationRegistry) -> Result<(), String> {
+    let text =
+        std::fs::read_to_string(path).map_err(|e| format!("Could not read '{}': {}", path, e))?;
+    let (_, diagnostics) = validate_str(&text, registry);
+    for diagnostic in diagnostics.iter() {
+        println!("{}: {}", path, diagnostic);
+    }
+    if has_errors(&diagnostics) {
+        return Err(format!("'{}' is not a valid configuration", path));
+    }
+    println!("{} is valid", path);
+    Ok(())
+}
@@ -1,59 +1,81 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
 use std::fs::{self, File};
 use std::io::Write;
 
+use crate::audit::AuditSettings;
+use crate::auth::AuthSettings;
+use crate::config_migration::CONFIG_VERSION;
+use crate::config_reload::ConfigReloadSettings;
+use crate::console_history::ConsoleHistorySettings;
+use crate::controlled_program::ControlledProgramDescriptor;
+use crate::environment::REDACTED;
 use crate::master::SlaveConnectionDescriptor;
-use crate::specializations::SpecializationRegistry;
-
-/// Validates `specialized_server_type` values in a config JSON, warning on unknown types.
-
-///
-/// Prints the position (array index) and allowed values, and states defaulting to generic behavior.
+use crate::metrics::MetricsSettings;
+use crate::prometheus::PrometheusSettings;
+use crate::resource_limits::CgroupSettings;
+use crate::tls::TlsSettings;
+
+/// How the configured servers differ between two configurations, by name.
+#[derive(Default, Debug)]
+pub struct ServerChanges {
+    /// Servers only in the new configuration.
+    pub added: Vec<String>,
+    /// Servers in both whose launch settings differ, so a running one must be restarted.
+    pub relaunch: Vec<String>,
+    /// Servers in both where only settings that apply without a restart differ.
+    pub updated: Vec<String>,
+    /// Servers only in the old configuration.
+    pub removed: Vec<String>,
+}
 
+/// Compares the server lists of two configurations.
 ///
 /// # Arguments
-/// * `config_json` - The JSON value representing the configuration.
-/// * `registry` - The specialization registry to check allowed types.
-pub fn validate_specializations_in_config(config_json: &Value, registry: &SpecializationRegistry) {
-    let allowed: Vec<String> = registry
-        .existing_names()
-        .into_iter()
-        .map(|name| format!("\"{}\"", name))
-        .collect();
-
-    if let Some(servers) = config_json.get("servers").and_then(|v| v.as_array()) {
-        for (i, server) in servers.iter().enumerate() {
-            if let Some(spec_type) = server
-                .get("specialized_server_type")
-                .and_then(|v| v.as_str())
-            {
-                if !spec_type.is_empty() && !registry.contains_key(spec_type) {
-                    let position = format!("at servers[{}]", i);
-
-                    eprintln!(
-
-                        "Warning: Server Specialization \"{}\" does not exist {}, allowed values: {}. Defaulting to generic, non-specialized behavior.",
-
-                        spec_type,
-
-                        position,
-
-                        allowed.join(", ")
-
-                    );
+/// * `old` - The servers configured so far.
+/// * `new` - The servers configured from now on.
+pub fn diff_servers(
+    old: &[ControlledProgramDescriptor],
+    new: &[ControlledProgramDescriptor],
+) -> ServerChanges {
+    let mut changes = ServerChanges::default();
+    for desc in new {
+        match old.iter().find(|old_desc| old_desc.name == desc.name) {
+            None => changes.added.push(desc.name.clone()),
+            Some(old_desc) if old_desc.launch_differs(desc) => {
+                changes.relaunch.push(desc.name.clone())
+            }
+            Some(old_desc) => {
+                if serde_json::to_value(old_desc).ok() != serde_json::to_value(desc).ok() {
+                    changes.updated.push(desc.name.clone());
                 }
             }
         }
     }
+    for desc in old {
+        if !new.iter().any(|new_desc| new_desc.name == desc.name) {
+            changes.removed.push(desc.name.clone());
+        }
+    }
+    changes
+}
+
+/// The JSON Schema of the configuration file, for editors to complete and check it with.
+pub fn schema() -> serde_json::Value {
+    serde_json::to_value(schemars::schema_for!(Config)).unwrap()
 }
 
 /// Main configuration struct for the server controller.
 ///
 /// Contains network settings, server descriptors, slave node info, and theme folder location.
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
 
 pub struct Config {
+    /// Version of the configuration format. Older files are upgraded when they are loaded, and
+    /// files without one count as version 0.
+    #[serde(default)]
+    pub config_version: u32,
+
     /// Network interface to bind to (e.g., "0.0.0.0").
     pub interface: String,
 
@@ -61,7 +83,7 @@ pub struct Config {
     pub port: String,
 
     /// List of server descriptors to manage.
-    pub servers: Vec<crate::controlled_program::ControlledProgramDescriptor>,
+    pub servers: Vec<ControlledProgramDescriptor>,
 
     /// Whether this node is a slave.
     pub slave: bool,
@@ -69,8 +91,44 @@ pub struct Config {
     /// List of slave node connection descriptors.
     pub slave_connections: Vec<SlaveConnectionDescriptor>,
 
+    /// Shared secret a master must prove it knows before a slave accepts its connection.
+    #[serde(default)]
+    pub slave_secret: Option<String>,
+
     /// Optional path to the themes folder.
     pub themes_folder: Option<String>,
+
+    /// Where and how much console history is kept on disk.
+    #[serde(default)]
+    pub console_history: ConsoleHistorySettings,
+
+    /// Login requirements and where user accounts are stored.
+    #[serde(default)]
+    pub auth: AuthSettings,
+
+    /// Whether and where control actions are recorded.
+    #[serde(default)]
+    pub audit: AuditSettings,
+
+    /// Certificate settings for serving the web UI and slave listener over TLS.
+    #[serde(default)]
+    pub tls: TlsSettings,
+
+    /// Whether and how often `config.json` is checked for changes to apply while running.
+    #[serde(default)]
+    pub config_reload: ConfigReloadSettings,
+
+    /// Whether and how often the resource usage of the servers is measured.
+    #[serde(default)]
+    pub metrics: MetricsSettings,
+
+    /// Whether Prometheus metrics are served at `/metrics`, and the token scrapers may use.
+    #[serde(default)]
+    pub prometheus: PrometheusSettings,
+
+    /// Where the cgroups of servers with resource limits are created. Only read at startup.
+    #[serde(default)]
+    pub cgroups: CgroupSettings,
 }
 
 impl Config {
@@ -79,6 +137,8 @@ impl Config {
     /// # Arguments
     /// * `new_config` - The new configuration to copy values from.
     pub fn change(&mut self, new_config: Config) {
+        self.config_version = new_config.config_version;
+
         self.interface = new_config.interface;
 
         self.port = new_config.port;
@@ -90,6 +150,88 @@ impl Config {
         self.slave = new_config.slave;
 
         self.slave_connections = new_config.slave_connections.clone();
+
+        self.slave_secret = new_config.slave_secret;
+
+        self.console_history = new_config.console_history;
+
+        self.auth = new_config.auth;
+
+        self.audit = new_config.audit;
+
+        self.tls = new_config.tls;
+
+        self.config_reload = new_config.config_reload;
+
+        self.metrics = new_config.metrics;
+
+        self.prometheus = new_config.prometheus;
+
+        self.cgroups = new_config.cgroups;
+    }
+
+    /// Returns a copy that is safe to send to clients, with secrets replaced by
+    /// [`REDACTED`]: the slave secrets, the Prometheus token and the server variables marked
+    /// as secrets.
+    pub fn redacted(&self) -> Config {
+        let mut config = self.clone();
+        let redact = |secret: &mut Option<String>| {
+            if secret.is_some() {
+                *secret = Some(REDACTED.to_string());
+            }
+        };
+        redact(&mut config.slave_secret);
+        redact(&mut config.prometheus.token);
+        for slave in config.slave_connections.iter_mut() {
+            redact(&mut slave.secret);
+        }
+        for desc in config.servers.iter_mut() {
+            desc.redact_secrets();
+        }
+        config
+    }
+
+    /// Puts back the secrets a client left redacted, taking them from the current
+    /// configuration. Servers are matched by name, or by position in the list if they were
+    /// renamed, and slaves by address and port.
+    ///
+    /// # Arguments
+    /// * `current` - The configuration the secrets are taken from.
+    pub fn restore_secrets(&mut self, current: &Config) {
+        if self.slave_secret.as_deref() == Some(REDACTED) {
+            self.slave_secret.clone_from(&current.slave_secret);
+        }
+        if self.prometheus.token.as_deref() == Some(REDACTED) {
+            self.prometheus.token.clone_from(&current.prometheus.token);
+        }
+        for slave in self.slave_connections.iter_mut() {
+            if slave.secret.as_deref() == Some(REDACTED) {
+                if let Some(old) = current
+                    .slave_connections
+                    .iter()
+                    .find(|old| old.address == slave.address && old.port == slave.port)
+                {
+                    slave.secret.clone_from(&old.secret);
+                }
+            }
+        }
+        let names: Vec<String> = self.servers.iter().map(|desc| desc.name.clone()).collect();
+        for (index, desc) in self.servers.iter_mut().enumerate() {
+            let old = current
+                .servers
+                .iter()
+                .find(|old| old.name == desc.name)
+                // A server whose old name is gone was renamed in place
+                .or_else(|| {
+                    current
+                        .servers
+                        .get(index)
+                        .filter(|old| !names.contains(&old.name))
+                });
+            if let Some(old) = old {
+                desc.restore_secrets(old);
+            }
+        }
     }
 
     /// Writes the configuration to a file as pretty-printed JSON.
@@ -123,6 +265,8 @@ impl Default for Config {
     /// Returns a default configuration with standard values.
     fn default() -> Self {
         Self {
+            config_version: CONFIG_VERSION,
+
             interface: "0.0.0.0".to_string(),
 
             port: "80".to_string(),
@@ -133,7 +277,89 @@ impl Default for Config {
 
             slave_connections: vec![],
 
+            slave_secret: None,
+
             themes_folder: Some("themes".to_string()),
+
+            console_history: ConsoleHistorySettings::default(),
+
+            auth: AuthSettings::default(),
+
+            audit: AuditSettings::default(),
+
+            tls: TlsSettings::default(),
+
+            config_reload: ConfigReloadSettings::default(),
+
+            metrics: MetricsSettings::default(),
+
+            prometheus: PrometheusSettings::default(),
+
+            cgroups: CgroupSettings::default(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::environment::EnvValue;
+
+    fn server(name: &str, password: &str) -> ControlledProgramDescriptor {
+        let mut desc = ControlledProgramDescriptor::new(name, "/bin/true", vec![], String::new());
+        desc.env.insert(
+            "PASSWORD".to_string(),
+            EnvValue::Detailed {
+                value: password.to_string(),
+                secret: true,
+            },
+        );
+        desc
+    }
+
+    fn config(servers: Vec<ControlledProgramDescriptor>) -> Config {
+        Config {
+            servers,
+            ..Config::default()
+        }
+    }
+
+    fn passwords(config: &Config) -> Vec<&str> {
+        config
+            .servers
+            .iter()
+            .map(|desc| desc.env["PASSWORD"].value())
+            .collect()
+    }
+
+    #[test]
+    fn secrets_follow_servers_by_name() {
+        let current = config(vec![server("a", "pa"), server("b", "pb")]);
+        let mut updated = current.redacted();
+        updated.servers.reverse();
+        updated.restore_secrets(&current);
+        assert_eq!(passwords(&updated), ["pb", "pa"]);
+    }
+
+    #[test]
+    fn renamed_server_keeps_its_secrets() {
+        let current = config(vec![server("a", "pa"), server("b", "pb")]);
+        let mut updated = current.redacted();
+        updated.servers[1].name = "renamed".to_string();
+        updated.restore_secrets(&current);
+        assert_eq!(passwords(&updated), ["pa", "pb"]);
+    }
+
+    #[test]
+    fn secrets_are_not_taken_from_servers_that_still_exist() {
+        let current = config(vec![server("a", "pa"), server("b", "pb")]);
+        let mut updated = current.redacted();
+        // A renamed server that also moved can't be told apart from a new one
+        updated.servers[1].name = "renamed".to_string();
+        updated.servers.swap(0, 1);
+        // Neither can a new server carrying a placeholder it has no source for
+        updated.servers.push(server("new", REDACTED));
+        updated.restore_secrets(&current);
+        assert_eq!(passwords(&updated), [REDACTED, "pa", REDACTED]);
+    }
+}
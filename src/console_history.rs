@@ -0,0 +1,434 @@
+/// Persistent, rotating console history for managed servers.
+///
+/// Every console line is appended to `{directory}/{server}-{hash}/console.log` as
+/// `timestamp<TAB>stream<TAB>text`. When the file grows past `max_file_bytes` it is rotated to
+/// `console.log.1`, `console.log.2`, ... and the oldest file past `max_files` is deleted.
+/// Timestamps only ever increase within a server's history, so a timestamp can be used as a
+/// paging cursor.
+use chrono::{DateTime, Duration, FixedOffset, Local};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+use tracing::*;
+
+use crate::controlled_program::OutputStream;
+use crate::files::{lines_backwards, sanitize_file_name, unique_file_name};
+
+/// Name of the file currently being written in each server's history directory.
+const HISTORY_FILE_NAME: &str = "console.log";
+
+/// Largest page of history a client can request at once.
+pub const MAX_PAGE_LINES: usize = 1000;
+
+/// Retention settings for console history, stored in `Config`.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct ConsoleHistorySettings {
+    /// Whether console output is written to disk at all.
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Folder that holds one history directory per server.
+    #[serde(default = "default_directory")]
+    pub directory: String,
+    /// Size at which the current history file is rotated.
+    #[serde(default = "default_max_file_bytes")]
+    pub max_file_bytes: u64,
+    /// Number of history files kept per server, including the current one.
+    #[serde(default = "default_max_files")]
+    pub max_files: usize,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_directory() -> String {
+    "logs/console".to_string()
+}
+
+fn default_max_file_bytes() -> u64 {
+    5 * 1024 * 1024
+}
+
+fn default_max_files() -> usize {
+    5
+}
+
+impl Default for ConsoleHistorySettings {
+    fn default() -> Self {
+        Self {
+            enabled: default_enabled(),
+            directory: default_directory(),
+            max_file_bytes: default_max_file_bytes(),
+            max_files: default_max_files(),
+        }
+    }
+}
+
+/// Where a history line came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HistoryStream {
+    /// Standard output of the server.
+    Out,
+    /// Standard error of the server.
+    Err,
+    /// Events recorded by the controller itself, such as exits.
+    Ctl,
+}
+
+impl HistoryStream {
+    fn as_str(&self) -> &'static str {
+        match self {
+            HistoryStream::Out => "out",
+            HistoryStream::Err => "err",
+            HistoryStream::Ctl => "ctl",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "out" => Some(HistoryStream::Out),
+            "err" => Some(HistoryStream::Err),
+            "ctl" => Some(HistoryStream::Ctl),
+            _ => None,
+        }
+    }
+}
+
+impl From<OutputStream> for HistoryStream {
+    fn from(stream: OutputStream) -> Self {
+        match stream {
+            OutputStream::Stdout => HistoryStream::Out,
+            OutputStream::Stderr => HistoryStream::Err,
+        }
+    }
+}
+
+/// A single raw console line waiting to be written to disk.
+#[derive(Clone, Debug)]
+pub struct HistoryEntry {
+    /// When the line was read.
+    pub timestamp: DateTime<Local>,
+    /// Which stream the line came from.
+    pub stream: HistoryStream,
+    /// The raw line, without its line ending.
+    pub text: String,
+}
+
+impl HistoryEntry {
+    /// Creates an entry timestamped now.
+    pub fn now(stream: HistoryStream, text: impl Into<String>) -> Self {
+        Self {
+            timestamp: Local::now(),
+            stream,
+            text: text.into(),
+        }
+    }
+
+    fn to_line(&self, timestamp: DateTime<Local>) -> String {
+        format!(
+            "{}\t{}\t{}\n",
+            timestamp.to_rfc3339(),
+            self.stream.as_str(),
+            self.text.replace(['\r', '\n'], "")
+        )
+    }
+}
+
+/// A history line read back from disk.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HistoryLine {
+    /// RFC 3339 timestamp of the line.
+    pub timestamp: String,
+    /// Which stream the line came from.
+    pub stream: HistoryStream,
+    /// The raw line.
+    pub text: String,
+}
+
+impl HistoryLine {
+    fn parse(line: &str) -> Option<Self> {
+        let mut parts = line.splitn(3, '\t');
+        let timestamp = parts.next()?.to_string();
+        let stream = HistoryStream::parse(parts.next()?)?;
+        let text = parts.next()?.to_string();
+        Some(Self {
+            timestamp,
+            stream,
+            text,
+        })
+    }
+
+    /// The parsed timestamp of the line, if it is valid.
+    fn time(&self) -> Option<DateTime<FixedOffset>> {
+        DateTime::parse_from_rfc3339(&self.timestamp).ok()
+    }
+}
+
+/// One page of history, oldest line first.
+pub struct HistoryPage {
+    /// The lines of this page.
+    pub lines: Vec<HistoryLine>,
+    /// Whether older matching lines exist beyond this page.
+    pub has_more: bool,
+    /// The `before` cursor of the next, older page, if there is one.
+    pub next_before: Option<String>,
+}
+
+/// An open history file, how many bytes it holds and the timestamp of its newest line.
+struct OpenHistoryFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    last_timestamp: Option<DateTime<Local>>,
+}
+
+/// Writer side of the console history, keeping one open file per server.
+#[derive(Default)]
+pub struct ConsoleHistory {
+    files: HashMap<String, OpenHistoryFile>,
+}
+
+impl ConsoleHistory {
+    /// Appends entries to a server's history, rotating files as needed.
+    ///
+    /// # Arguments
+    /// * `settings` - Current retention settings.
+    /// * `server_name` - The server the entries belong to.
+    /// * `entries` - The lines to write, oldest first.
+    pub fn append(
+        &mut self,
+        settings: &ConsoleHistorySettings,
+        server_name: &str,
+        entries: &[HistoryEntry],
+    ) -> io::Result<()> {
+        if !settings.enabled || entries.is_empty() {
+            return Ok(());
+        }
+        let path = history_file_path(settings, server_name);
+        // Settings may have moved the history folder since the file was opened
+        if self
+            .files
+            .get(server_name)
+            .is_some_and(|open| open.path != path)
+        {
+            self.files.remove(server_name);
+        }
+        if !self.files.contains_key(server_name) {
+            self.files
+                .insert(server_name.to_string(), open_history_file(&path)?);
+        }
+
+        for entry in entries {
+            // Lines read in the same instant, or after the clock went back, are moved just past
+            // the previous line so that no two lines share a timestamp
+            let timestamp = match self.files[server_name].last_timestamp {
+                Some(last) if entry.timestamp <= last => last + Duration::nanoseconds(1),
+                _ => entry.timestamp,
+            };
+            let line = entry.to_line(timestamp);
+            let needs_rotation = {
+                let open = &self.files[server_name];
+                open.size > 0 && open.size + line.len() as u64 > settings.max_file_bytes
+            };
+            if needs_rotation {
+                self.files.remove(server_name);
+                rotate(&path, settings.max_files)?;
+                self.files
+                    .insert(server_name.to_string(), open_history_file(&path)?);
+            }
+            let open = self.files.get_mut(server_name).unwrap();
+            open.file.write_all(line.as_bytes())?;
+            open.size += line.len() as u64;
+            open.last_timestamp = Some(timestamp);
+        }
+        self.files.get_mut(server_name).unwrap().file.flush()
+    }
+}
+
+/// Path of the history file currently being written for a server.
+fn history_file_path(settings: &ConsoleHistorySettings, server_name: &str) -> PathBuf {
+    Path::new(&settings.directory)
+        .join(unique_file_name(server_name))
+        .join(HISTORY_FILE_NAME)
+}
+
+/// Moves the history of configured servers out of the directories named after their sanitized
+/// name alone, which were used before the hash was added.
+///
+/// A directory that several configured servers map to is left alone, since it can't be told
+/// whose lines are in it.
+///
+/// # Arguments
+/// * `settings` - Current retention settings.
+/// * `server_names` - The names of the configured servers.
+pub fn adopt_legacy_history(settings: &ConsoleHistorySettings, server_names: &[String]) {
+    for server_name in server_names {
+        let sanitized = sanitize_file_name(server_name);
+        let legacy = Path::new(&settings.directory).join(&sanitized);
+        let Some(dir) = history_file_path(settings, server_name)
+            .parent()
+            .map(Path::to_path_buf)
+        else {
+            continue;
+        };
+        if !legacy.is_dir() || dir.exists() {
+            continue;
+        }
+        let shared = server_names
+            .iter()
+            .any(|other| other != server_name && sanitize_file_name(other) == sanitized);
+        if shared {
+            warn!(
+                "Console history in '{}' may belong to '{}' or to another server with a similar name, it is left where it is",
+                legacy.display(),
+                server_name
+            );
+            continue;
+        }
+        if let Err(e) = fs::rename(&legacy, &dir) {
+            warn!(
+                "Could not move the console history of '{}' to '{}': {}",
+                server_name,
+                dir.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Path of a rotated history file; index 0 is the current file.
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    if index == 0 {
+        path.to_path_buf()
+    } else {
+        PathBuf::from(format!("{}.{}", path.display(), index))
+    }
+}
+
+fn open_history_file(path: &Path) -> io::Result<OpenHistoryFile> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .read(true)
+        .open(path)?;
+    let size = file.metadata()?.len();
+    let mut last_timestamp = None;
+    lines_backwards(&mut file, size, |_, line| {
+        last_timestamp = HistoryLine::parse(line)
+            .and_then(|line| line.time())
+            .map(|time| time.with_timezone(&Local));
+        false
+    })?;
+    Ok(OpenHistoryFile {
+        path: path.to_path_buf(),
+        file,
+        size,
+        last_timestamp,
+    })
+}
+
+/// Shifts every history file up by one index, dropping the oldest beyond `max_files`.
+fn rotate(path: &Path, max_files: usize) -> io::Result<()> {
+    let max_files = max_files.max(1);
+    let oldest = rotated_path(path, max_files - 1);
+    if oldest.exists() {
+        fs::remove_file(&oldest)?;
+    }
+    for index in (0..max_files - 1).rev() {
+        let from = rotated_path(path, index);
+        if from.exists() {
+            fs::rename(&from, rotated_path(path, index + 1))?;
+        }
+    }
+    Ok(())
+}
+
+/// Timestamp of the oldest line of a history file, if it has a valid one.
+fn first_timestamp(file: &File) -> io::Result<Option<DateTime<FixedOffset>>> {
+    let mut line = String::new();
+    BufReader::new(file).read_line(&mut line)?;
+    Ok(HistoryLine::parse(line.trim_end_matches('\n')).and_then(|line| line.time()))
+}
+
+/// Reads one page of a server's history, counting back from the newest line.
+///
+/// Files are read from their end and only as far as the page needs, so recent pages stay quick
+/// however much history is kept. Blocking; call it from `spawn_blocking`.
+///
+/// # Arguments
+/// * `settings` - Current retention settings.
+/// * `server_name` - The server whose history to read.
+/// * `before` - Only lines older than this are returned; `None` starts at the newest line.
+/// * `limit` - Maximum number of lines to return.
+/// * `search` - Optional case-insensitive text the lines must contain.
+pub fn read_page(
+    settings: &ConsoleHistorySettings,
+    server_name: &str,
+    before: Option<DateTime<FixedOffset>>,
+    limit: usize,
+    search: Option<&str>,
+) -> io::Result<HistoryPage> {
+    let path = history_file_path(settings, server_name);
+    let search = search.map(|s| s.to_lowercase()).filter(|s| !s.is_empty());
+    let limit = limit.clamp(1, MAX_PAGE_LINES);
+
+    let mut newest_first: Vec<HistoryLine> = Vec::with_capacity(limit);
+    let mut has_more = false;
+    for index in 0..settings.max_files.max(1) {
+        let mut file = match File::open(rotated_path(&path, index)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+            Err(e) => return Err(e),
+        };
+        // A file that starts at or after the cursor holds nothing older than it
+        if let Some(before) = before {
+            if first_timestamp(&file)?.is_some_and(|first| first >= before) {
+                continue;
+            }
+        }
+        let end = file.metadata()?.len();
+        lines_backwards(&mut file, end, |_, line| {
+            let Some(line) = HistoryLine::parse(line) else {
+                return true;
+            };
+            if before.is_some_and(|before| line.time().is_none_or(|time| time >= before)) {
+                return true;
+            }
+            if search
+                .as_ref()
+                .is_some_and(|search| !line.text.to_lowercase().contains(search))
+            {
+                return true;
+            }
+            if newest_first.len() == limit {
+                // One more matching line exists past this page
+                has_more = true;
+                return false;
+            }
+            newest_first.push(line);
+            true
+        })?;
+        if has_more {
+            break;
+        }
+    }
+    newest_first.reverse();
+    let next_before = newest_first
+        .first()
+        .filter(|_| has_more)
+        .map(|line| line.timestamp.clone());
+    Ok(HistoryPage {
+        lines: newest_first,
+        has_more,
+        next_before,
+    })
+}
@@ -1,5 +1,19 @@
 use crate::ansi_to_html::ansi_to_html;
+use crate::backup::BackupDescriptor;
+use crate::console_history::HistoryEntry;
+use crate::environment::{self, EnvValue};
+#[cfg(unix)]
+use crate::pty::PtyMaster;
+use crate::resource_limits::{Confinement, ExitReason, ResourceLimits};
+use crate::restart_policy::RestartPolicy;
+use crate::run_as::{self, Credentials};
+use crate::scheduler::ScheduleDescriptor;
+// Pseudo-terminals are Unix only, so elsewhere there is never one to hold
+#[cfg(not(unix))]
+type PtyMaster = tokio::fs::File;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::process::Stdio;
 use tokio::{
     io::*,
@@ -7,9 +21,90 @@ use tokio::{
     time::{Duration, *},
 };
 
+/// Default number of seconds a server gets to shut down after its stop command is sent.
+pub const DEFAULT_STOP_TIMEOUT_SECS: u64 = 30;
+
+/// How long a process gets to exit after SIGTERM before it is killed outright.
+#[cfg(unix)]
+const SIGTERM_GRACE: Duration = Duration::from_secs(10);
+
+/// Console width advertised to servers through `COLUMNS`, and the initial PTY width.
+const DEFAULT_CONSOLE_COLUMNS: u16 = 120;
+
+/// Console height advertised to servers through `LINES`, and the initial PTY height.
+const DEFAULT_CONSOLE_ROWS: u16 = 30;
+
+/// How long an unterminated line (such as a prompt) is held back waiting for its newline.
+const PARTIAL_LINE_FLUSH: Duration = Duration::from_millis(50);
+
+/// Which of the process's output streams a console line came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+/// Spawns a task that splits a process output stream into lines and forwards them, tagged
+/// with their origin, until the stream closes.
+///
+/// # Arguments
+/// * `reader` - The process output stream to read.
+/// * `stream` - Which stream this is, attached to every forwarded line.
+/// * `tx` - Channel the tagged lines are sent to.
+fn spawn_line_reader<R>(
+    mut reader: R,
+    stream: OutputStream,
+    tx: tokio::sync::mpsc::UnboundedSender<(OutputStream, String)>,
+) where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut pending: Vec<u8> = Vec::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            let read = match timeout(PARTIAL_LINE_FLUSH, reader.read(&mut buf)).await {
+                Ok(Ok(0)) | Ok(Err(_)) => break,
+                Ok(Ok(read)) => read,
+                Err(_) => {
+                    // Nothing more arrived, so hand over whatever partial line is waiting
+                    if !pending.is_empty() {
+                        let line = String::from_utf8_lossy(&pending).replace('\r', "");
+                        pending.clear();
+                        if tx.send((stream, line)).is_err() {
+                            break;
+                        }
+                    }
+                    continue;
+                }
+            };
+            pending.extend_from_slice(&buf[..read]);
+            while let Some(newline) = pending.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = pending.drain(..=newline).collect();
+                let line = String::from_utf8_lossy(&line).replace(['\r', '\n'], "");
+                if tx.send((stream, line)).is_err() {
+                    return;
+                }
+            }
+        }
+        if !pending.is_empty() {
+            let _ = tx.send((stream, String::from_utf8_lossy(&pending).replace('\r', "")));
+        }
+    });
+}
+
+/// Wraps a rendered stderr line so the web console can style it apart from stdout.
+///
+/// A trailing `<br>` is kept outside the span so the console still splits the line cleanly.
+pub fn mark_stderr(line: &str) -> String {
+    match line.strip_suffix("<br>") {
+        Some(content) => format!("<span class=\"STDErrLine\">{}</span><br>", content),
+        None => format!("<span class=\"STDErrLine\">{}</span>", line),
+    }
+}
+
 /// Configuration descriptor for a server or program to be controlled by the application.
 /// Used for configuration and instantiation of server processes.
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
 pub struct ControlledProgramDescriptor {
     /// Display name of the server/program.
     pub name: String,
@@ -31,6 +126,44 @@ pub struct ControlledProgramDescriptor {
     pub specialized_server_info: Option<serde_json::Value>,
     /// Optional specialization options for specializations to use (serialized).
     pub specialization_options: Option<serde_json::Value>,
+    /// Seconds to wait for the server to exit after its stop command before escalating.
+    /// Defaults to [`DEFAULT_STOP_TIMEOUT_SECS`].
+    #[serde(default)]
+    pub stop_timeout_secs: Option<u64>,
+    /// Whether to run the process under a pseudo-terminal instead of plain pipes (Unix only).
+    #[serde(default)]
+    pub pty: bool,
+    /// How crashes are restarted and when the server is given up on.
+    #[serde(default)]
+    pub restart_policy: RestartPolicy,
+    /// Timed tasks such as nightly restarts, run by the scheduler.
+    #[serde(default)]
+    pub schedules: Vec<ScheduleDescriptor>,
+    /// What to back up, where to and how many backups to keep.
+    #[serde(default)]
+    pub backup: BackupDescriptor,
+    /// Environment variables set for the process. Values may use `${NAME}` and `@file:`
+    /// references and be marked as secrets, see [`crate::environment`].
+    #[serde(default)]
+    pub env: BTreeMap<String, EnvValue>,
+    /// Whether the process starts with only the variables the controller sets instead of
+    /// inheriting the controller's environment.
+    #[serde(default)]
+    pub env_clear: bool,
+    /// Memory, CPU, process and other limits, see [`crate::resource_limits`].
+    #[serde(default)]
+    pub limits: ResourceLimits,
+    /// User the process runs as, by name or ID, instead of the controller's. Needs the
+    /// controller to run as root.
+    #[serde(default)]
+    pub run_as_user: Option<String>,
+    /// Group the process runs as, by name or ID. Defaults to the primary group of
+    /// `run_as_user`.
+    #[serde(default)]
+    pub run_as_group: Option<String>,
+    /// Further groups the process is a member of, by name or ID.
+    #[serde(default)]
+    pub supplementary_groups: Vec<String>,
 }
 impl ControlledProgramDescriptor {
     /// Creates a new descriptor with all fields specified.
@@ -59,6 +192,17 @@ impl ControlledProgramDescriptor {
             specialized_server_type: None,
             specialized_server_info: None,
             specialization_options: None,
+            stop_timeout_secs: None,
+            pty: false,
+            restart_policy: RestartPolicy::default(),
+            schedules: vec![],
+            backup: BackupDescriptor::default(),
+            env: BTreeMap::new(),
+            env_clear: false,
+            limits: ResourceLimits::default(),
+            run_as_user: None,
+            run_as_group: None,
+            supplementary_groups: vec![],
         }
     }
 
@@ -80,27 +224,74 @@ impl ControlledProgramDescriptor {
             specialized_server_type: None,
             specialized_server_info: None,
             specialization_options: None,
+            stop_timeout_secs: None,
+            pty: false,
+            restart_policy: RestartPolicy::default(),
+            schedules: vec![],
+            backup: BackupDescriptor::default(),
+            env: BTreeMap::new(),
+            env_clear: false,
+            limits: ResourceLimits::default(),
+            run_as_user: None,
+            run_as_group: None,
+            supplementary_groups: vec![],
         }
     }
 
+    /// Whether a running instance of this server must be restarted to switch to `other`.
+    ///
+    /// Only settings the process is launched with count; the others are read from the
+    /// configuration when needed or applied to the instance with
+    /// [`ControlledProgramInstance::update_settings`].
+    pub fn launch_differs(&self, other: &ControlledProgramDescriptor) -> bool {
+        self.exe_path != other.exe_path
+            || self.arguments != other.arguments
+            || self.working_dir != other.working_dir
+            || self.pty != other.pty
+            || self.specialized_server_type != other.specialized_server_type
+            || self.specialization_options != other.specialization_options
+            || self.env != other.env
+            || self.env_clear != other.env_clear
+            || self.limits != other.limits
+            || self.run_as_user != other.run_as_user
+            || self.run_as_group != other.run_as_group
+            || self.supplementary_groups != other.supplementary_groups
+    }
+
+    /// Replaces the secret values of `env` with a placeholder, for sending to clients.
+    pub fn redact_secrets(&mut self) {
+        environment::redact(&mut self.env);
+    }
+
+    /// Puts back the values of `env` a client left as the redacted placeholder.
+    ///
+    /// # Arguments
+    /// * `current` - The configuration of the server the values are taken from.
+    pub fn restore_secrets(&mut self, current: &ControlledProgramDescriptor) {
+        environment::restore(&mut self.env, &current.env);
+    }
+
     /// Converts this descriptor into a running [`ControlledProgramInstance`].
     ///
     /// Attaches a specialization handler if specified.
     ///
     /// # Arguments
     /// * `registry` - The specialization registry to use for handler lookup.
+    ///
+    /// # Returns
+    /// * `Err` if the server's user or group doesn't exist or the process can't be started.
     pub fn into_instance(
         self,
         registry: &crate::specializations::SpecializationRegistry,
-    ) -> ControlledProgramInstance {
+    ) -> std::result::Result<ControlledProgramInstance, String> {
         use std::collections::HashMap;
 
         // Prepare default environment variables
         let mut envs: HashMap<String, String> = HashMap::new();
         envs.insert("TERM".to_string(), "xterm-256color".to_string());
         envs.insert("COLORTERM".to_string(), "truecolor".to_string());
-        envs.insert("COLUMNS".to_string(), "120".to_string());
-        envs.insert("LINES".to_string(), "30".to_string());
+        envs.insert("COLUMNS".to_string(), DEFAULT_CONSOLE_COLUMNS.to_string());
+        envs.insert("LINES".to_string(), DEFAULT_CONSOLE_ROWS.to_string());
         envs.insert(
             "TERM_PROGRAM".to_string(),
             "RustServerController".to_string(),
@@ -110,6 +301,8 @@ impl ControlledProgramDescriptor {
         let mut specialization_handler = None;
         let mut specialized_server_type = self.specialized_server_type.clone();
         let crash_prevention = self.crash_prevention;
+        let stop_timeout =
+            Duration::from_secs(self.stop_timeout_secs.unwrap_or(DEFAULT_STOP_TIMEOUT_SECS));
 
         // If specialization exists, allow it to modify envs before process spawn
         if let Some(ref typ) = self.specialized_server_type {
@@ -128,15 +321,35 @@ impl ControlledProgramDescriptor {
             }
         }
 
+        let credentials = run_as::for_server(&self)
+            .map_err(|e| format!("Could not start server '{}': {}", self.name, e))?;
+        if let Some((user, home)) = credentials
+            .as_ref()
+            .and_then(|credentials| credentials.account.as_ref())
+        {
+            envs.insert("USER".to_string(), user.clone());
+            envs.insert("LOGNAME".to_string(), user.clone());
+            envs.insert("HOME".to_string(), home.display().to_string());
+        }
+
+        // The server's own variables win over the defaults and the specialization's
+        environment::apply(&self.env, &mut envs)
+            .map_err(|e| format!("Could not start server '{}': {}", self.name, e))?;
+
         let mut instance = ControlledProgramInstance::new(
             self.name.as_str(),
             self.exe_path.as_str(),
             self.arguments,
             self.working_dir,
             envs,
-        );
+            self.env_clear,
+            self.pty,
+            &self.limits,
+            credentials.as_ref(),
+        )?;
         instance.specialized_server_type = specialized_server_type;
         instance.crash_prevention = crash_prevention;
+        instance.stop_timeout = stop_timeout;
 
         // If a specialization handler was attached, call init before assigning to instance
         if let Some(mut handler) = specialization_handler {
@@ -146,7 +359,7 @@ impl ControlledProgramDescriptor {
             instance.specialization_handler = None;
         }
 
-        instance
+        Ok(instance)
     }
 }
 impl Default for ControlledProgramDescriptor {
@@ -162,6 +375,17 @@ impl Default for ControlledProgramDescriptor {
             specialized_server_type: None,
             specialized_server_info: None,
             specialization_options: None,
+            stop_timeout_secs: None,
+            pty: false,
+            restart_policy: RestartPolicy::default(),
+            schedules: vec![],
+            backup: BackupDescriptor::default(),
+            env: BTreeMap::new(),
+            env_clear: false,
+            limits: ResourceLimits::default(),
+            run_as_user: None,
+            run_as_group: None,
+            supplementary_groups: vec![],
         }
     }
 }
@@ -195,6 +419,22 @@ pub struct ControlledProgramInstance {
     pub specialization_handler: Option<Box<dyn crate::specializations::ServerSpecialization>>,
     /// Tracks if the first specialization info update has been sent after spawn.
     pub specialization_info_sent: bool,
+    /// How long to wait for a graceful exit after the stop command is sent.
+    pub stop_timeout: Duration,
+    /// Lines read from stdout and stderr, tagged with their origin, waiting to be processed.
+    output_rx: tokio::sync::mpsc::UnboundedReceiver<(OutputStream, String)>,
+    /// Controller side of the pseudo-terminal when the server runs under one.
+    /// Console input is written here instead of to `process.stdin`.
+    pty_master: Option<PtyMaster>,
+    /// Raw console lines read since the supervisor last wrote them to the console history.
+    pending_history: Vec<HistoryEntry>,
+    /// Whether the server's configuration changed on disk after it was started, so it runs
+    /// with outdated settings until restarted.
+    pub restart_required: bool,
+    /// When the process was started.
+    pub started_at: chrono::DateTime<chrono::Local>,
+    /// The cgroup and limits the process runs under.
+    confinement: Confinement,
 }
 
 impl Drop for ControlledProgramInstance {
@@ -223,34 +463,96 @@ impl ControlledProgramInstance {
     /// * `exe_path` - Path to the executable.
     /// * `arguments` - Command-line arguments.
     /// * `working_dir` - Working directory for the process.
+    /// * `envs` - Environment variables for the process.
+    /// * `env_clear` - Whether the process gets only `envs` instead of inheriting the
+    ///   controller's environment as well.
+    /// * `use_pty` - Whether to attach the process to a pseudo-terminal (Unix only).
+    /// * `limits` - Resource limits applied to the process before it runs.
+    /// * `credentials` - The user and groups the process runs as, if not the controller's.
+    ///
+    /// # Returns
+    /// * `Err` if the working directory can't be created or the process can't be started.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         name: &str,
         exe_path: &str,
         arguments: Vec<String>,
         working_dir: String,
         envs: std::collections::HashMap<String, String>,
-    ) -> Self {
+        env_clear: bool,
+        use_pty: bool,
+        limits: &ResourceLimits,
+        credentials: Option<&Credentials>,
+    ) -> std::result::Result<Self, String> {
         use std::fs;
         use std::path::Path;
 
         // Ensure the working directory exists, create if it doesn't
         let working_dir_path = Path::new(&working_dir);
         if !working_dir_path.exists() {
-            if let Err(e) = fs::create_dir_all(working_dir_path) {
-                panic!(
+            let created: Vec<&Path> = working_dir_path
+                .ancestors()
+                .take_while(|dir| !dir.as_os_str().is_empty() && !dir.exists())
+                .collect();
+            fs::create_dir_all(working_dir_path).map_err(|e| {
+                format!(
                     "Failed to create working directory {:?}: {}",
                     working_dir_path, e
-                );
+                )
+            })?;
+            // The server's user must be able to write to the directories made for it
+            #[cfg(unix)]
+            if let Some(credentials) = credentials {
+                for dir in created {
+                    run_as::chown(dir, credentials).map_err(|e| {
+                        format!("Could not hand {:?} over to the server's user: {}", dir, e)
+                    })?;
+                }
             }
+            #[cfg(not(unix))]
+            let _ = (created, credentials);
         }
 
         let mut process = Command::new(exe_path);
-        let mut process = process
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .current_dir(working_dir.clone());
+        let mut process = process.current_dir(working_dir.clone());
+
+        #[cfg(unix)]
+        let pty_master = if use_pty {
+            match Self::attach_pty(process) {
+                Ok(master) => Some(master),
+                Err(e) => {
+                    tracing::error!(
+                        "Could not open a pseudo-terminal for '{}', falling back to pipes: {}",
+                        name,
+                        e
+                    );
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        #[cfg(not(unix))]
+        let pty_master: Option<PtyMaster> = {
+            if use_pty {
+                tracing::warn!(
+                    "Pseudo-terminals are only supported on Unix, running '{}' with pipes.",
+                    name
+                );
+            }
+            None
+        };
+        if pty_master.is_none() {
+            process = process
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped());
+        }
 
         // Set environment variables from the provided map
+        if env_clear {
+            process = process.env_clear();
+        }
         for (key, value) in envs.iter() {
             process = process.env(key, value);
         }
@@ -258,10 +560,51 @@ impl ControlledProgramInstance {
         for arg in arguments.iter() {
             process = process.arg(arg.replace("\\\\", "\\").replace('\"', ""));
         }
-        let child = process
+
+        let mut confinement = Confinement::new(name, limits);
+        #[cfg(unix)]
+        confinement.apply(process);
+        // Registered last, so everything before it still runs with the controller's rights
+        #[cfg(unix)]
+        if let Some(credentials) = credentials {
+            run_as::apply(credentials, process);
+        }
+        #[cfg(not(unix))]
+        if limits != &ResourceLimits::default() {
+            tracing::warn!(
+                "Resource limits are only supported on Unix, '{}' runs without them.",
+                name
+            );
+        }
+        let mut child = process
             .spawn()
-            .expect("Could not spawn process for server.");
-        Self {
+            .map_err(|e| format!("Could not start server '{}': {}", name, e))?;
+        confinement.started(name, child.id());
+        let (output_tx, output_rx) = tokio::sync::mpsc::unbounded_channel();
+        if let Some(stdout) = child.stdout.take() {
+            spawn_line_reader(stdout, OutputStream::Stdout, output_tx.clone());
+        }
+        if let Some(stderr) = child.stderr.take() {
+            spawn_line_reader(stderr, OutputStream::Stderr, output_tx.clone());
+        }
+        // The terminal merges stdout and stderr, so everything read from it counts as stdout
+        #[cfg(unix)]
+        let pty_master = pty_master.and_then(|master| {
+            let halves = master
+                .try_clone()
+                .and_then(|reader| Ok((PtyMaster::new(reader)?, PtyMaster::new(master)?)));
+            match halves {
+                Ok((reader, writer)) => {
+                    spawn_line_reader(reader, OutputStream::Stdout, output_tx);
+                    Some(writer)
+                }
+                Err(e) => {
+                    tracing::error!("Could not read the pseudo-terminal for '{}': {}", name, e);
+                    None
+                }
+            }
+        });
+        Ok(Self {
             name: name.to_owned(),
             executable_path: exe_path.to_owned(),
             command_line_args: arguments,
@@ -275,54 +618,131 @@ impl ControlledProgramInstance {
             specialized_server_info: None,
             specialization_handler: None,
             specialization_info_sent: false,
+            stop_timeout: Duration::from_secs(DEFAULT_STOP_TIMEOUT_SECS),
+            output_rx,
+            pty_master,
+            pending_history: vec![],
+            restart_required: false,
+            started_at: chrono::Local::now(),
+            confinement,
+        })
+    }
+
+    /// Connects the command's stdio to a new pseudo-terminal and makes it the controlling
+    /// terminal of the child's session.
+    ///
+    /// # Returns
+    /// * The master side of the PTY, used to read output, write input and resize.
+    #[cfg(unix)]
+    fn attach_pty(process: &mut Command) -> std::io::Result<std::fs::File> {
+        let pty = crate::pty::open(DEFAULT_CONSOLE_COLUMNS, DEFAULT_CONSOLE_ROWS)?;
+        process
+            .stdin(Stdio::from(pty.slave.try_clone()?))
+            .stdout(Stdio::from(pty.slave.try_clone()?))
+            .stderr(Stdio::from(pty.slave));
+        // SAFETY: the hook only makes async-signal-safe system calls.
+        unsafe {
+            process.pre_exec(crate::pty::attach_controlling_terminal);
+        }
+        Ok(pty.master)
+    }
+
+    /// Works out why the server's process ended, including whether one of its limits ended it.
+    ///
+    /// # Arguments
+    /// * `status` - The exit status of the process.
+    pub fn exit_reason(&self, status: &std::process::ExitStatus) -> ExitReason {
+        self.confinement.exit_reason(status)
+    }
+
+    /// Applies the settings of a changed descriptor that don't need a restart.
+    ///
+    /// # Arguments
+    /// * `desc` - The server's new configuration.
+    pub fn update_settings(&mut self, desc: &ControlledProgramDescriptor) {
+        self.crash_prevention = desc.crash_prevention;
+        self.stop_timeout =
+            Duration::from_secs(desc.stop_timeout_secs.unwrap_or(DEFAULT_STOP_TIMEOUT_SECS));
+    }
+
+    /// Writes a line of console input to the server.
+    ///
+    /// Goes to the pseudo-terminal when the server runs under one, otherwise to the stdin pipe.
+    ///
+    /// # Arguments
+    /// * `line` - The input to send, without a line ending.
+    pub async fn write_stdin(&mut self, line: &str) -> std::io::Result<()> {
+        if let Some(master) = self.pty_master.as_mut() {
+            // A terminal sends carriage return for Enter; the line discipline turns it into a newline
+            master.write_all(format!("{}\r", line).as_bytes()).await?;
+            return master.flush().await;
+        }
+        match self.process.stdin.as_mut() {
+            Some(stdin) => {
+                stdin.write_all(format!("{}\r\n", line).as_bytes()).await?;
+                stdin.flush().await
+            }
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                "server stdin is closed",
+            )),
+        }
+    }
+
+    /// Resizes the server's pseudo-terminal.
+    ///
+    /// # Arguments
+    /// * `columns` - New terminal width.
+    /// * `rows` - New terminal height.
+    ///
+    /// # Returns
+    /// * `Err` if the server is not running under a pseudo-terminal or the resize fails.
+    pub fn resize_console(&self, columns: u16, rows: u16) -> std::io::Result<()> {
+        #[cfg(unix)]
+        if let Some(master) = self.pty_master.as_ref() {
+            use std::os::fd::AsRawFd;
+            return crate::pty::resize(master.as_raw_fd(), columns, rows);
         }
+        let _ = (columns, rows);
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "server is not running under a pseudo-terminal",
+        ))
     }
 
     /// Reads and processes output from the server process.
     ///
-    /// Uses the specialization handler if available, otherwise applies ANSI to HTML conversion.
+    /// Drains the lines collected from stdout and stderr in the order they arrived. Uses the
+    /// specialization handler if available, otherwise applies ANSI to HTML conversion, and
+    /// marks stderr lines so the console can render them distinctly.
     /// Maintains a buffer of recent output lines.
     pub async fn read_output(&mut self) -> Option<String> {
         let mut out = String::new();
-        let mut has_more = true;
-
-        while has_more {
-            let mut buf = [0u8; 4096];
-            let take = self.process.stdout.as_mut();
-            let read = match timeout(Duration::from_millis(10), take.unwrap().read(&mut buf)).await
-            {
-                Ok(val) => val.unwrap(),
-                Err(_) => 0,
+
+        while let Ok((stream, single_line)) = self.output_rx.try_recv() {
+            self.pending_history
+                .push(HistoryEntry::now(stream.into(), single_line.clone()));
+            let transformed = if self.specialization_handler.is_some() {
+                let mut handler = self.specialization_handler.take();
+                let transformed = handler
+                    .as_mut()
+                    .and_then(|handler| handler.parse_output(single_line, stream, self));
+                self.specialization_handler = handler;
+                transformed
+            } else {
+                Some(ansi_to_html(&single_line))
             };
-            if read > 0 {
-                let new_str = String::from_utf8_lossy(&buf[0..read]);
-                // Always split into lines and process one at a time
-                for log_line in new_str.lines() {
-                    let single_line = log_line.replace('\r', "").replace('\n', "");
-                    if self.specialization_handler.is_some() {
-                        let mut handler = self.specialization_handler.take();
-                        if let Some(ref mut handler_inner) = handler {
-                            if let Some(transformed) =
-                                handler_inner.parse_output(single_line.clone(), self)
-                            {
-                                // If parse_output returns multi-line output, respect each line
-                                for output_line in transformed.lines() {
-                                    out.push_str(output_line);
-                                    out.push('\n');
-                                }
-                            }
-                        }
-                        self.specialization_handler = handler;
+            if let Some(transformed) = transformed {
+                // If parse_output returns multi-line output, respect each line
+                for output_line in transformed.lines() {
+                    if stream == OutputStream::Stderr {
+                        out.push_str(&mark_stderr(output_line));
                     } else {
-                        out.push_str(ansi_to_html(&single_line).as_str());
-                        out.push('\n');
+                        out.push_str(output_line);
                     }
+                    out.push('\n');
                 }
             }
-
-            if read < 1 {
-                has_more = false;
-            }
         }
 
         self.curr_output_in_progress += &out[..];
@@ -342,16 +762,67 @@ impl ControlledProgramInstance {
         }
     }
 
+    /// Takes the raw console lines collected since the last call, for the console history.
+    pub fn take_history(&mut self) -> Vec<HistoryEntry> {
+        std::mem::take(&mut self.pending_history)
+    }
+
     /// Stops the running server/program process.
     ///
-    /// Disables crash prevention, kills the process, and returns the exit code if available.
+    /// Disables crash prevention, then shuts the process down in stages: the specialization's
+    /// stop command is written to stdin and the process gets `stop_timeout` to exit, then it is
+    /// sent SIGTERM, and finally it is killed. Returns the exit code if available.
     pub async fn stop(&mut self) -> Option<i32> {
-        // Disable crash prevention so the process won't be restarted when killed
+        // Disable crash prevention so the process won't be restarted when it exits
         self.crash_prevention = false;
-        let _ = self.process.kill().await;
+        self.confinement.stop_requested();
+        if let Ok(Some(status)) = self.process.try_wait() {
+            return status.code();
+        }
 
-        // Wait a moment for the process to terminate and get the exit code
-        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        let stop_command = self
+            .specialization_handler
+            .as_ref()
+            .and_then(|handler| handler.stop_command());
+        let mut command_sent = false;
+        if let Some(command) = stop_command {
+            command_sent = self.write_stdin(&command).await.is_ok();
+        }
+        if command_sent {
+            if let Ok(Ok(status)) = timeout(self.stop_timeout, self.process.wait()).await {
+                return status.code();
+            }
+            tracing::warn!(
+                "Server '{}' did not exit within {}s of its stop command, escalating.",
+                self.name,
+                self.stop_timeout.as_secs()
+            );
+        }
+
+        #[cfg(unix)]
+        if let Some(pid) = self.process.id() {
+            use nix::sys::signal::{kill, Signal};
+            use nix::unistd::Pid;
+
+            if kill(Pid::from_raw(pid as i32), Signal::SIGTERM).is_ok() {
+                // Without a stop command SIGTERM is the graceful request, so it gets the full timeout
+                let grace = if command_sent {
+                    SIGTERM_GRACE
+                } else {
+                    self.stop_timeout
+                };
+                if let Ok(Ok(status)) = timeout(grace, self.process.wait()).await {
+                    return status.code();
+                }
+                tracing::warn!(
+                    "Server '{}' ignored SIGTERM for {}s, killing it.",
+                    self.name,
+                    grace.as_secs()
+                );
+            }
+        }
+
+        let _ = self.process.kill().await;
         match self.process.try_wait() {
             Ok(Some(status)) => status.code(),
             _ => None,
@@ -0,0 +1,151 @@
+/// Environment variables of managed servers.
+///
+/// Each server can set its own variables with the `env` map of its descriptor. Values are
+/// resolved when the server starts: `${NAME}` is replaced with the controller's own variable of
+/// that name, `$$` writes a single `$`, and a value that then starts with `@file:` is replaced
+/// with the contents of that file, so secrets such as license keys can stay out of
+/// `config.json`.
+///
+/// Values written as `{"value": "...", "secret": true}` are redacted from configurations sent
+/// to clients. Saving a redacted value back unchanged keeps the value it had.
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+
+/// What a secret is replaced with in configurations sent to clients.
+pub const REDACTED: &str = "<redacted>";
+
+/// Prefix of a value read from a file.
+pub const FILE_PREFIX: &str = "@file:";
+
+/// Parts of variable names that were taken to mean a secret before secrets were marked, used
+/// to mark the values of older configuration files when they are upgraded.
+pub const SECRET_NAME_PARTS: [&str; 6] =
+    ["KEY", "SECRET", "TOKEN", "PASS", "CREDENTIAL", "LICENSE"];
+
+/// The value of one variable in a server's `env` map.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum EnvValue {
+    /// A value written as a plain string, shown to clients as it is.
+    Plain(String),
+    /// A value written as an object, which can mark it as a secret.
+    Detailed {
+        /// The value, which may use `${NAME}` and `@file:` references.
+        value: String,
+        /// Whether the value is kept from clients.
+        #[serde(default)]
+        secret: bool,
+    },
+}
+
+impl EnvValue {
+    /// The value as written in the configuration.
+    pub fn value(&self) -> &str {
+        match self {
+            Self::Plain(value) | Self::Detailed { value, .. } => value,
+        }
+    }
+
+    /// Whether the value must be kept from clients.
+    pub fn is_secret(&self) -> bool {
+        matches!(self, Self::Detailed { secret: true, .. })
+    }
+}
+
+impl From<&str> for EnvValue {
+    fn from(value: &str) -> Self {
+        Self::Plain(value.to_string())
+    }
+}
+
+/// Replaces the secret values of an `env` map with [`REDACTED`].
+pub fn redact(env: &mut BTreeMap<String, EnvValue>) {
+    for value in env.values_mut() {
+        if value.is_secret() {
+            *value = EnvValue::Detailed {
+                value: REDACTED.to_string(),
+                secret: true,
+            };
+        }
+    }
+}
+
+/// Puts back the values a client left redacted, taking them from the current `env` map.
+pub fn restore(env: &mut BTreeMap<String, EnvValue>, current: &BTreeMap<String, EnvValue>) {
+    for (name, value) in env.iter_mut() {
+        if value.value() == REDACTED {
+            if let Some(current) = current.get(name) {
+                *value = EnvValue::Detailed {
+                    value: current.value().to_string(),
+                    secret: value.is_secret() || current.is_secret(),
+                };
+            }
+        }
+    }
+}
+
+/// Replaces `${NAME}` references with the controller's environment variables.
+fn expand(value: &str) -> Result<String, String> {
+    let mut expanded = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(after) = rest.strip_prefix("$$") {
+            expanded.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let end = after
+                .find('}')
+                .ok_or_else(|| format!("\"{}\" has a ${{ without a closing }}", value))?;
+            let name = &after[..end];
+            let resolved = std::env::var(name)
+                .map_err(|_| format!("${{{}}} is not set in the controller's environment", name))?;
+            expanded.push_str(&resolved);
+            rest = &after[end + 1..];
+        } else {
+            expanded.push('$');
+            rest = &rest[1..];
+        }
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Resolves one value of an `env` map.
+///
+/// # Returns
+/// * The value the variable is set to.
+/// * `Err` if a referenced variable isn't set or a referenced file can't be read.
+pub fn resolve(value: &str) -> Result<String, String> {
+    let expanded = expand(value)?;
+    match expanded.strip_prefix(FILE_PREFIX) {
+        Some(path) => fs::read_to_string(path)
+            .map(|contents| contents.trim_end_matches(['\r', '\n']).to_string())
+            .map_err(|e| format!("could not read '{}': {}", path, e)),
+        None => Ok(expanded),
+    }
+}
+
+/// Resolves a server's `env` map into `envs`, overriding what is already set there.
+///
+/// # Arguments
+/// * `env` - The variables from the server's descriptor.
+/// * `envs` - The environment the server is started with.
+///
+/// # Returns
+/// * `Err` naming the first variable that can't be resolved, as the server would otherwise
+///   start without it.
+pub fn apply(
+    env: &BTreeMap<String, EnvValue>,
+    envs: &mut HashMap<String, String>,
+) -> Result<(), String> {
+    for (name, value) in env {
+        let resolved =
+            resolve(value.value()).map_err(|e| format!("{} can't be set: {}", name, e))?;
+        envs.insert(name.clone(), resolved);
+    }
+    Ok(())
+}
@@ -1,10 +1,17 @@
-use std::{fs::File, io::Write};
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+};
 
 use tracing::*;
 
-use crate::configuration::{self, Config};
+use crate::config_migration::upgrade_file;
+use crate::config_validation::{has_errors, validate_str, Diagnostic, DiagnosticCode};
+use crate::configuration::Config;
+use crate::specializations::SpecializationRegistry;
 
-use crate::specializations;
+/// How much of a file `lines_backwards` reads at a time.
+const READ_CHUNK_BYTES: u64 = 64 * 1024;
 
 /// Reads the contents of a file at the given path and returns it as a String.
 ///
@@ -20,17 +27,24 @@ pub fn read_file(path: &str) -> Result<String, Box<dyn std::error::Error>> {
     Ok(data)
 }
 
-/// Loads a JSON configuration file from the given path, validating specializations.
+/// Loads a JSON configuration file from the given path and validates it.
 ///
 /// If the file does not exist or cannot be read, a default configuration is created and saved.
+/// A file for an older version is upgraded and saved, keeping a backup of the original.
 ///
 /// # Arguments
 /// * `path` - The path to the configuration file.
+/// * `registry` - The specializations servers may use.
 ///
 /// # Returns
-/// * `Config` struct loaded from the file or default if not found.
+/// * The configuration loaded from the file, or the default if not found, with any warnings
+///   about it.
+/// * `Err` with every problem found if the file can't be used.
 #[no_mangle]
-pub fn load_json(path: &str) -> Config {
+pub fn load_json(
+    path: &str,
+    registry: &SpecializationRegistry,
+) -> Result<(Config, Vec<Diagnostic>), Vec<Diagnostic>> {
     let data = read_file(path);
     let data: String = match data {
         Ok(d) => d,
@@ -45,11 +59,112 @@ pub fn load_json(path: &str) -> Config {
             str
         }
     };
-    // Validate specializations before deserializing Config
-    if let Ok(json_val) = serde_json::from_str::<serde_json::Value>(&data) {
-        let registry = specializations::init_builtin_registry();
-        configuration::validate_specializations_in_config(&json_val, &registry);
+    let mut notices = vec![];
+    let data = match upgrade_file(path, &data) {
+        Ok(Some((upgraded, notice))) => {
+            notices.push(notice);
+            upgraded
+        }
+        Ok(None) => data,
+        Err(e) => {
+            notices.push(Diagnostic::warning(
+                DiagnosticCode::OutdatedVersion,
+                "config_version",
+                format!("{}, so it is only upgraded in memory", e),
+            ));
+            data
+        }
+    };
+    match validate_str(&data, registry) {
+        (Some(config), diagnostics) if !has_errors(&diagnostics) => {
+            notices.extend(diagnostics);
+            Ok((config, notices))
+        }
+        (_, diagnostics) => Err(diagnostics),
+    }
+}
+
+/// Turns a server name into something safe to use as a single file or directory name.
+///
+/// Characters other than ASCII letters, digits, `-`, `_` and `.` are replaced with `_`.
+///
+/// # Arguments
+/// * `name` - The name to sanitize.
+pub fn sanitize_file_name(name: &str) -> String {
+    let sanitized: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if sanitized.trim_matches('.').is_empty() {
+        "_".to_string()
+    } else {
+        sanitized
+    }
+}
+
+/// A short hex hash of a name, telling apart names that [`sanitize_file_name`] turns into the
+/// same string, like "a b" and "a_b".
+///
+/// # Arguments
+/// * `name` - The name to hash.
+pub fn name_hash(name: &str) -> String {
+    ring::digest::digest(&ring::digest::SHA256, name.as_bytes()).as_ref()[..4]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Turns a server name into a file or directory name that is safe to use and no other server
+/// shares: the sanitized name followed by a hash of the real one.
+///
+/// # Arguments
+/// * `name` - The name to turn into a file name.
+pub fn unique_file_name(name: &str) -> String {
+    format!("{}-{}", sanitize_file_name(name), name_hash(name))
+}
+
+/// Calls `visit` with each line of a file that ends before `end` and the position the line
+/// starts at, newest first, until it returns `false`. Only as much of the file is read as the
+/// lines visited need.
+pub fn lines_backwards(
+    file: &mut File,
+    end: u64,
+    mut visit: impl FnMut(u64, &str) -> bool,
+) -> io::Result<()> {
+    let mut position = end;
+    // The start of the oldest line read so far, which may continue in the previous chunk
+    let mut partial: Vec<u8> = vec![];
+    while position > 0 {
+        let start = position.saturating_sub(READ_CHUNK_BYTES);
+        let mut chunk = vec![0; (position - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut chunk)?;
+        chunk.append(&mut partial);
+        position = start;
+        let mut line_end = chunk.len();
+        while let Some(newline) = chunk[..line_end].iter().rposition(|byte| *byte == b'\n') {
+            let line = &chunk[newline + 1..line_end];
+            if !line.is_empty()
+                && !visit(
+                    position + newline as u64 + 1,
+                    &String::from_utf8_lossy(line),
+                )
+            {
+                return Ok(());
+            }
+            line_end = newline;
+        }
+        chunk.truncate(line_end);
+        partial = chunk;
+    }
+    if !partial.is_empty() {
+        visit(0, &String::from_utf8_lossy(&partial));
     }
-    let json = serde_json::from_str(&data.clone());
-    json.unwrap()
+    Ok(())
 }
@@ -91,12 +91,9 @@ function dropdownClick(event) {
 }
 
 function get_ws_addr() {
-  return (
-    document.location.href
-      .replace("http", "ws")
-      .replace("https", "wss")
-      .replace("#", "") + "ws"
-  );
+  // Use wss:// whenever the page itself was served over TLS
+  const scheme = document.location.protocol === "https:" ? "wss://" : "ws://";
+  return scheme + document.location.host + "/ws";
 }
 
 function hotReloadWhenReady() {
@@ -114,6 +111,40 @@ function hotReloadWhenReady() {
   }, 1000);
 }
 window.commands = [];
+// Formats a byte count with a binary unit, e.g. "1.5 GiB"
+function formatBytes(bytes) {
+  const units = ["B", "KiB", "MiB", "GiB", "TiB"];
+  let unit = 0;
+  while (bytes >= 1024 && unit < units.length - 1) {
+    bytes /= 1024;
+    unit++;
+  }
+  return (unit === 0 ? bytes : bytes.toFixed(1)) + " " + units[unit];
+}
+// Shows the latest resource usage of a server next to its name
+function showServerMetrics(serverName, metrics) {
+  const elem = $("." + serverName + "dropdown .serverMetrics");
+  if (!metrics) {
+    elem.text("").removeAttr("title");
+    return;
+  }
+  elem.text(
+    "CPU " + metrics.cpu_percent + "% · RAM " + formatBytes(metrics.rss_bytes),
+  );
+  elem.attr(
+    "title",
+    metrics.processes +
+      " process(es), " +
+      metrics.threads +
+      " threads, " +
+      metrics.open_fds +
+      " open files, " +
+      formatBytes(metrics.read_bytes) +
+      " read, " +
+      formatBytes(metrics.write_bytes) +
+      " written",
+  );
+}
 function addServerDropdown(serverName, inactive) {
   console.log("adding a dropdown");
   let titleText = serverName;
@@ -125,7 +156,7 @@ function addServerDropdown(serverName, inactive) {
       serverName +
       'dropdown"><div class="innerTopBarDropDown"> <p class="serverName">' +
       titleText +
-      '</p>   <a href="#" class="button dropdownArrow"><svg clip-rule="evenodd" class="bloom" fill-rule="evenodd" stroke-linejoin="round" stroke-miterlimit="2" viewBox="0 0 24 24" xmlns="http://www.w3.org/2000/svg">  <path d="m16.843 10.211c.108-.141.157-.3.157-.456 0-.389-.306-.755-.749-.755h-8.501c-.445 0-.75.367-.75.755 0 .157.05.316.159.457 1.203 1.554 3.252 4.199 4.258 5.498.142.184.36.29.592.29.23 0 .449-.107.591-.291zm-7.564.289h5.446l-2.718 3.522z" fill-rule="nonzero"/>  </svg><svg clip-rule="evenodd" fill-rule="evenodd" stroke-linejoin="round" stroke-miterlimit="2" viewBox="0 0 24 24" xmlns="http://www.w3.org/2000/svg"><path d="m16.843 10.211c.108-.141.157-.3.157-.456 0-.389-.306-.755-.749-.755h-8.501c-.445 0-.75.367-.75.755 0 .157.05.316.159.457 1.203 1.554 3.252 4.199 4.258 5.498.142.184.36.29.592.29.23 0 .449-.107.591-.291zm-7.564.289h5.446l-2.718 3.522z" fill-rule="nonzero"/></svg></a></div><div class="dropdownDrop" style: "display: none;"><div class="serverSTDOut ' +
+      '</p> <p class="serverMetrics"></p>  <a href="#" class="button dropdownArrow"><svg clip-rule="evenodd" class="bloom" fill-rule="evenodd" stroke-linejoin="round" stroke-miterlimit="2" viewBox="0 0 24 24" xmlns="http://www.w3.org/2000/svg">  <path d="m16.843 10.211c.108-.141.157-.3.157-.456 0-.389-.306-.755-.749-.755h-8.501c-.445 0-.75.367-.75.755 0 .157.05.316.159.457 1.203 1.554 3.252 4.199 4.258 5.498.142.184.36.29.592.29.23 0 .449-.107.591-.291zm-7.564.289h5.446l-2.718 3.522z" fill-rule="nonzero"/>  </svg><svg clip-rule="evenodd" fill-rule="evenodd" stroke-linejoin="round" stroke-miterlimit="2" viewBox="0 0 24 24" xmlns="http://www.w3.org/2000/svg"><path d="m16.843 10.211c.108-.141.157-.3.157-.456 0-.389-.306-.755-.749-.755h-8.501c-.445 0-.75.367-.75.755 0 .157.05.316.159.457 1.203 1.554 3.252 4.199 4.258 5.498.142.184.36.29.592.29.23 0 .449-.107.591-.291zm-7.564.289h5.446l-2.718 3.522z" fill-rule="nonzero"/></svg></a></div><div class="dropdownDrop" style: "display: none;"><div class="STDInRow historyRow"><input class="historySearch" placeholder="search console history..."></input><button type="button" class="historyOlder">Load older</button></div><div class="serverSTDOut ' +
       serverName +
       'Out"></div><div class="serverSTDIn"><div class="STDInRow"><input class="STDInInput" placeholder="place input for STDIn here..."></input><button type="button" class="STDInSubmit">Submit</button></div></div></div></div>',
   );
@@ -163,6 +194,9 @@ function addServerDropdown(serverName, inactive) {
       socket.send(window.MessagePack.encode(obj));
     }
 
+    if (isStartCommand) {
+      dropdown.removeClass("crashLoop");
+    }
     // Clear console output if starting an inactive server
     if (isStartCommand && dropdown.hasClass("inactiveServer")) {
       // Clear console output and add starting message
@@ -356,29 +390,6 @@ function updateServerInfoSpecializations() {
     console.error("Error updating specialization UIs:", e);
   }
 }
-function generateSecureSalt(lengthInBytes) {
-  lengthInBytes = lengthInBytes / 2;
-  const saltArray = new Uint8Array(lengthInBytes);
-  crypto.getRandomValues(saltArray);
-
-  // Convert the Uint8Array directly to a hexadecimal string without doubling the length
-  let salt = "";
-  for (let i = 0; i < saltArray.length; i++) {
-    salt += (saltArray[i] < 16 ? "0" : "") + saltArray[i].toString(16);
-  }
-
-  return salt;
-}
-async function hashPasswordWithSalt(password, salt) {
-  const encoder = new TextEncoder();
-  const data = encoder.encode(password + salt);
-  const hashBuffer = await crypto.subtle.digest("SHA-256", data);
-  const hashArray = Array.from(new Uint8Array(hashBuffer));
-  const hashHex = hashArray
-    .map((byte) => byte.toString(16).padStart(2, "0"))
-    .join("");
-  return hashHex;
-}
 $(document).ready(function () {
   var socket;
   socket = new WebSocket(get_ws_addr());
@@ -454,6 +465,62 @@ $(document).ready(function () {
     return null;
   }
 
+  // Tell the server how many characters fit in a console so PTY-backed servers
+  // can lay out their output to match.
+  function sendConsoleSize(serverName) {
+    if (!socket || socket.readyState !== WebSocket.OPEN) return;
+    const servers = (window.config && window.config.servers) || [];
+    const desc = servers.find((server) => server.name === serverName);
+    if (!desc || !desc.pty) return;
+    const output = $("." + serverName + "Out");
+    if (!output.is(":visible")) return;
+    const probe = $(
+      '<p class="STDOutMessage" style="position: absolute; visibility: hidden; white-space: pre;">MMMMMMMMMM</p>',
+    );
+    output.append(probe);
+    const charWidth = probe[0].getBoundingClientRect().width / 10;
+    const lineHeight = probe[0].getBoundingClientRect().height;
+    const padding = parseFloat(probe.css("padding-left")) || 0;
+    probe.remove();
+    if (!charWidth || !lineHeight) return;
+    socket.send(
+      window.MessagePack.encode({
+        type: "resizeConsole",
+        server_name: serverName,
+        cols: Math.max(
+          1,
+          Math.floor((output.innerWidth() - padding) / charWidth),
+        ),
+        rows: Math.max(1, Math.floor(output.innerHeight() / lineHeight)),
+      }),
+    );
+  }
+
+  $(document).on("click", ".innerTopBarDropDown", function () {
+    const serverName = $(this)
+      .find(".serverName")
+      .text()
+      .replace(" (inactive)", "");
+    // Wait for the slide animation so the console has its final size
+    setTimeout(function () {
+      sendConsoleSize(serverName);
+    }, 300);
+  });
+
+  let consoleResizeTimer = null;
+  $(window).on("resize", function () {
+    clearTimeout(consoleResizeTimer);
+    consoleResizeTimer = setTimeout(function () {
+      $(".CentralMenuDropdown").each(function () {
+        const serverName = $(this)
+          .find(".serverName")
+          .text()
+          .replace(" (inactive)", "");
+        sendConsoleSize(serverName);
+      });
+    }, 250);
+  });
+
   function requestThemesList() {
     if (socket && socket.readyState === WebSocket.OPEN) {
       // Requesting themes list from server
@@ -561,6 +628,29 @@ $(document).ready(function () {
       editor.onDidChangeModelContent(function () {
         $(".editorText").val(editor.getValue());
       });
+
+      // Offer completion and checks from the configuration's schema
+      fetch("/api/config/schema")
+        .then(function (response) {
+          return response.ok ? response.json() : null;
+        })
+        .then(function (schema) {
+          if (schema && monaco.languages.json) {
+            monaco.languages.json.jsonDefaults.setDiagnosticsOptions({
+              validate: true,
+              schemas: [
+                {
+                  uri: window.location.origin + "/api/config/schema",
+                  fileMatch: ["*"],
+                  schema: schema,
+                },
+              ],
+            });
+          }
+        })
+        .catch(function (error) {
+          console.warn("[Config] Could not load the schema:", error);
+        });
     }
   }
 
@@ -618,6 +708,7 @@ $(document).ready(function () {
       case "ConfigInfo":
         // Always update config editor with received config
         window.config = obj.config;
+        window.configChangePending = false;
         const jsonConfig = JSON.stringify(obj.config, undefined, 4);
         $(".editorText").val(jsonConfig);
         if (editor) {
@@ -631,6 +722,33 @@ $(document).ready(function () {
           let serverName = server.name;
           addDropdownNoDupe(serverName, !server.active);
           processServerLogLines(serverName, server.output, true);
+          const nameElem = $("." + serverName + "dropdown .serverName");
+          if (server.next_retry) {
+            nameElem.attr(
+              "title",
+              "Crashed " +
+                server.restart_count +
+                " time(s) recently, restarting at " +
+                new Date(server.next_retry).toLocaleTimeString(),
+            );
+          } else {
+            nameElem.removeAttr("title");
+          }
+          // Servers on an unreachable slave keep their last known output
+          $("." + serverName + "dropdown").toggleClass(
+            "slaveOffline",
+            !!server.offline,
+          );
+          // Running with settings that were changed in config.json since it started
+          $("." + serverName + "dropdown").toggleClass(
+            "restartRequired",
+            !!server.restart_required,
+          );
+          // Also known to clients that connect after the server was given up on
+          if (server.crash_loop) {
+            $("." + serverName + "dropdown").addClass("crashLoop");
+          }
+          showServerMetrics(serverName, server.metrics);
         }
         window.serverInfoObj = obj;
         updateServerInfoSpecializations();
@@ -693,10 +811,82 @@ $(document).ready(function () {
           updateServerInfoSpecializations();
         }
         break;
+      case "ServerMetrics":
+        showServerMetrics(obj.server_name, obj.metrics);
+        break;
       case "ServerOutput":
         var str = obj.output;
         processServerLogLines(obj.server_name, str, false);
         break;
+      case "SchedulesList":
+        window.schedules = obj.schedules;
+        break;
+      case "ScheduleError":
+        console.warn(
+          "[Scheduler] Change for " + obj.server_name + " rejected:",
+          obj.error,
+        );
+        break;
+      case "ConfigError":
+        console.warn("[Config] " + obj.request + " rejected:", obj.error);
+        if (obj.request === "configChange" && window.configChangePending) {
+          window.configChangePending = false;
+          alert(
+            "The configuration was not saved:\n\n" +
+              obj.diagnostics
+                .map(function (diagnostic) {
+                  return (
+                    (diagnostic.path ? diagnostic.path + ": " : "") +
+                    diagnostic.message
+                  );
+                })
+                .join("\n"),
+          );
+        }
+        break;
+      case "BackupsList":
+        window.backups = window.backups || {};
+        window.backups[obj.server_name] = obj.backups;
+        break;
+      case "BackupStatus":
+        if (!obj.success) {
+          console.warn(
+            "[Backup] " + obj.action + " of " + obj.server_name + " failed:",
+            obj.error,
+          );
+        }
+        break;
+      case "AuditLog":
+        window.auditLog = obj;
+        break;
+      case "ServerActionResult":
+        if (!obj.success) {
+          console.warn(
+            "[Servers] " + obj.action + " of " + obj.server_name + " failed:",
+            obj.error,
+          );
+        }
+        break;
+      case "SlaveStatus":
+        window.slaveStatus = window.slaveStatus || {};
+        window.slaveStatus[obj.address + ":" + obj.port] = obj;
+        if (obj.state === "offline") {
+          console.warn(
+            "[Slaves] " + obj.address + ":" + obj.port + " is offline:",
+            obj.error,
+          );
+        }
+        break;
+      case "PermissionDenied":
+        console.warn("[Permissions]", obj.error);
+        break;
+      case "ServerCrashLoop":
+        // The controller gave up restarting this server until it is started by hand
+        $("." + obj.server_name + "dropdown").addClass("crashLoop");
+        break;
+      case "ConsoleHistory":
+        processConsoleHistory(obj);
+        break;
       case "themesList":
         // Received themes list from server
         // Handle received list of themes
@@ -777,6 +967,92 @@ $(document).ready(function () {
 
   // DRY function to process and append server log lines, both for bulk and live updates
   // If isBulk is true, only append new lines (for ServerInfo bulk updates)
+  // Per-server scrollback state: the cursor of the next older page and the active search
+  window.consoleHistory = {};
+
+  function requestConsoleHistory(serverName, before) {
+    if (!socket || socket.readyState !== WebSocket.OPEN) return;
+    const history = window.consoleHistory[serverName] || {
+      nextBefore: null,
+      search: "",
+    };
+    window.consoleHistory[serverName] = history;
+    socket.send(
+      window.MessagePack.encode({
+        type: "requestHistory",
+        server_name: serverName,
+        before: before,
+        limit: 200,
+        search: history.search === "" ? null : history.search,
+      }),
+    );
+  }
+
+  // Pages through the audit log; filters are user, server_name and action
+  window.requestAuditLog = function (offset, filters) {
+    if (!socket || socket.readyState !== WebSocket.OPEN) return;
+    socket.send(
+      window.MessagePack.encode(
+        Object.assign(
+          { type: "requestAuditLog", offset: offset || 0, limit: 100 },
+          filters,
+        ),
+      ),
+    );
+  };
+
+  function processConsoleHistory(obj) {
+    const history = window.consoleHistory[obj.server_name];
+    const outDiv = $("." + obj.server_name + "Out")[0];
+    if (!history || !outDiv) return;
+    // Ignore replies to a search that has since been changed
+    if ((obj.search ?? "") !== history.search) return;
+    const dropdown = $(outDiv).closest(".CentralMenuDropdown");
+    const lines = obj.lines.map(function (line) {
+      const p = $('<p class="STDOutMessage"></p>')[0];
+      p.innerHTML = line.output;
+      p.title = new Date(line.timestamp).toLocaleString();
+      return p;
+    });
+    if (obj.before == null) {
+      // A fresh page replaces the live view, keeping the newest line in sight
+      $(outDiv).empty().append(lines);
+      outDiv.scrollTop = outDiv.scrollHeight;
+    } else {
+      // Older lines go on top without moving what the user is reading
+      const previousHeight = outDiv.scrollHeight;
+      $(outDiv).prepend(lines);
+      outDiv.scrollTop += outDiv.scrollHeight - previousHeight;
+    }
+    history.nextBefore = obj.next_before;
+    dropdown.find(".historyOlder").prop("disabled", !obj.has_more);
+  }
+
+  $(document).on("click", ".historyOlder", function () {
+    const serverName = $(this)
+      .closest(".CentralMenuDropdown")
+      .find(".serverName")
+      .text()
+      .replace(" (inactive)", "");
+    const history = window.consoleHistory[serverName];
+    if (!history || history.nextBefore == null) return;
+    requestConsoleHistory(serverName, history.nextBefore);
+  });
+
+  $(document).on("keydown", ".historySearch", function (e) {
+    if (e.which !== 13) return;
+    const serverName = $(this)
+      .closest(".CentralMenuDropdown")
+      .find(".serverName")
+      .text()
+      .replace(" (inactive)", "");
+    window.consoleHistory[serverName] = {
+      nextBefore: null,
+      search: $(this).val().trim(),
+    };
+    requestConsoleHistory(serverName, null);
+  });
+
   function processServerLogLines(serverName, logString, isBulk) {
     var outDiv = $("." + serverName + "Out")[0];
     if (!outDiv) {
@@ -828,6 +1104,8 @@ $(document).ready(function () {
         updatedConfig: newConfig,
       };
       socket.send(JSON.stringify(obj));
+      // Errors about this save are shown to this client only
+      window.configChangePending = true;
 
       // Format the JSON and update the editor
       const formattedJson = JSON.stringify(newConfig, null, 4);
@@ -924,7 +1202,13 @@ $(document).ready(function () {
     Configuration: ".config",
     Stats: ".stat",
   };
-  $("#menu ul li a").click(function (e) {
+  $("#logout").click(function (e) {
+    e.preventDefault();
+    fetch("/api/logout", { method: "POST" }).finally(function () {
+      document.location.href = "/login";
+    });
+  });
+  $("#menu ul li a:not(#logout)").click(function (e) {
     $(".active").toggleClass("active");
     $(e.target.parentElement).toggleClass("active");
     $(".page").hide();
@@ -1,7 +1,10 @@
 use crate::{
+    config_reload::watch_config,
     master::create_slave_connections,
+    metrics::sample_metrics,
+    scheduler::run_scheduler,
     servers::start_servers,
-    slave::start_slave,
+    slave::{publish_events, start_slave},
     theme::{oklch, Theme},
     webserver::start_web_server,
 };
@@ -21,10 +24,28 @@ mod ansi_to_html;
 
 mod app_state;
 
+mod audit;
+
+mod auth;
+
+mod backup;
+
+mod config_edit;
+
+mod config_migration;
+
+mod config_reload;
+
+mod config_validation;
+
 mod configuration;
 
+mod console_history;
+
 mod controlled_program;
 
+mod environment;
+
 mod files;
 
 mod logging;
@@ -33,8 +54,27 @@ mod macros;
 
 mod master;
 
+mod metrics;
+
 mod messages;
 
+mod permissions;
+
+mod prometheus;
+
+#[cfg(unix)]
+mod pty;
+
+mod resource_limits;
+
+mod rest_api;
+
+mod restart_policy;
+
+mod run_as;
+
+mod scheduler;
+
 mod servers;
 
 mod slave;
@@ -43,6 +83,8 @@ mod specializations;
 
 mod theme;
 
+mod tls;
+
 mod webserver;
 
 mod websocket;
@@ -54,23 +96,90 @@ mod websocket;
 /// Handles graceful shutdown on Ctrl+C or T key.
 #[tokio::main]
 async fn main() -> Result<(), String> {
-    let config = load_json("config.json");
+    let args: Vec<String> = std::env::args().collect();
+    let specialization_registry = specializations::init_builtin_registry();
+    if let Some(position) = args.iter().position(|arg| arg == "--check-config") {
+        let path = args
+            .get(position + 1)
+            .map(String::as_str)
+            .unwrap_or("config.json");
+        return config_validation::check_file(path, &specialization_registry);
+    }
+    if let Some(position) = args.iter().position(|arg| arg == "--export-schema") {
+        let schema = serde_json::to_string_pretty(&configuration::schema()).unwrap();
+        return match args.get(position + 1) {
+            Some(path) => fs::write(path, schema)
+                .map_err(|e| format!("Could not write the schema to '{}': {}", path, e)),
+            None => {
+                println!("{}", schema);
+                Ok(())
+            }
+        };
+    }
+    let mut config = match load_json("config.json", &specialization_registry) {
+        Ok((config, warnings)) => {
+            for warning in warnings {
+                eprintln!("config.json: {}", warning);
+            }
+            config
+        }
+        Err(diagnostics) => {
+            for diagnostic in diagnostics.iter() {
+                eprintln!("config.json: {}", diagnostic);
+            }
+            return Err(
+                "config.json is not a valid configuration, fix the errors above and start again"
+                    .to_string(),
+            );
+        }
+    };
+    if let Some(position) = args.iter().position(|arg| arg == "--add-user") {
+        let Some(username) = args.get(position + 1) else {
+            return Err("Usage: --add-user <username> [--role <role>]...".to_string());
+        };
+        let roles: Vec<String> = args
+            .windows(2)
+            .filter(|pair| pair[0] == "--role")
+            .map(|pair| pair[1].clone())
+            .collect();
+        auth::add_user_from_cli(&config.auth, username, (!roles.is_empty()).then_some(roles))?;
+        println!("Saved user '{}' to {}", username, config.auth.users_file);
+        return Ok(());
+    }
     let slave: bool = config.slave;
     logging::init_logging();
+    if slave {
+        slave::ensure_secret(&mut config);
+    } else {
+        auth::ensure_admin(&config.auth);
+    }
+    tls::install_crypto_provider();
+    tls::ensure_certificate(&config.tls)?;
+    resource_limits::init_cgroups(&config.cgroups);
 
     // Ensure themes directory exists with default themes
     ensure_themes_directory(&config);
     let (tx, _rx) = broadcast::channel(100);
-    let specialization_registry = specializations::init_builtin_registry();
-    let mut app_state = app_state::AppState::new(tx, config, specialization_registry);
+    let app_state = app_state::AppState::new(tx, config, specialization_registry);
     let handles: Vec<tokio::task::JoinHandle<()>> = if slave {
-        spawn_tasks!(app_state.clone(), start_servers, start_slave)
+        spawn_tasks!(
+            app_state.clone(),
+            start_servers,
+            run_scheduler,
+            start_slave,
+            publish_events,
+            watch_config,
+            sample_metrics
+        )
     } else {
         spawn_tasks!(
             app_state.clone(),
             start_web_server,
             start_servers,
-            create_slave_connections
+            run_scheduler,
+            create_slave_connections,
+            watch_config,
+            sample_metrics
         )
     };
     {
@@ -79,20 +188,16 @@ async fn main() -> Result<(), String> {
     // Spawn shutdown handler to kill all child processes on exit
     let app_state_clone = app_state.clone();
     let shutdown = async move |reason: &str| {
-        use tokio::time::{timeout, Duration};
         info!(
             "Shutdown signal received ({}), terminating all child processes...",
             reason
         );
-        let mut servers = app_state_clone.servers.lock().await;
-        let shutdown_fut = async {
-            for server in servers.iter_mut() {
-                let _ = server.stop().await;
-            }
-        };
-        // Give all shutdown tasks a maximum of 2 seconds
-        let _ = timeout(Duration::from_secs(2), shutdown_fut).await;
-        info!("All child processes terminated (or timeout reached).");
+        app_state_clone.clone().stop();
+        // Take the servers out of the shared list so the lock isn't held while they shut down
+        let mut stopping: Vec<_> = app_state_clone.servers.lock().await.drain(..).collect();
+        // Each server is bounded by its own stop timeout, and they all stop in parallel
+        futures::future::join_all(stopping.iter_mut().map(|server| server.stop())).await;
+        info!("All child processes terminated.");
         std::process::exit(0);
     };
 
@@ -113,7 +218,7 @@ async fn main() -> Result<(), String> {
     #[allow(unused_variables)]
     let app_state_clone_t = app_state.clone();
     let _app_state_clone_t = app_state.clone();
-    tokio::spawn({
+    let t_key_handle = tokio::spawn({
         let shutdown = shutdown.clone();
         async move {
             use crossterm::event::{poll, read, Event, KeyCode};
@@ -131,7 +236,8 @@ async fn main() -> Result<(), String> {
         }
     });
 
-    let _ = tokio::spawn(async_listener!("t", app_state)).await;
+    // The T key handler runs the graceful shutdown and exits the process itself
+    let _ = t_key_handle.await;
     exit(0);
 }
 
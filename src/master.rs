@@ -1,21 +1,103 @@
-use crate::{app_state::AppState, configuration::Config, messages::*};
+use crate::{app_state::AppState, messages::*};
+use chrono::Local;
 use futures_util::{SinkExt, StreamExt};
-use rmp_serde::{from_slice, to_vec};
+use rand::Rng;
+use rmp_serde::from_slice;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::{error::Error, time::Duration};
-use tokio::{net::TcpStream, time};
+use std::{
+    collections::HashMap,
+    error::Error,
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::TcpStream,
+    sync::{mpsc, oneshot},
+    time,
+};
 use tokio_tungstenite::{
-    connect_async,
+    connect_async_tls_with_config,
     tungstenite::{Bytes, Message},
-    MaybeTlsStream, WebSocketStream,
+    Connector, MaybeTlsStream, WebSocketStream,
 };
-use tracing::error;
+use tracing::{error, info, warn};
+
+/// How often a connected slave is pinged to measure the link's latency.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Lines of output kept for each slave server, as many as a snapshot carries.
+const OUTPUT_LINES: usize = 150;
+
+/// Round-trip time above which a link counts as degraded.
+const DEGRADED_LATENCY: Duration = Duration::from_secs(1);
+
+/// How long a connected slave may go without answering before the link is given up.
+const OFFLINE_AFTER: Duration = Duration::from_secs(10);
+
+/// How often the status of a healthy link is broadcast again.
+const STATUS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long a forwarded server action may take before the client is told it failed. Stopping
+/// a server can take a while, so this is generous.
+const ACTION_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Wait before the first reconnection attempt.
+const INITIAL_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest wait between reconnection attempts.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// Share of a reconnection delay it is randomly moved by, so slaves that went down together
+/// aren't all retried at once.
+const RECONNECT_JITTER: f64 = 0.2;
+
+/// Health of the link to a slave node.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SlaveLinkState {
+    /// A connection attempt is in progress.
+    Connecting,
+    /// Connected, and answering quickly.
+    Online,
+    /// Connected, but answering pings slower than `DEGRADED_LATENCY`.
+    Degraded,
+    /// Not connected; a reconnection attempt is scheduled.
+    Offline,
+}
+
+/// A server action waiting to be sent to a slave, and where its result goes.
+struct SlaveRequest {
+    action: ServerAction,
+    reply: oneshot::Sender<ServerActionResult>,
+}
+
+/// The master's handle on the link to one slave node, stored in `AppState`.
+pub struct SlaveLink {
+    /// The latest health of the link.
+    pub status: SlaveStatus,
+    /// Hands server actions to the task running the link.
+    requests: mpsc::UnboundedSender<SlaveRequest>,
+}
 
 /// Descriptor for a slave connection, including address and port.
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, JsonSchema, Debug)]
 pub struct SlaveConnectionDescriptor {
     pub address: String,
     pub port: String,
+    /// Whether the slave serves its websocket over TLS.
+    #[serde(default)]
+    pub tls: bool,
+    /// PEM file with the certificate to trust for this slave, such as its self-signed one.
+    /// Publicly trusted certificates are accepted when this is not set.
+    #[serde(default)]
+    pub ca_cert: Option<String>,
+    /// SHA-256 fingerprint of the slave's certificate. When set, only that exact certificate is
+    /// accepted, and `ca_cert` is ignored.
+    #[serde(default)]
+    pub cert_fingerprint: Option<String>,
+    /// The slave's `slave_secret`, proving to it that this master may connect.
+    #[serde(default)]
+    pub secret: Option<String>,
 }
 
 /// Represents a connection to a slave node, including the websocket stream.
@@ -23,16 +105,28 @@ pub struct SlaveConnectionDescriptor {
 pub struct SlaveConnection {
     pub address: String,
     pub port: String,
+    #[serde(default)]
+    pub tls: bool,
+    #[serde(default)]
+    pub ca_cert: Option<String>,
+    #[serde(default)]
+    pub cert_fingerprint: Option<String>,
+    #[serde(skip)]
+    secret: Option<String>,
     #[serde(skip)]
     pub stream: Option<WebSocketStream<MaybeTlsStream<TcpStream>>>, // Public member to store the TcpStream
 }
 
 impl SlaveConnection {
-    /// Creates a new SlaveConnection instance with the given address and port.
-    pub fn new(address: String, port: String) -> Self {
+    /// Creates a new SlaveConnection instance from its configured descriptor.
+    pub fn new(descriptor: SlaveConnectionDescriptor) -> Self {
         Self {
-            address,
-            port,
+            address: descriptor.address,
+            port: descriptor.port,
+            tls: descriptor.tls,
+            ca_cert: descriptor.ca_cert,
+            cert_fingerprint: descriptor.cert_fingerprint,
+            secret: descriptor.secret,
             stream: None,
         }
     }
@@ -43,182 +137,566 @@ impl SlaveConnection {
     /// * `Ok(())` if the connection is successful.
     /// * `Err` if the connection fails.
     pub async fn create_connection(&mut self) -> Result<(), Box<dyn Error>> {
-        let addr = format!("ws://{}:{}/ws", self.address, self.port);
-        let (ws_stream, _) = connect_async(addr).await?;
+        let scheme = if self.tls { "wss" } else { "ws" };
+        let addr = format!("{}://{}:{}/ws", scheme, self.address, self.port);
+        let client_config = match (&self.cert_fingerprint, &self.ca_cert) {
+            _ if !self.tls => None,
+            (Some(fingerprint), _) => Some(crate::tls::client_config_pinned(fingerprint)),
+            (None, Some(ca_cert)) => Some(crate::tls::client_config_trusting(ca_cert)?),
+            (None, None) => None,
+        };
+        let connector = client_config.map(|config| Connector::Rustls(std::sync::Arc::new(config)));
+        let (mut ws_stream, _) =
+            connect_async_tls_with_config(addr, None, false, connector).await?;
+        let secret = self
+            .secret
+            .as_deref()
+            .ok_or("no secret is configured for this slave")?;
+        time::timeout(
+            Duration::from_secs(10),
+            answer_challenge(&mut ws_stream, secret),
+        )
+        .await
+        .map_err(|_| "the slave did not finish the handshake")??;
         self.stream = Some(ws_stream);
         Ok(())
     }
 
-    /// Requests server info from the slave node and updates the shared state.
+    /// Key the link is tracked under, `address:port`.
+    pub fn key(&self) -> String {
+        format!("{}:{}", self.address, self.port)
+    }
+
+    /// The descriptor attached to this slave's servers, which web clients see.
+    fn host_descriptor(&self) -> SlaveConnectionDescriptor {
+        SlaveConnectionDescriptor {
+            address: self.address.clone(),
+            port: self.port.clone(),
+            tls: self.tls,
+            ca_cert: self.ca_cert.clone(),
+            cert_fingerprint: self.cert_fingerprint.clone(),
+            // Shown to web clients, so the secret stays out
+            secret: None,
+        }
+    }
+
+    /// Whether a server entry belongs to this slave.
+    fn hosts(&self, server: &ServerInfo) -> bool {
+        server
+            .host
+            .as_ref()
+            .is_some_and(|host| host.address == self.address && host.port == self.port)
+    }
+
+    /// Asks the slave for a snapshot of its servers, after which its events apply.
+    ///
+    /// # Returns
+    /// * `Err` if there is no active connection or the message could not be sent.
+    async fn subscribe(&mut self) -> Result<(), Box<dyn Error>> {
+        let stream = self.stream.as_mut().ok_or("No active connection")?;
+        let subscribe = SlaveSubscribe {
+            r#type: "subscribe".to_owned(),
+        };
+        stream
+            .send(Message::Text(serde_json::to_string(&subscribe)?.into()))
+            .await?;
+        Ok(())
+    }
+
+    /// Replaces everything known about this slave's servers with a snapshot.
     ///
     /// # Arguments
     /// * `app_state` - The shared application state to update.
+    /// * `servers` - The servers in the snapshot.
+    async fn apply_snapshot(&self, app_state: &AppState, servers: Vec<ServerInfo>) {
+        let host = self.host_descriptor();
+        let mut slave_servers = app_state.slave_servers.lock().await;
+        slave_servers.retain(|server| !self.hosts(server));
+        slave_servers.extend(servers.into_iter().map(|server| ServerInfo {
+            host: Some(host.clone()),
+            offline: false,
+            ..server
+        }));
+    }
+
+    /// Applies one event from the slave to the shared list of slave servers, relaying the
+    /// slave's broadcasts to web clients.
+    ///
+    /// # Arguments
+    /// * `app_state` - The shared application state to update.
+    /// * `event` - The event, already checked to be the next in sequence.
+    async fn apply_event(&self, app_state: &AppState, event: SlaveEvent) {
+        let mut slave_servers = app_state.slave_servers.lock().await;
+        let existing = slave_servers
+            .iter()
+            .position(|server| server.name == event.server_name && self.hosts(server));
+        match (event.event.as_str(), existing) {
+            ("broadcast", _) => {
+                let Some(message) = event.message else {
+                    return;
+                };
+                if let (Some(index), Some(output)) = (
+                    existing,
+                    message
+                        .get("output")
+                        .and_then(|output| output.as_str())
+                        .filter(|_| message["type"] == "ServerOutput"),
+                ) {
+                    let entry = &mut slave_servers[index];
+                    let combined = entry.output.clone() + output;
+                    let lines: Vec<&str> = combined.split('\n').collect();
+                    entry.output = lines[lines.len().saturating_sub(OUTPUT_LINES)..].join("\n");
+                }
+                if let (Some(index), Some(metrics)) = (
+                    existing,
+                    message
+                        .get("metrics")
+                        .filter(|_| message["type"] == "ServerMetrics")
+                        .and_then(|metrics| serde_json::from_value(metrics.clone()).ok()),
+                ) {
+                    slave_servers[index].metrics = Some(metrics);
+                }
+                let _ = app_state.tx.send(message.to_string());
+            }
+            ("status", Some(index)) => {
+                if let Some(server) = event.server {
+                    let entry = &mut slave_servers[index];
+                    entry.active = server.active;
+                    entry.specialization = server.specialization;
+                    entry.specialized_info = server.specialized_info;
+                    entry.restart_count = server.restart_count;
+                    entry.next_retry = server.next_retry;
+                    entry.restart_required = server.restart_required;
+                    entry.metrics = server.metrics;
+                    entry.started_at = server.started_at;
+                    entry.crash_loop = server.crash_loop;
+                    entry.offline = false;
+                }
+            }
+            ("status", None) => {
+                if let Some(server) = event.server {
+                    slave_servers.push(ServerInfo {
+                        host: Some(self.host_descriptor()),
+                        offline: false,
+                        ..server
+                    });
+                }
+            }
+            ("removed", Some(index)) => {
+                slave_servers.remove(index);
+            }
+            _ => {}
+        }
+    }
+
+    /// Marks every server of this slave as offline, keeping its last known details.
+    async fn mark_servers_offline(&self, app_state: &AppState) {
+        let mut slave_servers = app_state.slave_servers.lock().await;
+        for server in slave_servers.iter_mut().filter(|server| self.hosts(server)) {
+            server.active = false;
+            server.offline = true;
+            server.metrics = None;
+            server.started_at = None;
+        }
+    }
+
+    /// Follows a connected slave until the link is lost, keeping its servers and link health up
+    /// to date.
+    ///
+    /// The slave is subscribed to once, and then pushes an event for every change. Each event
+    /// carries a sequence number; when one is skipped the master subscribes again for a fresh
+    /// snapshot. The slave is pinged every `HEARTBEAT_INTERVAL`, the link is degraded while pongs
+    /// take longer than `DEGRADED_LATENCY`, and given up on when nothing arrives for
+    /// `OFFLINE_AFTER`.
+    ///
+    /// Server actions from `requests` are sent on as they arrive, and their results handed back
+    /// once the slave answers.
+    ///
+    /// # Arguments
+    /// * `app_state` - The shared application state.
+    /// * `requests` - Server actions to forward to the slave.
     ///
     /// # Returns
-    /// * `Ok(())` if the request and update succeed.
-    /// * `Err` if there is no active connection or another error occurs.
-    pub async fn request_info(&mut self, app_state: AppState) -> Result<(), Box<dyn Error>> {
-        if let Some(stream) = &mut self.stream {
-            // Prepare your requestInfo message
-            let request_message = SInfoRequestMessage {
-                r#type: "requestInfo".to_owned(),
-                arguments: vec![true],
-            };
-            let msg_bytes = to_vec(&request_message).unwrap();
-            // Send requestInfo message as MessagePack binary
-            let message = Message::Binary(Bytes::from(msg_bytes));
-            let _res = stream.send(message).await;
-
-            // Read response
-            let def = ServerInfoMessage {
-                r#type: "".to_string(),
-                servers: vec![],
-                config: Config::default(),
+    /// * Why the link was lost.
+    async fn follow(
+        &mut self,
+        app_state: &AppState,
+        requests: &mut mpsc::UnboundedReceiver<SlaveRequest>,
+    ) -> String {
+        let mut heartbeat = time::interval(HEARTBEAT_INTERVAL);
+        heartbeat.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+        let mut ping_sent: Option<Instant> = None;
+        let mut last_heard = Instant::now();
+        let mut latency: Option<Duration> = None;
+        let mut reported: Option<(SlaveLinkState, Instant)> = None;
+        // Results still to come, by the request id sent to the slave
+        let mut in_flight: HashMap<String, oneshot::Sender<ServerActionResult>> = HashMap::new();
+        // Sequence number of the last event applied, once the snapshot has arrived
+        let mut last_seq: Option<u64> = None;
+        if let Err(e) = self.subscribe().await {
+            return e.to_string();
+        }
+        loop {
+            let Some(stream) = self.stream.as_mut() else {
+                return "No active connection".to_string();
             };
-            let mut _sinfo: ServerInfoMessage = def.clone();
-            if let Some(stream) = &mut self.stream {
-                while let Some(message) = stream.next().await {
-                    match message {
-                        Ok(msg) => {
-                            if let Message::Binary(bin) = msg {
-                                // Try to decode as MessagePack
-                                if let Ok(server_info_msg) = from_slice::<ServerInfoMessage>(&bin) {
-                                    let mut slave_servers = app_state.slave_servers.lock().await;
-                                    let s_message = to_vec(&server_info_msg).unwrap();
-                                    let s_def = to_vec(&def).unwrap();
-                                    if s_message != s_def && !server_info_msg.servers.is_empty() {
-                                        for server_info in server_info_msg.servers.iter() {
-                                            let new_info = ServerInfo {
-                                                name: server_info.name.clone(),
-                                                output: server_info.output.clone(),
-                                                active: server_info.active,
-                                                host: Some(SlaveConnectionDescriptor {
-                                                    address: self.address.clone(),
-                                                    port: self.port.clone(),
-                                                }),
-                                                specialization: server_info.specialization.clone(),
-                                                specialized_info: server_info
-                                                    .specialized_info
-                                                    .clone(),
-                                            };
-                                            let mut found_existing_server = false;
-                                            for existing_server in slave_servers.iter_mut() {
-                                                if existing_server.name == new_info.name {
-                                                    existing_server.output =
-                                                        new_info.output.clone();
-                                                    existing_server.specialization =
-                                                        new_info.specialization.clone();
-                                                    existing_server.specialized_info =
-                                                        new_info.specialized_info.clone();
-                                                    found_existing_server = true;
-                                                }
-                                            }
-                                            if !found_existing_server {
-                                                slave_servers.push(new_info);
-                                            }
-                                        }
+            tokio::select! {
+                _ = heartbeat.tick() => {
+                    if last_heard.elapsed() > OFFLINE_AFTER {
+                        return format!(
+                            "no reply for {} seconds",
+                            OFFLINE_AFTER.as_secs()
+                        );
+                    }
+                    if ping_sent.is_none() {
+                        if let Err(e) = stream.send(Message::Ping(Bytes::new())).await {
+                            return e.to_string();
+                        }
+                        ping_sent = Some(Instant::now());
+                    }
+                }
+                Some(request) = requests.recv() => {
+                    let id = uuid::Uuid::new_v4().to_string();
+                    let action = ServerAction {
+                        request_id: Some(id.clone()),
+                        ..request.action
+                    };
+                    if let Err(e) = self.send_action(&action).await {
+                        return e.to_string();
+                    }
+                    in_flight.insert(id, request.reply);
+                }
+                message = stream.next() => {
+                    let json = match message {
+                        Some(Ok(Message::Text(text))) => {
+                            serde_json::from_str::<serde_json::Value>(&text).ok()
+                        }
+                        Some(Ok(Message::Binary(bin))) => {
+                            from_slice::<serde_json::Value>(&bin).ok()
+                        }
+                        Some(Ok(Message::Pong(_))) => {
+                            if let Some(sent) = ping_sent.take() {
+                                latency = Some(sent.elapsed());
+                            }
+                            None
+                        }
+                        Some(Ok(Message::Close(_))) | None => {
+                            return "the slave closed the connection".to_string();
+                        }
+                        Some(Ok(_)) => None,
+                        Some(Err(e)) => return e.to_string(),
+                    };
+                    last_heard = Instant::now();
+                    match json.as_ref().and_then(|json| json["type"].as_str()) {
+                        Some("SlaveSnapshot") => {
+                            if let Ok(snapshot) =
+                                serde_json::from_value::<SlaveSnapshot>(json.unwrap())
+                            {
+                                self.apply_snapshot(app_state, snapshot.servers).await;
+                                last_seq = Some(snapshot.seq);
+                            }
+                        }
+                        Some("SlaveEvent") => {
+                            let Ok(event) = serde_json::from_value::<SlaveEvent>(json.unwrap())
+                            else {
+                                continue;
+                            };
+                            match last_seq {
+                                // Still waiting for the snapshot, which will include it
+                                None => {}
+                                Some(seq) if event.seq <= seq => {}
+                                Some(seq) if event.seq == seq + 1 => {
+                                    last_seq = Some(event.seq);
+                                    self.apply_event(app_state, event).await;
+                                }
+                                Some(seq) => {
+                                    warn!(
+                                        "Missed events {} to {} from slave {}, resynchronizing",
+                                        seq + 1,
+                                        event.seq - 1,
+                                        self.key()
+                                    );
+                                    last_seq = None;
+                                    if let Err(e) = self.subscribe().await {
+                                        return e.to_string();
                                     }
-                                } else if let Ok(text) = std::str::from_utf8(&bin) {
-                                    // If not valid MessagePack, treat as UTF-8 text (e.g., ServerOutput)
-                                    let _ = app_state.tx.send(text.to_string());
                                 }
                             }
                         }
-                        Err(_e) => {
-                            // Handle error
+                        Some("ServerActionResult") => {
+                            if let Ok(result) =
+                                serde_json::from_value::<ServerActionResult>(json.unwrap())
+                            {
+                                if let Some(reply) = result
+                                    .request_id
+                                    .as_ref()
+                                    .and_then(|id| in_flight.remove(id))
+                                {
+                                    let _ = reply.send(result);
+                                }
+                            }
                         }
+                        _ => {}
                     }
                 }
             }
-            Ok(())
-        } else {
-            Err("No active connection".into())
+            // An overdue ping counts as the latency until it is answered
+            let overdue = ping_sent
+                .map(|sent| sent.elapsed())
+                .filter(|waited| *waited > DEGRADED_LATENCY);
+            let current_latency = latency.max(overdue);
+            let link_state = if current_latency.is_some_and(|latency| latency > DEGRADED_LATENCY) {
+                SlaveLinkState::Degraded
+            } else {
+                SlaveLinkState::Online
+            };
+            let due = reported.is_none_or(|(reported_state, at)| {
+                reported_state != link_state || at.elapsed() >= STATUS_INTERVAL
+            });
+            // Wait for the first pong, so the latency is known when going online
+            if due && latency.is_some() {
+                self.publish_status(
+                    app_state,
+                    link_state,
+                    current_latency.map(|latency| latency.as_millis() as u64),
+                    None,
+                    None,
+                )
+                .await;
+                reported = Some((link_state, Instant::now()));
+            }
         }
     }
 
-    /// Sends stdin input to a server on the slave node.
-    ///
-    /// # Arguments
-    /// * `server_name` - The name of the server to send input to.
-    /// * `message` - The input message to send.
-    ///
-    /// # Returns
-    /// * `Ok(())` if the message is sent successfully.
-    /// * `Err` if there is a connection or protocol error.
-    #[allow(dead_code)]
-    pub async fn write_stdin(
-        &mut self,
-        server_name: String,
-        message: String,
-    ) -> Result<(), Box<dyn Error>> {
-        // Prepare your stdin message
-        let stdin_message = StdinInput {
-            r#type: "stdinInput".to_owned(),
-            server_name,
-            value: message,
+    /// Records the link's health and broadcasts it as a `SlaveStatus`.
+    async fn publish_status(
+        &self,
+        app_state: &AppState,
+        state: SlaveLinkState,
+        latency_ms: Option<u64>,
+        error: Option<String>,
+        next_retry: Option<String>,
+    ) {
+        let status = SlaveStatus {
+            r#type: "SlaveStatus".to_owned(),
+            address: self.address.clone(),
+            port: self.port.clone(),
+            state,
+            latency_ms,
+            error,
+            next_retry,
         };
-        let msg_bytes = to_vec(&stdin_message).unwrap();
-        // Send stdin message as MessagePack binary
-        if let Some(stream) = &mut self.stream {
-            let message = Message::Binary(Bytes::from(msg_bytes));
-            let _ = tokio::time::timeout(Duration::from_secs_f64(1. / 1000.), stream.send(message))
-                .await;
+        let message = serde_json::to_string(&status).unwrap();
+        if let Some(link) = app_state.slave_links.lock().await.get_mut(&self.key()) {
+            link.status = status;
         }
+        let _ = app_state.tx.send(message);
+    }
 
-        // You might want to read a response or confirmation from the slave
-        // Depending on your protocol, handle the response here
+    /// Sends a server action to the slave node.
+    ///
+    /// # Returns
+    /// * `Err` if there is no active connection or the message could not be sent.
+    async fn send_action(&mut self, action: &ServerAction) -> Result<(), Box<dyn Error>> {
+        let stream = self.stream.as_mut().ok_or("No active connection")?;
+        stream
+            .send(Message::Text(serde_json::to_string(action)?.into()))
+            .await?;
+        Ok(())
+    }
+}
 
+/// Proves to a slave that this master knows its secret, by signing the challenge it sends first.
+///
+/// # Returns
+/// * `Err` if the slave refused the answer or the handshake broke off.
+async fn answer_challenge(
+    stream: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
+    secret: &str,
+) -> Result<(), Box<dyn Error>> {
+    let challenge: SlaveAuthChallenge = read_handshake_message(stream).await?;
+    let response = SlaveAuthResponse {
+        r#type: "authResponse".to_owned(),
+        hmac: crate::slave::sign_challenge(secret, &challenge.nonce),
+    };
+    stream
+        .send(Message::Text(serde_json::to_string(&response)?.into()))
+        .await?;
+    let result: SlaveAuthResult = read_handshake_message(stream).await?;
+    if result.success {
         Ok(())
+    } else {
+        Err("the slave rejected the secret".into())
     }
 }
 
-/// Creates and manages connections to all configured slave nodes.
-/// Polls each slave for server info at a fixed interval and updates shared state.
+/// Reads the next text message of the handshake.
+async fn read_handshake_message<T: serde::de::DeserializeOwned>(
+    stream: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
+) -> Result<T, Box<dyn Error>> {
+    loop {
+        match stream.next().await {
+            Some(Ok(Message::Text(text))) => return Ok(serde_json::from_str(&text)?),
+            Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+            Some(Ok(_)) => return Err("unexpected message during the handshake".into()),
+            Some(Err(e)) => return Err(e.into()),
+            None => return Err("the slave closed the connection".into()),
+        }
+    }
+}
+
+/// Builds the result of an action that could not be carried out.
+pub fn action_failed(action: &ServerAction, error: String) -> ServerActionResult {
+    ServerActionResult {
+        r#type: "ServerActionResult".to_owned(),
+        request_id: action.request_id.clone(),
+        server_name: action.server_name.clone(),
+        action: action.action.clone(),
+        success: false,
+        error: Some(error),
+        exit_code: None,
+    }
+}
+
+/// Carries out a server action on the slave hosting the server, and waits for its result.
 ///
 /// # Arguments
 /// * `state` - The shared application state.
-pub async fn create_slave_connections(state: AppState) {
-    let mut slaves: Vec<SlaveConnection> = vec![];
-    let conf = state.config.lock().await;
-    let config: Config = conf.clone();
-    drop(conf);
-    for slave_desc in config.slave_connections {
-        let mut slave = SlaveConnection::new(slave_desc.address.clone(), slave_desc.port.clone());
-        let conn_res = slave.create_connection().await;
-        match conn_res {
-            Ok(_) => {
-                println!("Success connecting to a slave node!");
-                slaves.push(slave);
+/// * `host` - The slave the server runs on.
+/// * `action` - What to do; its `request_id` is kept in the result.
+pub async fn forward_action(
+    state: &AppState,
+    host: &SlaveConnectionDescriptor,
+    action: ServerAction,
+) -> ServerActionResult {
+    let key = format!("{}:{}", host.address, host.port);
+    let (reply_tx, reply) = oneshot::channel();
+    {
+        let links = state.slave_links.lock().await;
+        let Some(link) = links.get(&key) else {
+            return action_failed(&action, format!("Slave {} is not configured", key));
+        };
+        if !matches!(
+            link.status.state,
+            SlaveLinkState::Online | SlaveLinkState::Degraded
+        ) {
+            return action_failed(&action, format!("Slave {} is offline", key));
+        }
+        let request = SlaveRequest {
+            action: action.clone(),
+            reply: reply_tx,
+        };
+        if link.requests.send(request).is_err() {
+            return action_failed(&action, format!("Slave {} is offline", key));
+        }
+    }
+    match time::timeout(ACTION_TIMEOUT, reply).await {
+        Ok(Ok(result)) => ServerActionResult {
+            request_id: action.request_id,
+            ..result
+        },
+        Ok(Err(_)) => action_failed(&action, format!("Lost connection to slave {}", key)),
+        Err(_) => action_failed(&action, format!("Slave {} did not answer in time", key)),
+    }
+}
+
+/// Delay before the reconnection attempt that follows `failures` failed ones in a row.
+fn reconnect_delay(failures: u32) -> Duration {
+    let base = INITIAL_RECONNECT_DELAY
+        .saturating_mul(1u32 << failures.min(16))
+        .min(MAX_RECONNECT_DELAY);
+    let factor = 1.0 + rand::thread_rng().gen_range(-RECONNECT_JITTER..=RECONNECT_JITTER);
+    base.mul_f64(factor)
+}
+
+/// Keeps the link to one slave node up for as long as the controller runs.
+///
+/// Each time the connection fails or is lost, the slave's servers are marked offline and the
+/// next attempt waits twice as long as the previous one, up to `MAX_RECONNECT_DELAY`.
+///
+/// # Arguments
+/// * `state` - The shared application state.
+/// * `descriptor` - The slave to connect to.
+async fn run_slave_link(state: AppState, descriptor: SlaveConnectionDescriptor) {
+    let mut slave = SlaveConnection::new(descriptor);
+    let (requests_tx, mut requests) = mpsc::unbounded_channel();
+    state.slave_links.lock().await.insert(
+        slave.key(),
+        SlaveLink {
+            status: SlaveStatus {
+                r#type: "SlaveStatus".to_owned(),
+                address: slave.address.clone(),
+                port: slave.port.clone(),
+                state: SlaveLinkState::Connecting,
+                latency_ms: None,
+                error: None,
+                next_retry: None,
+            },
+            requests: requests_tx,
+        },
+    );
+    let mut failures: u32 = 0;
+    loop {
+        slave
+            .publish_status(&state, SlaveLinkState::Connecting, None, None, None)
+            .await;
+        // The error is turned into a string so it isn't held across the polling below
+        let connected = slave.create_connection().await.map_err(|e| e.to_string());
+        let error = match connected {
+            Ok(()) => {
+                info!("Connected to slave {}", slave.key());
+                failures = 0;
+                let reason = slave.follow(&state, &mut requests).await;
+                slave.stream = None;
+                warn!("Lost connection to slave {}: {}", slave.key(), reason);
+                reason
             }
             Err(what) => {
                 error!(
                     "Error connecting to: {}:{}, Message: {}",
-                    &slave_desc.address, &slave_desc.port, what
+                    &slave.address, &slave.port, what
                 );
+                failures = failures.saturating_add(1);
+                what.to_string()
             }
+        };
+        slave.mark_servers_offline(&state).await;
+        // Actions queued while the link went down are failed rather than sent much later
+        while let Ok(request) = requests.try_recv() {
+            let _ = request.reply.send(action_failed(
+                &request.action,
+                format!("Lost connection to slave {}", slave.key()),
+            ));
         }
+        let delay = reconnect_delay(failures);
+        let next_retry = Local::now() + chrono::Duration::from_std(delay).unwrap_or_default();
+        slave
+            .publish_status(
+                &state,
+                SlaveLinkState::Offline,
+                None,
+                Some(error),
+                Some(next_retry.to_rfc3339()),
+            )
+            .await;
+        time::sleep(delay).await;
     }
-    {
-        let mut slaves_list = state.slave_connections.lock().await;
-        for slave in slaves {
-            slaves_list.push(slave);
-        }
-    }
-    // Create the polling loop at 4 polls per second
-    {
-        let mut interval = time::interval(Duration::from_millis(250)); // 4 times per second
-        loop {
-            interval.tick().await;
-            // Perform actions on each tick, such as checking the status of connections,
-            // sending keep-alive messages, etc.
-            let mut slaves = state.slave_connections.lock().await;
-            for slave in slaves.iter_mut() {
-                let _ = tokio::time::timeout(
-                    Duration::from_secs_f64(10. / 1000.),
-                    slave.request_info(state.clone()),
-                )
-                .await;
-            }
-        }
+}
+
+/// Creates and manages connections to all configured slave nodes.
+///
+/// Each slave gets its own link task, which reconnects with exponential backoff whenever the
+/// slave can't be reached.
+///
+/// # Arguments
+/// * `state` - The shared application state.
+pub async fn create_slave_connections(state: AppState) {
+    let slave_connections = state.config.lock().await.slave_connections.clone();
+    let links: Vec<_> = slave_connections
+        .into_iter()
+        .map(|descriptor| tokio::spawn(run_slave_link(state.clone(), descriptor)))
+        .collect();
+    for link in links {
+        let _ = link.await;
     }
 }
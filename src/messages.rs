@@ -1,4 +1,5 @@
-use crate::master::SlaveConnectionDescriptor;
+use crate::master::{SlaveConnectionDescriptor, SlaveLinkState};
+use crate::metrics::ProcessMetrics;
 use serde::{Deserialize, Serialize};
 
 /// Message types and data structures for server communication and web API.
@@ -21,6 +22,54 @@ pub struct ServerInfo {
     pub specialization: Option<String>,
     /// Optional extra info for specialized servers.
     pub specialized_info: Option<serde_json::Value>,
+    /// Crash restarts counted inside the server's restart policy window.
+    #[serde(default)]
+    pub restart_count: u32,
+    /// When a pending crash restart will happen (RFC 3339), if one is scheduled.
+    #[serde(default)]
+    pub next_retry: Option<String>,
+    /// Whether the slave hosting this server can't be reached, so the rest of the entry is stale.
+    #[serde(default)]
+    pub offline: bool,
+    /// Whether the server's configuration changed since it was started, so it must be restarted
+    /// for the changes to apply.
+    #[serde(default)]
+    pub restart_required: bool,
+    /// The latest resource usage of the server, if it is running and was measured.
+    #[serde(default)]
+    pub metrics: Option<ProcessMetrics>,
+    /// When the server was started (RFC 3339), if it is running.
+    #[serde(default)]
+    pub started_at: Option<String>,
+    /// Whether the server kept crashing and its restart policy gave up on it.
+    #[serde(default)]
+    pub crash_loop: bool,
+}
+
+/// Message broadcast when a server keeps crashing and will no longer be restarted.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ServerCrashLoop {
+    /// The type of message (should be "ServerCrashLoop").
+    pub r#type: String,
+    /// The name of the server that was given up on.
+    pub server_name: String,
+    /// Crashes counted inside the restart policy window.
+    pub restarts: u32,
+    /// Length of the restart policy window in seconds.
+    pub window_secs: u64,
+    /// Exit code of the last crash.
+    pub exit_code: i32,
+}
+
+/// Message broadcast each time a running server's resource usage is measured.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ServerMetrics {
+    /// The type of message (should be "ServerMetrics").
+    pub r#type: String,
+    /// The name of the server that was measured.
+    pub server_name: String,
+    /// Its resource usage.
+    pub metrics: ProcessMetrics,
 }
 
 /// Message for sending console output to the web client.
@@ -81,6 +130,372 @@ pub struct StdinInput {
     pub value: String,
 }
 
+/// Request to act on one server, on this node or a slave. Answered with a `ServerActionResult`
+/// sent only to the requesting client.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ServerAction {
+    /// The type of message (should be "serverAction").
+    pub r#type: String,
+    /// Chosen by the client and copied into the result, so it can tell replies apart.
+    #[serde(default)]
+    pub request_id: Option<String>,
+    /// The name of the server to act on.
+    pub server_name: String,
+    /// What to do: "stdin", "start", "stop", "restart" or "terminate".
+    pub action: String,
+    /// The line to send, for "stdin".
+    #[serde(default)]
+    pub value: Option<String>,
+}
+
+/// Outcome of a `ServerAction`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ServerActionResult {
+    /// The type of message (should be "ServerActionResult").
+    pub r#type: String,
+    /// The `request_id` of the action.
+    pub request_id: Option<String>,
+    /// The server acted on.
+    pub server_name: String,
+    /// What was done.
+    pub action: String,
+    /// Whether it worked.
+    pub success: bool,
+    /// Why it failed, if it did.
+    pub error: Option<String>,
+    /// The server's exit code, for "stop" and "terminate".
+    pub exit_code: Option<i32>,
+}
+
+/// Request from a web client for a page of a server's console history.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RequestHistory {
+    /// The type of message (should be "requestHistory").
+    pub r#type: String,
+    /// The name of the server whose history is requested.
+    pub server_name: String,
+    /// Timestamp cursor from a previous page; only lines older than it are returned.
+    #[serde(default)]
+    pub before: Option<String>,
+    /// Maximum number of lines to return.
+    #[serde(default)]
+    pub limit: Option<usize>,
+    /// Optional case-insensitive text the returned lines must contain.
+    #[serde(default)]
+    pub search: Option<String>,
+}
+
+/// A page of console history sent in reply to `RequestHistory`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ConsoleHistoryPage {
+    /// The type of message (should be "ConsoleHistory").
+    pub r#type: String,
+    /// The name of the server the history belongs to.
+    pub server_name: String,
+    /// The `before` cursor that was requested.
+    pub before: Option<String>,
+    /// The search that was applied, if any.
+    pub search: Option<String>,
+    /// The lines of this page, oldest first.
+    pub lines: Vec<HistoryLineInfo>,
+    /// Whether older matching lines exist beyond this page.
+    pub has_more: bool,
+    /// The `before` cursor of the next, older page, if there is one.
+    pub next_before: Option<String>,
+}
+
+/// A single console history line prepared for display.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct HistoryLineInfo {
+    /// RFC 3339 timestamp of the line.
+    pub timestamp: String,
+    /// Which stream the line came from ("out", "err" or "ctl").
+    pub stream: crate::console_history::HistoryStream,
+    /// The line rendered as HTML.
+    pub output: String,
+}
+
+/// Request from a web client to add a schedule to a server, replacing one with the same id.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AddSchedule {
+    /// The type of message (should be "addSchedule").
+    pub r#type: String,
+    /// The name of the server the schedule belongs to.
+    pub server_name: String,
+    /// The schedule to add.
+    pub schedule: crate::scheduler::ScheduleDescriptor,
+}
+
+/// Request from a web client to remove a schedule from a server.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RemoveSchedule {
+    /// The type of message (should be "removeSchedule").
+    pub r#type: String,
+    /// The name of the server the schedule belongs to.
+    pub server_name: String,
+    /// The id of the schedule to remove.
+    pub id: String,
+}
+
+/// A schedule together with the server it belongs to and when it fires next.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ScheduleInfo {
+    /// The name of the server the schedule belongs to.
+    pub server_name: String,
+    /// The schedule itself.
+    pub schedule: crate::scheduler::ScheduleDescriptor,
+    /// When the schedule fires next (RFC 3339), if it is active.
+    pub next_run: Option<String>,
+}
+
+/// Message listing every configured schedule.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SchedulesList {
+    /// The type of message (should be "SchedulesList").
+    pub r#type: String,
+    /// All schedules across all servers.
+    pub schedules: Vec<ScheduleInfo>,
+}
+
+/// Message sent when a schedule request could not be carried out.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ScheduleError {
+    /// The type of message (should be "ScheduleError").
+    pub r#type: String,
+    /// The name of the server the request was about.
+    pub server_name: String,
+    /// Why the request failed.
+    pub error: String,
+}
+
+/// Request from a web client to add a server to the configuration.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AddServer {
+    /// The type of message (should be "addServer").
+    pub r#type: String,
+    /// The server to add.
+    pub server: crate::controlled_program::ControlledProgramDescriptor,
+}
+
+/// Request from a web client to replace the configuration of one server.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct UpdateServer {
+    /// The type of message (should be "updateServer").
+    pub r#type: String,
+    /// The name of the server to update.
+    pub server_name: String,
+    /// Its new configuration, which may give it a new name.
+    pub server: crate::controlled_program::ControlledProgramDescriptor,
+}
+
+/// Request from a web client to remove a server from the configuration.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RemoveServer {
+    /// The type of message (should be "removeServer").
+    pub r#type: String,
+    /// The name of the server to remove.
+    pub server_name: String,
+}
+
+/// Request from a web client to change top level settings of the configuration.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct UpdateGlobalSettings {
+    /// The type of message (should be "updateGlobalSettings").
+    pub r#type: String,
+    /// The settings to change by name, such as `"console_history"`. Others keep their value.
+    pub settings: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Message sent when a configuration change could not be carried out.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ConfigError {
+    /// The type of message (should be "ConfigError").
+    pub r#type: String,
+    /// The request that failed, such as "addServer".
+    pub request: String,
+    /// The server the request was about, if any.
+    pub server_name: Option<String>,
+    /// Why the request failed.
+    pub error: String,
+    /// Every problem found in the configuration the request would have led to.
+    pub diagnostics: Vec<crate::config_validation::Diagnostic>,
+}
+
+/// Request from a web client about a server's backups: `triggerBackup`, `listBackups` or
+/// `restoreBackup`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BackupRequest {
+    /// The type of message (`triggerBackup`, `listBackups` or `restoreBackup`).
+    pub r#type: String,
+    /// The name of the server the request is about.
+    pub server_name: String,
+    /// The backup to restore; only used by `restoreBackup`.
+    #[serde(default)]
+    pub file_name: Option<String>,
+}
+
+/// Message listing a server's backups, newest first.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BackupsList {
+    /// The type of message (should be "BackupsList").
+    pub r#type: String,
+    /// The name of the server the backups belong to.
+    pub server_name: String,
+    /// The backups on disk.
+    pub backups: Vec<crate::backup::BackupInfo>,
+}
+
+/// Message sent when a backup or restore finishes.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BackupStatus {
+    /// The type of message (should be "BackupStatus").
+    pub r#type: String,
+    /// The name of the server that was backed up or restored.
+    pub server_name: String,
+    /// What was done: "backup" or "restore".
+    pub action: String,
+    /// Whether it worked.
+    pub success: bool,
+    /// The archive that was written or restored.
+    pub file_name: Option<String>,
+    /// Why it failed, if it did.
+    pub error: Option<String>,
+}
+
+/// Message sent to a client whose request it lacks the permission for.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PermissionDenied {
+    /// The type of message (should be "PermissionDenied").
+    pub r#type: String,
+    /// What was refused.
+    pub error: String,
+}
+
+/// Request from a web client for a page of the audit log.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RequestAuditLog {
+    /// The type of message (should be "requestAuditLog").
+    pub r#type: String,
+    /// Which entries to return; every field is optional.
+    #[serde(flatten)]
+    pub query: crate::audit::AuditQuery,
+}
+
+/// A page of the audit log sent in reply to `RequestAuditLog`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AuditLogPage {
+    /// The type of message (should be "AuditLog").
+    pub r#type: String,
+    /// The request this page answers.
+    pub query: crate::audit::AuditQuery,
+    /// The entries of this page, newest first.
+    pub entries: Vec<crate::audit::AuditEntry>,
+    /// Whether older matching entries exist beyond this page.
+    pub has_more: bool,
+    /// The `before` cursor of the next, older page, if there is one.
+    pub next_before: Option<u64>,
+}
+
+/// First message a slave sends to a connecting master, asking it to prove it knows the secret.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SlaveAuthChallenge {
+    /// The type of message (should be "AuthChallenge").
+    pub r#type: String,
+    /// Random hex string to sign; never reused.
+    pub nonce: String,
+}
+
+/// The master's answer to a `SlaveAuthChallenge`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SlaveAuthResponse {
+    /// The type of message (should be "authResponse").
+    pub r#type: String,
+    /// Hex HMAC-SHA256 of the nonce, keyed with the shared secret.
+    pub hmac: String,
+}
+
+/// The slave's verdict on a `SlaveAuthResponse`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SlaveAuthResult {
+    /// The type of message (should be "AuthResult").
+    pub r#type: String,
+    /// Whether the master may now send requests.
+    pub success: bool,
+}
+
+/// Sent by a master to a slave when it connects, and again whenever it misses an event.
+/// Answered with a `SlaveSnapshot`, after which the slave's `SlaveEvent`s apply to it.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SlaveSubscribe {
+    /// The type of message (should be "subscribe").
+    pub r#type: String,
+}
+
+/// Everything a master needs to know about a slave's servers.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SlaveSnapshot {
+    /// The type of message (should be "SlaveSnapshot").
+    pub r#type: String,
+    /// Sequence number of the last event the snapshot includes.
+    pub seq: u64,
+    /// The slave's servers, with their recent output.
+    pub servers: Vec<ServerInfo>,
+}
+
+/// One change on a slave, pushed to the masters connected to it.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SlaveEvent {
+    /// The type of message (should be "SlaveEvent").
+    pub r#type: String,
+    /// Position of the event in the slave's stream. Each event is numbered one higher than the
+    /// one before, so a master that sees a gap knows it missed something.
+    pub seq: u64,
+    /// What happened: "broadcast", "status" or "removed".
+    pub event: String,
+    /// The server the event concerns.
+    pub server_name: String,
+    /// For "broadcast", the message the slave broadcast about the server, such as a
+    /// `ServerOutput` carrying new output.
+    #[serde(default)]
+    pub message: Option<serde_json::Value>,
+    /// For "status", the server's new state, without its output.
+    #[serde(default)]
+    pub server: Option<ServerInfo>,
+}
+
+/// Message broadcast when the health of the link to a slave node changes, and periodically
+/// while it is connected.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct SlaveStatus {
+    /// The type of message (should be "SlaveStatus").
+    pub r#type: String,
+    /// The slave's address, as configured.
+    pub address: String,
+    /// The slave's port, as configured.
+    pub port: String,
+    /// The state of the link.
+    pub state: SlaveLinkState,
+    /// Round-trip time of the latest heartbeat ping, in milliseconds.
+    pub latency_ms: Option<u64>,
+    /// Why the link was lost, while it is down.
+    pub error: Option<String>,
+    /// When the next reconnection attempt will happen (RFC 3339), while it is down.
+    pub next_retry: Option<String>,
+}
+
+/// Message sent by a web client when its console view changes size.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ResizeConsole {
+    /// The type of message (should be "resizeConsole").
+    pub r#type: String,
+    /// The name of the server whose console was resized.
+    pub server_name: String,
+    /// Visible console width in characters.
+    pub cols: u16,
+    /// Visible console height in lines.
+    pub rows: u16,
+}
+
 /// Message containing configuration information.
 #[derive(Clone, Serialize, Deserialize)]
 pub struct ConfigInfo {
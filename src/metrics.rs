@@ -0,0 +1,257 @@
+/// Resource usage of the managed servers.
+///
+/// Every few seconds each running server is measured together with every process it started,
+/// so a server run through a wrapper script is counted in full. The numbers are read from
+/// `/proc`, so they are only available on Linux. The latest measurements are part of
+/// `ServerInfo`, and each measurement is also broadcast as a `ServerMetrics` message, which
+/// slaves pass on to their masters like their other broadcasts.
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, time::Duration};
+use tokio::time::Instant;
+
+use crate::{app_state::AppState, messages::ServerMetrics};
+
+/// Metrics settings, stored in `Config`.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct MetricsSettings {
+    /// Whether the servers' resource usage is measured.
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// How often the servers are measured.
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_interval_secs() -> u64 {
+    5
+}
+
+impl Default for MetricsSettings {
+    fn default() -> Self {
+        Self {
+            enabled: default_enabled(),
+            interval_secs: default_interval_secs(),
+        }
+    }
+}
+
+/// Resource usage of a server and the processes it started.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ProcessMetrics {
+    /// CPU time used since the last measurement, in percent of one core, so a server keeping
+    /// two cores busy shows 200.
+    pub cpu_percent: f64,
+    /// Resident memory.
+    pub rss_bytes: u64,
+    /// Threads across all processes.
+    pub threads: u64,
+    /// Open file descriptors across all processes.
+    pub open_fds: u64,
+    /// Bytes the processes have read from storage since they started.
+    pub read_bytes: u64,
+    /// Bytes the processes have written to storage since they started.
+    pub write_bytes: u64,
+    /// How many processes were measured: the server and everything it started.
+    pub processes: u64,
+}
+
+/// One reading of a process tree, before CPU time is turned into a percentage.
+#[derive(Default)]
+struct Sample {
+    /// CPU time used so far, in clock ticks.
+    cpu_ticks: u64,
+    /// Everything else, with `cpu_percent` left at 0.
+    metrics: ProcessMetrics,
+}
+
+#[cfg(target_os = "linux")]
+mod procfs {
+    use std::{collections::HashMap, fs};
+
+    use super::Sample;
+
+    /// The parent of every process, keyed by process ID.
+    fn parents() -> HashMap<u32, u32> {
+        let Ok(entries) = fs::read_dir("/proc") else {
+            return HashMap::new();
+        };
+        entries
+            .flatten()
+            .filter_map(|entry| entry.file_name().to_str()?.parse::<u32>().ok())
+            .filter_map(|pid| {
+                let fields = stat_fields(pid)?;
+                Some((pid, fields.get(1)?.parse().ok()?))
+            })
+            .collect()
+    }
+
+    /// The fields of `/proc/<pid>/stat` after the command name, starting with the state.
+    fn stat_fields(pid: u32) -> Option<Vec<String>> {
+        let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+        // The command name is in parentheses and may itself contain spaces and parentheses
+        let rest = &stat[stat.rfind(')')? + 1..];
+        Some(rest.split_whitespace().map(str::to_owned).collect())
+    }
+
+    /// Adds one process to a sample.
+    fn add_process(pid: u32, sample: &mut Sample, page_size: u64) {
+        let Some(fields) = stat_fields(pid) else {
+            return;
+        };
+        let field = |index: usize| {
+            fields
+                .get(index)
+                .and_then(|value| value.parse::<u64>().ok())
+                .unwrap_or(0)
+        };
+        // utime and stime, then num_threads and rss in pages
+        sample.cpu_ticks += field(11) + field(12);
+        sample.metrics.threads += field(17);
+        sample.metrics.rss_bytes += field(21) * page_size;
+        sample.metrics.processes += 1;
+        if let Ok(fds) = fs::read_dir(format!("/proc/{}/fd", pid)) {
+            sample.metrics.open_fds += fds.count() as u64;
+        }
+        // Only readable for processes of the same user
+        if let Ok(io) = fs::read_to_string(format!("/proc/{}/io", pid)) {
+            for line in io.lines() {
+                let Some((key, value)) = line.split_once(':') else {
+                    continue;
+                };
+                let value = value.trim().parse::<u64>().unwrap_or(0);
+                match key {
+                    "read_bytes" => sample.metrics.read_bytes += value,
+                    "write_bytes" => sample.metrics.write_bytes += value,
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Clock ticks per second, the unit of CPU time in `/proc`.
+    pub fn ticks_per_second() -> f64 {
+        let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+        if ticks > 0 {
+            ticks as f64
+        } else {
+            100.0
+        }
+    }
+
+    /// Measures processes together with every process they started.
+    ///
+    /// # Arguments
+    /// * `roots` - The process IDs of the servers.
+    ///
+    /// # Returns
+    /// * A sample for each root that still exists, keyed by its process ID.
+    pub fn sample_trees(roots: &[u32]) -> HashMap<u32, Sample> {
+        let page_size = match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+            size if size > 0 => size as u64,
+            _ => 4096,
+        };
+        let mut children: HashMap<u32, Vec<u32>> = HashMap::new();
+        for (pid, parent) in parents() {
+            children.entry(parent).or_default().push(pid);
+        }
+        let mut samples = HashMap::new();
+        for &root in roots {
+            let mut sample = Sample::default();
+            let mut pending = vec![root];
+            while let Some(pid) = pending.pop() {
+                add_process(pid, &mut sample, page_size);
+                if let Some(kids) = children.get(&pid) {
+                    pending.extend(kids);
+                }
+            }
+            if sample.metrics.processes > 0 {
+                samples.insert(root, sample);
+            }
+        }
+        samples
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod procfs {
+    use std::collections::HashMap;
+
+    use super::Sample;
+
+    pub fn ticks_per_second() -> f64 {
+        100.0
+    }
+
+    /// There is no `/proc` to read, so nothing is measured.
+    pub fn sample_trees(_roots: &[u32]) -> HashMap<u32, Sample> {
+        HashMap::new()
+    }
+}
+
+/// Measures the running servers on an interval, keeping the latest numbers in the state and
+/// broadcasting them.
+///
+/// # Arguments
+/// * `state` - The shared application state.
+pub async fn sample_metrics(state: AppState) {
+    if cfg!(not(target_os = "linux")) {
+        tracing::info!("Server metrics are only available on Linux");
+        return;
+    }
+    let ticks_per_second = procfs::ticks_per_second();
+    // CPU time of each process tree at the previous measurement, keyed by process ID
+    let mut previous: HashMap<u32, (Instant, u64)> = HashMap::new();
+    loop {
+        let settings = state.config.lock().await.metrics.clone();
+        tokio::time::sleep(Duration::from_secs(settings.interval_secs.max(1))).await;
+        if !settings.enabled {
+            state.metrics.lock().await.clear();
+            previous.clear();
+            continue;
+        }
+        let running: Vec<(String, u32)> = state
+            .servers
+            .lock()
+            .await
+            .iter()
+            .filter_map(|server| Some((server.name.clone(), server.process.id()?)))
+            .collect();
+        let roots: Vec<u32> = running.iter().map(|(_, pid)| *pid).collect();
+        // Reading /proc blocks
+        let mut samples = tokio::task::spawn_blocking(move || procfs::sample_trees(&roots))
+            .await
+            .unwrap_or_default();
+        let now = Instant::now();
+
+        let mut latest = HashMap::new();
+        let mut measured = HashMap::new();
+        for (server_name, pid) in running {
+            let Some(sample) = samples.remove(&pid) else {
+                continue;
+            };
+            let mut metrics = sample.metrics;
+            if let Some((at, ticks)) = previous.get(&pid) {
+                let elapsed = now.duration_since(*at).as_secs_f64();
+                if elapsed > 0.0 {
+                    let used = sample.cpu_ticks.saturating_sub(*ticks) as f64 / ticks_per_second;
+                    metrics.cpu_percent = (used / elapsed * 1000.0).round() / 10.0;
+                }
+            }
+            measured.insert(pid, (now, sample.cpu_ticks));
+            let message = ServerMetrics {
+                r#type: "ServerMetrics".to_owned(),
+                server_name: server_name.clone(),
+                metrics: metrics.clone(),
+            };
+            let _ = state.tx.send(serde_json::to_string(&message).unwrap());
+            latest.insert(server_name, metrics);
+        }
+        previous = measured;
+        *state.metrics.lock().await = latest;
+    }
+}
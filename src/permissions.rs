@@ -0,0 +1,352 @@
+/// Role-based permissions for logged-in users.
+///
+/// Roles are defined in the `auth.roles` object of the configuration as a list of grants, each
+/// giving some permissions on some (or all) servers. Users are given roles in the users file,
+/// and everything they can do or see through the websocket and the HTTP API is checked
+/// against the resulting [`Access`].
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Something a user can be allowed to do.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Permission {
+    /// See the server, its console output and history.
+    ViewConsole,
+    /// Send console commands to the server.
+    SendStdin,
+    /// Start, stop and restart the server.
+    StartStop,
+    /// Change the server's configuration and schedules. Replacing the whole configuration
+    /// needs this on every server.
+    EditConfig,
+    /// Take, list, download and restore backups of the server.
+    ManageBackups,
+    /// Read the audit log. Only meaningful without a `servers` list.
+    ViewAuditLog,
+    /// Every permission.
+    #[serde(rename = "*")]
+    All,
+}
+
+/// Permissions given on a set of servers.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct Grant {
+    /// What is allowed.
+    pub permissions: Vec<Permission>,
+    /// Which servers it is allowed on. Empty means every server.
+    #[serde(default)]
+    pub servers: Vec<String>,
+}
+
+impl Grant {
+    fn gives(&self, permission: Permission) -> bool {
+        self.permissions
+            .iter()
+            .any(|granted| *granted == permission || *granted == Permission::All)
+    }
+
+    fn covers(&self, server_name: &str) -> bool {
+        self.servers.is_empty() || self.servers.iter().any(|name| name == server_name)
+    }
+}
+
+/// A named set of grants.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct Role {
+    /// The permissions the role gives.
+    pub grants: Vec<Grant>,
+}
+
+/// The roles available out of the box: `admin` can do everything, `viewer` can watch every
+/// console.
+pub fn default_roles() -> BTreeMap<String, Role> {
+    BTreeMap::from([
+        (
+            "admin".to_string(),
+            Role {
+                grants: vec![Grant {
+                    permissions: vec![Permission::All],
+                    servers: vec![],
+                }],
+            },
+        ),
+        (
+            "viewer".to_string(),
+            Role {
+                grants: vec![Grant {
+                    permissions: vec![Permission::ViewConsole],
+                    servers: vec![],
+                }],
+            },
+        ),
+    ])
+}
+
+/// What one client is allowed to do.
+#[derive(Clone, Debug)]
+pub struct Access {
+    /// The logged-in user, if any.
+    username: Option<String>,
+    /// The grants of the client's roles, or `None` if it is not restricted at all.
+    grants: Option<Vec<Grant>>,
+}
+
+impl Access {
+    /// Access for clients that aren't subject to permissions: everyone when logging in is
+    /// turned off, and the master talking to a slave.
+    pub fn unrestricted() -> Self {
+        Self {
+            username: None,
+            grants: None,
+        }
+    }
+
+    /// Access given by a user's roles. Unknown role names give nothing.
+    ///
+    /// # Arguments
+    /// * `username` - The user.
+    /// * `role_names` - The roles of the user.
+    /// * `roles` - The role definitions from the configuration.
+    pub fn for_user(username: &str, role_names: &[String], roles: &BTreeMap<String, Role>) -> Self {
+        Self {
+            username: Some(username.to_owned()),
+            grants: Some(
+                role_names
+                    .iter()
+                    .filter_map(|name| roles.get(name))
+                    .flat_map(|role| role.grants.iter().cloned())
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Whether the client may do something to a server.
+    pub fn allows(&self, permission: Permission, server_name: &str) -> bool {
+        self.grants.as_ref().is_none_or(|grants| {
+            grants
+                .iter()
+                .any(|grant| grant.gives(permission) && grant.covers(server_name))
+        })
+    }
+
+    /// Whether the client may do something to every server, including ones added later.
+    pub fn allows_everywhere(&self, permission: Permission) -> bool {
+        self.grants.as_ref().is_none_or(|grants| {
+            grants
+                .iter()
+                .any(|grant| grant.gives(permission) && grant.servers.is_empty())
+        })
+    }
+
+    /// Checks a permission, describing what was refused if it isn't given.
+    pub fn require(&self, permission: Permission, server_name: &str) -> Result<(), String> {
+        if self.allows(permission, server_name) {
+            Ok(())
+        } else {
+            Err(format!(
+                "Permission denied: {} on '{}'",
+                permission.name(),
+                server_name
+            ))
+        }
+    }
+
+    /// Checks a permission on every server, describing what was refused if it isn't given.
+    pub fn require_everywhere(&self, permission: Permission) -> Result<(), String> {
+        if self.allows_everywhere(permission) {
+            Ok(())
+        } else {
+            Err(format!(
+                "Permission denied: {} on all servers",
+                permission.name()
+            ))
+        }
+    }
+
+    /// The logged-in user, or `"anonymous"` when logging in is turned off.
+    pub fn username(&self) -> &str {
+        self.username.as_deref().unwrap_or("anonymous")
+    }
+
+    /// Whether the client is restricted at all.
+    pub fn is_restricted(&self) -> bool {
+        self.grants.is_some()
+    }
+
+    /// Removes servers the client may not see from an outgoing broadcast.
+    ///
+    /// Messages about a single server (anything with a `server_name`) are dropped entirely,
+    /// server lists and configurations are trimmed down to the visible servers.
+    ///
+    /// # Arguments
+    /// * `message` - The parsed broadcast.
+    ///
+    /// # Returns
+    /// * `false` if the message should not be sent to this client at all.
+    pub fn filter_broadcast(&self, message: &mut serde_json::Value) -> bool {
+        if !self.is_restricted() {
+            return true;
+        }
+        if let Some(server_name) = message.get("server_name").and_then(|name| name.as_str()) {
+            return self.allows(Permission::ViewConsole, server_name);
+        }
+        let visible = |entry: &serde_json::Value, key: &str| {
+            entry
+                .get(key)
+                .and_then(|name| name.as_str())
+                .is_some_and(|name| self.allows(Permission::ViewConsole, name))
+        };
+        if let Some(servers) = message
+            .get_mut("servers")
+            .and_then(|servers| servers.as_array_mut())
+        {
+            servers.retain(|server| visible(server, "name"));
+        }
+        if let Some(schedules) = message
+            .get_mut("schedules")
+            .and_then(|schedules| schedules.as_array_mut())
+        {
+            schedules.retain(|schedule| visible(schedule, "server_name"));
+        }
+        if let Some(servers) = message
+            .get_mut("config")
+            .and_then(|config| config.get_mut("servers"))
+            .and_then(|servers| servers.as_array_mut())
+        {
+            servers.retain(|server| visible(server, "name"));
+        }
+        true
+    }
+}
+
+impl Permission {
+    /// The name of the permission as written in the configuration.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Permission::ViewConsole => "view-console",
+            Permission::SendStdin => "send-stdin",
+            Permission::StartStop => "start-stop",
+            Permission::EditConfig => "edit-config",
+            Permission::ManageBackups => "manage-backups",
+            Permission::ViewAuditLog => "view-audit-log",
+            Permission::All => "*",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Permission::*;
+
+    fn roles() -> BTreeMap<String, Role> {
+        let mut roles = default_roles();
+        roles.extend(
+            serde_json::from_str::<BTreeMap<String, Role>>(
+                r#"{
+                    "operator": {"grants": [
+                        {"permissions": ["start-stop", "send-stdin"], "servers": ["mc"]},
+                        {"permissions": ["view-console"], "servers": ["mc", "vs"]}
+                    ]},
+                    "auditor": {"grants": [{"permissions": ["view-audit-log"]}]}
+                }"#,
+            )
+            .unwrap(),
+        );
+        roles
+    }
+
+    fn user(role_names: &[&str]) -> Access {
+        let role_names: Vec<String> = role_names.iter().map(|name| name.to_string()).collect();
+        Access::for_user("someone", &role_names, &roles())
+    }
+
+    #[test]
+    fn allows_per_server_and_global_grants() {
+        let cases: [(&[&str], Permission, &str, bool); 18] = [
+            (&["admin"], EditConfig, "mc", true),
+            (&["admin"], ViewAuditLog, "anything", true),
+            (&["viewer"], ViewConsole, "mc", true),
+            (&["viewer"], ViewConsole, "added-later", true),
+            (&["viewer"], SendStdin, "mc", false),
+            (&["operator"], StartStop, "mc", true),
+            (&["operator"], SendStdin, "mc", true),
+            (&["operator"], StartStop, "vs", false),
+            (&["operator"], ViewConsole, "vs", true),
+            (&["operator"], ViewConsole, "other", false),
+            (&["operator"], EditConfig, "mc", false),
+            (&["operator"], StartStop, "MC", false),
+            (&["operator", "viewer"], ViewConsole, "other", true),
+            (&["operator", "viewer"], StartStop, "other", false),
+            (&["auditor"], ViewAuditLog, "mc", true),
+            (&["auditor"], ViewConsole, "mc", false),
+            (&["missing"], ViewConsole, "mc", false),
+            (&[], ViewConsole, "mc", false),
+        ];
+        for (role_names, permission, server, expected) in cases {
+            let access = user(role_names);
+            assert_eq!(
+                access.allows(permission, server),
+                expected,
+                "{:?} {} on {}",
+                role_names,
+                permission.name(),
+                server
+            );
+            assert_eq!(access.require(permission, server).is_ok(), expected);
+        }
+    }
+
+    #[test]
+    fn everywhere_needs_a_grant_without_a_server_list() {
+        let cases: [(&[&str], Permission, bool); 8] = [
+            (&["admin"], EditConfig, true),
+            (&["viewer"], ViewConsole, true),
+            (&["viewer"], EditConfig, false),
+            (&["operator"], StartStop, false),
+            (&["operator"], ViewConsole, false),
+            (&["operator", "viewer"], ViewConsole, true),
+            (&["auditor"], ViewAuditLog, true),
+            (&["missing"], ViewAuditLog, false),
+        ];
+        for (role_names, permission, expected) in cases {
+            let access = user(role_names);
+            assert_eq!(
+                access.allows_everywhere(permission),
+                expected,
+                "{:?} {}",
+                role_names,
+                permission.name()
+            );
+            assert_eq!(access.require_everywhere(permission).is_ok(), expected);
+        }
+    }
+
+    #[test]
+    fn unrestricted_access_allows_everything() {
+        let access = Access::unrestricted();
+        assert!(!access.is_restricted());
+        assert_eq!(access.username(), "anonymous");
+        for permission in [ViewConsole, SendStdin, StartStop, EditConfig, ManageBackups] {
+            assert!(access.require(permission, "mc").is_ok());
+            assert!(access.require_everywhere(permission).is_ok());
+        }
+    }
+
+    #[test]
+    fn refusals_name_the_permission_and_server() {
+        let access = user(&["operator"]);
+        assert!(access.is_restricted());
+        assert_eq!(access.username(), "someone");
+        assert_eq!(
+            access.require(EditConfig, "mc"),
+            Err("Permission denied: edit-config on 'mc'".to_string())
+        );
+        assert_eq!(
+            access.require_everywhere(StartStop),
+            Err("Permission denied: start-stop on all servers".to_string())
+        );
+    }
+}
@@ -0,0 +1,346 @@
+/// Prometheus metrics at `/metrics`.
+///
+/// Served in the text exposition format from the web server. Every server, including those on
+/// slave nodes, is described by whether it is running, its crash restarts, its uptime, its
+/// [resource usage](crate::metrics) and the numeric values its specialization reports, such
+/// as `player_count`. The controller itself adds its open websocket connections and the
+/// health of its slave links.
+///
+/// The endpoint follows the same login rules as the rest of the web server. Scrapers that
+/// can't log in can be given a token of their own in the `prometheus` settings instead.
+use axum::{
+    extract::State,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Extension,
+};
+use chrono::{DateTime, Local};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt::Write, sync::atomic::Ordering};
+
+use crate::{
+    app_state::AppState,
+    master::SlaveLinkState,
+    messages::ServerInfo,
+    metrics::ProcessMetrics,
+    permissions::{Access, Permission},
+    websocket::server_info_message,
+};
+
+/// Where the metrics are served.
+pub const METRICS_PATH: &str = "/metrics";
+
+/// Prometheus settings, stored in `Config`.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct PrometheusSettings {
+    /// Whether `/metrics` is served.
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Token scrapers can send as `Authorization: Bearer <token>` instead of logging in.
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl Default for PrometheusSettings {
+    fn default() -> Self {
+        Self {
+            enabled: default_enabled(),
+            token: None,
+        }
+    }
+}
+
+/// Whether a request token is the scrape token of the settings.
+///
+/// # Arguments
+/// * `settings` - The Prometheus settings.
+/// * `token` - The bearer token the request carries.
+pub fn token_allows(settings: &PrometheusSettings, token: &str) -> bool {
+    let Some(expected) = settings.token.as_deref().filter(|token| !token.is_empty()) else {
+        return false;
+    };
+    // Compared in full every time, so the time taken says nothing about the token
+    expected.len() == token.len()
+        && expected
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Escapes a label value for the exposition format.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Turns a specialization status field into a valid metric name part.
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect::<String>()
+        .to_ascii_lowercase()
+}
+
+/// A metric family being written: its samples as label sets and values.
+type Samples = Vec<(Vec<(&'static str, String)>, f64)>;
+
+/// A resource usage family: its name, type, help text and how to read it from the metrics.
+type UsageFamily = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&ProcessMetrics) -> f64,
+);
+
+/// Collects the metrics of a scrape and renders them as text.
+#[derive(Default)]
+struct Exposition {
+    text: String,
+}
+
+impl Exposition {
+    /// Writes one metric family with its help text and type. Empty families are left out.
+    fn family(&mut self, name: &str, kind: &str, help: &str, samples: Samples) {
+        if samples.is_empty() {
+            return;
+        }
+        let _ = writeln!(self.text, "# HELP {} {}", name, help);
+        let _ = writeln!(self.text, "# TYPE {} {}", name, kind);
+        for (labels, value) in samples {
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(key, value)| format!("{}=\"{}\"", key, escape(value)))
+                .collect();
+            let _ = writeln!(self.text, "{}{{{}}} {}", name, labels.join(","), value);
+        }
+    }
+
+    /// Writes a family without labels.
+    fn single(&mut self, name: &str, kind: &str, help: &str, value: f64) {
+        let _ = writeln!(self.text, "# HELP {} {}", name, help);
+        let _ = writeln!(self.text, "# TYPE {} {}", name, kind);
+        let _ = writeln!(self.text, "{} {}", name, value);
+    }
+}
+
+/// The labels that identify a server: its name and the slave it runs on, empty for this node.
+fn server_labels(server: &ServerInfo) -> Vec<(&'static str, String)> {
+    let host = server
+        .host
+        .as_ref()
+        .map(|host| format!("{}:{}", host.address, host.port))
+        .unwrap_or_default();
+    vec![("server", server.name.clone()), ("host", host)]
+}
+
+/// Builds one family with a value for every server that has one.
+fn per_server(servers: &[ServerInfo], value: impl Fn(&ServerInfo) -> Option<f64>) -> Samples {
+    servers
+        .iter()
+        .filter_map(|server| Some((server_labels(server), value(server)?)))
+        .collect()
+}
+
+/// Renders every metric.
+///
+/// # Arguments
+/// * `state` - The shared application state.
+/// * `access` - What the scraper may see; servers it can't view are left out.
+async fn render(state: &AppState, access: &Access) -> String {
+    let mut servers = server_info_message(state, false).await.servers;
+    servers.retain(|server| access.allows(Permission::ViewConsole, &server.name));
+    let now = Local::now();
+    let mut out = Exposition::default();
+
+    out.family(
+        "rsc_server_up",
+        "gauge",
+        "Whether the server is running.",
+        per_server(&servers, |server| Some(f64::from(u8::from(server.active)))),
+    );
+    out.family(
+        "rsc_server_crash_loop",
+        "gauge",
+        "Whether the server kept crashing and is no longer restarted.",
+        per_server(&servers, |server| {
+            Some(f64::from(u8::from(server.crash_loop)))
+        }),
+    );
+    out.family(
+        "rsc_server_recent_restarts",
+        "gauge",
+        "Crash restarts inside the server's restart policy window.",
+        per_server(&servers, |server| Some(f64::from(server.restart_count))),
+    );
+    out.family(
+        "rsc_server_uptime_seconds",
+        "gauge",
+        "Seconds since the server was started.",
+        per_server(&servers, |server| {
+            let started = DateTime::parse_from_rfc3339(server.started_at.as_deref()?).ok()?;
+            Some((now.fixed_offset() - started).num_seconds().max(0) as f64)
+        }),
+    );
+
+    let usage: [UsageFamily; 7] = [
+        (
+            "rsc_server_cpu_percent",
+            "gauge",
+            "CPU use of the server's processes, in percent of one core.",
+            |metrics| metrics.cpu_percent,
+        ),
+        (
+            "rsc_server_resident_memory_bytes",
+            "gauge",
+            "Resident memory of the server's processes.",
+            |metrics| metrics.rss_bytes as f64,
+        ),
+        (
+            "rsc_server_threads",
+            "gauge",
+            "Threads of the server's processes.",
+            |metrics| metrics.threads as f64,
+        ),
+        (
+            "rsc_server_open_fds",
+            "gauge",
+            "Open file descriptors of the server's processes.",
+            |metrics| metrics.open_fds as f64,
+        ),
+        (
+            "rsc_server_read_bytes_total",
+            "counter",
+            "Bytes the server's processes read from storage.",
+            |metrics| metrics.read_bytes as f64,
+        ),
+        (
+            "rsc_server_written_bytes_total",
+            "counter",
+            "Bytes the server's processes wrote to storage.",
+            |metrics| metrics.write_bytes as f64,
+        ),
+        (
+            "rsc_server_processes",
+            "gauge",
+            "Processes of the server: the server and everything it started.",
+            |metrics| metrics.processes as f64,
+        ),
+    ];
+    for (name, kind, help, value) in usage {
+        out.family(
+            name,
+            kind,
+            help,
+            per_server(&servers, |server| server.metrics.as_ref().map(value)),
+        );
+    }
+
+    // Numbers and flags the specializations report, such as player_count, by field name
+    let mut status: BTreeMap<String, Samples> = BTreeMap::new();
+    for server in servers.iter().filter(|server| server.active) {
+        let (Some(specialization), Some(serde_json::Value::Object(fields))) =
+            (&server.specialization, &server.specialized_info)
+        else {
+            continue;
+        };
+        for (field, value) in fields {
+            let value = match value {
+                serde_json::Value::Number(number) => number.as_f64(),
+                serde_json::Value::Bool(flag) => Some(f64::from(u8::from(*flag))),
+                _ => None,
+            };
+            if let Some(value) = value {
+                let mut labels = server_labels(server);
+                labels.push(("specialization", specialization.clone()));
+                status
+                    .entry(sanitize(field))
+                    .or_default()
+                    .push((labels, value));
+            }
+        }
+    }
+    for (field, samples) in status {
+        out.family(
+            &format!("rsc_server_status_{}", field),
+            "gauge",
+            &format!("The {} the server's specialization reports.", field),
+            samples,
+        );
+    }
+
+    out.single(
+        "rsc_websocket_clients",
+        "gauge",
+        "Open websocket connections.",
+        state.websocket_clients.load(Ordering::Relaxed) as f64,
+    );
+    let links: Vec<_> = state
+        .slave_links
+        .lock()
+        .await
+        .values()
+        .map(|link| link.status.clone())
+        .collect();
+    let mut link_states: Samples = vec![];
+    let mut latencies: Samples = vec![];
+    for status in links.iter() {
+        let slave = format!("{}:{}", status.address, status.port);
+        for (state, name) in [
+            (SlaveLinkState::Connecting, "connecting"),
+            (SlaveLinkState::Online, "online"),
+            (SlaveLinkState::Degraded, "degraded"),
+            (SlaveLinkState::Offline, "offline"),
+        ] {
+            link_states.push((
+                vec![("slave", slave.clone()), ("state", name.to_string())],
+                f64::from(u8::from(status.state == state)),
+            ));
+        }
+        if let Some(latency) = status.latency_ms {
+            latencies.push((vec![("slave", slave)], latency as f64));
+        }
+    }
+    out.family(
+        "rsc_slave_link_state",
+        "gauge",
+        "The state of the link to each slave node, 1 for the current one.",
+        link_states,
+    );
+    out.family(
+        "rsc_slave_latency_milliseconds",
+        "gauge",
+        "Round-trip time of the latest heartbeat ping to each slave node.",
+        latencies,
+    );
+    out.text
+}
+
+/// Serves the metrics.
+///
+/// # Arguments
+/// * `state` - The shared application state.
+/// * `access` - What the scraper is allowed to see.
+pub async fn serve_metrics(
+    State(state): State<AppState>,
+    Extension(access): Extension<Access>,
+) -> Response {
+    if !state.config.lock().await.prometheus.enabled {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    (
+        [(
+            header::CONTENT_TYPE,
+            "text/plain; version=0.0.4; charset=utf-8",
+        )],
+        render(&state, &access).await,
+    )
+        .into_response()
+}
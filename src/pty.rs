@@ -0,0 +1,163 @@
+/// Pseudo-terminal support for managed servers on Unix.
+///
+/// Servers spawned under a PTY see a real terminal on stdin/stdout/stderr, so they keep
+/// colour output and line editing on instead of falling back to plain pipe behaviour.
+use nix::pty::{openpty, Winsize};
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    os::fd::{AsRawFd, OwnedFd, RawFd},
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use tokio::io::{unix::AsyncFd, AsyncRead, AsyncWrite, ReadBuf};
+
+/// Both ends of a freshly opened pseudo-terminal.
+pub struct PtyPair {
+    /// Controller side, read and written by this application.
+    pub master: File,
+    /// Terminal side, handed to the child as its stdio.
+    pub slave: OwnedFd,
+}
+
+/// Opens a new pseudo-terminal with the given size.
+///
+/// The master end is marked close-on-exec so the child only inherits the slave end.
+///
+/// # Arguments
+/// * `columns` - Initial terminal width.
+/// * `rows` - Initial terminal height.
+pub fn open(columns: u16, rows: u16) -> io::Result<PtyPair> {
+    let size = Winsize {
+        ws_row: rows,
+        ws_col: columns,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    let pty = openpty(Some(&size), None).map_err(io::Error::from)?;
+    // SAFETY: fcntl on a descriptor we own, with no pointers involved.
+    if unsafe { libc::fcntl(pty.master.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(PtyPair {
+        master: File::from(pty.master),
+        slave: pty.slave,
+    })
+}
+
+/// The master side of a pseudo-terminal, registered with the runtime so that reading and
+/// writing it waits for readiness instead of holding a blocking thread.
+pub struct PtyMaster {
+    fd: AsyncFd<File>,
+}
+
+impl PtyMaster {
+    /// Switches a master descriptor to non-blocking mode and registers it with the runtime.
+    ///
+    /// Must be called from within the runtime.
+    ///
+    /// # Arguments
+    /// * `master` - The master side of the PTY, or a duplicate of it.
+    pub fn new(master: File) -> io::Result<Self> {
+        let fd = master.as_raw_fd();
+        // SAFETY: fcntl on a descriptor we own, with no pointers involved.
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags == -1 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } == -1
+        {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            fd: AsyncFd::new(master)?,
+        })
+    }
+}
+
+impl AsRawFd for PtyMaster {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl AsyncRead for PtyMaster {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            let mut guard = ready!(self.fd.poll_read_ready(cx))?;
+            let unfilled = buf.initialize_unfilled();
+            match guard.try_io(|fd| fd.get_ref().read(unfilled)) {
+                Ok(Ok(read)) => {
+                    buf.advance(read);
+                    return Poll::Ready(Ok(()));
+                }
+                Ok(Err(e)) => return Poll::Ready(Err(e)),
+                // Readiness was stale, wait for the next notification
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for PtyMaster {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let mut guard = ready!(self.fd.poll_write_ready(cx))?;
+            match guard.try_io(|fd| fd.get_ref().write(buf)) {
+                Ok(result) => return Poll::Ready(result),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Writes go straight to the terminal, there is nothing to flush
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Makes the PTY on stdin the controlling terminal of a new session.
+///
+/// Must only be called in the child between fork and exec; it only uses
+/// async-signal-safe system calls.
+pub fn attach_controlling_terminal() -> io::Result<()> {
+    // SAFETY: setsid and ioctl are async-signal-safe and only touch the calling process.
+    unsafe {
+        if libc::setsid() == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::ioctl(0, libc::TIOCSCTTY as _, 0) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Changes the size of a pseudo-terminal, which also sends SIGWINCH to its foreground process.
+///
+/// # Arguments
+/// * `master` - The master side of the PTY.
+/// * `columns` - New terminal width.
+/// * `rows` - New terminal height.
+pub fn resize(master: RawFd, columns: u16, rows: u16) -> io::Result<()> {
+    let size = libc::winsize {
+        ws_row: rows,
+        ws_col: columns,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    // SAFETY: TIOCSWINSZ only reads the winsize struct we pass in.
+    if unsafe { libc::ioctl(master, libc::TIOCSWINSZ as _, &size) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,633 @@
+/// Resource limits of managed servers.
+///
+/// The `limits` of a server cap what it can take from the host. Memory, CPU and process limits
+/// are enforced by putting the server in a cgroup of its own, created inside a cgroup v2
+/// subtree delegated to the controller, such as the one systemd gives a service with
+/// `Delegate=yes`. Where there is no such subtree they can't be applied, which is logged when
+/// such a server starts. The nice value, I/O priority and the open files and core size rlimits
+/// are set on the process itself and work either way.
+///
+/// When a server exits, the event counters of its cgroup tell whether it was killed for running
+/// out of memory or ran into one of its limits, which is reported as its [`ExitReason`].
+use once_cell::sync::OnceCell;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    fs::{self, File},
+    path::PathBuf,
+    process::ExitStatus,
+};
+
+/// Bytes in a megabyte, the unit of the memory and core size limits.
+const MB: u64 = 1024 * 1024;
+
+/// The delegated subtree the servers' cgroups are created in, `None` if cgroups can't be used.
+static CGROUP_ROOT: OnceCell<Option<PathBuf>> = OnceCell::new();
+
+/// Cgroup settings, stored in `Config`. Only read at startup.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct CgroupSettings {
+    /// Whether servers with memory, CPU or process limits are put in cgroups.
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// The cgroup v2 directory delegated to the controller, such as
+    /// `/sys/fs/cgroup/system.slice/rsc.service`. Defaults to the controller's own cgroup.
+    #[serde(default)]
+    pub path: Option<String>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl Default for CgroupSettings {
+    fn default() -> Self {
+        Self {
+            enabled: default_enabled(),
+            path: None,
+        }
+    }
+}
+
+/// I/O scheduling classes, from the kernel's `ioprio_set`.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum IoClass {
+    /// Served before everything else. Needs root.
+    Realtime,
+    /// The class every process starts in.
+    #[default]
+    BestEffort,
+    /// Only served when no other process needs the disk.
+    Idle,
+}
+
+/// The I/O priority of a server.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq)]
+pub struct IoNice {
+    /// The scheduling class.
+    #[serde(default)]
+    pub class: IoClass,
+    /// The priority within the class, from 0 (highest) to 7 (lowest). Ignored for `idle`.
+    #[serde(default = "default_io_level")]
+    pub level: u8,
+}
+
+fn default_io_level() -> u8 {
+    4
+}
+
+impl IoNice {
+    /// The value `ioprio_set` takes: the class in the top bits and the level in the low ones.
+    #[cfg(target_os = "linux")]
+    fn ioprio(&self) -> libc::c_int {
+        let (class, level) = match self.class {
+            IoClass::Realtime => (1, self.level),
+            IoClass::BestEffort => (2, self.level),
+            IoClass::Idle => (3, 0),
+        };
+        (class << 13) | libc::c_int::from(level.min(7))
+    }
+}
+
+/// Limits on what a server may take from the host, all unset by default.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct ResourceLimits {
+    /// Memory the server may use, in megabytes. A server that can't be brought back under it
+    /// is killed by the kernel.
+    pub memory_max_mb: Option<u64>,
+    /// CPU time the server may use, in percent of one core, so 200 allows two full cores.
+    pub cpu_quota_percent: Option<u32>,
+    /// Share of the CPU while it is contended, from 1 to 10000, relative to other cgroups,
+    /// which have 100.
+    pub cpu_weight: Option<u32>,
+    /// Processes and threads the server may have at once.
+    pub max_pids: Option<u64>,
+    /// Scheduling priority, from -20 (highest) to 19 (lowest). Raising it needs root.
+    pub nice: Option<i32>,
+    /// I/O priority (Linux only).
+    pub ionice: Option<IoNice>,
+    /// Open files each process of the server may have.
+    pub open_files: Option<u64>,
+    /// Largest core dump, in megabytes. 0 turns core dumps off.
+    pub core_size_mb: Option<u64>,
+}
+
+impl ResourceLimits {
+    /// Whether any limit needs a cgroup to be enforced.
+    fn needs_cgroup(&self) -> bool {
+        self.memory_max_mb.is_some()
+            || self.cpu_quota_percent.is_some()
+            || self.cpu_weight.is_some()
+            || self.max_pids.is_some()
+    }
+}
+
+/// Event counters of a server's cgroup, compared before and after it ran.
+#[derive(Default, Clone, Copy)]
+struct LimitEvents {
+    /// Processes killed by the OOM killer (`oom_kill` in `memory.events`). Reaching
+    /// `memory.max` alone isn't counted, as that also happens when the kernel just reclaims
+    /// page cache.
+    oom_kills: u64,
+    /// Times a fork failed because of `pids.max` (`max` in `pids.events`).
+    pids_max: u64,
+}
+
+#[cfg(target_os = "linux")]
+mod cgroup {
+    use std::{
+        fs, io,
+        path::{Path, PathBuf},
+    };
+
+    use super::{LimitEvents, ResourceLimits, MB};
+
+    /// Where the cgroup v2 hierarchy is mounted.
+    const MOUNT: &str = "/sys/fs/cgroup";
+
+    /// The controllers the limits use.
+    const CONTROLLERS: [&str; 3] = ["memory", "cpu", "pids"];
+
+    /// The cgroup this process is in, from `/proc/self/cgroup`.
+    fn own_cgroup() -> Option<PathBuf> {
+        let text = fs::read_to_string("/proc/self/cgroup").ok()?;
+        let path = text.lines().find_map(|line| line.strip_prefix("0::"))?;
+        match path.trim_start_matches('/') {
+            "" => Some(PathBuf::from(MOUNT)),
+            path => Some(Path::new(MOUNT).join(path)),
+        }
+    }
+
+    /// Hands the controllers down to the cgroups created in `root`.
+    fn enable(root: &Path, controllers: &[&str]) -> io::Result<()> {
+        let line: Vec<String> = controllers
+            .iter()
+            .map(|controller| format!("+{}", controller))
+            .collect();
+        fs::write(root.join("cgroup.subtree_control"), line.join(" "))
+    }
+
+    /// Prepares the delegated subtree for the servers' cgroups.
+    ///
+    /// # Arguments
+    /// * `path` - The delegated directory, or `None` for this process's own cgroup.
+    ///
+    /// # Returns
+    /// * The directory the servers' cgroups are created in.
+    /// * `Err` saying why cgroups can't be used.
+    pub fn setup(path: Option<&str>) -> Result<PathBuf, String> {
+        let root = match path {
+            Some(path) => PathBuf::from(path),
+            None => own_cgroup().ok_or("this system has no cgroup v2 hierarchy")?,
+        };
+        let available = fs::read_to_string(root.join("cgroup.controllers"))
+            .map_err(|_| format!("{} is not a cgroup v2 directory", root.display()))?;
+        let controllers: Vec<&str> = CONTROLLERS
+            .into_iter()
+            .filter(|controller| available.split_whitespace().any(|name| name == *controller))
+            .collect();
+        if controllers.is_empty() {
+            return Err(format!(
+                "none of the memory, cpu and pids controllers are delegated to {}",
+                root.display()
+            ));
+        }
+        if enable(&root, &controllers).is_err() {
+            // Controllers are only handed down by cgroups without processes of their own,
+            // so the controller moves itself into a leaf first
+            let leaf = root.join("controller");
+            if !leaf.is_dir() {
+                fs::create_dir(&leaf)
+                    .map_err(|e| format!("could not create {}: {}", leaf.display(), e))?;
+            }
+            fs::write(leaf.join("cgroup.procs"), std::process::id().to_string())
+                .map_err(|e| format!("could not move into {}: {}", leaf.display(), e))?;
+            enable(&root, &controllers).map_err(|e| {
+                format!(
+                    "could not enable the {} controllers in {}: {}",
+                    controllers.join(", "),
+                    root.display(),
+                    e
+                )
+            })?;
+        }
+        Ok(root)
+    }
+
+    /// Creates the cgroup of a server, or reuses it from an earlier run, and writes its limits.
+    ///
+    /// Limits that aren't set are written as their unlimited values, so none are left over
+    /// from the earlier run.
+    pub fn prepare(root: &Path, server_name: &str, limits: &ResourceLimits) -> io::Result<PathBuf> {
+        // Names that only differ in replaced characters, like "a b" and "a_b", are told apart
+        // by a hash of the real name
+        let hash = crate::files::name_hash(server_name);
+        let name: String = server_name
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        let cgroup = root.join(format!("server-{}-{}", name, hash));
+        if !cgroup.is_dir() {
+            fs::create_dir(&cgroup)?;
+        }
+        let settings = [
+            (
+                "memory.max",
+                limits.memory_max_mb.is_some(),
+                limits
+                    .memory_max_mb
+                    .map_or("max".to_string(), |mb| (mb * MB).to_string()),
+            ),
+            (
+                "cpu.max",
+                limits.cpu_quota_percent.is_some(),
+                limits
+                    .cpu_quota_percent
+                    .map_or("max 100000".to_string(), |percent| {
+                        format!("{} 100000", u64::from(percent) * 1000)
+                    }),
+            ),
+            (
+                "cpu.weight",
+                limits.cpu_weight.is_some(),
+                limits.cpu_weight.unwrap_or(100).to_string(),
+            ),
+            (
+                "pids.max",
+                limits.max_pids.is_some(),
+                limits
+                    .max_pids
+                    .map_or("max".to_string(), |pids| pids.to_string()),
+            ),
+        ];
+        for (file, set, value) in settings {
+            match fs::write(cgroup.join(file), value) {
+                Err(e) if set => {
+                    return Err(io::Error::new(
+                        e.kind(),
+                        format!("could not write {}: {}", file, e),
+                    ))
+                }
+                _ => {}
+            }
+        }
+        Ok(cgroup)
+    }
+
+    /// Reads one counter of an events file such as `memory.events`.
+    fn event_count(cgroup: &Path, file: &str, key: &str) -> u64 {
+        fs::read_to_string(cgroup.join(file))
+            .ok()
+            .and_then(|text| {
+                text.lines().find_map(|line| {
+                    let (name, value) = line.split_once(' ')?;
+                    (name == key).then(|| value.trim().parse().ok())?
+                })
+            })
+            .unwrap_or(0)
+    }
+
+    /// Reads the event counters of a server's cgroup.
+    pub fn events(cgroup: &Path) -> LimitEvents {
+        LimitEvents {
+            oom_kills: event_count(cgroup, "memory.events", "oom_kill"),
+            pids_max: event_count(cgroup, "pids.events", "max"),
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod cgroup {
+    use std::{
+        io,
+        path::{Path, PathBuf},
+    };
+
+    use super::{LimitEvents, ResourceLimits};
+
+    pub fn setup(_path: Option<&str>) -> Result<PathBuf, String> {
+        Err("cgroups are only available on Linux".to_string())
+    }
+
+    pub fn prepare(
+        _root: &Path,
+        _server_name: &str,
+        _limits: &ResourceLimits,
+    ) -> io::Result<PathBuf> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    pub fn events(_cgroup: &Path) -> LimitEvents {
+        LimitEvents::default()
+    }
+}
+
+/// Prepares the cgroup subtree the servers' cgroups are created in. Called once at startup.
+///
+/// # Arguments
+/// * `settings` - The cgroup settings from the configuration.
+pub fn init_cgroups(settings: &CgroupSettings) {
+    let root = if settings.enabled {
+        match cgroup::setup(settings.path.as_deref()) {
+            Ok(root) => {
+                tracing::info!("Servers with limits get cgroups in {}", root.display());
+                Some(root)
+            }
+            Err(e) => {
+                tracing::info!(
+                    "Cgroups can't be used ({}), memory, CPU and process limits aren't applied",
+                    e
+                );
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let _ = CGROUP_ROOT.set(root);
+}
+
+/// How a server process is confined, kept for as long as it runs.
+pub struct Confinement {
+    /// The limits the server was started with.
+    limits: ResourceLimits,
+    /// The server's cgroup, if it has one.
+    cgroup: Option<PathBuf>,
+    /// The cgroup's `cgroup.procs`, open for the new process to move itself into.
+    procs: Option<File>,
+    /// The cgroup's event counters before the server started.
+    baseline: LimitEvents,
+    /// Whether the controller asked the server to stop, so its exit isn't put down to a limit.
+    stop_requested: bool,
+}
+
+impl Confinement {
+    /// Prepares the confinement of a server that is about to start.
+    ///
+    /// # Arguments
+    /// * `server_name` - The server being started.
+    /// * `limits` - Its limits.
+    pub fn new(server_name: &str, limits: &ResourceLimits) -> Self {
+        let mut confinement = Self {
+            limits: limits.clone(),
+            cgroup: None,
+            procs: None,
+            baseline: LimitEvents::default(),
+            stop_requested: false,
+        };
+        if !limits.needs_cgroup() {
+            return confinement;
+        }
+        let root = CGROUP_ROOT.get().cloned().flatten();
+        let prepared = root
+            .as_deref()
+            .map(|root| cgroup::prepare(root, server_name, limits));
+        match prepared {
+            Some(Ok(cgroup)) => {
+                match File::options()
+                    .write(true)
+                    .open(cgroup.join("cgroup.procs"))
+                {
+                    Ok(procs) => {
+                        confinement.baseline = cgroup::events(&cgroup);
+                        confinement.procs = Some(procs);
+                        confinement.cgroup = Some(cgroup);
+                    }
+                    Err(e) => tracing::error!(
+                        "Could not open the cgroup of server '{}': {}",
+                        server_name,
+                        e
+                    ),
+                }
+            }
+            Some(Err(e)) => tracing::error!(
+                "Could not set up the cgroup of server '{}': {}",
+                server_name,
+                e
+            ),
+            None => {}
+        }
+        // An address space limit would stand in badly for memory_max_mb, as programs like Java
+        // reserve far more than they use and wouldn't start
+        if confinement.cgroup.is_none() {
+            tracing::warn!(
+                "Server '{}' runs without its memory, CPU and process limits, they need cgroups",
+                server_name
+            );
+        }
+        confinement
+    }
+
+    /// Makes a command apply the limits in the new process before it runs the server.
+    ///
+    /// Limits the process isn't allowed to set, such as a negative nice value without root,
+    /// are skipped rather than keeping the server from starting.
+    #[cfg(unix)]
+    pub fn apply(&self, process: &mut tokio::process::Command) {
+        use std::os::fd::AsRawFd;
+
+        let procs = self.procs.as_ref().map(|procs| procs.as_raw_fd());
+        let mut rlimits = vec![];
+        if let Some(files) = self.limits.open_files {
+            rlimits.push((libc::RLIMIT_NOFILE, files));
+        }
+        if let Some(mb) = self.limits.core_size_mb {
+            rlimits.push((libc::RLIMIT_CORE, mb * MB));
+        }
+        let nice = self.limits.nice;
+        #[cfg(target_os = "linux")]
+        let ioprio = self.limits.ionice.map(|ionice| ionice.ioprio());
+        if procs.is_none() && rlimits.is_empty() && nice.is_none() && self.limits.ionice.is_none() {
+            return;
+        }
+        // SAFETY: the hook only makes async-signal-safe system calls, on a descriptor that is
+        // open until the process has been spawned.
+        unsafe {
+            process.pre_exec(move || {
+                if let Some(procs) = procs {
+                    // Writing 0 moves the writing process
+                    libc::write(procs, b"0".as_ptr().cast(), 1);
+                }
+                for (resource, value) in rlimits.iter() {
+                    let value = *value as libc::rlim_t;
+                    let limit = libc::rlimit {
+                        rlim_cur: value,
+                        rlim_max: value,
+                    };
+                    // Without the right to raise the hard limit, go as far as it allows
+                    if libc::setrlimit(*resource, &limit) != 0 {
+                        let mut current = libc::rlimit {
+                            rlim_cur: 0,
+                            rlim_max: 0,
+                        };
+                        if libc::getrlimit(*resource, &mut current) == 0 {
+                            current.rlim_cur = value.min(current.rlim_max);
+                            libc::setrlimit(*resource, &current);
+                        }
+                    }
+                }
+                if let Some(nice) = nice {
+                    libc::setpriority(libc::PRIO_PROCESS, 0, nice);
+                }
+                #[cfg(target_os = "linux")]
+                if let Some(ioprio) = ioprio {
+                    // Who 1 is IOPRIO_WHO_PROCESS, and process 0 the calling one
+                    libc::syscall(libc::SYS_ioprio_set, 1, 0, ioprio);
+                }
+                Ok(())
+            });
+        }
+    }
+
+    /// Checks that a spawned server ended up in its cgroup.
+    ///
+    /// # Arguments
+    /// * `server_name` - The server that was started.
+    /// * `pid` - Its process ID.
+    pub fn started(&mut self, server_name: &str, pid: Option<u32>) {
+        // The new process has moved itself by now, so the file is no longer needed
+        self.procs = None;
+        let (Some(cgroup), Some(pid)) = (self.cgroup.as_ref(), pid) else {
+            return;
+        };
+        let joined = fs::read_to_string(cgroup.join("cgroup.procs"))
+            .is_ok_and(|procs| procs.lines().any(|line| line.trim() == pid.to_string()));
+        if !joined {
+            tracing::error!(
+                "Server '{}' could not be moved into {}, its memory, CPU and process limits don't apply",
+                server_name,
+                cgroup.display()
+            );
+        }
+    }
+
+    /// Records that the controller is stopping the server.
+    pub fn stop_requested(&mut self) {
+        self.stop_requested = true;
+    }
+
+    /// Works out why a server's process ended.
+    ///
+    /// # Arguments
+    /// * `status` - How the process ended.
+    pub fn exit_reason(&self, status: &ExitStatus) -> ExitReason {
+        let code = status.code();
+        #[cfg(unix)]
+        let signal = std::os::unix::process::ExitStatusExt::signal(status);
+        #[cfg(not(unix))]
+        let signal: Option<i32> = None;
+        let ended = match (code, signal) {
+            (_, Some(signal)) => ExitReason::Killed { signal },
+            (code, None) => ExitReason::Exited {
+                code: code.unwrap_or(-1),
+            },
+        };
+        let Some(cgroup) = self.cgroup.as_deref() else {
+            return ended;
+        };
+        if status.success() || self.stop_requested {
+            return ended;
+        }
+        let events = cgroup::events(cgroup);
+        let limit = |limit: &'static str, value: Option<u64>| ExitReason::LimitReached {
+            limit,
+            value: value.unwrap_or_default(),
+            ended: Box::new(ended.clone()),
+        };
+        // SIGKILL is what the OOM killer sends
+        if events.oom_kills > self.baseline.oom_kills && signal == Some(9) {
+            ExitReason::OutOfMemory {
+                memory_max_mb: self.limits.memory_max_mb,
+            }
+        } else if events.oom_kills > self.baseline.oom_kills {
+            // One of the server's other processes was killed for it
+            limit("memory_max_mb", self.limits.memory_max_mb)
+        } else if events.pids_max > self.baseline.pids_max {
+            limit("max_pids", self.limits.max_pids)
+        } else {
+            ended
+        }
+    }
+}
+
+impl Drop for Confinement {
+    /// Removes the server's cgroup. This fails harmlessly if processes the server started are
+    /// still in it, and the cgroup is then reused by the next run.
+    fn drop(&mut self) {
+        if let Some(cgroup) = self.cgroup.as_deref() {
+            let _ = fs::remove_dir(cgroup);
+        }
+    }
+}
+
+/// Why a server's process ended.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ExitReason {
+    /// It exited by itself.
+    Exited {
+        /// The exit code.
+        code: i32,
+    },
+    /// It was killed by a signal.
+    Killed {
+        /// The signal number.
+        signal: i32,
+    },
+    /// The kernel killed it because it couldn't be kept under its memory limit.
+    OutOfMemory {
+        /// The limit it had.
+        memory_max_mb: Option<u64>,
+    },
+    /// It ended after one of its limits refused it something.
+    LimitReached {
+        /// The limit, by its setting name.
+        limit: &'static str,
+        /// The value of the limit.
+        value: u64,
+        /// How it ended.
+        ended: Box<ExitReason>,
+    },
+}
+
+impl fmt::Display for ExitReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExitReason::Exited { code } => write!(f, "Server exited with code {}", code),
+            ExitReason::Killed { signal } => {
+                #[cfg(unix)]
+                if let Ok(name) = nix::sys::signal::Signal::try_from(*signal) {
+                    return write!(f, "Server was killed by signal {} ({})", signal, name);
+                }
+                write!(f, "Server was killed by signal {}", signal)
+            }
+            ExitReason::OutOfMemory {
+                memory_max_mb: Some(mb),
+            } => write!(
+                f,
+                "Server was killed for running out of memory (memory_max_mb is {})",
+                mb
+            ),
+            ExitReason::OutOfMemory {
+                memory_max_mb: None,
+            } => write!(f, "Server was killed for running out of memory"),
+            ExitReason::LimitReached {
+                limit,
+                value,
+                ended,
+            } => write!(
+                f,
+                "{} after reaching its {} limit of {}",
+                ended, limit, value
+            ),
+        }
+    }
+}
@@ -0,0 +1,602 @@
+/// HTTP JSON API for scripting the controller.
+///
+/// Mounted under `/api` next to the websocket. Every route goes through the same helpers the
+/// websocket handlers use, and failures are returned as an HTTP status with a
+/// `{"error": "..."}` body.
+use axum::{
+    extract::{rejection::JsonRejection, ConnectInfo, Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use chrono::DateTime;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use tracing::*;
+
+use crate::{
+    app_state::AppState,
+    audit::{self, AuditEntry, AuditQuery},
+    auth, config_edit,
+    config_validation::describe_errors,
+    configuration::{self, Config},
+    console_history::{self, HistoryLine, HistoryStream, MAX_PAGE_LINES},
+    controlled_program::ControlledProgramDescriptor,
+    messages::{ServerAction, ServerInfo, SlaveStatus},
+    permissions::{Access, Permission},
+    websocket::{self, server_info_message},
+};
+
+/// Number of output lines returned when a request does not set `lines`.
+const DEFAULT_OUTPUT_LINES: usize = 200;
+
+/// An error returned by an API route.
+pub struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, message)
+    }
+
+    fn forbidden(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::FORBIDDEN, message)
+    }
+
+    fn not_found(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, message)
+    }
+
+    fn conflict(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::CONFLICT, message)
+    }
+
+    fn internal(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, message)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (
+            self.status,
+            Json(serde_json::json!({ "error": self.message })),
+        )
+            .into_response()
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        Self::new(rejection.status(), rejection.body_text())
+    }
+}
+
+/// Body of `POST /api/servers/{name}/stdin`.
+#[derive(Deserialize)]
+struct StdinRequest {
+    /// The console command to send, without a line ending.
+    command: String,
+}
+
+/// Reply of `POST /api/servers/{name}/stop`.
+#[derive(Serialize)]
+struct StopResponse {
+    /// The exit code of the server, if the platform reported one.
+    exit_code: Option<i32>,
+}
+
+/// Query of `GET /api/servers/{name}/output`.
+#[derive(Deserialize)]
+struct OutputQuery {
+    /// How many of the newest lines to return.
+    lines: Option<usize>,
+    /// Timestamp cursor from a previous page; only lines older than it are returned.
+    before: Option<String>,
+    /// Case-insensitive text the lines must contain.
+    search: Option<String>,
+}
+
+/// A line of `GET /api/servers/{name}/output`.
+#[derive(Serialize)]
+struct OutputLine {
+    /// RFC 3339 timestamp of the line, if it was read from the console history.
+    timestamp: Option<String>,
+    /// Which stream the line came from.
+    stream: HistoryStream,
+    /// The line, as raw text from the console history or as the HTML the web console shows.
+    text: String,
+}
+
+impl From<HistoryLine> for OutputLine {
+    fn from(line: HistoryLine) -> Self {
+        OutputLine {
+            timestamp: Some(line.timestamp),
+            stream: line.stream,
+            text: line.text,
+        }
+    }
+}
+
+/// Reply of `GET /api/servers/{name}/output`.
+#[derive(Serialize)]
+struct OutputResponse {
+    /// The lines, oldest first.
+    lines: Vec<OutputLine>,
+    /// Whether older lines exist beyond these.
+    has_more: bool,
+    /// The `before` cursor of the next, older page, if there is one.
+    next_before: Option<String>,
+}
+
+/// Reply of `GET /api/audit`.
+#[derive(Serialize)]
+struct AuditResponse {
+    /// The entries, newest first.
+    entries: Vec<AuditEntry>,
+    /// Whether older entries exist beyond these.
+    has_more: bool,
+    /// The `before` cursor of the next, older page, if there is one.
+    next_before: Option<u64>,
+}
+
+/// Builds the router for the API, to be nested under `/api`.
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/login", post(auth::login))
+        .route("/logout", post(auth::logout))
+        .route("/servers", get(list_servers).post(add_server))
+        .route(
+            "/servers/{name}",
+            get(get_server).put(update_server).delete(remove_server),
+        )
+        .route("/servers/{name}/start", post(start_server))
+        .route("/servers/{name}/stop", post(stop_server))
+        .route("/servers/{name}/restart", post(restart_server))
+        .route("/servers/{name}/stdin", post(send_stdin))
+        .route("/servers/{name}/output", get(get_output))
+        .route(
+            "/config",
+            get(get_config).put(put_config).patch(patch_config),
+        )
+        .route("/config/schema", get(get_config_schema))
+        .route("/audit", get(get_audit_log))
+        .route("/slaves", get(list_slaves))
+}
+
+/// Fails with 403 unless the client may do something to a server.
+fn ensure_allowed(access: &Access, permission: Permission, name: &str) -> Result<(), ApiError> {
+    access
+        .require(permission, name)
+        .map_err(ApiError::forbidden)
+}
+
+/// Fails with 404 unless a server of that name is configured.
+async fn ensure_configured(state: &AppState, name: &str) -> Result<(), ApiError> {
+    if state
+        .config
+        .lock()
+        .await
+        .servers
+        .iter()
+        .any(|desc| desc.name == name)
+    {
+        Ok(())
+    } else {
+        Err(ApiError::not_found(format!(
+            "Server '{}' is not configured",
+            name
+        )))
+    }
+}
+
+/// Fails with 404 unless a server of that name is configured here or runs on a slave.
+async fn ensure_known(state: &AppState, name: &str) -> Result<(), ApiError> {
+    if ensure_configured(state, name).await.is_ok()
+        || state
+            .slave_servers
+            .lock()
+            .await
+            .iter()
+            .any(|server| server.name == name)
+    {
+        Ok(())
+    } else {
+        Err(ApiError::not_found(format!(
+            "Server '{}' is not known",
+            name
+        )))
+    }
+}
+
+/// Carries out a server action the same way the websocket does, forwarding it to the slave the
+/// server runs on if it isn't one of this node's. The action is recorded in the audit log.
+///
+/// # Returns
+/// * The server's exit code for "stop", or 409 with the reason the action failed.
+async fn run_action(
+    state: &AppState,
+    access: &Access,
+    address: SocketAddr,
+    name: String,
+    action: &str,
+    value: Option<String>,
+) -> Result<Option<i32>, ApiError> {
+    let action = ServerAction {
+        r#type: "serverAction".to_owned(),
+        request_id: None,
+        server_name: name,
+        action: action.to_owned(),
+        value,
+    };
+    let result = websocket::run_server_action(state, access, address, action).await;
+    if result.success {
+        Ok(result.exit_code)
+    } else {
+        Err(ApiError::conflict(result.error.unwrap_or_default()))
+    }
+}
+
+/// Lists every configured server the client may see and whether it is running.
+async fn list_servers(
+    State(state): State<AppState>,
+    Extension(access): Extension<Access>,
+) -> Json<Vec<ServerInfo>> {
+    let mut servers = server_info_message(&state, false).await.servers;
+    servers.retain(|server| access.allows(Permission::ViewConsole, &server.name));
+    Json(servers)
+}
+
+/// Shows one server and whether it is running.
+async fn get_server(
+    State(state): State<AppState>,
+    Extension(access): Extension<Access>,
+    Path(name): Path<String>,
+) -> Result<Json<ServerInfo>, ApiError> {
+    ensure_allowed(&access, Permission::ViewConsole, &name)?;
+    server_info_message(&state, false)
+        .await
+        .servers
+        .into_iter()
+        .find(|server| server.name == name)
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found(format!("Server '{}' is not configured", name)))
+}
+
+/// Starts a server that isn't running.
+async fn start_server(
+    State(state): State<AppState>,
+    Extension(access): Extension<Access>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    ensure_allowed(&access, Permission::StartStop, &name)?;
+    ensure_known(&state, &name).await?;
+    run_action(&state, &access, address, name, "start", None).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Gracefully stops a running server and waits for it to exit.
+async fn stop_server(
+    State(state): State<AppState>,
+    Extension(access): Extension<Access>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Path(name): Path<String>,
+) -> Result<Json<StopResponse>, ApiError> {
+    ensure_allowed(&access, Permission::StartStop, &name)?;
+    ensure_known(&state, &name).await?;
+    let exit_code = run_action(&state, &access, address, name, "stop", None).await?;
+    Ok(Json(StopResponse { exit_code }))
+}
+
+/// Stops a server if it is running, then starts it again.
+async fn restart_server(
+    State(state): State<AppState>,
+    Extension(access): Extension<Access>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    ensure_allowed(&access, Permission::StartStop, &name)?;
+    ensure_known(&state, &name).await?;
+    run_action(&state, &access, address, name, "restart", None).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Sends a console command to a running server.
+async fn send_stdin(
+    State(state): State<AppState>,
+    Extension(access): Extension<Access>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Path(name): Path<String>,
+    body: Result<Json<StdinRequest>, JsonRejection>,
+) -> Result<StatusCode, ApiError> {
+    let Json(request) = body?;
+    ensure_allowed(&access, Permission::SendStdin, &name)?;
+    ensure_known(&state, &name).await?;
+    run_action(
+        &state,
+        &access,
+        address,
+        name,
+        "stdin",
+        Some(request.command),
+    )
+    .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Returns a server's recent console output from its console history, or from the output
+/// kept in memory for the web console when console history is disabled.
+async fn get_output(
+    State(state): State<AppState>,
+    Extension(access): Extension<Access>,
+    Path(name): Path<String>,
+    Query(query): Query<OutputQuery>,
+) -> Result<Json<OutputResponse>, ApiError> {
+    ensure_allowed(&access, Permission::ViewConsole, &name)?;
+    ensure_configured(&state, &name).await?;
+    let settings = state.config.lock().await.console_history.clone();
+    let limit = query.lines.unwrap_or(DEFAULT_OUTPUT_LINES);
+    if !settings.enabled {
+        if query.before.is_some() {
+            return Err(ApiError::bad_request(
+                "Console history is disabled, there are no older pages",
+            ));
+        }
+        return Ok(Json(
+            memory_output(&state, &name, limit, query.search.as_deref()).await,
+        ));
+    }
+    let before = query
+        .before
+        .as_deref()
+        .map(DateTime::parse_from_rfc3339)
+        .transpose()
+        .map_err(|e| ApiError::bad_request(format!("Invalid before cursor: {}", e)))?;
+    let page = tokio::task::spawn_blocking(move || {
+        console_history::read_page(&settings, &name, before, limit, query.search.as_deref())
+    })
+    .await
+    .map_err(|e| ApiError::internal(e.to_string()))?
+    .map_err(|e| {
+        warn!("Could not read console history: {}", e);
+        ApiError::internal(e.to_string())
+    })?;
+    Ok(Json(OutputResponse {
+        lines: page.lines.into_iter().map(OutputLine::from).collect(),
+        has_more: page.has_more,
+        next_before: page.next_before,
+    }))
+}
+
+/// Builds an output reply from the recent output the web console shows for a running server.
+///
+/// # Arguments
+/// * `state` - The shared application state.
+/// * `name` - The name of the server.
+/// * `limit` - How many of the newest lines to return.
+/// * `search` - Case-insensitive text the lines must contain.
+///
+/// # Returns
+/// The matching lines, oldest first, or no lines if the server is not running.
+async fn memory_output(
+    state: &AppState,
+    name: &str,
+    limit: usize,
+    search: Option<&str>,
+) -> OutputResponse {
+    let output = state
+        .servers
+        .lock()
+        .await
+        .iter()
+        .find(|server| server.name == name)
+        .map(|server| server.curr_output_in_progress.clone())
+        .unwrap_or_default();
+    let search = search.map(str::to_lowercase);
+    let mut lines: Vec<OutputLine> = output
+        .lines()
+        .filter(|line| !line.is_empty())
+        .filter(|line| {
+            search
+                .as_ref()
+                .is_none_or(|search| line.to_lowercase().contains(search))
+        })
+        .map(|line| OutputLine {
+            timestamp: None,
+            stream: if line.starts_with("<span class=\"STDErrLine\">") {
+                HistoryStream::Err
+            } else {
+                HistoryStream::Out
+            },
+            text: line.to_string(),
+        })
+        .collect();
+    let keep = limit.min(MAX_PAGE_LINES);
+    if lines.len() > keep {
+        lines.drain(..lines.len() - keep);
+    }
+    OutputResponse {
+        lines,
+        has_more: false,
+        next_before: None,
+    }
+}
+
+/// Returns the current configuration, with secrets redacted.
+async fn get_config(
+    State(state): State<AppState>,
+    Extension(access): Extension<Access>,
+) -> Result<Json<Config>, ApiError> {
+    access
+        .require_everywhere(Permission::EditConfig)
+        .map_err(ApiError::forbidden)?;
+    Ok(Json(state.config.lock().await.redacted()))
+}
+
+/// Returns the JSON Schema of the configuration, which any logged-in client may read.
+async fn get_config_schema() -> Json<serde_json::Value> {
+    Json(configuration::schema())
+}
+
+/// Replaces the configuration, restarting only the servers whose configuration changed.
+async fn put_config(
+    State(state): State<AppState>,
+    Extension(access): Extension<Access>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    body: Result<Json<Config>, JsonRejection>,
+) -> Result<Json<Config>, ApiError> {
+    let Json(config) = body?;
+    access
+        .require_everywhere(Permission::EditConfig)
+        .map_err(ApiError::forbidden)?;
+    let server_names: Vec<&str> = config
+        .servers
+        .iter()
+        .map(|desc| desc.name.as_str())
+        .collect();
+    audit::record(
+        &state,
+        AuditEntry::new(&access, address, "config-change")
+            .summary(format!("servers: {}", server_names.join(", "))),
+    )
+    .await;
+    config_edit::apply_config_change(&state, config)
+        .await
+        .map_err(|diagnostics| ApiError::bad_request(describe_errors(&diagnostics)))?;
+    Ok(Json(state.config.lock().await.redacted()))
+}
+
+/// Changes top level settings of the configuration without touching the servers.
+async fn patch_config(
+    State(state): State<AppState>,
+    Extension(access): Extension<Access>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    body: Result<Json<serde_json::Map<String, serde_json::Value>>, JsonRejection>,
+) -> Result<Json<Config>, ApiError> {
+    let Json(settings) = body?;
+    access
+        .require_everywhere(Permission::EditConfig)
+        .map_err(ApiError::forbidden)?;
+    let names: Vec<&str> = settings.keys().map(String::as_str).collect();
+    audit::record(
+        &state,
+        AuditEntry::new(&access, address, "global-settings").summary(names.join(", ")),
+    )
+    .await;
+    config_edit::update_global_settings(&state, settings)
+        .await
+        .map_err(|diagnostics| ApiError::bad_request(describe_errors(&diagnostics)))?;
+    Ok(Json(state.config.lock().await.redacted()))
+}
+
+/// Adds a server to the configuration, starting it if it is marked for auto-start.
+async fn add_server(
+    State(state): State<AppState>,
+    Extension(access): Extension<Access>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    body: Result<Json<ControlledProgramDescriptor>, JsonRejection>,
+) -> Result<StatusCode, ApiError> {
+    let Json(server) = body?;
+    ensure_allowed(&access, Permission::EditConfig, &server.name)?;
+    if ensure_configured(&state, &server.name).await.is_ok() {
+        return Err(ApiError::conflict(format!(
+            "Server '{}' already exists",
+            server.name
+        )));
+    }
+    audit::record(
+        &state,
+        AuditEntry::new(&access, address, "add-server").on(&server.name),
+    )
+    .await;
+    config_edit::add_server(&state, server)
+        .await
+        .map_err(|diagnostics| ApiError::bad_request(describe_errors(&diagnostics)))?;
+    Ok(StatusCode::CREATED)
+}
+
+/// Replaces the configuration of a server, restarting it only if its launch settings changed.
+async fn update_server(
+    State(state): State<AppState>,
+    Extension(access): Extension<Access>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Path(name): Path<String>,
+    body: Result<Json<ControlledProgramDescriptor>, JsonRejection>,
+) -> Result<StatusCode, ApiError> {
+    let Json(server) = body?;
+    ensure_allowed(&access, Permission::EditConfig, &name)?;
+    ensure_allowed(&access, Permission::EditConfig, &server.name)?;
+    ensure_configured(&state, &name).await?;
+    let mut entry = AuditEntry::new(&access, address, "update-server").on(&name);
+    if server.name != name {
+        entry = entry.summary(format!("renamed to {}", server.name));
+    }
+    audit::record(&state, entry).await;
+    config_edit::update_server(&state, &name, server)
+        .await
+        .map_err(|diagnostics| ApiError::bad_request(describe_errors(&diagnostics)))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Removes a server from the configuration, stopping it if it is running.
+async fn remove_server(
+    State(state): State<AppState>,
+    Extension(access): Extension<Access>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    ensure_allowed(&access, Permission::EditConfig, &name)?;
+    ensure_configured(&state, &name).await?;
+    audit::record(
+        &state,
+        AuditEntry::new(&access, address, "remove-server").on(&name),
+    )
+    .await;
+    config_edit::remove_server(&state, &name)
+        .await
+        .map_err(|diagnostics| ApiError::bad_request(describe_errors(&diagnostics)))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Returns a page of the audit log, newest entry first.
+async fn get_audit_log(
+    State(state): State<AppState>,
+    Extension(access): Extension<Access>,
+    Query(query): Query<AuditQuery>,
+) -> Result<Json<AuditResponse>, ApiError> {
+    access
+        .require_everywhere(Permission::ViewAuditLog)
+        .map_err(ApiError::forbidden)?;
+    let page = audit::query(&state, query)
+        .await
+        .map_err(ApiError::internal)?;
+    Ok(Json(AuditResponse {
+        entries: page.entries,
+        has_more: page.has_more,
+        next_before: page.next_before,
+    }))
+}
+
+/// Lists the health of the link to every slave node, as last broadcast in `SlaveStatus`.
+async fn list_slaves(State(state): State<AppState>) -> Json<Vec<SlaveStatus>> {
+    let mut slaves: Vec<SlaveStatus> = state
+        .slave_links
+        .lock()
+        .await
+        .values()
+        .map(|link| link.status.clone())
+        .collect();
+    slaves.sort_by(|a, b| (&a.address, &a.port).cmp(&(&b.address, &b.port)));
+    Json(slaves)
+}
@@ -0,0 +1,337 @@
+/// Crash-loop detection and restart backoff for managed servers.
+///
+/// Each crash is recorded against the server's [`RestartPolicy`]. Restarts are delayed with an
+/// exponential backoff based on how many happened inside the policy window, and once
+/// `max_restarts` is reached inside that window the controller gives up on the server until it
+/// is started again by hand.
+use chrono::{DateTime, Local};
+use rand::Rng;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use tokio::time::{Duration, Instant};
+
+use crate::controlled_program::ControlledProgramDescriptor;
+
+/// Restart settings for a server, stored on its `ControlledProgramDescriptor`.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct RestartPolicy {
+    /// Crashes allowed inside `window_secs` before the server is given up on.
+    #[serde(default = "default_max_restarts")]
+    pub max_restarts: u32,
+    /// Length of the sliding window crashes are counted in.
+    #[serde(default = "default_window_secs")]
+    pub window_secs: u64,
+    /// Delay before the first restart.
+    #[serde(default = "default_initial_backoff_ms")]
+    pub initial_backoff_ms: u64,
+    /// Upper bound on the delay between restarts.
+    #[serde(default = "default_max_backoff_ms")]
+    pub max_backoff_ms: u64,
+    /// Random spread applied to each delay, as a fraction of it (0.2 = ±20%).
+    #[serde(default = "default_jitter")]
+    pub jitter: f64,
+}
+
+fn default_max_restarts() -> u32 {
+    5
+}
+
+fn default_window_secs() -> u64 {
+    300
+}
+
+fn default_initial_backoff_ms() -> u64 {
+    1000
+}
+
+fn default_max_backoff_ms() -> u64 {
+    60_000
+}
+
+fn default_jitter() -> f64 {
+    0.2
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            max_restarts: default_max_restarts(),
+            window_secs: default_window_secs(),
+            initial_backoff_ms: default_initial_backoff_ms(),
+            max_backoff_ms: default_max_backoff_ms(),
+            jitter: default_jitter(),
+        }
+    }
+}
+
+impl RestartPolicy {
+    /// Delay before the restart that follows `recent_restarts` restarts inside the window.
+    fn backoff(&self, recent_restarts: u32) -> Duration {
+        let base = self
+            .initial_backoff_ms
+            .saturating_mul(1u64 << recent_restarts.min(32))
+            .min(self.max_backoff_ms.max(self.initial_backoff_ms));
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let factor = if jitter > 0.0 {
+            1.0 + rand::thread_rng().gen_range(-jitter..=jitter)
+        } else {
+            1.0
+        };
+        Duration::from_millis((base as f64 * factor) as u64)
+    }
+}
+
+/// What the supervisor should do about a crash.
+pub enum RestartDecision {
+    /// Restart the server once the delay has passed.
+    Retry {
+        /// How long to wait before restarting.
+        delay: Duration,
+        /// Which restart inside the window this is, starting at 1.
+        attempt: u32,
+    },
+    /// Too many crashes inside the window; stop restarting.
+    GiveUp {
+        /// Crashes counted inside the window.
+        restarts: u32,
+    },
+}
+
+/// Crash history and pending restart of a single server.
+#[derive(Default)]
+pub struct RestartTracker {
+    /// When each recent restart was scheduled, oldest first.
+    restarts: VecDeque<Instant>,
+    /// The restart waiting for its backoff to pass, if any.
+    pending: Option<PendingRestart>,
+    /// Whether the latest crash was given up on.
+    gave_up: bool,
+}
+
+/// A restart waiting for its backoff to pass.
+struct PendingRestart {
+    descriptor: ControlledProgramDescriptor,
+    due: Instant,
+    due_wall: DateTime<Local>,
+}
+
+impl RestartTracker {
+    /// Records a crash and decides whether and when the server comes back.
+    ///
+    /// # Arguments
+    /// * `policy` - The server's restart policy.
+    /// * `descriptor` - Descriptor to start the server from when the backoff has passed.
+    pub fn on_crash(
+        &mut self,
+        policy: &RestartPolicy,
+        descriptor: ControlledProgramDescriptor,
+    ) -> RestartDecision {
+        self.on_crash_at(policy, descriptor, Instant::now())
+    }
+
+    /// [`on_crash`](Self::on_crash) for a crash that happened at `now`.
+    fn on_crash_at(
+        &mut self,
+        policy: &RestartPolicy,
+        descriptor: ControlledProgramDescriptor,
+        now: Instant,
+    ) -> RestartDecision {
+        let window = Duration::from_secs(policy.window_secs);
+        while self
+            .restarts
+            .front()
+            .is_some_and(|at| now.duration_since(*at) > window)
+        {
+            self.restarts.pop_front();
+        }
+        let recent = self.restarts.len() as u32;
+        if recent >= policy.max_restarts {
+            self.pending = None;
+            self.gave_up = true;
+            return RestartDecision::GiveUp { restarts: recent };
+        }
+        let delay = policy.backoff(recent);
+        self.restarts.push_back(now);
+        self.gave_up = false;
+        self.pending = Some(PendingRestart {
+            descriptor,
+            due: now + delay,
+            due_wall: Local::now() + chrono::Duration::from_std(delay).unwrap_or_default(),
+        });
+        RestartDecision::Retry {
+            delay,
+            attempt: recent + 1,
+        }
+    }
+
+    /// Takes the pending restart if its backoff has passed.
+    pub fn take_due(&mut self) -> Option<ControlledProgramDescriptor> {
+        self.take_due_at(Instant::now())
+    }
+
+    /// [`take_due`](Self::take_due) as of `now`.
+    fn take_due_at(&mut self, now: Instant) -> Option<ControlledProgramDescriptor> {
+        if self
+            .pending
+            .as_ref()
+            .is_some_and(|pending| pending.due <= now)
+        {
+            self.pending.take().map(|pending| pending.descriptor)
+        } else {
+            None
+        }
+    }
+
+    /// Number of restarts counted inside the current window.
+    pub fn restart_count(&self) -> u32 {
+        self.restarts.len() as u32
+    }
+
+    /// When the pending restart will happen, as an RFC 3339 timestamp.
+    pub fn next_retry(&self) -> Option<String> {
+        self.pending
+            .as_ref()
+            .map(|pending| pending.due_wall.to_rfc3339())
+    }
+
+    /// Whether the server kept crashing and is no longer restarted.
+    pub fn gave_up(&self) -> bool {
+        self.gave_up
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(jitter: f64) -> RestartPolicy {
+        RestartPolicy {
+            max_restarts: 3,
+            window_secs: 60,
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 10_000,
+            jitter,
+        }
+    }
+
+    fn retry_delay(decision: RestartDecision, expected_attempt: u32) -> Duration {
+        match decision {
+            RestartDecision::Retry { delay, attempt } => {
+                assert_eq!(attempt, expected_attempt);
+                delay
+            }
+            RestartDecision::GiveUp { restarts } => {
+                panic!("gave up after {} restarts", restarts)
+            }
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let policy = policy(0.0);
+        let delays: Vec<u64> = [0, 1, 2, 3, 4, 5, 40]
+            .into_iter()
+            .map(|restarts| policy.backoff(restarts).as_millis() as u64)
+            .collect();
+        assert_eq!(delays, [1000, 2000, 4000, 8000, 10_000, 10_000, 10_000]);
+    }
+
+    #[test]
+    fn cap_below_the_initial_backoff_keeps_the_initial_backoff() {
+        let policy = RestartPolicy {
+            max_backoff_ms: 10,
+            ..policy(0.0)
+        };
+        assert_eq!(policy.backoff(3), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn jitter_stays_within_its_bounds() {
+        let policy = policy(0.2);
+        for _ in 0..500 {
+            let delay = policy.backoff(1).as_millis();
+            assert!((1600..=2400).contains(&delay), "{}", delay);
+        }
+        let wild = RestartPolicy {
+            jitter: 5.0,
+            ..policy
+        };
+        for _ in 0..500 {
+            assert!(wild.backoff(1).as_millis() <= 4000);
+        }
+    }
+
+    #[test]
+    fn gives_up_once_max_restarts_happened_in_the_window() {
+        let policy = policy(0.0);
+        let start = Instant::now();
+        let mut tracker = RestartTracker::default();
+        for (n, expected) in [1000, 2000, 4000].into_iter().enumerate() {
+            let at = start + Duration::from_secs(n as u64);
+            let decision = tracker.on_crash_at(&policy, ControlledProgramDescriptor::default(), at);
+            assert_eq!(retry_delay(decision, n as u32 + 1).as_millis(), expected);
+            assert!(tracker.next_retry().is_some());
+        }
+        assert_eq!(tracker.restart_count(), 3);
+        let decision = tracker.on_crash_at(
+            &policy,
+            ControlledProgramDescriptor::default(),
+            start + Duration::from_secs(10),
+        );
+        assert!(matches!(decision, RestartDecision::GiveUp { restarts: 3 }));
+        assert!(tracker.gave_up());
+        assert!(tracker.next_retry().is_none());
+        assert!(tracker
+            .take_due_at(start + Duration::from_secs(3600))
+            .is_none());
+    }
+
+    #[test]
+    fn restarts_outside_the_window_are_forgotten() {
+        let policy = policy(0.0);
+        let start = Instant::now();
+        let mut tracker = RestartTracker::default();
+        for n in 0..3 {
+            tracker.on_crash_at(
+                &policy,
+                ControlledProgramDescriptor::default(),
+                start + Duration::from_secs(n),
+            );
+        }
+        // Only the restart at 2s is less than 60s old.
+        let decision = tracker.on_crash_at(
+            &policy,
+            ControlledProgramDescriptor::default(),
+            start + Duration::from_millis(61_500),
+        );
+        assert_eq!(retry_delay(decision, 2), Duration::from_millis(2000));
+        assert!(!tracker.gave_up());
+        assert_eq!(tracker.restart_count(), 2);
+        // Long after the window everything is forgotten and the backoff starts over.
+        let decision = tracker.on_crash_at(
+            &policy,
+            ControlledProgramDescriptor::default(),
+            start + Duration::from_secs(600),
+        );
+        assert_eq!(retry_delay(decision, 1), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn pending_restart_is_due_after_its_backoff() {
+        let policy = policy(0.0);
+        let start = Instant::now();
+        let mut tracker = RestartTracker::default();
+        tracker.on_crash_at(&policy, ControlledProgramDescriptor::default(), start);
+        assert!(tracker
+            .take_due_at(start + Duration::from_millis(999))
+            .is_none());
+        assert!(tracker
+            .take_due_at(start + Duration::from_millis(1000))
+            .is_some());
+        assert!(tracker.next_retry().is_none());
+        assert!(tracker
+            .take_due_at(start + Duration::from_secs(5))
+            .is_none());
+    }
+}
@@ -0,0 +1,195 @@
+/// Running servers as other users.
+///
+/// A server with `run_as_user` or `run_as_group` is started with that user's and group's IDs
+/// instead of the controller's, so a compromised server can't reach the other servers' files or
+/// `config.json`. Switching users needs the controller to run as root. The names are looked up
+/// each time the server starts, and the new process drops root's privileges right before it
+/// runs the server, after its [limits](crate::resource_limits) have been applied.
+use crate::controlled_program::ControlledProgramDescriptor;
+
+/// Who a server runs as.
+#[derive(Clone, Debug)]
+pub struct Credentials {
+    /// The user ID.
+    pub uid: u32,
+    /// The primary group ID.
+    pub gid: u32,
+    /// The supplementary group IDs.
+    pub groups: Vec<u32>,
+    /// The user's name and home directory, if the user has an entry in the user database.
+    pub account: Option<(String, std::path::PathBuf)>,
+}
+
+#[cfg(unix)]
+mod unix {
+    use nix::unistd::{getegid, geteuid, Group, Uid, User};
+
+    use super::Credentials;
+
+    /// Looks up a user by name, or by ID if it is a number.
+    fn user(name: &str) -> Result<Option<User>, String> {
+        let found = match name.parse::<u32>() {
+            Ok(uid) => User::from_uid(Uid::from_raw(uid)),
+            Err(_) => User::from_name(name),
+        };
+        found.map_err(|e| format!("could not look up user '{}': {}", name, e))
+    }
+
+    /// Looks up a group ID by name, or takes it as is if it is a number.
+    fn group(name: &str) -> Result<u32, String> {
+        if let Ok(gid) = name.parse::<u32>() {
+            return Ok(gid);
+        }
+        Group::from_name(name)
+            .map_err(|e| format!("could not look up group '{}': {}", name, e))?
+            .map(|group| group.gid.as_raw())
+            .ok_or_else(|| format!("group '{}' does not exist", name))
+    }
+
+    /// Looks up the IDs of a user, its group and supplementary groups.
+    pub fn lookup(
+        run_as_user: Option<&str>,
+        run_as_group: Option<&str>,
+        supplementary_groups: &[String],
+    ) -> Result<Credentials, String> {
+        let account = run_as_user.map(user).transpose()?.flatten();
+        let uid = match (run_as_user, &account) {
+            (_, Some(account)) => account.uid.as_raw(),
+            // A numeric ID doesn't need an entry in the user database
+            (Some(name), None) => name
+                .parse::<u32>()
+                .map_err(|_| format!("user '{}' does not exist", name))?,
+            (None, None) => geteuid().as_raw(),
+        };
+        let gid = match (run_as_group, &account) {
+            (Some(name), _) => group(name)?,
+            (None, Some(account)) => account.gid.as_raw(),
+            (None, None) if run_as_user.is_some() => {
+                return Err(format!(
+                    "user {} has no entry in the user database, so run_as_group must be set",
+                    uid
+                ))
+            }
+            (None, None) => getegid().as_raw(),
+        };
+        let mut groups = vec![gid];
+        for name in supplementary_groups {
+            let gid = group(name)?;
+            if !groups.contains(&gid) {
+                groups.push(gid);
+            }
+        }
+        Ok(Credentials {
+            uid,
+            gid,
+            groups,
+            account: account.map(|account| (account.name, account.dir)),
+        })
+    }
+
+    /// Checks that the controller may switch to the credentials.
+    pub fn check_privileges(credentials: &Credentials) -> Result<(), String> {
+        let euid = geteuid();
+        if euid.is_root() {
+            return Ok(());
+        }
+        if credentials.uid == euid.as_raw()
+            && credentials.gid == getegid().as_raw()
+            && credentials.groups.len() == 1
+        {
+            return Ok(());
+        }
+        let current = User::from_uid(euid)
+            .ok()
+            .flatten()
+            .map_or(euid.to_string(), |user| user.name);
+        Err(format!(
+            "the controller runs as '{}', not root, so it can't switch to another user or group",
+            current
+        ))
+    }
+
+    /// Makes the new process switch to the credentials right before it runs the server.
+    pub fn apply(credentials: &Credentials, process: &mut tokio::process::Command) {
+        let uid = credentials.uid as libc::uid_t;
+        let gid = credentials.gid as libc::gid_t;
+        let groups: Vec<libc::gid_t> = credentials
+            .groups
+            .iter()
+            .map(|gid| *gid as libc::gid_t)
+            .collect();
+        // SAFETY: the hook only makes async-signal-safe system calls. Groups go first, as
+        // only root may change them, and the user last, as that gives up root.
+        unsafe {
+            process.pre_exec(move || {
+                if libc::setgroups(groups.len() as _, groups.as_ptr()) != 0
+                    || libc::setgid(gid) != 0
+                    || libc::setuid(uid) != 0
+                {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+
+    /// Hands a directory the controller created over to the server's user and group.
+    pub fn chown(path: &std::path::Path, credentials: &Credentials) -> std::io::Result<()> {
+        std::os::unix::fs::chown(path, Some(credentials.uid), Some(credentials.gid))
+    }
+}
+
+/// Looks up who a server runs as.
+///
+/// # Arguments
+/// * `desc` - The server's configuration.
+///
+/// # Returns
+/// * `None` if it runs as the controller's own user.
+/// * `Err` if a user or group doesn't exist.
+#[cfg(unix)]
+pub fn lookup(desc: &ControlledProgramDescriptor) -> Result<Option<Credentials>, String> {
+    if desc.run_as_user.is_none()
+        && desc.run_as_group.is_none()
+        && desc.supplementary_groups.is_empty()
+    {
+        return Ok(None);
+    }
+    unix::lookup(
+        desc.run_as_user.as_deref(),
+        desc.run_as_group.as_deref(),
+        &desc.supplementary_groups,
+    )
+    .map(Some)
+}
+
+#[cfg(not(unix))]
+pub fn lookup(desc: &ControlledProgramDescriptor) -> Result<Option<Credentials>, String> {
+    if desc.run_as_user.is_none()
+        && desc.run_as_group.is_none()
+        && desc.supplementary_groups.is_empty()
+    {
+        return Ok(None);
+    }
+    Err("running servers as another user is only supported on Unix".to_string())
+}
+
+/// Looks up who a server runs as and checks that the controller may switch to them.
+///
+/// # Arguments
+/// * `desc` - The server's configuration.
+///
+/// # Returns
+/// * `None` if it runs as the controller's own user.
+/// * `Err` if a user or group doesn't exist or the controller isn't root.
+pub fn for_server(desc: &ControlledProgramDescriptor) -> Result<Option<Credentials>, String> {
+    let credentials = lookup(desc)?;
+    #[cfg(unix)]
+    if let Some(credentials) = credentials.as_ref() {
+        unix::check_privileges(credentials)?;
+    }
+    Ok(credentials)
+}
+
+#[cfg(unix)]
+pub use unix::{apply, chown};
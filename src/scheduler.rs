@@ -0,0 +1,297 @@
+/// Scheduled tasks for managed servers.
+///
+/// Each server descriptor can carry a list of schedules that fire on a cron expression or a
+/// fixed interval. A schedule runs one action (a console command, restart, stop, start or
+/// backup) and can send countdown announcements to the server's console before it fires.
+use chrono::{DateTime, Duration as ChronoDuration, Local};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, str::FromStr};
+use tracing::*;
+
+use crate::{app_state::AppState, servers};
+
+/// How often the scheduler checks for due announcements and actions.
+const TICK: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// A scheduled task, stored in a server descriptor's `schedules` list.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct ScheduleDescriptor {
+    /// Name of the schedule, unique per server.
+    pub id: String,
+    /// Cron expression, either five fields (`min hour day month weekday`) or six with seconds first.
+    #[serde(default)]
+    pub cron: Option<String>,
+    /// Run every this many seconds instead of on a cron expression.
+    #[serde(default)]
+    pub interval_secs: Option<u64>,
+    /// What to do when the schedule fires.
+    pub action: ScheduledAction,
+    /// Commands sent to the server's console ahead of the action.
+    #[serde(default)]
+    pub countdown: Vec<Announcement>,
+    /// Whether the schedule is active.
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+/// What a schedule does when it fires.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ScheduledAction {
+    /// Sends a command to the server's console.
+    Command {
+        /// The command to send.
+        command: String,
+    },
+    /// Gracefully stops the server and starts it again.
+    Restart,
+    /// Gracefully stops the server.
+    Stop,
+    /// Starts the server if it isn't running.
+    Start,
+    /// Takes a backup of the server.
+    Backup,
+}
+
+/// A console command sent a fixed time before a schedule fires.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct Announcement {
+    /// How long before the action the command is sent.
+    pub seconds_before: u64,
+    /// The command to send, e.g. `say Restarting in 5 minutes`.
+    pub command: String,
+}
+
+impl ScheduleDescriptor {
+    /// Checks that the schedule has a usable trigger.
+    ///
+    /// # Returns
+    /// * `Err` with a readable reason if the schedule can never fire.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.id.trim().is_empty() {
+            return Err("Schedule id must not be empty".to_string());
+        }
+        match (&self.cron, self.interval_secs) {
+            (Some(_), Some(_)) => {
+                Err("Schedule must set either cron or interval_secs, not both".to_string())
+            }
+            (None, None) => Err("Schedule must set either cron or interval_secs".to_string()),
+            (None, Some(0)) => Err("interval_secs must be greater than zero".to_string()),
+            (None, Some(_)) => Ok(()),
+            (Some(cron), None) => parse_cron(cron).map(|_| ()),
+        }
+    }
+
+    /// Works out when the schedule next fires after `after`.
+    fn next_run(&self, after: DateTime<Local>) -> Option<DateTime<Local>> {
+        if let Some(interval) = self.interval_secs.filter(|secs| *secs > 0) {
+            return Some(after + ChronoDuration::seconds(interval as i64));
+        }
+        let schedule = parse_cron(self.cron.as_deref()?).ok()?;
+        schedule.after(&after).next()
+    }
+}
+
+/// Parses a cron expression, accepting the common five-field form by assuming second zero.
+fn parse_cron(expression: &str) -> Result<cron::Schedule, String> {
+    let expression = expression.trim();
+    let expression = if expression.split_whitespace().count() == 5 {
+        format!("0 {}", expression)
+    } else {
+        expression.to_string()
+    };
+    cron::Schedule::from_str(&expression)
+        .map_err(|e| format!("Invalid cron expression '{}': {}", expression, e))
+}
+
+/// Runtime state of one schedule.
+struct ScheduleState {
+    /// The descriptor the state was computed from, to notice edits.
+    descriptor: ScheduleDescriptor,
+    /// When the action fires next.
+    next_run: Option<DateTime<Local>>,
+    /// Countdown announcements already sent for `next_run`, by `seconds_before`.
+    announced: Vec<u64>,
+}
+
+impl ScheduleState {
+    fn new(descriptor: ScheduleDescriptor, now: DateTime<Local>) -> Self {
+        let mut state = Self {
+            descriptor,
+            next_run: None,
+            announced: vec![],
+        };
+        state.plan(now);
+        state
+    }
+
+    /// Picks the next run after `now` and skips announcements whose time has already passed.
+    fn plan(&mut self, now: DateTime<Local>) {
+        self.next_run = if self.descriptor.enabled {
+            self.descriptor.next_run(now)
+        } else {
+            None
+        };
+        self.announced = match self.next_run {
+            Some(next_run) => self
+                .descriptor
+                .countdown
+                .iter()
+                .filter(|a| next_run - ChronoDuration::seconds(a.seconds_before as i64) <= now)
+                .map(|a| a.seconds_before)
+                .collect(),
+            None => vec![],
+        };
+    }
+}
+
+/// Runtime state of every schedule, keyed by server name and schedule id.
+#[derive(Default)]
+pub struct Scheduler {
+    schedules: HashMap<(String, String), ScheduleState>,
+}
+
+impl Scheduler {
+    /// When a schedule fires next, if it is active.
+    pub fn next_run(&self, server_name: &str, id: &str) -> Option<DateTime<Local>> {
+        self.schedules
+            .get(&(server_name.to_string(), id.to_string()))
+            .and_then(|state| state.next_run)
+    }
+}
+
+/// Something the scheduler has to do this tick.
+enum Due {
+    Announce {
+        server_name: String,
+        command: String,
+    },
+    Run {
+        server_name: String,
+        id: String,
+        action: ScheduledAction,
+    },
+}
+
+/// Runs the scheduler loop, firing announcements and actions as they come due.
+///
+/// Schedules are read from the configuration every tick, so schedules added, edited or removed
+/// at runtime take effect without a restart.
+///
+/// # Arguments
+/// * `state` - The shared application state.
+pub async fn run_scheduler(state: AppState) {
+    let mut interval = tokio::time::interval(TICK);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    loop {
+        interval.tick().await;
+        let configured: Vec<(String, ScheduleDescriptor)> = state
+            .config
+            .lock()
+            .await
+            .servers
+            .iter()
+            .flat_map(|desc| {
+                desc.schedules
+                    .iter()
+                    .map(move |schedule| (desc.name.clone(), schedule.clone()))
+            })
+            .collect();
+
+        let now = Local::now();
+        let mut due = vec![];
+        {
+            let mut scheduler = state.scheduler.lock().await;
+            scheduler.schedules.retain(|(server_name, id), _| {
+                configured
+                    .iter()
+                    .any(|(name, schedule)| name == server_name && &schedule.id == id)
+            });
+            for (server_name, descriptor) in configured {
+                let key = (server_name.clone(), descriptor.id.clone());
+                let schedule = scheduler
+                    .schedules
+                    .entry(key)
+                    .or_insert_with(|| ScheduleState::new(descriptor.clone(), now));
+                if schedule.descriptor != descriptor {
+                    *schedule = ScheduleState::new(descriptor, now);
+                }
+                let Some(next_run) = schedule.next_run else {
+                    continue;
+                };
+                for announcement in schedule.descriptor.countdown.iter() {
+                    let at = next_run - ChronoDuration::seconds(announcement.seconds_before as i64);
+                    if at <= now && !schedule.announced.contains(&announcement.seconds_before) {
+                        schedule.announced.push(announcement.seconds_before);
+                        due.push(Due::Announce {
+                            server_name: server_name.clone(),
+                            command: announcement.command.clone(),
+                        });
+                    }
+                }
+                if next_run <= now {
+                    due.push(Due::Run {
+                        server_name: server_name.clone(),
+                        id: schedule.descriptor.id.clone(),
+                        action: schedule.descriptor.action.clone(),
+                    });
+                    schedule.plan(now);
+                }
+            }
+        }
+
+        for task in due {
+            match task {
+                Due::Announce {
+                    server_name,
+                    command,
+                } => {
+                    if let Err(e) = servers::send_stdin(&state, &server_name, &command).await {
+                        debug!("Skipped schedule announcement: {}", e);
+                    }
+                }
+                Due::Run {
+                    server_name,
+                    id,
+                    action,
+                } => {
+                    // Restarts can take a while, so don't hold up other schedules
+                    tokio::spawn(run_action(state.clone(), server_name, id, action));
+                }
+            }
+        }
+    }
+}
+
+/// Runs a scheduled action against a server.
+///
+/// # Arguments
+/// * `state` - The shared application state.
+/// * `server_name` - The server the schedule belongs to.
+/// * `id` - The schedule id, for logging.
+/// * `action` - What to do.
+async fn run_action(state: AppState, server_name: String, id: String, action: ScheduledAction) {
+    info!("Running schedule '{}' for server '{}'", id, server_name);
+    let result = match action {
+        ScheduledAction::Command { command } => {
+            servers::send_stdin(&state, &server_name, &command).await
+        }
+        ScheduledAction::Restart => servers::restart_server(&state, &server_name).await,
+        ScheduledAction::Stop => servers::stop_server(&state, &server_name).await.map(|_| ()),
+        ScheduledAction::Start => servers::start_server(&state, &server_name).await,
+        ScheduledAction::Backup => crate::backup::run_backup(&state, &server_name)
+            .await
+            .map(|_| ()),
+    };
+    if let Err(e) = result {
+        warn!(
+            "Schedule '{}' for server '{}' failed: {}",
+            id, server_name, e
+        );
+    }
+}
@@ -1,5 +1,10 @@
 use crate::{
-    app_state::AppState, controlled_program::ControlledProgramDescriptor, messages::ConsoleOutput,
+    app_state::AppState,
+    console_history::{HistoryEntry, HistoryStream},
+    controlled_program::{ControlledProgramDescriptor, ControlledProgramInstance},
+    messages::{ConsoleOutput, ServerCrashLoop},
+    resource_limits::ExitReason,
+    restart_policy::RestartDecision,
 };
 /// Server management and process monitoring utilities.
 ///
@@ -28,17 +33,20 @@ pub fn format_exit_message(exit_code: impl std::fmt::Display) -> String {
 /// # Arguments
 /// * `state` - The shared application state.
 /// * `server_name` - The name of the server that exited.
-/// * `exit_code` - The exit code of the server.
+/// * `reason` - Why the server exited.
 /// * `server_type` - The specialized server type, if any.
 pub async fn send_termination_message(
     state: &AppState,
     server_name: String,
-    exit_code: i32,
+    reason: &ExitReason,
     server_type: Option<String>,
 ) {
     let termination_msg = ConsoleOutput {
         r#type: "ServerOutput".to_owned(),
-        output: format_exit_message(exit_code),
+        output: format!(
+            "<span style=\"color: var(--warning, #FFA500);\">[{}]</span>",
+            reason
+        ),
         server_name,
         server_type,
     };
@@ -46,33 +54,81 @@ pub async fn send_termination_message(
         .tx
         .send(serde_json::to_string(&termination_msg).unwrap());
 }
+/// Sends a controller notice, such as a pending restart, to a server's web console.
+///
+/// # Arguments
+/// * `state` - The shared application state.
+/// * `server_name` - The name of the server the notice is about.
+/// * `server_type` - The specialized server type, if any.
+/// * `notice` - The notice text; it is HTML-escaped before sending.
+pub fn send_console_notice(
+    state: &AppState,
+    server_name: String,
+    server_type: Option<String>,
+    notice: &str,
+) {
+    let msg = ConsoleOutput {
+        r#type: "ServerOutput".to_owned(),
+        output: format!(
+            "<span style=\"color: var(--warning, #FFA500);\">[{}]</span>",
+            crate::ansi_to_html::escape_html(notice)
+        ),
+        server_name,
+        server_type,
+    };
+    let _ = state.tx.send(serde_json::to_string(&msg).unwrap());
+}
+
+/// Logs why a server could not be started and shows it in the server's web console.
+///
+/// # Arguments
+/// * `state` - The shared application state.
+/// * `server_name` - The name of the server that didn't start.
+/// * `server_type` - The specialized server type, if any.
+/// * `error` - Why it didn't start.
+pub fn report_start_failure(
+    state: &AppState,
+    server_name: &str,
+    server_type: Option<String>,
+    error: &str,
+) {
+    error!("{}", error);
+    send_console_notice(state, server_name.to_owned(), server_type, error);
+}
+
 /// Starts all servers marked for auto-start in the configuration.
 ///
-/// Spawns a background task to process server stdout.
+/// Console history kept under the directory names of older versions is moved first. Spawns a
+/// background task to process server stdout.
 ///
 /// # Arguments
 /// * `_state` - The shared application state.
 #[no_mangle]
 pub async fn start_servers(state: AppState) {
     let mut config = state.config.lock().await;
+    let names: Vec<String> = config
+        .servers
+        .iter()
+        .map(|desc| desc.name.clone())
+        .collect();
+    crate::console_history::adopt_legacy_history(&config.console_history, &names);
     for server_desc in config.servers.iter_mut() {
         if server_desc.auto_start {
             let new_desc = server_desc.clone();
             let mut servers = state.servers.lock().await;
-            let instance = new_desc.into_instance(&state.specialization_registry);
-            // After starting a new server, send specialization info update
-            if let Some(handler) = instance.specialization_handler.as_ref() {
-                let info = handler.get_status();
-                let update = crate::messages::ServerSpecializationInfoUpdate {
-                    r#type: "ServerSpecializationInfoUpdate".to_owned(),
-                    server_name: instance.name.clone(),
-                    info,
-                    specialization: instance.specialized_server_type.clone().unwrap_or_default(),
-                    active: instance.active,
-                };
-                let _ = state.tx.send(serde_json::to_string(&update).unwrap());
+            match new_desc.into_instance(&state.specialization_registry) {
+                Ok(instance) => {
+                    // After starting a new server, send specialization info update
+                    send_specialization_info(&state, &instance);
+                    servers.push(instance);
+                }
+                Err(e) => report_start_failure(
+                    &state,
+                    &server_desc.name,
+                    server_desc.specialized_server_type.clone(),
+                    &e,
+                ),
             }
-            servers.push(instance);
             drop(servers);
         }
     }
@@ -91,23 +147,39 @@ pub async fn process_stdout(state: AppState) {
         {
             let mut new_instances = vec![];
             let mut to_remove = vec![];
+            let mut history: Vec<(String, Vec<HistoryEntry>)> = vec![];
             let mut servers = state.servers.lock().await;
             for (index, server) in servers.iter_mut().enumerate() {
                 let status = server.process.try_wait();
                 match status {
                     Ok(Some(stat)) => {
-                        let exit_code = stat.code().unwrap();
+                        // A process killed by a signal has no exit code
+                        let exit_code = stat.code().unwrap_or(-1);
+                        let reason = server.exit_reason(&stat);
                         warn!(
-                            "A child process has closed! index: {} ExitCode: {}",
-                            index, exit_code
+                            "A child process has closed! index: {} ExitCode: {} ({})",
+                            index, exit_code, reason
                         );
                         // Mark as inactive
                         server.active = false;
+                        // Relay whatever the process printed right before it exited
+                        if let Some(val) = server.read_output().await {
+                            let out = ConsoleOutput {
+                                r#type: "ServerOutput".to_owned(),
+                                output: val,
+                                server_name: server.name.clone(),
+                                server_type: server.specialized_server_type.clone(),
+                            };
+                            let _ = state.tx.send(serde_json::to_string(&out).unwrap());
+                        }
+                        let mut entries = server.take_history();
+                        entries.push(HistoryEntry::now(HistoryStream::Ctl, reason.to_string()));
+                        history.push((server.name.clone(), entries));
                         // Send termination message to web console
                         send_termination_message(
                             &state,
                             server.name.clone(),
-                            exit_code,
+                            &reason,
                             server.specialized_server_type.clone(),
                         )
                         .await;
@@ -134,28 +206,79 @@ pub async fn process_stdout(state: AppState) {
                             let _ = state.tx.send(serde_json::to_string(&update).unwrap());
                         }
                         if exit_code != 0 && server.crash_prevention {
-                            info!("Server ID: {} has crashed, restarting it...", index);
-                            let mut descriptor = ControlledProgramDescriptor::new(
-                                server.name.as_str(),
-                                server.executable_path.as_str(),
-                                server.command_line_args.clone(),
-                                server.working_dir.clone(),
-                            );
-                            // set_specialization removed; assign directly if needed
-                            descriptor.specialized_server_type =
-                                server.specialized_server_type.clone();
-
-                            // Lookup the original crash_prevention setting from config to preserve it
+                            // Restart from the configured descriptor so every setting carries over
                             let config = state.config.lock().await;
-                            for server_config in config.servers.iter() {
-                                if server_config.name == server.name {
-                                    descriptor.crash_prevention = server_config.crash_prevention;
-                                    break;
+                            let configured = config
+                                .servers
+                                .iter()
+                                .find(|server_config| server_config.name == server.name)
+                                .cloned();
+                            drop(config);
+                            let descriptor = configured.unwrap_or_else(|| {
+                                let mut descriptor = ControlledProgramDescriptor::new(
+                                    server.name.as_str(),
+                                    server.executable_path.as_str(),
+                                    server.command_line_args.clone(),
+                                    server.working_dir.clone(),
+                                );
+                                descriptor.specialized_server_type =
+                                    server.specialized_server_type.clone();
+                                descriptor
+                            });
+                            let policy = descriptor.restart_policy.clone();
+                            let decision = state
+                                .restart_trackers
+                                .lock()
+                                .await
+                                .entry(server.name.clone())
+                                .or_default()
+                                .on_crash(&policy, descriptor);
+                            match decision {
+                                RestartDecision::Retry { delay, attempt } => {
+                                    info!(
+                                        "Server '{}' has crashed, restarting it in {:.1}s (attempt {} of {})",
+                                        server.name,
+                                        delay.as_secs_f64(),
+                                        attempt,
+                                        policy.max_restarts
+                                    );
+                                    send_console_notice(
+                                        &state,
+                                        server.name.clone(),
+                                        server.specialized_server_type.clone(),
+                                        &format!(
+                                            "Restarting in {:.1}s (attempt {} of {})",
+                                            delay.as_secs_f64(),
+                                            attempt,
+                                            policy.max_restarts
+                                        ),
+                                    );
+                                }
+                                RestartDecision::GiveUp { restarts } => {
+                                    error!(
+                                        "Server '{}' crashed {} times within {}s, giving up on it until it is started again.",
+                                        server.name, restarts, policy.window_secs
+                                    );
+                                    send_console_notice(
+                                        &state,
+                                        server.name.clone(),
+                                        server.specialized_server_type.clone(),
+                                        &format!(
+                                            "Crashed {} times within {}s, not restarting again until started by hand",
+                                            restarts, policy.window_secs
+                                        ),
+                                    );
+                                    let crash_loop = ServerCrashLoop {
+                                        r#type: "ServerCrashLoop".to_owned(),
+                                        server_name: server.name.clone(),
+                                        restarts,
+                                        window_secs: policy.window_secs,
+                                        exit_code,
+                                    };
+                                    let _ =
+                                        state.tx.send(serde_json::to_string(&crash_loop).unwrap());
                                 }
                             }
-                            drop(config);
-
-                            new_instances.push(descriptor);
                         } else if exit_code != 0 {
                             info!("Server ID: {} has crashed, but crash prevention is disabled. Not restarting.", index);
                         }
@@ -165,24 +288,27 @@ pub async fn process_stdout(state: AppState) {
                     Err(_e) => {}
                 }
             }
+            // Start crashed servers whose backoff has passed, unless they were started some other way
+            let mut trackers = state.restart_trackers.lock().await;
+            for (name, tracker) in trackers.iter_mut() {
+                if servers.iter().any(|server| &server.name == name) {
+                    continue;
+                }
+                if let Some(desc) = tracker.take_due() {
+                    new_instances.push(desc);
+                }
+            }
+            drop(trackers);
             for desc in new_instances {
-                let instance = desc.into_instance(&state.specialization_registry);
-                // After starting a new server, send specialization info update
-                if let Some(handler) = instance.specialization_handler.as_ref() {
-                    let info = handler.get_status();
-                    let update = crate::messages::ServerSpecializationInfoUpdate {
-                        r#type: "ServerSpecializationInfoUpdate".to_owned(),
-                        server_name: instance.name.clone(),
-                        info,
-                        specialization: instance
-                            .specialized_server_type
-                            .clone()
-                            .unwrap_or_default(),
-                        active: instance.active,
-                    };
-                    let _ = state.tx.send(serde_json::to_string(&update).unwrap());
+                let (name, server_type) = (desc.name.clone(), desc.specialized_server_type.clone());
+                match desc.into_instance(&state.specialization_registry) {
+                    Ok(instance) => {
+                        // After starting a new server, send specialization info update
+                        send_specialization_info(&state, &instance);
+                        servers.push(instance);
+                    }
+                    Err(e) => report_start_failure(&state, &name, server_type, &e),
                 }
-                servers.push(instance);
             }
             // Remove servers in reverse order to avoid index shifting
             to_remove.sort_unstable_by(|a, b| b.cmp(a));
@@ -197,6 +323,10 @@ pub async fn process_stdout(state: AppState) {
                 )
                 .await
                 .unwrap_or_default();
+                let entries = server.take_history();
+                if !entries.is_empty() {
+                    history.push((server.name.clone(), entries));
+                }
                 if let Some(val) = str {
                     if !val.is_empty() {
                         let out = ConsoleOutput {
@@ -243,9 +373,214 @@ pub async fn process_stdout(state: AppState) {
                 }
             }
             drop(servers);
+            for (server_name, entries) in history {
+                append_history(&state, &server_name, &entries).await;
+            }
         }
         const REFRESHES_PER_SECOND: f64 = 10.;
         const SECONDS_TO_SLEEP: f64 = 1000. / REFRESHES_PER_SECOND / 1000.;
         std::thread::sleep(std::time::Duration::from_secs_f64(SECONDS_TO_SLEEP));
     }
 }
+
+/// Broadcasts the specialization status of a freshly started server, if it has one.
+///
+/// # Arguments
+/// * `state` - The shared application state.
+/// * `instance` - The server that was just started.
+fn send_specialization_info(state: &AppState, instance: &ControlledProgramInstance) {
+    if let Some(handler) = instance.specialization_handler.as_ref() {
+        let update = crate::messages::ServerSpecializationInfoUpdate {
+            r#type: "ServerSpecializationInfoUpdate".to_owned(),
+            server_name: instance.name.clone(),
+            info: handler.get_status(),
+            specialization: instance.specialized_server_type.clone().unwrap_or_default(),
+            active: instance.active,
+        };
+        let _ = state.tx.send(serde_json::to_string(&update).unwrap());
+    }
+}
+
+/// Writes entries to a server's console history, logging rather than failing on I/O errors.
+///
+/// # Arguments
+/// * `state` - The shared application state.
+/// * `server_name` - The server the entries belong to.
+/// * `entries` - The lines to write, oldest first.
+async fn append_history(state: &AppState, server_name: &str, entries: &[HistoryEntry]) {
+    let settings = state.config.lock().await.console_history.clone();
+    if let Err(e) = state
+        .console_history
+        .lock()
+        .await
+        .append(&settings, server_name, entries)
+    {
+        warn!(
+            "Could not write console history for '{}': {}",
+            server_name, e
+        );
+    }
+}
+
+/// Starts a configured server that is not already running.
+///
+/// A manual start also clears any crash-loop state of the server.
+///
+/// # Arguments
+/// * `state` - The shared application state.
+/// * `server_name` - The name of the server to start.
+///
+/// # Returns
+/// * `Err` if the server is already running, is still stopping, is being backed up or restored,
+///   or is not in the configuration.
+pub async fn start_server(state: &AppState, server_name: &str) -> Result<(), String> {
+    let mut servers = state.servers.lock().await;
+    if servers.iter().any(|server| server.name == server_name) {
+        return Err(format!("Server '{}' is already running", server_name));
+    }
+    if state.stopping.lock().await.contains(server_name) {
+        return Err(format!("Server '{}' is still stopping", server_name));
+    }
+    // A restore rewrites the working directory under the server, and a backup is reading it
+    if state.backups_in_progress.lock().await.contains(server_name) {
+        return Err(format!(
+            "Server '{}' can't start, a backup or restore is running",
+            server_name
+        ));
+    }
+    let descriptor = state
+        .config
+        .lock()
+        .await
+        .servers
+        .iter()
+        .find(|desc| desc.name == server_name)
+        .cloned()
+        .ok_or_else(|| format!("Server '{}' is not configured", server_name))?;
+    state.restart_trackers.lock().await.remove(server_name);
+    let server_type = descriptor.specialized_server_type.clone();
+    let instance = descriptor
+        .into_instance(&state.specialization_registry)
+        // The caller logs the error, so it is only shown in the console here
+        .inspect_err(|e| send_console_notice(state, server_name.to_owned(), server_type, e))?;
+    send_specialization_info(state, &instance);
+    servers.push(instance);
+    Ok(())
+}
+
+/// Gracefully stops a running server and removes it from the running list.
+///
+/// Any pending crash restart is cancelled first, so a server waiting out its backoff can be
+/// stopped too. The server is then taken out of the list so the lock isn't held while it shuts
+/// down, and marked as stopping so it isn't started again before its process has exited.
+///
+/// # Arguments
+/// * `state` - The shared application state.
+/// * `server_name` - The name of the server to stop.
+///
+/// # Returns
+/// * The exit code of the server if available, or `Err` if it was neither running nor about to
+///   be restarted.
+pub async fn stop_server(state: &AppState, server_name: &str) -> Result<Option<i32>, String> {
+    let mut servers = state.servers.lock().await;
+    let position = servers.iter().position(|server| server.name == server_name);
+    let mut trackers = state.restart_trackers.lock().await;
+    let restart_pending = trackers
+        .get(server_name)
+        .is_some_and(|tracker| tracker.next_retry().is_some());
+    if position.is_some() || restart_pending {
+        trackers.remove(server_name);
+    }
+    drop(trackers);
+    let Some(index) = position else {
+        drop(servers);
+        if !restart_pending {
+            return Err(format!("Server '{}' is not running", server_name));
+        }
+        info!("Cancelled the pending restart of server '{}'", server_name);
+        let server_type = state
+            .config
+            .lock()
+            .await
+            .servers
+            .iter()
+            .find(|desc| desc.name == server_name)
+            .and_then(|desc| desc.specialized_server_type.clone());
+        send_console_notice(
+            state,
+            server_name.to_owned(),
+            server_type,
+            "Restart cancelled, the server stays stopped",
+        );
+        return Ok(None);
+    };
+    let mut server = servers.remove(index);
+    state.stopping.lock().await.insert(server_name.to_owned());
+    drop(servers);
+
+    let exit_code = server.stop().await;
+    state.stopping.lock().await.remove(server_name);
+    // Relay whatever the process printed while shutting down
+    if let Some(output) = server.read_output().await {
+        let out = ConsoleOutput {
+            r#type: "ServerOutput".to_owned(),
+            output,
+            server_name: server.name.clone(),
+            server_type: server.specialized_server_type.clone(),
+        };
+        let _ = state.tx.send(serde_json::to_string(&out).unwrap());
+    }
+    let exit_text = exit_code
+        .map(|code| code.to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    let mut entries = server.take_history();
+    entries.push(HistoryEntry::now(
+        HistoryStream::Ctl,
+        format!("Server stopped with code {}", exit_text),
+    ));
+    append_history(state, &server.name, &entries).await;
+    let out = ConsoleOutput {
+        r#type: "ServerOutput".to_owned(),
+        output: format_exit_message(exit_text),
+        server_name: server.name.clone(),
+        server_type: server.specialized_server_type.clone(),
+    };
+    let _ = state.tx.send(serde_json::to_string(&out).unwrap());
+    Ok(exit_code)
+}
+
+/// Gracefully stops a server if it is running, then starts it again from its configuration.
+///
+/// # Arguments
+/// * `state` - The shared application state.
+/// * `server_name` - The name of the server to restart.
+pub async fn restart_server(state: &AppState, server_name: &str) -> Result<(), String> {
+    // Checked before stopping, since the start would be refused and leave the server down
+    if state.backups_in_progress.lock().await.contains(server_name) {
+        return Err(format!(
+            "Server '{}' can't restart, a backup or restore is running",
+            server_name
+        ));
+    }
+    // A server that isn't running is simply started
+    let _ = stop_server(state, server_name).await;
+    start_server(state, server_name).await
+}
+
+/// Sends a line of console input to a running server.
+///
+/// # Arguments
+/// * `state` - The shared application state.
+/// * `server_name` - The name of the server to send input to.
+/// * `line` - The input to send, without a line ending.
+pub async fn send_stdin(state: &AppState, server_name: &str, line: &str) -> Result<(), String> {
+    let mut servers = state.servers.lock().await;
+    let server = servers
+        .iter_mut()
+        .find(|server| server.name == server_name)
+        .ok_or_else(|| format!("Server '{}' is not running", server_name))?;
+    server
+        .write_stdin(line)
+        .await
+        .map_err(|e| format!("Could not write to '{}': {}", server_name, e))
+}
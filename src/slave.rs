@@ -2,10 +2,192 @@
 ///
 /// Provides the HTTP and websocket interface for slave nodes, allowing the master
 /// to communicate and control servers running on this node.
-use axum::{routing::get, Router};
-use tracing::info;
+///
+/// Every connection must first prove it knows the slave's shared secret: the slave sends a
+/// random nonce, the master answers with its HMAC-SHA256 keyed with the secret, and only then
+/// are its messages handled.
+///
+/// Masters aren't sent every broadcast like web clients are. Instead the slave keeps a numbered
+/// stream of events about its servers: what it broadcasts about each of them, such as new
+/// output, and a status event whenever one starts, stops or otherwise changes. A master asks for
+/// a snapshot when it connects and after missing an event, and otherwise only receives events,
+/// so an idle slave sends next to nothing.
+use axum::{
+    extract::{
+        ws::{Message, WebSocket},
+        ConnectInfo, State, WebSocketUpgrade,
+    },
+    response::Response,
+    routing::get,
+    Router,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::broadcast::{self, error::RecvError},
+    time,
+};
+use tracing::{info, warn};
+
+use crate::{
+    app_state::AppState,
+    auth::random_token,
+    configuration::Config,
+    messages::*,
+    permissions::Access,
+    websocket::{handle_socket, server_info_message},
+};
+
+/// How long a connecting master has to answer the challenge.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
-use crate::{app_state::AppState, websocket::handle_ws_upgrade};
+/// How often the servers are checked for status changes to push.
+const STATUS_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Events waiting to be sent before a slow master starts missing them.
+const EVENT_BUFFER: usize = 1024;
+
+/// The numbered stream of events masters are sent, stored in `AppState`.
+#[derive(Clone)]
+pub struct SlaveEventFeed {
+    /// Serialized `SlaveEvent`s, for the connected masters.
+    pub tx: broadcast::Sender<String>,
+    /// Sequence number of the latest event.
+    seq: Arc<AtomicU64>,
+}
+
+impl SlaveEventFeed {
+    /// Creates an empty feed.
+    pub fn new() -> Self {
+        Self {
+            tx: broadcast::channel(EVENT_BUFFER).0,
+            seq: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Sequence number of the latest event.
+    pub fn current(&self) -> u64 {
+        self.seq.load(Ordering::SeqCst)
+    }
+
+    /// Numbers an event and sends it to every connected master.
+    fn publish(&self, mut event: SlaveEvent) {
+        event.seq = self.seq.fetch_add(1, Ordering::SeqCst) + 1;
+        let _ = self.tx.send(serde_json::to_string(&event).unwrap());
+    }
+
+    /// Sends every connected master a fresh snapshot under a new sequence number, for when
+    /// events were lost before they could be published.
+    ///
+    /// # Arguments
+    /// * `state` - The shared application state.
+    async fn resync(&self, state: &AppState) {
+        self.seq.fetch_add(1, Ordering::SeqCst);
+        let snapshot = snapshot(state).await;
+        let _ = self.tx.send(serde_json::to_string(&snapshot).unwrap());
+    }
+}
+
+/// Builds an event about a server, numbered when it is published.
+fn event(kind: &str, server_name: &str) -> SlaveEvent {
+    SlaveEvent {
+        r#type: "SlaveEvent".to_owned(),
+        seq: 0,
+        event: kind.to_owned(),
+        server_name: server_name.to_owned(),
+        message: None,
+        server: None,
+    }
+}
+
+/// Builds the snapshot sent to a master that subscribes.
+///
+/// # Arguments
+/// * `state` - The shared application state.
+pub async fn snapshot(state: &AppState) -> SlaveSnapshot {
+    // Read first: events published while the servers are gathered are already included, and
+    // the master skips them
+    let seq = state.slave_events.current();
+    SlaveSnapshot {
+        r#type: "SlaveSnapshot".to_owned(),
+        seq,
+        servers: server_info_message(state, true).await.servers,
+    }
+}
+
+/// Turns what happens to this slave's servers into events for the connected masters.
+///
+/// Every broadcast about a server is passed on as it is, and the servers are checked a few
+/// times a second for status changes.
+///
+/// # Arguments
+/// * `state` - The shared application state.
+pub async fn publish_events(state: AppState) {
+    let feed = state.slave_events.clone();
+    let mut rx = state.tx.subscribe();
+    let mut interval = time::interval(STATUS_CHECK_INTERVAL);
+    // The last status published for each server
+    let mut known: HashMap<String, serde_json::Value> = HashMap::new();
+    loop {
+        tokio::select! {
+            message = rx.recv() => match message {
+                Ok(text) => {
+                    let Ok(json) = serde_json::from_str::<serde_json::Value>(&text) else {
+                        continue;
+                    };
+                    let Some(server_name) = json
+                        .get("server_name")
+                        .and_then(|name| name.as_str())
+                        .map(str::to_owned)
+                    else {
+                        continue;
+                    };
+                    feed.publish(SlaveEvent {
+                        message: Some(json),
+                        ..event("broadcast", &server_name)
+                    });
+                }
+                // Broadcasts were missed, so the masters must be made to resynchronize
+                Err(RecvError::Lagged(_)) => feed.resync(&state).await,
+                Err(RecvError::Closed) => return,
+            },
+            _ = interval.tick() => {
+                let servers = server_info_message(&state, false).await.servers;
+                let mut present: HashSet<String> = HashSet::new();
+                for server in servers {
+                    present.insert(server.name.clone());
+                    // Metrics change on every measurement and reach the masters as broadcasts
+                    let status = serde_json::to_value(ServerInfo {
+                        metrics: None,
+                        ..server.clone()
+                    })
+                    .unwrap();
+                    if known.get(&server.name) != Some(&status) {
+                        known.insert(server.name.clone(), status);
+                        feed.publish(SlaveEvent {
+                            server: Some(server.clone()),
+                            ..event("status", &server.name)
+                        });
+                    }
+                }
+                known.retain(|name, _| {
+                    let keep = present.contains(name);
+                    if !keep {
+                        feed.publish(event("removed", name));
+                    }
+                    keep
+                });
+            }
+        }
+    }
+}
 
 /// Builds the Axum router for the slave node web server.
 ///
@@ -17,10 +199,154 @@ use crate::{app_state::AppState, websocket::handle_ws_upgrade};
 /// # Returns
 /// * `Router<AppState>` with the websocket route registered.
 async fn get_router(_state: AppState) -> Router<AppState> {
-    let router: Router<AppState> = Router::new().route("/ws", get(handle_ws_upgrade));
+    let router: Router<AppState> = Router::new().route("/ws", get(handle_master_upgrade));
     router
 }
 
+/// Generates the shared secret masters use to connect, if the configuration has none yet.
+///
+/// The new secret is saved to `config.json` and printed once to standard error so it can be
+/// copied to the master. It is kept out of the log so it doesn't end up in the log file.
+///
+/// # Arguments
+/// * `config` - The slave's configuration.
+pub fn ensure_secret(config: &mut Config) {
+    if config
+        .slave_secret
+        .as_ref()
+        .is_some_and(|secret| !secret.is_empty())
+    {
+        return;
+    }
+    let secret = random_token(32);
+    eprintln!(
+        "Generated a slave secret: {}. Set it as the \"secret\" of this slave in the master's slave_connections.",
+        secret
+    );
+    warn!("Generated a slave secret, it was printed to standard error and saved to config.json");
+    config.slave_secret = Some(secret);
+    config.update_config_file("config.json");
+}
+
+/// Lowercase hex HMAC-SHA256 of a challenge nonce, keyed with the shared secret.
+pub fn sign_challenge(secret: &str, nonce: &str) -> String {
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, secret.as_bytes());
+    ring::hmac::sign(&key, nonce.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Checks a master's answer to a challenge in constant time.
+fn verify_challenge(secret: &str, nonce: &str, answer: &str) -> bool {
+    let Some(tag) = decode_hex(answer) else {
+        return false;
+    };
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, secret.as_bytes());
+    ring::hmac::verify(&key, nonce.as_bytes(), &tag).is_ok()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    // from_str_radix would also take a sign
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Runs the challenge on a freshly opened connection.
+///
+/// # Returns
+/// * `Err` describing why the peer was rejected.
+async fn authenticate_master(socket: &mut WebSocket, secret: &str) -> Result<(), String> {
+    if secret.is_empty() {
+        return Err("no slave_secret is configured".to_string());
+    }
+    let nonce = random_token(32);
+    let challenge = SlaveAuthChallenge {
+        r#type: "AuthChallenge".to_owned(),
+        nonce: nonce.clone(),
+    };
+    socket
+        .send(Message::Text(
+            serde_json::to_string(&challenge).unwrap().into(),
+        ))
+        .await
+        .map_err(|e| e.to_string())?;
+    let answer = loop {
+        match socket.recv().await {
+            Some(Ok(Message::Text(text))) => break text.to_string(),
+            Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+            Some(Ok(_)) => return Err("expected an authResponse message".to_string()),
+            Some(Err(e)) => return Err(e.to_string()),
+            None => return Err("connection closed during the handshake".to_string()),
+        }
+    };
+    let success = serde_json::from_str::<SlaveAuthResponse>(&answer)
+        .is_ok_and(|response| verify_challenge(secret, &nonce, &response.hmac));
+    let result = SlaveAuthResult {
+        r#type: "AuthResult".to_owned(),
+        success,
+    };
+    let _ = socket
+        .send(Message::Text(
+            serde_json::to_string(&result).unwrap().into(),
+        ))
+        .await;
+    if success {
+        Ok(())
+    } else {
+        Err("wrong secret".to_string())
+    }
+}
+
+/// Accepts a websocket connection from a master, handing it to the regular websocket handler
+/// once it has passed the challenge.
+///
+/// # Arguments
+/// * `ws` - The websocket upgrade request.
+/// * `state` - The shared application state.
+/// * `address` - Where the peer connected from.
+async fn handle_master_upgrade(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+) -> Response {
+    ws.on_upgrade(move |mut socket| async move {
+        let secret = state
+            .config
+            .lock()
+            .await
+            .slave_secret
+            .clone()
+            .unwrap_or_default();
+        match tokio::time::timeout(HANDSHAKE_TIMEOUT, authenticate_master(&mut socket, &secret))
+            .await
+        {
+            Ok(Ok(())) => {
+                info!("Master connected from {}", address);
+                let events = state.slave_events.tx.subscribe();
+                handle_socket(socket, state, Access::unrestricted(), address, events, true).await;
+            }
+            Ok(Err(e)) => {
+                warn!("Rejected connection from {}: {}", address, e);
+                let _ = socket.send(Message::Close(None)).await;
+            }
+            Err(_) => {
+                warn!(
+                    "Rejected connection from {}: no answer to the challenge",
+                    address
+                );
+                let _ = socket.send(Message::Close(None)).await;
+            }
+        }
+    })
+}
+
 /// Starts the Axum web server for the slave node.
 ///
 /// Binds to the configured address and serves the websocket API for master-slave communication.
@@ -32,15 +358,64 @@ pub async fn start_slave(_state: AppState) {
     let config = _state.config.lock().await;
     let mut address = config.interface.clone();
     address += (":".to_owned() + config.port.clone().as_str()).as_str();
+    let tls = config.tls.clone();
     drop(config);
-    info!("Starting server on {}", address.replace("0.0.0.0", "*"));
+    info!(
+        "Starting server on {}://{}",
+        if tls.enabled { "wss" } else { "ws" },
+        address.replace("0.0.0.0", "*")
+    );
 
     let stateful_router = router.with_state(_state);
-    use axum::serve;
-    use tokio::net::TcpListener;
+    crate::tls::serve(address, stateful_router, tls).await;
+}
 
-    let listener = TcpListener::bind(&address).await.unwrap();
-    serve(listener, stateful_router.into_make_service())
-        .await
-        .unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign_challenge_is_hex_hmac_sha256() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            sign_challenge("Jefe", "what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn verify_challenge_accepts_the_signed_answer() {
+        let answer = sign_challenge("secret", "nonce");
+        assert!(verify_challenge("secret", "nonce", &answer));
+        assert!(verify_challenge(
+            "secret",
+            "nonce",
+            &answer.to_ascii_uppercase()
+        ));
+    }
+
+    #[test]
+    fn verify_challenge_rejects_wrong_answers() {
+        let answer = sign_challenge("secret", "nonce");
+        assert!(!verify_challenge("other secret", "nonce", &answer));
+        assert!(!verify_challenge("secret", "other nonce", &answer));
+        assert!(!verify_challenge("secret", "nonce", &answer[..62]));
+        assert!(!verify_challenge("secret", "nonce", &answer[..63]));
+        assert!(!verify_challenge("secret", "nonce", ""));
+
+        let mut tampered = answer.into_bytes();
+        tampered[0] = if tampered[0] == b'0' { b'1' } else { b'0' };
+        let tampered = String::from_utf8(tampered).unwrap();
+        assert!(!verify_challenge("secret", "nonce", &tampered));
+    }
+
+    #[test]
+    fn decode_hex_reads_pairs_of_digits() {
+        assert_eq!(decode_hex(""), Some(vec![]));
+        assert_eq!(decode_hex("00ff7A"), Some(vec![0x00, 0xff, 0x7a]));
+        assert_eq!(decode_hex("abc"), None);
+        assert_eq!(decode_hex("zz"), None);
+        assert_eq!(decode_hex("+1"), None);
+        assert_eq!(decode_hex("é0"), None);
+    }
 }
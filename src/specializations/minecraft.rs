@@ -1,7 +1,7 @@
 use super::ServerSpecialization;
 use crate::ansi_to_html::escape_html;
 use crate::app_state::AppState;
-use crate::controlled_program::ControlledProgramInstance;
+use crate::controlled_program::{ControlledProgramInstance, OutputStream};
 use regex::Regex;
 use serde_json::json;
 use std::path::Path;
@@ -101,6 +101,8 @@ impl ServerSpecialization for MinecraftSpecialization {
 
         line: String,
 
+        _stream: OutputStream,
+
         _instance: &mut ControlledProgramInstance,
     ) -> Option<String> {
         // Player join regex
@@ -211,21 +213,56 @@ impl ServerSpecialization for MinecraftSpecialization {
                     .tx
                     .send(serde_json::to_string(&eula_console_msg).unwrap());
 
-                // Restart the server
-                let mut desc = crate::controlled_program::ControlledProgramDescriptor::new(
-                    &name,
-                    &exe_path,
-                    args,
-                    working_dir,
-                );
-                desc.specialized_server_type = specialized_server_type;
-                desc.crash_prevention = crash_prevention;
+                // Restart the server from its configuration, so its user, limits and
+                // environment carry over
+                let configured = state
+                    .config
+                    .lock()
+                    .await
+                    .servers
+                    .iter()
+                    .find(|desc| desc.name == name)
+                    .cloned();
+                let desc = configured.unwrap_or_else(|| {
+                    let mut desc = crate::controlled_program::ControlledProgramDescriptor::new(
+                        &name,
+                        &exe_path,
+                        args,
+                        working_dir,
+                    );
+                    desc.specialized_server_type = specialized_server_type.clone();
+                    desc.crash_prevention = crash_prevention;
+                    desc
+                });
                 let mut servers = state.servers.lock().await;
-                servers.push(desc.into_instance(&state.specialization_registry));
+                match desc.into_instance(&state.specialization_registry) {
+                    Ok(instance) => servers.push(instance),
+                    Err(e) => crate::servers::report_start_failure(
+                        &state,
+                        &name,
+                        specialized_server_type,
+                        &e,
+                    ),
+                }
             }
         });
     }
 
+    /// Minecraft saves all worlds and exits on `stop`.
+    fn stop_command(&self) -> Option<String> {
+        Some("stop".to_string())
+    }
+
+    /// Turns autosave off and flushes every world to disk so the backup sees a consistent copy.
+    fn pre_backup_commands(&self) -> Vec<String> {
+        vec!["save-off".to_string(), "save-all flush".to_string()]
+    }
+
+    /// Turns autosave back on after the backup.
+    fn post_backup_commands(&self) -> Vec<String> {
+        vec!["save-on".to_string()]
+    }
+
     /// Returns the current status for this specialization.
     ///
     /// For Minecraft, this should return the current specialized_server_info if available.
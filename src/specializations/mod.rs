@@ -8,7 +8,7 @@ pub mod minecraft;
 pub mod terraria;
 pub mod vintage_story;
 
-use crate::controlled_program::ControlledProgramInstance;
+use crate::controlled_program::{ControlledProgramInstance, OutputStream};
 use dashmap::DashMap;
 use std::sync::Arc;
 
@@ -46,11 +46,13 @@ pub trait ServerSpecialization: Send + Sync {
 
     /// Called for each output line from the server process.
     ///
-    /// Takes ownership of the log line. Return `Some(String)` to transform the line,
+    /// Takes ownership of the log line. `stream` tells whether the line was written to
+    /// stdout or stderr. Return `Some(String)` to transform the line,
     /// or `None` to omit it from output.
     fn parse_output(
         &mut self,
         line: String,
+        stream: OutputStream,
         instance: &mut ControlledProgramInstance,
     ) -> Option<String>;
 
@@ -67,6 +69,30 @@ pub trait ServerSpecialization: Send + Sync {
         // Default: do nothing
     }
 
+    /// Returns the console command that asks the server to shut down cleanly.
+    ///
+    /// Written to the server's stdin when it is stopped, before any signals are sent.
+    /// Return `None` to go straight to SIGTERM. Default implementation returns `None`.
+    fn stop_command(&self) -> Option<String> {
+        None
+    }
+
+    /// Returns console commands to run before a backup of the server's files is taken.
+    ///
+    /// Use this to flush pending saves and pause autosaving so the files are consistent
+    /// while they are archived. Default implementation returns no commands.
+    fn pre_backup_commands(&self) -> Vec<String> {
+        vec![]
+    }
+
+    /// Returns console commands to run once a backup has finished, successful or not.
+    ///
+    /// Use this to undo whatever `pre_backup_commands` paused. Default implementation returns
+    /// no commands.
+    fn post_backup_commands(&self) -> Vec<String> {
+        vec![]
+    }
+
     /// Returns the current status/info for this specialization.
     ///
     /// By convention, status is usually stored in the instance's `specialized_server_info`.
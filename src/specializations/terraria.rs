@@ -1,5 +1,5 @@
 use super::ServerSpecialization;
-use crate::controlled_program::ControlledProgramInstance;
+use crate::controlled_program::{ControlledProgramInstance, OutputStream};
 use serde_json::{json, Value};
 
 /// Specialization for Terraria servers.
@@ -39,6 +39,7 @@ impl ServerSpecialization for TerrariaSpecialization {
     fn parse_output(
         &mut self,
         line: String,
+        _stream: OutputStream,
         instance: &mut ControlledProgramInstance,
     ) -> Option<String> {
         // Parse Terraria server output lines to update state.
@@ -55,6 +56,11 @@ impl ServerSpecialization for TerrariaSpecialization {
         self.last_status_update = false;
     }
 
+    /// Terraria's `exit` command saves the world before shutting down.
+    fn stop_command(&self) -> Option<String> {
+        Some("exit".to_string())
+    }
+
     /// Returns the current status for this specialization.
 
     ///
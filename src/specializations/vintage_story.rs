@@ -1,4 +1,7 @@
-use crate::{controlled_program::ControlledProgramInstance, specializations::ServerSpecialization};
+use crate::{
+    controlled_program::{ControlledProgramInstance, OutputStream},
+    specializations::ServerSpecialization,
+};
 use std::env;
 use std::path::{Path, PathBuf};
 
@@ -126,6 +129,7 @@ impl ServerSpecialization for VintageStoryServerSpecialization {
     fn parse_output(
         &mut self,
         line: String,
+        _stream: OutputStream,
         _instance: &mut ControlledProgramInstance,
     ) -> Option<String> {
         // Update player count and calendar paused state from log lines
@@ -193,6 +197,11 @@ impl ServerSpecialization for VintageStoryServerSpecialization {
     fn set_status_update_sent(&mut self) {
         self.last_status_update = false;
     }
+
+    /// Vintage Story saves the world and shuts down on `/stop`.
+    fn stop_command(&self) -> Option<String> {
+        Some("/stop".to_string())
+    }
 }
 
 // Colorize a single Vintage Story log line using theme colors
@@ -0,0 +1,408 @@
+/// TLS for the web UI and slave listeners.
+///
+/// When the `tls` object of the configuration is enabled, both listeners serve HTTPS and `wss://`
+/// with the certificate and key at the configured paths. The files are checked for changes
+/// every few seconds and reloaded in place, so a renewed certificate is picked up without a
+/// restart. If the files don't exist yet a self-signed certificate can be generated for them.
+///
+/// Masters can pin a slave's certificate by its SHA-256 fingerprint, which the slave logs when
+/// it starts, instead of trusting it through a certificate authority.
+use axum::Router;
+use axum_server::tls_rustls::RustlsConfig;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    net::{SocketAddr, ToSocketAddrs},
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tracing::*;
+
+/// TLS settings for the listeners, stored in `Config`.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct TlsSettings {
+    /// Whether the listeners use TLS at all.
+    #[serde(default)]
+    pub enabled: bool,
+    /// PEM file holding the certificate chain, leaf certificate first.
+    #[serde(default = "default_cert_path")]
+    pub cert_path: String,
+    /// PEM file holding the private key.
+    #[serde(default = "default_key_path")]
+    pub key_path: String,
+    /// Whether to generate a self-signed certificate when the files don't exist.
+    #[serde(default = "default_generate_self_signed")]
+    pub generate_self_signed: bool,
+    /// Host names a generated certificate is valid for.
+    #[serde(default = "default_self_signed_names")]
+    pub self_signed_names: Vec<String>,
+    /// How often the files are checked for changes.
+    #[serde(default = "default_reload_interval_secs")]
+    pub reload_interval_secs: u64,
+}
+
+fn default_cert_path() -> String {
+    "tls/cert.pem".to_string()
+}
+
+fn default_key_path() -> String {
+    "tls/key.pem".to_string()
+}
+
+fn default_generate_self_signed() -> bool {
+    true
+}
+
+fn default_self_signed_names() -> Vec<String> {
+    vec!["localhost".to_string()]
+}
+
+fn default_reload_interval_secs() -> u64 {
+    30
+}
+
+impl Default for TlsSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            cert_path: default_cert_path(),
+            key_path: default_key_path(),
+            generate_self_signed: default_generate_self_signed(),
+            self_signed_names: default_self_signed_names(),
+            reload_interval_secs: default_reload_interval_secs(),
+        }
+    }
+}
+
+/// Selects the crypto provider used for every TLS connection, both served and to slaves.
+///
+/// ring is the only provider compiled in; call this once before any TLS is set up.
+pub fn install_crypto_provider() {
+    let _ = rustls::crypto::ring::default_provider().install_default();
+}
+
+/// Builds a client configuration that trusts only the certificates in a PEM file, such as a
+/// slave's self-signed certificate.
+///
+/// # Arguments
+/// * `ca_cert` - Path of the PEM file to trust.
+pub fn client_config_trusting(ca_cert: &str) -> Result<rustls::ClientConfig, String> {
+    use rustls::pki_types::{pem::PemObject, CertificateDer};
+
+    let mut roots = rustls::RootCertStore::empty();
+    let certs = CertificateDer::pem_file_iter(ca_cert)
+        .map_err(|e| format!("Could not read '{}': {}", ca_cert, e))?;
+    for cert in certs {
+        let cert = cert.map_err(|e| format!("Could not parse '{}': {}", ca_cert, e))?;
+        roots
+            .add(cert)
+            .map_err(|e| format!("Could not trust '{}': {}", ca_cert, e))?;
+    }
+    Ok(rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth())
+}
+
+/// Hex SHA-256 fingerprint of a DER certificate, as used for pinning.
+pub fn fingerprint(der: &[u8]) -> String {
+    ring::digest::digest(&ring::digest::SHA256, der)
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Fingerprint of the first certificate in a PEM file.
+pub fn certificate_fingerprint(cert_path: &str) -> Result<String, String> {
+    use rustls::pki_types::{pem::PemObject, CertificateDer};
+
+    CertificateDer::from_pem_file(cert_path)
+        .map(|cert| fingerprint(&cert))
+        .map_err(|e| format!("Could not read '{}': {}", cert_path, e))
+}
+
+/// Brings a fingerprint written as `AB:CD:...` or `abcd...` to the form [`fingerprint`] returns.
+fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint
+        .chars()
+        .filter(|c| c.is_ascii_hexdigit())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+/// Accepts exactly one server certificate, identified by its fingerprint, whoever issued it and
+/// whatever names it is for.
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    fingerprint: String,
+    provider: Arc<rustls::crypto::CryptoProvider>,
+}
+
+impl rustls::client::danger::ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::pki_types::CertificateDer<'_>,
+        _intermediates: &[rustls::pki_types::CertificateDer<'_>],
+        _server_name: &rustls::pki_types::ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: rustls::pki_types::UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        if fingerprint(end_entity) == self.fingerprint {
+            Ok(rustls::client::danger::ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "certificate does not match the pinned fingerprint".to_string(),
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// Builds a client configuration that only accepts the certificate with the given fingerprint.
+///
+/// # Arguments
+/// * `fingerprint` - Hex SHA-256 fingerprint of the certificate, with or without colons.
+pub fn client_config_pinned(fingerprint: &str) -> rustls::ClientConfig {
+    let verifier = PinnedCertVerifier {
+        fingerprint: normalize_fingerprint(fingerprint),
+        provider: Arc::new(rustls::crypto::ring::default_provider()),
+    };
+    rustls::ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth()
+}
+
+/// Makes sure the certificate and key exist when TLS is enabled, generating a self-signed pair
+/// if allowed.
+///
+/// # Returns
+/// * `Err` describing what is missing or why the certificate couldn't be written.
+pub fn ensure_certificate(settings: &TlsSettings) -> Result<(), String> {
+    if !settings.enabled {
+        return Ok(());
+    }
+    let cert_exists = Path::new(&settings.cert_path).exists();
+    let key_exists = Path::new(&settings.key_path).exists();
+    if cert_exists && key_exists {
+        return Ok(());
+    }
+    if !settings.generate_self_signed || cert_exists || key_exists {
+        return Err(format!(
+            "TLS is enabled but '{}' or '{}' does not exist",
+            settings.cert_path, settings.key_path
+        ));
+    }
+    let certified = rcgen::generate_simple_self_signed(settings.self_signed_names.clone())
+        .map_err(|e| format!("Could not generate a self-signed certificate: {}", e))?;
+    write_pem(&settings.cert_path, &certified.cert.pem(), false)?;
+    write_pem(
+        &settings.key_path,
+        &certified.key_pair.serialize_pem(),
+        true,
+    )?;
+    warn!(
+        "Generated a self-signed certificate for {} at '{}'. Browsers will warn about it until it is replaced with a trusted one.",
+        settings.self_signed_names.join(", "),
+        settings.cert_path
+    );
+    Ok(())
+}
+
+/// Writes a PEM file, creating its folder and keeping private keys readable by the owner only.
+fn write_pem(path: &str, contents: &str, private: bool) -> Result<(), String> {
+    let path = Path::new(path);
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    fs::write(path, contents)
+        .map_err(|e| format!("Could not write '{}': {}", path.display(), e))?;
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600)).map_err(|e| e.to_string())?;
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+    Ok(())
+}
+
+/// When the certificate and key were last changed.
+fn modified(settings: &TlsSettings) -> Option<(SystemTime, SystemTime)> {
+    let cert = fs::metadata(&settings.cert_path).ok()?.modified().ok()?;
+    let key = fs::metadata(&settings.key_path).ok()?.modified().ok()?;
+    Some((cert, key))
+}
+
+/// Reloads the certificate whenever its files change.
+///
+/// # Arguments
+/// * `config` - The TLS configuration the listener is serving with.
+/// * `settings` - Where the files are and how often to check them.
+async fn watch_certificate(config: RustlsConfig, settings: TlsSettings) {
+    let mut last_modified = modified(&settings);
+    let mut interval =
+        tokio::time::interval(Duration::from_secs(settings.reload_interval_secs.max(1)));
+    loop {
+        interval.tick().await;
+        let current = modified(&settings);
+        if current.is_none() || current == last_modified {
+            continue;
+        }
+        // Only remember the change once it loads, so a half-written pair is retried
+        match config
+            .reload_from_pem_file(&settings.cert_path, &settings.key_path)
+            .await
+        {
+            Ok(()) => {
+                info!("Reloaded TLS certificate from '{}'", settings.cert_path);
+                log_fingerprint(&settings);
+                last_modified = current;
+            }
+            Err(e) => warn!("Could not reload TLS certificate: {}", e),
+        }
+    }
+}
+
+/// Logs the fingerprint of the served certificate, for masters that pin it.
+fn log_fingerprint(settings: &TlsSettings) {
+    match certificate_fingerprint(&settings.cert_path) {
+        Ok(fingerprint) => info!("TLS certificate SHA-256 fingerprint: {}", fingerprint),
+        Err(e) => warn!("{}", e),
+    }
+}
+
+/// Serves a router on an address, over TLS if it is enabled.
+///
+/// # Arguments
+/// * `address` - The `interface:port` to listen on.
+/// * `router` - The router to serve, with its state already applied.
+/// * `settings` - The TLS settings from the configuration.
+pub async fn serve(address: String, router: Router, settings: TlsSettings) {
+    let service = router.into_make_service_with_connect_info::<SocketAddr>();
+    if !settings.enabled {
+        let listener = tokio::net::TcpListener::bind(&address).await.unwrap();
+        axum::serve(listener, service).await.unwrap();
+        return;
+    }
+    let config = RustlsConfig::from_pem_file(&settings.cert_path, &settings.key_path)
+        .await
+        .expect("Failed to load TLS certificate");
+    log_fingerprint(&settings);
+    let addr = address
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .expect("Failed to resolve listen address");
+    tokio::spawn(watch_certificate(config.clone(), settings));
+    axum_server::bind_rustls(addr, config)
+        .serve(service)
+        .await
+        .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::{
+        client::danger::ServerCertVerifier,
+        pki_types::{CertificateDer, ServerName, UnixTime},
+    };
+
+    fn certificate() -> CertificateDer<'static> {
+        rcgen::generate_simple_self_signed(vec!["localhost".to_string()])
+            .unwrap()
+            .cert
+            .der()
+            .clone()
+    }
+
+    fn verify(verifier: &PinnedCertVerifier, cert: &CertificateDer<'_>) -> bool {
+        verifier
+            .verify_server_cert(
+                cert,
+                &[],
+                &ServerName::try_from("localhost").unwrap(),
+                &[],
+                UnixTime::now(),
+            )
+            .is_ok()
+    }
+
+    fn pinned(fingerprint: &str) -> PinnedCertVerifier {
+        PinnedCertVerifier {
+            fingerprint: normalize_fingerprint(fingerprint),
+            provider: Arc::new(rustls::crypto::ring::default_provider()),
+        }
+    }
+
+    #[test]
+    fn normalize_fingerprint_accepts_colons_and_uppercase() {
+        assert_eq!(normalize_fingerprint("AB:cd:0F"), "abcd0f");
+        assert_eq!(normalize_fingerprint(" ab cd\n"), "abcd");
+
+        let cert = certificate();
+        let colons = fingerprint(&cert)
+            .to_ascii_uppercase()
+            .as_bytes()
+            .chunks(2)
+            .map(|pair| std::str::from_utf8(pair).unwrap())
+            .collect::<Vec<_>>()
+            .join(":");
+        assert_eq!(normalize_fingerprint(&colons), fingerprint(&cert));
+    }
+
+    #[test]
+    fn pinned_verifier_accepts_only_the_pinned_certificate() {
+        let cert = certificate();
+        let other = certificate();
+        let verifier = pinned(&fingerprint(&cert).to_ascii_uppercase());
+        assert!(verify(&verifier, &cert));
+        assert!(!verify(&verifier, &other));
+    }
+
+    #[test]
+    fn pinned_verifier_rejects_a_malformed_pin() {
+        let cert = certificate();
+        assert!(!verify(&pinned(""), &cert));
+        assert!(!verify(&pinned(&fingerprint(&cert)[..62]), &cert));
+    }
+}
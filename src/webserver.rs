@@ -5,18 +5,21 @@
 use crate::websocket::*;
 use axum::{
     body::Body,
-    extract::State,
-    http::StatusCode,
+    extract::{Path, Request, State},
+    http::{header, HeaderValue, StatusCode},
+    middleware,
     response::{Html, IntoResponse, Response},
     routing::get,
-    Router,
+    Extension, Router,
 };
 use axum_extra::response::JavaScript;
 
-use tower_http::services::ServeDir;
+use tower::ServiceExt;
+use tower_http::services::{ServeDir, ServeFile};
 use tracing::*;
 
 use crate::app_state::AppState;
+use crate::permissions::{Access, Permission};
 
 /// Serves the main JavaScript file for the web UI.
 ///
@@ -42,7 +45,18 @@ async fn get_router(_state: AppState) -> Router<AppState> {
         .route("/index.js", get(js_serve))
         .route("/msgpack.min.js", get(msgpack_serve))
         .route("/ws", get(handle_ws_upgrade))
-        .route("/favicon.ico", get(handle_icon));
+        .nest("/api", crate::rest_api::router())
+        .route("/backups/{server}/{file}", get(download_backup))
+        .route("/favicon.ico", get(handle_icon))
+        .route("/login", get(login_serve))
+        .route(
+            crate::prometheus::METRICS_PATH,
+            get(crate::prometheus::serve_metrics),
+        )
+        .layer(middleware::from_fn_with_state(
+            _state.clone(),
+            crate::auth::require_auth,
+        ));
     router
 }
 /// Serves the favicon for the web UI.
@@ -58,6 +72,43 @@ async fn handle_icon(State(_state): State<AppState>) -> impl IntoResponse {
         .unwrap()
 }
 
+/// Serves a backup archive as a download.
+///
+/// # Arguments
+/// * `state` - The shared application state.
+/// * `access` - What the client is allowed to do.
+/// * `server` - The server the backup belongs to.
+/// * `file` - File name of the archive.
+async fn download_backup(
+    State(state): State<AppState>,
+    Extension(access): Extension<Access>,
+    Path((server, file)): Path<(String, String)>,
+    request: Request,
+) -> Response {
+    if !access.allows(Permission::ManageBackups, &server) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    let Some(path) = crate::backup::backup_path(&state, &server, &file).await else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    match ServeFile::new(path).oneshot(request).await {
+        Ok(response) => {
+            let mut response = response.map(Body::new);
+            if let Ok(value) = HeaderValue::from_str(&format!("attachment; filename=\"{}\"", file))
+            {
+                response
+                    .headers_mut()
+                    .insert(header::CONTENT_DISPOSITION, value);
+            }
+            response
+        }
+        Err(e) => {
+            warn!("Failed to serve backup '{}': {}", file, e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
 /// Serves the msgpack.min.js file for the web UI.
 async fn msgpack_serve(State(_state): State<AppState>) -> Response {
     let js_bytes: &'static [u8] = include_bytes!("html_src/msgpack.min.js");
@@ -75,22 +126,29 @@ async fn msgpack_serve(State(_state): State<AppState>) -> Response {
 /// * `_state` - The shared application state.
 #[no_mangle]
 pub async fn start_web_server(_state: AppState) {
-    use axum::serve;
-    use tokio::net::TcpListener;
-
     let router = get_router(_state.clone()).await;
     let config = _state.config.lock().await;
     let mut address = config.interface.clone();
     address += (":".to_owned() + config.port.clone().as_str()).as_str();
+    let tls = config.tls.clone();
     drop(config);
-    info!("Starting server on {}", address.replace("0.0.0.0", "*"));
+    info!(
+        "Starting server on {}://{}",
+        if tls.enabled { "https" } else { "http" },
+        address.replace("0.0.0.0", "*")
+    );
 
     let stateful_router = router.with_state(_state);
-    let listener = TcpListener::bind(&address).await.unwrap();
-    serve(listener, stateful_router.into_make_service())
-        .await
-        .unwrap();
+    crate::tls::serve(address, stateful_router, tls).await;
 }
+/// Serves the login page.
+///
+/// # Arguments
+/// * `_state` - The shared application state (unused).
+async fn login_serve(State(_state): State<AppState>) -> Html<&'static str> {
+    Html(include_str!("html_src/login.html"))
+}
+
 /// Serves the main HTML page for the web UI, inlining the CSS.
 ///
 /// # Arguments
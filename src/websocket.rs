@@ -2,17 +2,17 @@
 ///
 /// Provides websocket upgrade, message processing, and helpers for communication
 /// between the web UI and the backend using [`AppState`].
-use crate::servers::format_exit_message;
 use axum::{
     extract::{
         ws::{Message, Utf8Bytes, WebSocket},
-        State, WebSocketUpgrade,
+        ConnectInfo, State, WebSocketUpgrade,
     },
     response::Response,
+    Extension,
 };
 use futures_util::{SinkExt, StreamExt};
 use serde::Deserialize;
-use tokio::io::AsyncWriteExt;
+use std::net::SocketAddr;
 use tracing::*;
 
 /// Converts a `String` to `Utf8Bytes` for axum WebSocket messages.
@@ -39,44 +39,550 @@ fn utf8bytes_to_string(bytes: Utf8Bytes) -> String {
 
 #[allow(unused_imports)]
 use crate::master::SlaveConnection;
+use crate::master::{self, SlaveConnectionDescriptor};
 #[allow(unused_imports)]
 use crate::servers::send_termination_message;
 use crate::{
-    app_state::AppState, configuration::Config, controlled_program::ControlledProgramDescriptor,
-    messages::*, theme::ThemeCollection,
+    app_state::AppState,
+    audit::{self, AuditEntry},
+    config_edit,
+    config_validation::{describe_errors, Diagnostic},
+    configuration::Config,
+    messages::*,
+    permissions::{Access, Permission},
+    servers,
+    theme::ThemeCollection,
 };
+
+/// Builds the `ServerInfo` list sent in reply to `requestInfo`.
+///
+/// Running servers come first, followed by configured servers that are not running, then
+/// the servers reported by slave nodes.
+///
+/// # Arguments
+/// * `state` - The shared application state.
+/// * `include_output` - Whether to include the recent console output of running servers.
+pub async fn server_info_message(state: &AppState, include_output: bool) -> ServerInfoMessage {
+    let servers = state.servers.lock().await;
+    let config = state.config.lock().await;
+    let trackers = state.restart_trackers.lock().await;
+    let metrics = state.metrics.lock().await;
+    let mut info = ServerInfoMessage {
+        r#type: "ServerInfo".to_owned(),
+        servers: vec![],
+        config: config.redacted(),
+    };
+    let restart_fields = |name: &str| {
+        trackers
+            .get(name)
+            .map(|tracker| {
+                (
+                    tracker.restart_count(),
+                    tracker.next_retry(),
+                    tracker.gave_up(),
+                )
+            })
+            .unwrap_or((0, None, false))
+    };
+    let mut used_names: Vec<String> = vec![];
+    for server in servers.iter() {
+        used_names.push(server.name.clone());
+        let specialized_info = if let Some(handler) = server.specialization_handler.as_ref() {
+            handler.get_status()
+        } else {
+            server
+                .specialized_server_info
+                .clone()
+                .unwrap_or(serde_json::Value::Null)
+        };
+        let (restart_count, next_retry, _) = restart_fields(&server.name);
+        let mut s_info = ServerInfo {
+            name: server.name.clone(),
+            output: "".to_owned(),
+            active: true,
+            specialization: server.specialized_server_type.clone(),
+            specialized_info: Some(specialized_info),
+            host: None,
+            restart_count,
+            next_retry,
+            offline: false,
+            restart_required: server.restart_required,
+            metrics: metrics.get(&server.name).cloned(),
+            started_at: Some(server.started_at.to_rfc3339()),
+            crash_loop: false,
+        };
+        if include_output {
+            let cl: String = server.curr_output_in_progress.clone();
+            let split: Vec<&str> = cl.split("\n").collect();
+            let mut inp = split.len();
+            if inp < 150 {
+                inp = 0;
+            } else {
+                inp -= 150;
+            }
+            s_info.output = split[inp..split.len()].join("\n");
+        }
+        info.servers.push(s_info);
+    }
+    for server_config in config.servers.iter() {
+        if !used_names.contains(&server_config.name) {
+            let (restart_count, next_retry, crash_loop) = restart_fields(&server_config.name);
+            info.servers.push(ServerInfo {
+                name: server_config.name.clone(),
+                output: "".to_owned(),
+                active: false,
+                specialization: server_config.specialized_server_type.clone(),
+                specialized_info: server_config.specialized_server_info.clone(),
+                host: None,
+                restart_count,
+                next_retry,
+                offline: false,
+                restart_required: false,
+                metrics: None,
+                started_at: None,
+                crash_loop,
+            })
+        }
+    }
+    // Servers on slave nodes, as last reported by them
+    for slave_server in state.slave_servers.lock().await.iter() {
+        let mut s_info = slave_server.clone();
+        if !include_output {
+            s_info.output = "".to_owned();
+        }
+        info.servers.push(s_info);
+    }
+    info
+}
+
+/// Collects every configured schedule along with when it fires next.
+///
+/// # Arguments
+/// * `state` - The shared application state.
+async fn schedules_list(state: &AppState) -> SchedulesList {
+    let configured: Vec<(String, crate::scheduler::ScheduleDescriptor)> = state
+        .config
+        .lock()
+        .await
+        .servers
+        .iter()
+        .flat_map(|desc| {
+            desc.schedules
+                .iter()
+                .map(move |schedule| (desc.name.clone(), schedule.clone()))
+        })
+        .collect();
+    let scheduler = state.scheduler.lock().await;
+    SchedulesList {
+        r#type: "SchedulesList".to_owned(),
+        schedules: configured
+            .into_iter()
+            .map(|(server_name, schedule)| ScheduleInfo {
+                next_run: scheduler
+                    .next_run(&server_name, &schedule.id)
+                    .map(|next_run| next_run.to_rfc3339()),
+                server_name,
+                schedule,
+            })
+            .collect(),
+    }
+}
+
+/// Broadcasts the backups of a server, or logs why they couldn't be listed.
+///
+/// # Arguments
+/// * `state` - The shared application state.
+/// * `server_name` - The server whose backups to list.
+async fn send_backups_list(state: &AppState, server_name: String) {
+    match crate::backup::backups_of(state, &server_name).await {
+        Ok(backups) => {
+            let list = BackupsList {
+                r#type: "BackupsList".to_owned(),
+                server_name,
+                backups,
+            };
+            let _ = state.tx.send(serde_json::to_string(&list).unwrap());
+        }
+        Err(e) => warn!("Could not list backups of '{}': {}", server_name, e),
+    }
+}
+
+/// Applies an `addSchedule` or `removeSchedule` request to the configuration and saves it.
+///
+/// # Arguments
+/// * `state` - The shared application state.
+/// * `server_name` - The server whose schedules are changed.
+/// * `change` - Edits the server's schedule list, or returns why it can't.
+async fn change_schedules<F>(state: &AppState, server_name: &str, change: F) -> Result<(), String>
+where
+    F: FnOnce(&mut Vec<crate::scheduler::ScheduleDescriptor>) -> Result<(), String>,
+{
+    let mut config = state.config.lock().await;
+    let desc = config
+        .servers
+        .iter_mut()
+        .find(|desc| desc.name == server_name)
+        .ok_or_else(|| format!("Server '{}' is not configured", server_name))?;
+    change(&mut desc.schedules)?;
+    config.update_config_file("config.json");
+    let config_info = ConfigInfo {
+        r#type: "ConfigInfo".to_owned(),
+        config: config.redacted(),
+    };
+    let _ = state.tx.send(serde_json::to_string(&config_info).unwrap());
+    Ok(())
+}
+
+/// Broadcasts why a configuration change was rejected; a successful change already broadcast
+/// the new configuration.
+///
+/// # Arguments
+/// * `state` - The shared application state.
+/// * `request` - The type of the request, such as "addServer".
+/// * `server_name` - The server the request was about, if any.
+/// * `result` - The outcome of the change.
+fn send_config_result(
+    state: &AppState,
+    request: &str,
+    server_name: Option<String>,
+    result: Result<(), Vec<Diagnostic>>,
+) {
+    if let Err(diagnostics) = result {
+        let error = describe_errors(&diagnostics);
+        warn!("{} rejected: {}", request, error);
+        let error = ConfigError {
+            r#type: "ConfigError".to_owned(),
+            request: request.to_owned(),
+            server_name,
+            error,
+            diagnostics,
+        };
+        let _ = state.tx.send(serde_json::to_string(&error).unwrap());
+    }
+}
+
+/// Broadcasts the outcome of a schedule change: the updated list, or why it was rejected.
+///
+/// # Arguments
+/// * `state` - The shared application state.
+/// * `server_name` - The server whose schedules were changed.
+/// * `result` - The outcome of the change.
+async fn send_schedule_result(state: &AppState, server_name: String, result: Result<(), String>) {
+    match result {
+        Ok(()) => {
+            let list = schedules_list(state).await;
+            let _ = state.tx.send(serde_json::to_string(&list).unwrap());
+        }
+        Err(error) => {
+            warn!("Schedule change rejected: {}", error);
+            let error = ScheduleError {
+                r#type: "ScheduleError".to_owned(),
+                server_name,
+                error,
+            };
+            let _ = state.tx.send(serde_json::to_string(&error).unwrap());
+        }
+    }
+}
+
+/// Number of history lines returned when a request does not set a limit.
+const DEFAULT_HISTORY_PAGE_LINES: usize = 200;
+
+/// Reads the page of console history asked for by a `requestHistory` message.
+///
+/// # Arguments
+/// * `text` - The request as a JSON string.
+/// * `state` - The shared application state.
+///
+/// # Returns
+/// * The `ConsoleHistory` reply as a JSON string, or `None` if the request was malformed.
+async fn console_history_page(text: &str, state: &AppState) -> Option<String> {
+    use crate::ansi_to_html::{ansi_to_html, escape_html};
+    use crate::console_history::{self, HistoryStream};
+    use crate::controlled_program::mark_stderr;
+    use chrono::DateTime;
+
+    let request: RequestHistory = match serde_json::from_str(text) {
+        Ok(request) => request,
+        Err(e) => {
+            debug!("Error parsing requestHistory message: {}", e);
+            return None;
+        }
+    };
+    let settings = state.config.lock().await.console_history.clone();
+    let server_name = request.server_name.clone();
+    let search = request.search.clone();
+    let limit = request.limit.unwrap_or(DEFAULT_HISTORY_PAGE_LINES);
+    let before = match request.before.as_deref().map(DateTime::parse_from_rfc3339) {
+        None => None,
+        Some(Ok(before)) => Some(before),
+        Some(Err(e)) => {
+            debug!("Invalid requestHistory cursor: {}", e);
+            return None;
+        }
+    };
+    let page = tokio::task::spawn_blocking(move || {
+        console_history::read_page(&settings, &server_name, before, limit, search.as_deref())
+    })
+    .await;
+    let page = match page {
+        Ok(Ok(page)) => page,
+        Ok(Err(e)) => {
+            warn!(
+                "Could not read console history for '{}': {}",
+                request.server_name, e
+            );
+            console_history::HistoryPage {
+                lines: vec![],
+                has_more: false,
+                next_before: None,
+            }
+        }
+        Err(e) => {
+            error!("Console history reader panicked: {}", e);
+            return None;
+        }
+    };
+    let lines = page
+        .lines
+        .into_iter()
+        .map(|line| HistoryLineInfo {
+            output: match line.stream {
+                HistoryStream::Out => ansi_to_html(&line.text),
+                HistoryStream::Err => mark_stderr(&ansi_to_html(&line.text)),
+                HistoryStream::Ctl => format!(
+                    "<span style=\"color: var(--warning, #FFA500);\">[{}]</span>",
+                    escape_html(&line.text)
+                ),
+            },
+            timestamp: line.timestamp,
+            stream: line.stream,
+        })
+        .collect();
+    let reply = ConsoleHistoryPage {
+        r#type: "ConsoleHistory".to_owned(),
+        server_name: request.server_name,
+        before: request.before,
+        search: request.search,
+        lines,
+        has_more: page.has_more,
+        next_before: page.next_before,
+    };
+    serde_json::to_string(&reply).ok()
+}
+
+/// Reads the page of the audit log asked for by a `requestAuditLog` message.
+///
+/// # Arguments
+/// * `text` - The request as a JSON string.
+/// * `state` - The shared application state.
+///
+/// # Returns
+/// * The `AuditLog` reply as a JSON string, or `None` if the request was malformed.
+async fn audit_log_page(text: &str, state: &AppState) -> Option<String> {
+    let request: RequestAuditLog = match serde_json::from_str(text) {
+        Ok(request) => request,
+        Err(e) => {
+            debug!("Error parsing requestAuditLog message: {}", e);
+            return None;
+        }
+    };
+    let page = audit::query(state, request.query.clone())
+        .await
+        .unwrap_or_else(|_| audit::AuditPage {
+            entries: vec![],
+            has_more: false,
+            next_before: None,
+        });
+    let reply = AuditLogPage {
+        r#type: "AuditLog".to_owned(),
+        query: request.query,
+        entries: page.entries,
+        has_more: page.has_more,
+        next_before: page.next_before,
+    };
+    serde_json::to_string(&reply).ok()
+}
+
+/// Checks whether a client may send a message, before it is handled.
+///
+/// Requests that only read data are let through here and trimmed down when the reply is sent.
+///
+/// # Arguments
+/// * `access` - What the client is allowed to do.
+/// * `text` - The message as a JSON string.
+/// * `state` - The shared application state.
+///
+/// # Returns
+/// * `Err` describing the missing permission if the message is refused.
+async fn authorize(access: &Access, text: &str, state: &AppState) -> Result<(), String> {
+    if !access.is_restricted() {
+        return Ok(());
+    }
+    let json: serde_json::Value =
+        serde_json::from_str(text).map_err(|_| "Malformed message".to_string())?;
+    let server_name = json["server_name"].as_str().unwrap_or_default();
+    match json["type"].as_str().unwrap_or_default() {
+        "requestHistory" => access.require(Permission::ViewConsole, server_name),
+        // The terminal size is shared by everyone watching, so changing it is like typing in it
+        "resizeConsole" => access.require(Permission::SendStdin, server_name),
+        "stdinInput" => {
+            let running = state
+                .servers
+                .lock()
+                .await
+                .iter()
+                .any(|server| server.name == server_name)
+                || state
+                    .slave_servers
+                    .lock()
+                    .await
+                    .iter()
+                    .any(|server| server.name == server_name && server.active);
+            // "start" on a stopped server starts it instead of being sent to it, and "stop"
+            // cancels its pending crash restart
+            if !running && matches!(json["value"].as_str(), Some("start" | "stop")) {
+                access.require(Permission::StartStop, server_name)
+            } else {
+                access.require(Permission::SendStdin, server_name)
+            }
+        }
+        "configChange" | "updateGlobalSettings" => {
+            access.require_everywhere(Permission::EditConfig)
+        }
+        "addServer" => access.require(
+            Permission::EditConfig,
+            json["server"]["name"].as_str().unwrap_or_default(),
+        ),
+        "removeServer" => access.require(Permission::EditConfig, server_name),
+        // Renaming a server needs the permission on its new name as well
+        "updateServer" => access
+            .require(Permission::EditConfig, server_name)
+            .and(access.require(
+                Permission::EditConfig,
+                json["server"]["name"].as_str().unwrap_or_default(),
+            )),
+        "addSchedule" | "removeSchedule" => access.require(Permission::EditConfig, server_name),
+        "triggerBackup" | "listBackups" | "restoreBackup" => {
+            access.require(Permission::ManageBackups, server_name)
+        }
+        "requestAuditLog" => access.require_everywhere(Permission::ViewAuditLog),
+        "serverAction" => match json["action"].as_str() {
+            Some("stdin") => access.require(Permission::SendStdin, server_name),
+            _ => access.require(Permission::StartStop, server_name),
+        },
+        _ => Ok(()),
+    }
+}
+
+/// Applies a client's permissions to an outgoing broadcast.
+///
+/// # Returns
+/// * The message to send, or `None` if the client may not see it.
+fn visible_to(access: &Access, message: String) -> Option<String> {
+    if !access.is_restricted() {
+        return Some(message);
+    }
+    let Ok(mut json) = serde_json::from_str::<serde_json::Value>(&message) else {
+        return Some(message);
+    };
+    access.filter_broadcast(&mut json).then(|| json.to_string())
+}
+
+/// Applies a client's permissions to a reply listing servers, removing those it may not see.
+fn trimmed_for(access: &Access, message: String) -> String {
+    visible_to(access, message).unwrap_or_default()
+}
+
+/// Tells a client its message was refused.
+async fn send_permission_denied<S>(sender: &tokio::sync::Mutex<S>, access: &Access, error: String)
+where
+    S: futures_util::Sink<Message> + Unpin,
+{
+    warn!("Refused message from '{}': {}", access.username(), error);
+    let denied = PermissionDenied {
+        r#type: "PermissionDenied".to_owned(),
+        error,
+    };
+    let msg = serde_json::to_string(&denied).unwrap();
+    let _ = sender
+        .lock()
+        .await
+        .send(Message::Text(string_to_utf8bytes(msg)))
+        .await;
+}
+
 /// Handles websocket upgrade requests from the web client.
 ///
 /// # Arguments
 /// * `ws` - The websocket upgrade request.
 /// * `state` - The shared application state.
+/// * `access` - What the logged-in user may do.
+/// * `address` - Where the client connected from.
 ///
 /// # Returns
 /// * `Response` that upgrades the connection to a websocket.
 #[no_mangle]
-pub async fn handle_ws_upgrade(ws: WebSocketUpgrade, State(state): State<AppState>) -> Response {
+pub async fn handle_ws_upgrade(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    Extension(access): Extension<Access>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+) -> Response {
     // println!("Handling a socket...");
-    ws.on_upgrade(move |socket| handle_socket(socket, state))
+    let broadcasts = state.tx.subscribe();
+    ws.on_upgrade(move |socket| handle_socket(socket, state, access, address, broadcasts, false))
 }
 /// Handles a websocket connection, spawning send and receive tasks.
 ///
 /// # Arguments
 /// * `socket` - The websocket connection.
 /// * `state` - The shared application state.
+/// * `access` - What the client may do and see.
+/// * `address` - Where the client connected from, for the audit log.
+/// * `rx` - What to send the client besides replies: the broadcasts for web clients, or the
+///   slave's events for a master.
+/// * `master_link` - Whether the client is a master that passed the slave's challenge. Only
+///   masters may `subscribe` to the unfiltered snapshot of every server.
 
-async fn handle_socket(socket: WebSocket, state: AppState) {
-    use std::sync::Arc;
-    use tokio::sync::Mutex;
+pub async fn handle_socket(
+    socket: WebSocket,
+    state: AppState,
+    access: Access,
+    address: SocketAddr,
+    mut rx: tokio::sync::broadcast::Receiver<String>,
+    master_link: bool,
+) {
+    use std::sync::{atomic::Ordering, Arc};
+    use tokio::sync::{broadcast::error::RecvError, Mutex};
 
+    let clients = state.websocket_clients.clone();
+    clients.fetch_add(1, Ordering::Relaxed);
     let (sender, mut reciever) = socket.split();
     let sender = Arc::new(Mutex::new(sender));
-    let mut rx = state.tx.subscribe();
 
     // Send task: send MessagePack binary for all except config (which is JSON/text)
     let send_task_handle = {
         let sender = sender.clone();
+        let access = access.clone();
+        let state = state.clone();
         async move {
-            while let Ok(val) = rx.recv().await {
+            loop {
+                let val = match rx.recv().await {
+                    Ok(val) => val,
+                    // A master that fell behind is sent a fresh snapshot to resynchronize from,
+                    // and the events it still gets that the snapshot covers are skipped by it
+                    Err(RecvError::Lagged(_)) if master_link => {
+                        serde_json::to_string(&crate::slave::snapshot(&state).await).unwrap()
+                    }
+                    // Web clients catch up with the next broadcasts
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                };
+                // Leave out servers this client may not see
+                let Some(val) = visible_to(&access, val) else {
+                    continue;
+                };
                 if val.trim_start().starts_with('{') && val.contains("\"type\":\"ConfigInfo\"") {
                     let _ = sender
                         .lock()
@@ -123,86 +629,38 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
                 match msg {
                     Ok(Message::Text(text)) => {
                         let text_str = utf8bytes_to_string(text);
+                        if let Err(error) = authorize(&access, &text_str, &state).await {
+                            send_permission_denied(&sender, &access, error).await;
+                            continue;
+                        }
+                        if let Some(action) = server_action_from(&text_str, &state).await {
+                            spawn_server_action(
+                                action,
+                                state.clone(),
+                                access.clone(),
+                                address,
+                                sender.clone(),
+                                false,
+                            );
+                            continue;
+                        }
                         if let Ok(json) = serde_json::from_str::<serde_json::Value>(&text_str) {
                             if let Some(ev_type) = json.get("type").and_then(|v| v.as_str()) {
                                 match ev_type {
                                     "requestInfo" => {
                                         // Compose ServerInfoMessage for this client only
-                                        let servers = state.servers.lock().await;
-                                        let val = if let Ok(v) =
+                                        let include_output =
                                             serde_json::from_str::<SInfoRequestMessage>(&text_str)
-                                        {
-                                            v
-                                        } else {
-                                            SInfoRequestMessage {
-                                                r#type: "requestInfo".to_owned(),
-                                                arguments: vec![true],
-                                            }
-                                        };
-                                        let config = state.config.lock().await;
-                                        let mut info = ServerInfoMessage {
-                                            r#type: "ServerInfo".to_owned(),
-                                            servers: vec![],
-                                            config: config.clone(),
-                                        };
-                                        drop(config);
-                                        let mut used_names: Vec<String> = vec![];
-                                        for server in servers.iter() {
-                                            used_names.push(server.name.clone());
-                                            let specialized_info = if let Some(handler) =
-                                                server.specialization_handler.as_ref()
-                                            {
-                                                handler.get_status()
-                                            } else {
-                                                server
-                                                    .specialized_server_info
-                                                    .clone()
-                                                    .unwrap_or(serde_json::Value::Null)
-                                            };
-                                            let mut s_info = ServerInfo {
-                                                name: server.name.clone(),
-                                                output: "".to_owned(),
-                                                active: true,
-                                                specialization: server
-                                                    .specialized_server_type
-                                                    .clone(),
-                                                specialized_info: Some(specialized_info),
-                                                host: None,
-                                            };
-                                            if val.arguments.first().copied().unwrap_or(false) {
-                                                let cl: String =
-                                                    server.curr_output_in_progress.clone();
-                                                let split: Vec<&str> = cl.split("\n").collect();
-                                                let mut inp = split.len();
-                                                if inp < 150 {
-                                                    inp = 0;
-                                                } else {
-                                                    inp -= 150;
-                                                }
-                                                s_info.output = split[inp..split.len()].join("\n");
-                                            }
-                                            info.servers.push(s_info);
-                                        }
-                                        drop(servers);
-                                        let config = state.config.lock().await;
-                                        for server_config in config.servers.iter() {
-                                            if !used_names.contains(&server_config.name) {
-                                                info.servers.push(ServerInfo {
-                                                    name: server_config.name.clone(),
-                                                    output: "".to_owned(),
-                                                    active: false,
-                                                    specialization: server_config
-                                                        .specialized_server_type
-                                                        .clone(),
-                                                    specialized_info: server_config
-                                                        .specialized_server_info
-                                                        .clone(),
-                                                    host: None,
+                                                .map(|v| {
+                                                    v.arguments.first().copied().unwrap_or(false)
                                                 })
-                                            }
-                                        }
-                                        drop(config);
-                                        let msg = serde_json::to_string(&info).unwrap();
+                                                .unwrap_or(true);
+                                        let info =
+                                            server_info_message(&state, include_output).await;
+                                        let msg = trimmed_for(
+                                            &access,
+                                            serde_json::to_string(&info).unwrap(),
+                                        );
                                         let _ = sender
                                             .lock()
                                             .await
@@ -215,9 +673,12 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
                                         let config = state.config.lock().await;
                                         let config_info = ConfigInfo {
                                             r#type: "ConfigInfo".to_owned(),
-                                            config: config.clone(),
+                                            config: config.redacted(),
                                         };
-                                        let msg = serde_json::to_string(&config_info).unwrap();
+                                        let msg = trimmed_for(
+                                            &access,
+                                            serde_json::to_string(&config_info).unwrap(),
+                                        );
                                         let _ = sender
                                             .lock()
                                             .await
@@ -230,9 +691,12 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
                                         let config = state.config.lock().await;
                                         let config_info = ConfigInfo {
                                             r#type: "ConfigInfo".to_owned(),
-                                            config: config.clone(),
+                                            config: config.redacted(),
                                         };
-                                        let msg = serde_json::to_string(&config_info).unwrap();
+                                        let msg = trimmed_for(
+                                            &access,
+                                            serde_json::to_string(&config_info).unwrap(),
+                                        );
                                         let _ = sender
                                             .lock()
                                             .await
@@ -326,16 +790,73 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
                                             .await;
                                         handled = true;
                                     }
+                                    "requestHistory" => {
+                                        if let Some(msg) =
+                                            console_history_page(&text_str, &state).await
+                                        {
+                                            let _ = sender
+                                                .lock()
+                                                .await
+                                                .send(Message::Text(string_to_utf8bytes(msg)))
+                                                .await;
+                                        }
+                                        handled = true;
+                                    }
+                                    "requestAuditLog" => {
+                                        if let Some(msg) = audit_log_page(&text_str, &state).await {
+                                            let _ = sender
+                                                .lock()
+                                                .await
+                                                .send(Message::Text(string_to_utf8bytes(msg)))
+                                                .await;
+                                        }
+                                        handled = true;
+                                    }
+                                    "subscribe" if master_link => {
+                                        let snapshot = crate::slave::snapshot(&state).await;
+                                        let msg = serde_json::to_string(&snapshot).unwrap();
+                                        let _ = sender
+                                            .lock()
+                                            .await
+                                            .send(Message::Text(string_to_utf8bytes(msg)))
+                                            .await;
+                                        handled = true;
+                                    }
                                     _ => {}
                                 }
                             }
                         }
                         if !handled {
-                            tokio::spawn(process_message(text_str, state.clone()));
+                            tokio::spawn(process_message(
+                                text_str,
+                                state.clone(),
+                                access.clone(),
+                                address,
+                            ));
                         }
                     }
                     Ok(Message::Binary(bin)) => {
                         let mut handled = false;
+                        if let Some(decoded) = rmp_serde::from_slice::<serde_json::Value>(&bin)
+                            .ok()
+                            .and_then(|val| serde_json::to_string(&val).ok())
+                        {
+                            if let Err(error) = authorize(&access, &decoded, &state).await {
+                                send_permission_denied(&sender, &access, error).await;
+                                continue;
+                            }
+                            if let Some(action) = server_action_from(&decoded, &state).await {
+                                spawn_server_action(
+                                    action,
+                                    state.clone(),
+                                    access.clone(),
+                                    address,
+                                    sender.clone(),
+                                    true,
+                                );
+                                continue;
+                            }
+                        }
                         if let Ok(val) = rmp_serde::from_slice::<serde_json::Value>(&bin) {
                             if let Ok(decoded) = serde_json::to_string(&val) {
                                 if let Ok(json) =
@@ -345,89 +866,22 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
                                     {
                                         match ev_type {
                                             "requestInfo" => {
-                                                let servers = state.servers.lock().await;
-                                                let val = if let Ok(v) =
-                                                    serde_json::from_str::<SInfoRequestMessage>(
-                                                        &decoded,
-                                                    ) {
-                                                    v
-                                                } else {
-                                                    SInfoRequestMessage {
-                                                        r#type: "requestInfo".to_owned(),
-                                                        arguments: vec![true],
-                                                    }
-                                                };
-                                                let config = state.config.lock().await;
-                                                let mut info = ServerInfoMessage {
-                                                    r#type: "ServerInfo".to_owned(),
-                                                    servers: vec![],
-                                                    config: config.clone(),
-                                                };
-                                                drop(config);
-                                                let mut used_names: Vec<String> = vec![];
-                                                for server in servers.iter() {
-                                                    used_names.push(server.name.clone());
-                                                    let specialized_info = if let Some(handler) =
-                                                        server.specialization_handler.as_ref()
-                                                    {
-                                                        handler.get_status()
-                                                    } else {
-                                                        server
-                                                            .specialized_server_info
-                                                            .clone()
-                                                            .unwrap_or(serde_json::Value::Null)
-                                                    };
-                                                    let mut s_info = ServerInfo {
-                                                        name: server.name.clone(),
-                                                        output: "".to_owned(),
-                                                        active: true,
-                                                        specialization: server
-                                                            .specialized_server_type
-                                                            .clone(),
-                                                        specialized_info: Some(specialized_info),
-                                                        host: None,
-                                                    };
-                                                    if val
-                                                        .arguments
-                                                        .first()
-                                                        .copied()
-                                                        .unwrap_or(false)
-                                                    {
-                                                        let cl: String =
-                                                            server.curr_output_in_progress.clone();
-                                                        let split: Vec<&str> =
-                                                            cl.split("\n").collect();
-                                                        let mut inp = split.len();
-                                                        if inp < 150 {
-                                                            inp = 0;
-                                                        } else {
-                                                            inp -= 150;
-                                                        }
-                                                        s_info.output =
-                                                            split[inp..split.len()].join("\n");
-                                                    }
-                                                    info.servers.push(s_info);
-                                                }
-                                                drop(servers);
-                                                let config = state.config.lock().await;
-                                                for server_config in config.servers.iter() {
-                                                    if !used_names.contains(&server_config.name) {
-                                                        info.servers.push(ServerInfo {
-                                                            name: server_config.name.clone(),
-                                                            output: "".to_owned(),
-                                                            active: false,
-                                                            specialization: server_config
-                                                                .specialized_server_type
-                                                                .clone(),
-                                                            specialized_info: server_config
-                                                                .specialized_server_info
-                                                                .clone(),
-                                                            host: None,
-                                                        })
-                                                    }
-                                                }
-                                                drop(config);
-                                                let msg = serde_json::to_string(&info).unwrap();
+                                                let include_output = serde_json::from_str::<
+                                                    SInfoRequestMessage,
+                                                >(
+                                                    &decoded
+                                                )
+                                                .map(|v| {
+                                                    v.arguments.first().copied().unwrap_or(false)
+                                                })
+                                                .unwrap_or(true);
+                                                let info =
+                                                    server_info_message(&state, include_output)
+                                                        .await;
+                                                let msg = trimmed_for(
+                                                    &access,
+                                                    serde_json::to_string(&info).unwrap(),
+                                                );
                                                 if let Ok(bin) = rmp_serde::to_vec_named(
                                                     &serde_json::from_str::<serde_json::Value>(
                                                         &msg,
@@ -567,6 +1021,44 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
                                                 }
                                                 handled = true;
                                             }
+                                            "requestHistory" => {
+                                                if let Some(msg) =
+                                                    console_history_page(&decoded, &state).await
+                                                {
+                                                    if let Ok(bin) = rmp_serde::to_vec_named(
+                                                        &serde_json::from_str::<serde_json::Value>(
+                                                            &msg,
+                                                        )
+                                                        .unwrap(),
+                                                    ) {
+                                                        let _ = sender
+                                                            .lock()
+                                                            .await
+                                                            .send(Message::Binary(bin.into()))
+                                                            .await;
+                                                    }
+                                                }
+                                                handled = true;
+                                            }
+                                            "requestAuditLog" => {
+                                                if let Some(msg) =
+                                                    audit_log_page(&decoded, &state).await
+                                                {
+                                                    if let Ok(bin) = rmp_serde::to_vec_named(
+                                                        &serde_json::from_str::<serde_json::Value>(
+                                                            &msg,
+                                                        )
+                                                        .unwrap(),
+                                                    ) {
+                                                        let _ = sender
+                                                            .lock()
+                                                            .await
+                                                            .send(Message::Binary(bin.into()))
+                                                            .await;
+                                                    }
+                                                }
+                                                handled = true;
+                                            }
                                             _ => {}
                                         }
                                     }
@@ -576,7 +1068,12 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
                         if !handled {
                             if let Ok(decoded) = rmp_serde::from_slice::<serde_json::Value>(&bin) {
                                 if let Ok(decoded_str) = serde_json::to_string(&decoded) {
-                                    tokio::spawn(process_message(decoded_str, state.clone()));
+                                    tokio::spawn(process_message(
+                                        decoded_str,
+                                        state.clone(),
+                                        access.clone(),
+                                        address,
+                                    ));
                                 }
                             }
                         }
@@ -597,6 +1094,7 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
             send_task.abort()
         },
     };
+    clients.fetch_sub(1, Ordering::Relaxed);
 }
 
 /// Passes stdin input to a running server process.
@@ -606,12 +1104,10 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
 /// * `server_name` - The name of the server to send input to.
 /// * `state` - The shared application state.
 async fn pass_stdin(message: StdinInput, server_name: String, state: AppState) {
-    let value = message.value + "\r\n";
     let mut servers = state.servers.lock().await;
     for server in servers.iter_mut() {
         if server.name == server_name {
-            let stdi = server.process.stdin.as_mut().unwrap();
-            let res = stdi.write_all(value.as_bytes()).await;
+            let res = server.write_stdin(&message.value).await;
             match res {
                 Ok(_) => {}
                 Err(error) => {
@@ -623,6 +1119,157 @@ async fn pass_stdin(message: StdinInput, server_name: String, state: AppState) {
     }
     drop(servers);
 }
+
+/// Finds the slave a server runs on, if it isn't one of this node's own servers.
+///
+/// # Arguments
+/// * `state` - The shared application state.
+/// * `server_name` - The name of the server.
+async fn slave_host(state: &AppState, server_name: &str) -> Option<SlaveConnectionDescriptor> {
+    let local = state
+        .servers
+        .lock()
+        .await
+        .iter()
+        .any(|server| server.name == server_name)
+        || state
+            .config
+            .lock()
+            .await
+            .servers
+            .iter()
+            .any(|desc| desc.name == server_name);
+    if local {
+        return None;
+    }
+    state
+        .slave_servers
+        .lock()
+        .await
+        .iter()
+        .find(|server| server.name == server_name)
+        .and_then(|server| server.host.clone())
+}
+
+/// Carries out a server action on this node, or on the slave the server runs on.
+///
+/// # Arguments
+/// * `state` - The shared application state.
+/// * `access` - The client taking the action; the message has already passed [`authorize`].
+/// * `address` - Where the client connected from, for the audit log.
+/// * `action` - What to do.
+pub async fn run_server_action(
+    state: &AppState,
+    access: &Access,
+    address: SocketAddr,
+    action: ServerAction,
+) -> ServerActionResult {
+    if !matches!(
+        action.action.as_str(),
+        "stdin" | "start" | "stop" | "restart" | "terminate"
+    ) {
+        let error = format!("Unknown action '{}'", action.action);
+        return master::action_failed(&action, error);
+    }
+    let value = action.value.clone().unwrap_or_default();
+    let mut entry = AuditEntry::new(access, address, &action.action).on(&action.server_name);
+    if action.action == "stdin" {
+        entry = entry.command(&value);
+    }
+    audit::record(state, entry).await;
+    if let Some(host) = slave_host(state, &action.server_name).await {
+        return master::forward_action(state, &host, action).await;
+    }
+    let name = action.server_name.as_str();
+    let outcome = match action.action.as_str() {
+        "stdin" => servers::send_stdin(state, name, &value).await.map(|_| None),
+        "start" => servers::start_server(state, name).await.map(|_| None),
+        "restart" => servers::restart_server(state, name).await.map(|_| None),
+        _ => servers::stop_server(state, name).await,
+    };
+    match outcome {
+        Ok(exit_code) => ServerActionResult {
+            r#type: "ServerActionResult".to_owned(),
+            request_id: action.request_id,
+            server_name: action.server_name,
+            action: action.action,
+            success: true,
+            error: None,
+            exit_code,
+        },
+        Err(error) => master::action_failed(&action, error),
+    }
+}
+
+/// Picks out the messages that are answered with a `ServerActionResult`: `serverAction`, and
+/// `stdinInput` for a server on a slave.
+///
+/// # Arguments
+/// * `text` - The message as a JSON string.
+/// * `state` - The shared application state.
+async fn server_action_from(text: &str, state: &AppState) -> Option<ServerAction> {
+    let json: serde_json::Value = serde_json::from_str(text).ok()?;
+    match json["type"].as_str()? {
+        "serverAction" => serde_json::from_value(json).ok(),
+        "stdinInput" => {
+            let input: StdinInput = serde_json::from_value(json).ok()?;
+            slave_host(state, &input.server_name).await?;
+            let active = state
+                .slave_servers
+                .lock()
+                .await
+                .iter()
+                .any(|server| server.name == input.server_name && server.active);
+            // Same as for local servers: "start" starts a stopped server, "stop" cancels its
+            // pending restart
+            let action = match input.value.as_str() {
+                "start" | "stop" if !active => input.value.as_str(),
+                _ => "stdin",
+            };
+            Some(ServerAction {
+                r#type: "serverAction".to_owned(),
+                request_id: None,
+                server_name: input.server_name,
+                action: action.to_owned(),
+                value: Some(input.value),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Runs a server action in the background and sends its result to the requesting client only.
+///
+/// # Arguments
+/// * `sender` - The client's side of the socket.
+/// * `binary` - Whether to answer in MessagePack, as the request was.
+fn spawn_server_action<S>(
+    action: ServerAction,
+    state: AppState,
+    access: Access,
+    address: SocketAddr,
+    sender: std::sync::Arc<tokio::sync::Mutex<S>>,
+    binary: bool,
+) where
+    S: futures_util::Sink<Message> + Unpin + Send + 'static,
+{
+    tokio::spawn(async move {
+        let result = run_server_action(&state, &access, address, action).await;
+        if let Some(error) = &result.error {
+            warn!(
+                "{} of '{}' failed: {}",
+                result.action, result.server_name, error
+            );
+        }
+        let message = if binary {
+            Message::Binary(rmp_serde::to_vec_named(&result).unwrap().into())
+        } else {
+            Message::Text(string_to_utf8bytes(serde_json::to_string(&result).unwrap()))
+        };
+        let _ = sender.lock().await.send(message).await;
+    });
+}
+
 /// Processes a message received from the web client over websocket.
 ///
 /// Handles requests for config, themes, server info, stdin input, config changes, and server termination.
@@ -630,7 +1277,9 @@ async fn pass_stdin(message: StdinInput, server_name: String, state: AppState) {
 /// # Arguments
 /// * `text` - The received message as a string.
 /// * `state` - The shared application state.
-async fn process_message(text: String, state: AppState) {
+/// * `access` - What the sending client may do; the message has already passed [`authorize`].
+/// * `address` - Where the client connected from, for the audit log.
+async fn process_message(text: String, state: AppState, access: Access, address: SocketAddr) {
     // (No change to this function, but ensure that all .send(Message::Text(...)) in this file use Utf8Bytes::from(val) and all received Message::Text(text) are handled as Utf8Bytes and converted to String as needed.)
     // The main changes are in handle_socket above.
     // If you need to propagate Utf8Bytes usage deeper, do so in the master.rs file as well.
@@ -684,26 +1333,39 @@ async fn process_message(text: String, state: AppState) {
                         }
                     }
                     drop(servers);
+                    if is_active_server {
+                        audit::record(
+                            &state,
+                            AuditEntry::new(&access, address, "stdin")
+                                .on(&server_name)
+                                .command(&value.value),
+                        )
+                        .await;
+                    }
                     let config = state.config.lock().await;
                     #[allow(unused)]
                     let slave = config.slave;
                     drop(config);
+                    // If not active, "stop" cancels a pending crash restart
+                    if !is_active_server
+                        && value.value == "stop"
+                        && servers::stop_server(&state, &server_name).await.is_ok()
+                    {
+                        audit::record(
+                            &state,
+                            AuditEntry::new(&access, address, "stop").on(&server_name),
+                        )
+                        .await;
+                    }
                     // If not active, and value is "start", start the server
                     if !is_active_server && value.value == "start" {
-                        let config = state.config.lock().await;
-                        let mut desc: ControlledProgramDescriptor =
-                            ControlledProgramDescriptor::new("", "", vec![], "".to_owned());
-                        let mut found = false;
-                        for server_desc in config.servers.iter() {
-                            if server_desc.name == value.server_name {
-                                desc = server_desc.clone();
-                                found = true;
-                            }
-                        }
-                        if found {
-                            let mut servers = state.servers.lock().await;
-                            servers.push(desc.into_instance(&state.specialization_registry));
-                            drop(servers);
+                        audit::record(
+                            &state,
+                            AuditEntry::new(&access, address, "start").on(&server_name),
+                        )
+                        .await;
+                        if let Err(e) = servers::start_server(&state, &server_name).await {
+                            warn!("Could not start server '{}': {}", server_name, e);
                         }
                     }
                 }
@@ -729,51 +1391,280 @@ async fn process_message(text: String, state: AppState) {
                     return;
                 }
             };
-            let mut servers = state.servers.lock().await;
-            let mut config = state.config.lock().await;
-
-            // Stop all servers before changing config
-            for server in servers.iter_mut() {
-                let _ = server.stop().await;
+            let server_names: Vec<&str> = message
+                .updated_config
+                .servers
+                .iter()
+                .map(|desc| desc.name.as_str())
+                .collect();
+            audit::record(
+                &state,
+                AuditEntry::new(&access, address, "config-change")
+                    .summary(format!("servers: {}", server_names.join(", "))),
+            )
+            .await;
+            let result = config_edit::apply_config_change(&state, message.updated_config).await;
+            send_config_result(&state, "configChange", None, result);
+        }
+        "addServer" => {
+            let message: AddServer = match serde_json::from_str(&text) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    debug!("Error parsing addServer message: {}", e);
+                    return;
+                }
+            };
+            let server_name = message.server.name.clone();
+            audit::record(
+                &state,
+                AuditEntry::new(&access, address, "add-server").on(&server_name),
+            )
+            .await;
+            let result = config_edit::add_server(&state, message.server).await;
+            send_config_result(&state, "addServer", Some(server_name), result);
+        }
+        "updateServer" => {
+            let message: UpdateServer = match serde_json::from_str(&text) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    debug!("Error parsing updateServer message: {}", e);
+                    return;
+                }
+            };
+            let mut entry =
+                AuditEntry::new(&access, address, "update-server").on(&message.server_name);
+            if message.server.name != message.server_name {
+                entry = entry.summary(format!("renamed to {}", message.server.name));
             }
-            servers.clear();
-            config.change(message.updated_config);
-            config.update_config_file("config.json");
-            // Auto-start servers if needed
-            for desc in config.servers.iter_mut() {
-                if desc.auto_start {
-                    let desc_clone = desc.clone();
-                    servers.push(desc_clone.into_instance(&state.specialization_registry));
+            audit::record(&state, entry).await;
+            let result =
+                config_edit::update_server(&state, &message.server_name, message.server).await;
+            send_config_result(&state, "updateServer", Some(message.server_name), result);
+        }
+        "removeServer" => {
+            let message: RemoveServer = match serde_json::from_str(&text) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    debug!("Error parsing removeServer message: {}", e);
+                    return;
+                }
+            };
+            audit::record(
+                &state,
+                AuditEntry::new(&access, address, "remove-server").on(&message.server_name),
+            )
+            .await;
+            let result = config_edit::remove_server(&state, &message.server_name).await;
+            send_config_result(&state, "removeServer", Some(message.server_name), result);
+        }
+        "updateGlobalSettings" => {
+            let message: UpdateGlobalSettings = match serde_json::from_str(&text) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    debug!("Error parsing updateGlobalSettings message: {}", e);
+                    return;
+                }
+            };
+            let names: Vec<&str> = message.settings.keys().map(String::as_str).collect();
+            audit::record(
+                &state,
+                AuditEntry::new(&access, address, "global-settings").summary(names.join(", ")),
+            )
+            .await;
+            let result = config_edit::update_global_settings(&state, message.settings).await;
+            send_config_result(&state, "updateGlobalSettings", None, result);
+        }
+        "listSchedules" => {
+            let list = schedules_list(&state).await;
+            let _ = state.tx.send(serde_json::to_string(&list).unwrap());
+        }
+        "addSchedule" => {
+            let message: AddSchedule = match serde_json::from_str(&text) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    debug!("Error parsing addSchedule message: {}", e);
+                    return;
+                }
+            };
+            let schedule = message.schedule;
+            audit::record(
+                &state,
+                AuditEntry::new(&access, address, "add-schedule")
+                    .on(&message.server_name)
+                    .summary(schedule.id.clone()),
+            )
+            .await;
+            let result = match schedule.validate() {
+                Ok(()) => {
+                    change_schedules(&state, &message.server_name, |schedules| {
+                        schedules.retain(|existing| existing.id != schedule.id);
+                        schedules.push(schedule);
+                        Ok(())
+                    })
+                    .await
+                }
+                Err(e) => Err(e),
+            };
+            send_schedule_result(&state, message.server_name, result).await;
+        }
+        "removeSchedule" => {
+            let message: RemoveSchedule = match serde_json::from_str(&text) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    debug!("Error parsing removeSchedule message: {}", e);
+                    return;
+                }
+            };
+            audit::record(
+                &state,
+                AuditEntry::new(&access, address, "remove-schedule")
+                    .on(&message.server_name)
+                    .summary(message.id.clone()),
+            )
+            .await;
+            let result = change_schedules(&state, &message.server_name, |schedules| {
+                let before = schedules.len();
+                schedules.retain(|existing| existing.id != message.id);
+                if schedules.len() == before {
+                    Err(format!("No schedule with id '{}'", message.id))
+                } else {
+                    Ok(())
+                }
+            })
+            .await;
+            send_schedule_result(&state, message.server_name, result).await;
+        }
+        "triggerBackup" | "listBackups" | "restoreBackup" => {
+            let message: BackupRequest = match serde_json::from_str(&text) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    debug!("Error parsing {} message: {}", ev_type, e);
+                    return;
                 }
+            };
+            match ev_type {
+                "triggerBackup" => {
+                    audit::record(
+                        &state,
+                        AuditEntry::new(&access, address, "backup").on(&message.server_name),
+                    )
+                    .await
+                }
+                "restoreBackup" => {
+                    audit::record(
+                        &state,
+                        AuditEntry::new(&access, address, "restore-backup")
+                            .on(&message.server_name)
+                            .summary(message.file_name.clone().unwrap_or_default()),
+                    )
+                    .await
+                }
+                _ => {}
             }
-            // Broadcast ConfigInfo to all clients
-            let config_info = ConfigInfo {
-                r#type: "ConfigInfo".to_owned(),
-                config: config.clone(),
+            // Backups and restores can take minutes, so don't hold up other messages
+            let state = state.clone();
+            let ev_type = ev_type.to_owned();
+            tokio::spawn(async move {
+                match (ev_type.as_str(), &message.file_name) {
+                    ("triggerBackup", _) => {
+                        let _ = crate::backup::run_backup(&state, &message.server_name).await;
+                    }
+                    ("restoreBackup", Some(file_name)) => {
+                        let _ =
+                            crate::backup::restore_backup(&state, &message.server_name, file_name)
+                                .await;
+                    }
+                    ("restoreBackup", None) => {
+                        debug!("restoreBackup message is missing file_name");
+                        return;
+                    }
+                    _ => {}
+                }
+                send_backups_list(&state, message.server_name).await;
+            });
+        }
+        "resizeConsole" => {
+            let message: ResizeConsole = match serde_json::from_str(&text) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    debug!("Error parsing resizeConsole message: {}", e);
+                    return;
+                }
             };
-            let _ = state.tx.send(serde_json::to_string(&config_info).unwrap());
-            drop(config);
-            drop(servers);
+            if message.cols == 0 || message.rows == 0 {
+                return;
+            }
+            let servers = state.servers.lock().await;
+            if let Some(server) = servers
+                .iter()
+                .find(|server| server.name == message.server_name)
+            {
+                if let Err(e) = server.resize_console(message.cols, message.rows) {
+                    trace!("Could not resize console of '{}': {}", server.name, e);
+                }
+            }
         }
         // getConfig is now handled per-client in handle_socket, do nothing here
         "getConfig" => {}
         "terminateServers" => {
-            let mut servers = state.servers.lock().await;
-            for server in servers.iter_mut() {
-                let exit_code = server.stop().await;
-                let msg = format_exit_message(
-                    exit_code
-                        .map(|c| c.to_string())
-                        .unwrap_or_else(|| "unknown".to_string()),
-                );
-                let server_output = serde_json::json!({
-                    "type": "ServerOutput",
-                    "server_name": server.name.clone(),
-                    "output": msg,
-                });
-                let _ = state.tx.send(server_output.to_string());
-            }
-            servers.clear();
+            // Running servers and crashed ones waiting for their backoff, which should stay down
+            // as well, leaving the ones this client may not stop
+            let mut names: Vec<String> = state
+                .servers
+                .lock()
+                .await
+                .iter()
+                .map(|server| server.name.clone())
+                .chain(state.restart_trackers.lock().await.keys().cloned())
+                .filter(|name| access.allows(Permission::StartStop, name))
+                .collect();
+            names.sort();
+            names.dedup();
+            audit::record(
+                &state,
+                AuditEntry::new(&access, address, "terminate")
+                    .summary(format!("servers: {}", names.join(", "))),
+            )
+            .await;
+            futures_util::future::join_all(names.iter().map(|name| {
+                let state = state.clone();
+                async move {
+                    // Fails for servers that stopped or were stopped meanwhile, which is fine
+                    if let Err(e) = servers::stop_server(&state, name).await {
+                        debug!("Not terminating '{}': {}", name, e);
+                    }
+                }
+            }))
+            .await;
+            // Servers on slaves are stopped by their slave, which reports their exit itself
+            let remote: Vec<ServerInfo> = state
+                .slave_servers
+                .lock()
+                .await
+                .iter()
+                .filter(|server| {
+                    server.active && access.allows(Permission::StartStop, &server.name)
+                })
+                .cloned()
+                .collect();
+            futures_util::future::join_all(remote.into_iter().filter_map(|server| {
+                let host = server.host?;
+                let action = ServerAction {
+                    r#type: "serverAction".to_owned(),
+                    request_id: None,
+                    server_name: server.name,
+                    action: "terminate".to_owned(),
+                    value: None,
+                };
+                let state = state.clone();
+                Some(async move {
+                    let result = master::forward_action(&state, &host, action).await;
+                    if let Some(error) = result.error {
+                        warn!("Could not terminate '{}': {}", result.server_name, error);
+                    }
+                })
+            }))
+            .await;
         }
         _ => {}
     }